@@ -0,0 +1,183 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use futures::Stream;
+use serde::Deserialize;
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::error::ProxyError;
+use crate::proxy::AppState;
+use crate::usage_export::{self, ExportFormat};
+
+/// Refusal of a deployment-wide view to a tenant key
+fn tenant_forbidden() -> Response {
+    ProxyError::Permission("Tenant keys can only see their own requests and usage".to_string()).into_response()
+}
+
+/// Server-sent stream of live proxy events (requests, token refreshes, errors). Not
+/// available to tenants, since events span all of them.
+pub async fn admin_events(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if state.tenant_of(&headers).is_some() {
+        return tenant_forbidden();
+    }
+    events_stream(state).into_response()
+}
+
+fn events_stream(state: AppState) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let mut receiver = state.events.subscribe();
+
+    let stream = async_stream::stream! {
+        loop {
+            match receiver.recv().await {
+                Ok(envelope) => {
+                    let data = serde_json::to_string(&envelope).unwrap_or_default();
+                    yield Ok(Event::default().event(envelope.event.name()).data(data));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Admin event subscriber lagged, skipped {} events", skipped);
+                    yield Ok(Event::default().event("lagged").data(skipped.to_string()));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestsQuery {
+    pub limit: Option<usize>,
+    /// Only this tenant's requests; tenant keys always get just their own
+    pub tenant: Option<String>,
+}
+
+/// Most recent requests handled by the proxy, newest first
+pub async fn admin_requests(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<RequestsQuery>,
+) -> impl IntoResponse {
+    let limit = query.limit.unwrap_or(usize::MAX);
+    let tenant = state.tenant_of(&headers).map(|t| t.name.clone()).or(query.tenant);
+    let records = match &tenant {
+        Some(tenant) => state.history.recent_for_tenant(tenant, limit),
+        None => state.history.recent(limit),
+    };
+    Json(json!({
+        "count": records.len(),
+        "requests": records,
+    }))
+}
+
+/// Most recent token refresh attempts of the main account, newest first
+pub async fn admin_refreshes(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<RequestsQuery>,
+) -> impl IntoResponse {
+    if state.tenant_of(&headers).is_some() {
+        return tenant_forbidden();
+    }
+    match state.oauth_manager.refresh_audit().recent(query.limit.unwrap_or(100)) {
+        Ok(entries) => Json(json!({
+            "count": entries.len(),
+            "refreshes": entries,
+        }))
+        .into_response(),
+        Err(e) => ProxyError::Internal(format!("Failed to read refresh audit log: {}", e)).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsageExportQuery {
+    /// RFC 3339 time or date, inclusive
+    pub from: Option<String>,
+    /// RFC 3339 time or date, exclusive
+    pub to: Option<String>,
+    pub format: Option<String>,
+    /// Only this usage bucket; tenant keys always get just their own
+    pub tenant: Option<String>,
+}
+
+/// Persisted usage records in a time range, as CSV or Parquet
+pub async fn usage_export(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<UsageExportQuery>,
+) -> impl IntoResponse {
+    let bad_request = |message: String| ProxyError::InvalidRequest(message).into_response();
+    let format = query.format.as_deref().unwrap_or("csv");
+    let Some(format) = ExportFormat::parse(format) else {
+        return bad_request(format!("unknown export format '{}', expected csv or parquet", format));
+    };
+    let (from, to) = match usage_export::parse_range(query.from.as_deref(), query.to.as_deref()) {
+        Ok(range) => range,
+        Err(message) => return bad_request(message),
+    };
+
+    let bucket = state.tenant_of(&headers).map(|t| t.usage_bucket.clone()).or(query.tenant);
+    let exported = state.usage_log.read(from, to).and_then(|mut records| {
+        if let Some(bucket) = &bucket {
+            records.retain(|r| r.tenant.as_ref() == Some(bucket));
+        }
+        usage_export::export(&records, format)
+    });
+    match exported {
+        Ok(body) => (
+            [
+                (header::CONTENT_TYPE, format.content_type().to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"usage.{}\"", format.extension()),
+                ),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(e) => ProxyError::Internal(format!("Failed to export usage: {}", e)).into_response(),
+    }
+}
+
+/// Prometheus scrape endpoint
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    // Without tokens, report 0 so "about to expire" alerts fire
+    let expires_in = match state.oauth_manager.storage().load_tokens() {
+        Ok(Some(tokens)) => tokens.expires_at - chrono::Utc::now().timestamp(),
+        _ => 0,
+    };
+    state.metrics.set_token_expires_in(expires_in);
+    state.metrics.set_admission(&state.admission.snapshot());
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+/// Rolling latency percentiles, error rate and throughput over the last minute, 5 minutes
+/// and hour, with the admission queue's current load and wait times
+pub async fn stats(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if state.tenant_of(&headers).is_some() {
+        return tenant_forbidden();
+    }
+    let windows: serde_json::Map<String, serde_json::Value> = state
+        .stats
+        .snapshot()
+        .into_iter()
+        .map(|(label, window)| (label.to_string(), json!(window)))
+        .collect();
+    Json(json!({
+        "windows": windows,
+        "admission": state.admission.snapshot(),
+        "queue_wait": state.metrics.queue_waits(),
+    }))
+    .into_response()
+}
@@ -0,0 +1,374 @@
+use axum::{body::Body, response::Response};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Assumed request duration until the first request completes
+const INITIAL_HOLD_SECS: f64 = 1.0;
+
+/// Scheduling class of a request. Queued requests of a higher class (declared first)
+/// are always admitted before those of a lower one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Requested per call with X-Maximize-Priority by keys allowed to do so
+    Urgent,
+    #[default]
+    Interactive,
+    Batch,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::Urgent, Priority::Interactive, Priority::Batch];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "urgent" => Some(Priority::Urgent),
+            "interactive" => Some(Priority::Interactive),
+            "batch" => Some(Priority::Batch),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::Urgent => "urgent",
+            Priority::Interactive => "interactive",
+            Priority::Batch => "batch",
+        }
+    }
+}
+
+/// Waiters of one priority class, served round-robin by key so no single key can
+/// monopolize the class
+#[derive(Default)]
+struct ClassQueue {
+    /// Keys with waiters, in the order they get their next turn
+    turns: VecDeque<String>,
+    waiters: HashMap<String, VecDeque<oneshot::Sender<AdmissionPermit>>>,
+}
+
+impl ClassQueue {
+    fn push(&mut self, key: String, waiter: oneshot::Sender<AdmissionPermit>) {
+        let queue = self.waiters.entry(key.clone()).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(key);
+        }
+        queue.push_back(waiter);
+    }
+
+    fn pop(&mut self) -> Option<oneshot::Sender<AdmissionPermit>> {
+        let key = self.turns.pop_front()?;
+        let queue = self.waiters.get_mut(&key)?;
+        let waiter = queue.pop_front();
+        if queue.is_empty() {
+            self.waiters.remove(&key);
+        } else {
+            self.turns.push_back(key);
+        }
+        waiter
+    }
+
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+
+    /// Waiters whose client is still waiting
+    fn live_waiters(&self) -> usize {
+        self.waiters.values().flatten().filter(|w| !w.is_closed()).count()
+    }
+}
+
+struct QueueState {
+    in_flight: usize,
+    classes: HashMap<Priority, ClassQueue>,
+    /// Moving average of how long requests hold a slot, for Retry-After estimates
+    avg_hold_secs: f64,
+    /// Lower concurrency limit set while the upstream account is short on capacity
+    throttle: Option<usize>,
+}
+
+impl QueueState {
+    fn queued(&self) -> usize {
+        self.classes.values().map(|class| class.live_waiters()).sum()
+    }
+
+    fn has_waiters(&self) -> bool {
+        self.classes.values().any(|class| !class.is_empty())
+    }
+
+    /// Next live waiter: highest priority class first, skipping clients that gave up
+    fn next_waiter(&mut self) -> Option<oneshot::Sender<AdmissionPermit>> {
+        for priority in Priority::ALL {
+            let Some(class) = self.classes.get_mut(&priority) else {
+                continue;
+            };
+            while let Some(waiter) = class.pop() {
+                if !waiter.is_closed() {
+                    return Some(waiter);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Current load of the admission queue, as reported on /stats and /metrics
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueSnapshot {
+    /// Requests holding a slot, streaming ones until their last byte is sent
+    pub in_flight: usize,
+    /// Clients still waiting for a slot, by priority class
+    pub queued: BTreeMap<Priority, usize>,
+    /// Concurrency limit in effect, lowered by any throttle; None if unlimited
+    pub limit: Option<usize>,
+}
+
+/// Why a request was turned away instead of queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueRejection {
+    /// `max_queue_depth` requests were already waiting
+    Full { retry_after: u64 },
+    /// No slot freed up within `max_wait`
+    Timeout { retry_after: u64 },
+}
+
+impl QueueRejection {
+    /// Seconds the client should wait before retrying
+    pub fn retry_after(&self) -> u64 {
+        match self {
+            QueueRejection::Full { retry_after } | QueueRejection::Timeout { retry_after } => *retry_after,
+        }
+    }
+}
+
+/// Overload thresholds and the traffic shed once one is exceeded
+#[derive(Debug, Clone, Default)]
+pub struct OverloadPolicy {
+    /// Requests in flight above which shedding starts; 0 disables the check
+    pub max_in_flight: usize,
+    /// Resident memory in MiB above which shedding starts; 0 disables the check
+    pub max_memory_mb: u64,
+    /// Priority classes rejected while overloaded
+    pub shed_priorities: Vec<Priority>,
+    /// Also reject non-streaming requests while overloaded
+    pub shed_non_streaming: bool,
+}
+
+impl OverloadPolicy {
+    /// Why the proxy counts as overloaded right now, if it does
+    pub fn overloaded(&self, in_flight: usize) -> Option<String> {
+        if self.max_in_flight > 0 && in_flight >= self.max_in_flight {
+            return Some(format!("{} requests in flight", in_flight));
+        }
+        if self.max_memory_mb > 0 {
+            if let Some(resident_mb) = resident_memory_mb() {
+                if resident_mb >= self.max_memory_mb {
+                    return Some(format!("{} MiB resident memory", resident_mb));
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether a request of this kind is rejected while overloaded
+    pub fn sheds(&self, priority: Priority, stream: bool) -> bool {
+        self.shed_priorities.contains(&priority) || (self.shed_non_streaming && !stream)
+    }
+}
+
+/// Resident set size of this process; only available on Linux
+fn resident_memory_mb() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+        let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+        // Assumes 4 KiB pages, which holds on the platforms this runs on
+        Some(pages * 4096 / (1024 * 1024))
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Limits how many requests are forwarded upstream at once. Requests over the limit
+/// wait for a slot, ordered by their priority.
+pub struct AdmissionQueue {
+    /// Concurrent requests allowed; 0 admits everything immediately unless throttled
+    max_in_flight: usize,
+    /// Waiting requests allowed; 0 means unbounded
+    max_queue_depth: usize,
+    /// Longest a request may wait for a slot
+    max_wait: Option<Duration>,
+    state: Mutex<QueueState>,
+}
+
+impl AdmissionQueue {
+    pub fn new(max_in_flight: usize, max_queue_depth: usize, max_wait: Option<Duration>) -> Arc<Self> {
+        Arc::new(Self {
+            max_in_flight,
+            max_queue_depth,
+            max_wait,
+            state: Mutex::new(QueueState {
+                in_flight: 0,
+                classes: HashMap::new(),
+                avg_hold_secs: INITIAL_HOLD_SECS,
+                throttle: None,
+            }),
+        })
+    }
+
+    /// Wait for a slot. `key` identifies the client for fair sharing within its class.
+    pub async fn acquire(self: &Arc<Self>, key: &str, priority: Priority) -> Result<AdmissionPermit, QueueRejection> {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            // Don't overtake requests that are already waiting
+            if self.limit(&state).is_none_or(|limit| state.in_flight < limit && !state.has_waiters()) {
+                state.in_flight += 1;
+                return Ok(self.permit());
+            }
+
+            let queued = state.queued();
+            if self.max_queue_depth > 0 && queued >= self.max_queue_depth {
+                return Err(QueueRejection::Full {
+                    retry_after: self.estimate_wait(&state, queued),
+                });
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            state.classes.entry(priority).or_default().push(key.to_string(), sender);
+            receiver
+        };
+
+        // Waiters are only dropped once their receiver is gone, so this always receives.
+        // A permit sent just as the timeout fires is dropped with the receiver, freeing the slot.
+        let received = match self.max_wait {
+            Some(max_wait) => tokio::time::timeout(max_wait, receiver).await,
+            None => Ok(receiver.await),
+        };
+        match received {
+            Ok(permit) => Ok(permit.expect("admission queue dropped a waiting request")),
+            Err(_) => {
+                let state = self.state.lock().unwrap();
+                Err(QueueRejection::Timeout {
+                    retry_after: self.estimate_wait(&state, state.queued()),
+                })
+            }
+        }
+    }
+
+    /// Requests currently holding a slot
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let state = self.state.lock().unwrap();
+        let queued = Priority::ALL
+            .into_iter()
+            .map(|priority| (priority, state.classes.get(&priority).map_or(0, ClassQueue::live_waiters)))
+            .collect();
+        QueueSnapshot {
+            in_flight: state.in_flight,
+            queued,
+            limit: self.limit(&state),
+        }
+    }
+
+    /// Concurrency limit in effect: the configured one, lowered by the throttle; None if unlimited
+    fn limit(&self, state: &QueueState) -> Option<usize> {
+        match (self.max_in_flight, state.throttle) {
+            (0, throttle) => throttle,
+            (max, None) => Some(max),
+            (max, Some(throttle)) => Some(throttle.min(max)),
+        }
+    }
+
+    /// Throttle currently lowering the concurrency limit, if any
+    pub fn throttle(&self) -> Option<usize> {
+        self.state.lock().unwrap().throttle
+    }
+
+    /// Lower the concurrency limit to `limit` (at least 1), or lift the throttle with None.
+    /// Requests already in flight keep their slots; waiters are admitted if the limit rose.
+    pub fn set_throttle(self: &Arc<Self>, limit: Option<usize>) {
+        let admitted = {
+            let mut state = self.state.lock().unwrap();
+            state.throttle = limit.map(|limit| limit.max(1));
+            let mut admitted = Vec::new();
+            while self.limit(&state).is_none_or(|limit| state.in_flight < limit) {
+                let Some(waiter) = state.next_waiter() else {
+                    break;
+                };
+                state.in_flight += 1;
+                admitted.push(waiter);
+            }
+            admitted
+        };
+        for waiter in admitted {
+            let _ = waiter.send(self.permit());
+        }
+    }
+
+    /// Seconds until `queued` waiting requests have been admitted, at the recent pace
+    fn estimate_wait(&self, state: &QueueState, queued: usize) -> u64 {
+        let rounds = (queued + 1) as f64 / self.limit(state).unwrap_or(1).max(1) as f64;
+        (rounds * state.avg_hold_secs).ceil().max(1.0) as u64
+    }
+
+    fn permit(self: &Arc<Self>) -> AdmissionPermit {
+        AdmissionPermit {
+            queue: self.clone(),
+            admitted_at: Instant::now(),
+        }
+    }
+
+    /// Hand the finished request's slot to the next waiter, or free it
+    fn release(self: &Arc<Self>, held: Duration) {
+        let next = {
+            let mut state = self.state.lock().unwrap();
+            state.avg_hold_secs = 0.8 * state.avg_hold_secs + 0.2 * held.as_secs_f64();
+            // Under a throttle lowered below the requests in flight, let slots drain
+            let over_limit = self.limit(&state).is_some_and(|limit| state.in_flight > limit);
+            let next = if over_limit { None } else { state.next_waiter() };
+            if next.is_none() {
+                state.in_flight -= 1;
+            }
+            next
+        };
+
+        // If the waiter gave up in the meantime, the returned permit is dropped and
+        // releases the slot again to the one after it
+        if let Some(waiter) = next {
+            let _ = waiter.send(self.permit());
+        }
+    }
+}
+
+/// A slot in the admission queue, released when dropped
+pub struct AdmissionPermit {
+    queue: Arc<AdmissionQueue>,
+    admitted_at: Instant,
+}
+
+impl AdmissionPermit {
+    /// Keep the slot until the response body has been fully sent, so streaming
+    /// responses count as in flight for as long as they run
+    pub fn hold_until_complete(self, response: Response) -> Response {
+        response.map(|body| {
+            let stream = body.into_data_stream().map(move |chunk| {
+                let _permit = &self;
+                chunk
+            });
+            Body::from_stream(stream)
+        })
+    }
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        self.queue.release(self.admitted_at.elapsed());
+    }
+}
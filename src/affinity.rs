@@ -0,0 +1,44 @@
+use sha2::{Digest, Sha256};
+
+use crate::proxy::AnthropicMessageRequest;
+
+/// Key identifying which cached prefix a request builds on.
+///
+/// Requests in the same session share a key; otherwise the key is derived from the
+/// stable part of the prompt (model, tools, system and the first user turn), so every
+/// turn of a conversation hashes the same way.
+pub fn affinity_key(request: &AnthropicMessageRequest, session_id: Option<&str>) -> String {
+    if let Some(session_id) = session_id {
+        return format!("session:{}", session_id);
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(request.model.as_bytes());
+    if let Some(tools) = &request.tools {
+        hasher.update(serde_json::to_vec(tools).unwrap_or_default());
+    }
+    if let Some(system) = &request.system {
+        hasher.update(system.to_string().as_bytes());
+    }
+    if let Some(first) = request.messages.first() {
+        hasher.update(first.to_string().as_bytes());
+    }
+
+    let digest = hasher.finalize();
+    format!("prefix:{}", digest.iter().take(8).map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+fn score(key: &str, account: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(key.as_bytes())
+        .chain_update([0u8])
+        .chain_update(account.as_bytes())
+        .finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Pick an account for `key` by rendezvous hashing, so the same key keeps landing on the
+/// same account and only keys owned by a removed account move when the pool changes.
+pub fn pick_account<'a>(key: &str, accounts: &'a [String]) -> Option<&'a String> {
+    accounts.iter().max_by_key(|account| score(key, account))
+}
@@ -0,0 +1,234 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+
+use crate::events::{EventBus, ProxyEvent};
+use crate::oauth::OAuthManager;
+use crate::settings::{AlertWebhook, Settings, WebhookFormat};
+
+/// How often token expiry is checked
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Conditions that trigger an alert
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    TokenRefreshFailed,
+    TokenRevoked,
+    TokenExpiring,
+    UpstreamUnauthorized,
+}
+
+impl AlertKind {
+    /// Name used in webhook `events` filters and payloads
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlertKind::TokenRefreshFailed => "token_refresh_failed",
+            AlertKind::TokenRevoked => "token_revoked",
+            AlertKind::TokenExpiring => "token_expiring",
+            AlertKind::UpstreamUnauthorized => "upstream_unauthorized",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub message: String,
+    pub details: Value,
+    pub timestamp: i64,
+}
+
+impl Alert {
+    pub fn new(kind: AlertKind, message: impl Into<String>, details: Value) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            details,
+            timestamp: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    /// Webhook body in the shape the destination expects
+    pub fn payload(&self, format: WebhookFormat) -> Value {
+        match format {
+            WebhookFormat::Json => json!(self),
+            WebhookFormat::Slack => json!({
+                "text": format!("🚨 *maximize* `{}`\n{}", self.kind.name(), self.message)
+            }),
+            WebhookFormat::Discord => {
+                // Discord rejects messages over 2000 characters
+                let content: String = format!("🚨 **maximize** `{}`\n{}", self.kind.name(), self.message)
+                    .chars()
+                    .take(2000)
+                    .collect();
+                json!({ "content": content })
+            }
+        }
+    }
+}
+
+/// Delivers alerts to the configured webhooks
+pub struct Notifier {
+    webhooks: Vec<AlertWebhook>,
+    client: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new(webhooks: Vec<AlertWebhook>) -> Self {
+        Self {
+            webhooks,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    pub async fn send(&self, alert: &Alert) {
+        warn!("🚨 Alert {}: {}", alert.kind.name(), alert.message);
+
+        let targets = self
+            .webhooks
+            .iter()
+            .filter(|w| w.events.is_empty() || w.events.iter().any(|e| e == alert.kind.name()));
+        for webhook in targets {
+            match self.client.post(&webhook.url).json(&alert.payload(webhook.format)).send().await {
+                Ok(response) if response.status().is_success() => {}
+                Ok(response) => error!("Alert webhook ({:?}) returned {}", webhook.format, response.status()),
+                Err(e) => error!("Failed to deliver alert ({:?}): {}", webhook.format, e),
+            }
+        }
+    }
+}
+
+/// Start background alerting if any webhook is configured: watches proxy events for failed
+/// refreshes and sustained upstream 401s, and periodically checks for tokens about to
+/// expire without a way to refresh them. Must be called from within a Tokio runtime.
+pub fn spawn_monitor(settings: &Settings, oauth_manager: Arc<OAuthManager>) {
+    if settings.alert_webhooks.is_empty() {
+        return;
+    }
+    info!("🚨 Alerts enabled, posting to {} webhook(s)", settings.alert_webhooks.len());
+
+    let notifier = Arc::new(Notifier::new(settings.alert_webhooks.clone()));
+    let refresh_failing = Arc::new(AtomicBool::new(false));
+    tokio::spawn(watch_events(
+        oauth_manager.events().clone(),
+        notifier.clone(),
+        refresh_failing.clone(),
+        settings.alert_unauthorized_threshold,
+    ));
+    tokio::spawn(watch_expiry(
+        oauth_manager,
+        notifier,
+        refresh_failing,
+        settings.alert_expiry_warning_hours,
+    ));
+}
+
+async fn watch_events(
+    events: EventBus,
+    notifier: Arc<Notifier>,
+    refresh_failing: Arc<AtomicBool>,
+    unauthorized_threshold: u64,
+) {
+    let mut receiver = events.subscribe();
+    let mut consecutive_unauthorized = 0u64;
+
+    loop {
+        let envelope = match receiver.recv().await {
+            Ok(envelope) => envelope,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+
+        match envelope.event {
+            ProxyEvent::TokenRefreshFailed { message } => {
+                // Alert on the first failure only, until a refresh succeeds again
+                if refresh_failing.swap(true, Ordering::Relaxed) {
+                    continue;
+                }
+                let alert = Alert::new(
+                    AlertKind::TokenRefreshFailed,
+                    format!("Token refresh failed: {}", message),
+                    json!({ "error": message }),
+                );
+                notifier.send(&alert).await;
+            }
+            ProxyEvent::TokenRevoked { message } => {
+                let alert = Alert::new(
+                    AlertKind::TokenRevoked,
+                    "Refresh token was revoked; log in again to restore access",
+                    json!({ "error": message }),
+                );
+                notifier.send(&alert).await;
+            }
+            ProxyEvent::RequestFailed { status: 401, message, .. } => {
+                consecutive_unauthorized += 1;
+                // Alert once when the threshold is crossed, not on every 401 after it
+                if consecutive_unauthorized == unauthorized_threshold {
+                    let alert = Alert::new(
+                        AlertKind::UpstreamUnauthorized,
+                        format!("{} consecutive requests failed with 401", consecutive_unauthorized),
+                        json!({ "count": consecutive_unauthorized, "last_error": message }),
+                    );
+                    notifier.send(&alert).await;
+                }
+            }
+            ProxyEvent::TokenRefreshed { .. } => {
+                refresh_failing.store(false, Ordering::Relaxed);
+                consecutive_unauthorized = 0;
+            }
+            ProxyEvent::RequestFinished { .. } => {
+                consecutive_unauthorized = 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn watch_expiry(
+    oauth_manager: Arc<OAuthManager>,
+    notifier: Arc<Notifier>,
+    refresh_failing: Arc<AtomicBool>,
+    warning_hours: u64,
+) {
+    let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+    // Expiry time already alerted about, so each token is reported once
+    let mut alerted_for: Option<i64> = None;
+
+    loop {
+        interval.tick().await;
+
+        let storage = oauth_manager.storage();
+        let Ok(Some(tokens)) = storage.load_tokens() else {
+            continue;
+        };
+        // Refreshable tokens are renewed on demand; only warn when refreshing isn't possible
+        let can_refresh = !tokens.refresh_token.is_empty()
+            && tokens.revoked_at.is_none()
+            && !refresh_failing.load(Ordering::Relaxed);
+        if can_refresh || alerted_for == Some(tokens.expires_at) {
+            continue;
+        }
+
+        let remaining = tokens.expires_at - chrono::Utc::now().timestamp();
+        if remaining < (warning_hours * 3600) as i64 {
+            let alert = Alert::new(
+                AlertKind::TokenExpiring,
+                format!(
+                    "Access token expires in {} minutes and cannot be refreshed",
+                    remaining.max(0) / 60
+                ),
+                json!({ "expires_at": tokens.expires_at, "expires_in_seconds": remaining }),
+            );
+            notifier.send(&alert).await;
+            alerted_for = Some(tokens.expires_at);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use axum::body::Bytes;
+use base64::{engine::general_purpose, Engine};
+use futures::{Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::io;
+
+use crate::proxy::AnthropicMessageRequest;
+use crate::settings::BedrockConfig;
+use crate::upstream::UpstreamResponse;
+
+/// `anthropic_version` Bedrock expects in the request body
+const BEDROCK_ANTHROPIC_VERSION: &str = "bedrock-2023-05-31";
+
+/// Sends Messages API requests to Anthropic models on AWS Bedrock, signed with SigV4
+pub struct BedrockClient {
+    config: BedrockConfig,
+    access_key_id: String,
+    secret_access_key: String,
+    client: reqwest::Client,
+}
+
+impl BedrockClient {
+    /// None unless AWS credentials are configured
+    pub fn new(config: &BedrockConfig, client: reqwest::Client) -> Option<Self> {
+        Some(Self {
+            access_key_id: config.access_key_id.clone()?,
+            secret_access_key: config.secret_access_key.clone()?,
+            config: config.clone(),
+            client,
+        })
+    }
+
+    /// Bedrock model ID for an Anthropic model name: configured, or derived the way
+    /// Bedrock names Anthropic's models
+    pub fn model_id(&self, model: &str) -> String {
+        self.config
+            .model_ids
+            .get(model)
+            .cloned()
+            .unwrap_or_else(|| format!("anthropic.{}-v1:0", model))
+    }
+
+    pub async fn send(
+        &self,
+        request: &AnthropicMessageRequest,
+        betas: &[&str],
+    ) -> Result<UpstreamResponse, reqwest::Error> {
+        let action = if request.stream { "invoke-with-response-stream" } else { "invoke" };
+        let host = format!("bedrock-runtime.{}.amazonaws.com", self.config.region);
+        let path = format!("/model/{}/{}", uri_encode(&self.model_id(&request.model)), action);
+
+        // Bedrock takes the model from the path and the version and betas in the body, and
+        // has no service tiers
+        let mut body = serde_json::to_value(request).unwrap_or_else(|_| json!({}));
+        if let Value::Object(map) = &mut body {
+            map.remove("model");
+            map.remove("stream");
+            map.remove("service_tier");
+            map.insert("anthropic_version".to_string(), json!(BEDROCK_ANTHROPIC_VERSION));
+            if !betas.is_empty() {
+                map.insert("anthropic_beta".to_string(), json!(betas));
+            }
+        }
+        let payload = serde_json::to_vec(&body).unwrap_or_default();
+
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let mut headers = vec![
+            ("content-type", "application/json".to_string()),
+            ("host", host.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.config.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let authorization = self.authorization(&path, &headers, &payload, &amz_date);
+
+        let mut builder = self
+            .client
+            .post(format!("https://{}{}", host, path))
+            .header("authorization", authorization)
+            .body(payload);
+        for (name, value) in headers {
+            builder = builder.header(name, value);
+        }
+        let response = builder.send().await?;
+        Ok(UpstreamResponse::bedrock(response, request.stream))
+    }
+
+    /// SigV4 Authorization header. `headers` must be sorted by lowercase name.
+    fn authorization(&self, path: &str, headers: &[(&str, String)], payload: &[u8], amz_date: &str) -> String {
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/bedrock/aws4_request", date, self.config.region);
+
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        // Services other than S3 expect the already-encoded path to be encoded again
+        let canonical_request = format!(
+            "POST\n{}\n\n{}\n{}\n{}",
+            uri_encode_path(path),
+            canonical_headers,
+            signed_headers,
+            hex(&Sha256::digest(payload))
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let mut key = hmac(format!("AWS4{}", self.secret_access_key).as_bytes(), date.as_bytes());
+        for part in [self.config.region.as_str(), "bedrock", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        )
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// RFC 3986 encoding of everything but unreserved characters
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn uri_encode_path(path: &str) -> String {
+    path.split('/').map(uri_encode).collect::<Vec<_>>().join("/")
+}
+
+/// Convert Bedrock's binary event stream (application/vnd.amazon.eventstream) into
+/// the server-sent events Anthropic's API would have sent
+pub fn event_stream_to_sse(
+    upstream: impl Stream<Item = io::Result<Bytes>> + Send + 'static,
+) -> impl Stream<Item = io::Result<Bytes>> + Send + 'static {
+    async_stream::stream! {
+        let mut upstream = Box::pin(upstream);
+        let mut buffer: Vec<u8> = Vec::new();
+
+        while let Some(chunk) = upstream.next().await {
+            match chunk {
+                Ok(bytes) => buffer.extend_from_slice(&bytes),
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+
+            let mut events = String::new();
+            while let Some(message) = take_message(&mut buffer) {
+                match message {
+                    Ok(message) => events.push_str(&message.to_sse()),
+                    Err(e) => {
+                        yield Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                        return;
+                    }
+                }
+            }
+            if !events.is_empty() {
+                yield Ok(Bytes::from(events));
+            }
+        }
+    }
+}
+
+/// One decoded event stream message
+struct EventMessage {
+    message_type: String,
+    event_type: String,
+    payload: Vec<u8>,
+}
+
+impl EventMessage {
+    fn to_sse(&self) -> String {
+        let payload: Value = serde_json::from_slice(&self.payload).unwrap_or(Value::Null);
+
+        if self.message_type == "event" && self.event_type == "chunk" {
+            // The Anthropic event, base64-encoded
+            let event = payload
+                .get("bytes")
+                .and_then(|b| b.as_str())
+                .and_then(|b| general_purpose::STANDARD.decode(b).ok())
+                .and_then(|b| serde_json::from_slice::<Value>(&b).ok());
+            return match event {
+                Some(event) => {
+                    let name = event.get("type").and_then(|t| t.as_str()).unwrap_or("message").to_string();
+                    format!("event: {}\ndata: {}\n\n", name, event)
+                }
+                None => String::new(),
+            };
+        }
+
+        let message = payload
+            .get("message")
+            .and_then(|m| m.as_str())
+            .unwrap_or("Bedrock stream error");
+        let error = json!({
+            "type": "error",
+            "error": {"type": self.event_type, "message": message}
+        });
+        format!("event: error\ndata: {}\n\n", error)
+    }
+}
+
+/// Remove and decode the first complete message in `buffer`, if there is one
+fn take_message(buffer: &mut Vec<u8>) -> Option<Result<EventMessage, String>> {
+    if buffer.len() < 12 {
+        return None;
+    }
+    let total_len = u32::from_be_bytes(buffer[0..4].try_into().unwrap()) as usize;
+    let headers_len = u32::from_be_bytes(buffer[4..8].try_into().unwrap()) as usize;
+    if total_len < 16 + headers_len {
+        return Some(Err(format!("invalid event stream message length {}", total_len)));
+    }
+    if buffer.len() < total_len {
+        return None;
+    }
+
+    let message: Vec<u8> = buffer.drain(..total_len).collect();
+    let headers = &message[12..12 + headers_len];
+    let payload = message[12 + headers_len..total_len - 4].to_vec();
+
+    Some(parse_headers(headers).map(|(message_type, event_type)| EventMessage {
+        message_type,
+        event_type,
+        payload,
+    }))
+}
+
+/// The `:message-type` and `:event-type` (or `:exception-type`) headers of a message
+fn parse_headers(headers: &[u8]) -> Result<(String, String), String> {
+    let truncated = || "truncated event stream header".to_string();
+    let mut message_type = String::new();
+    let mut event_type = String::new();
+    let mut pos = 0;
+
+    while pos < headers.len() {
+        let name_len = headers[pos] as usize;
+        let name = headers.get(pos + 1..pos + 1 + name_len).ok_or_else(truncated)?;
+        pos += 1 + name_len;
+        let value_type = *headers.get(pos).ok_or_else(truncated)?;
+        pos += 1;
+        let value_len = match value_type {
+            0 | 1 => 0,
+            2 => 1,
+            3 => 2,
+            4 => 4,
+            5 | 8 => 8,
+            9 => 16,
+            6 | 7 => {
+                let len = headers.get(pos..pos + 2).ok_or_else(truncated)?;
+                pos += 2;
+                u16::from_be_bytes([len[0], len[1]]) as usize
+            }
+            other => return Err(format!("unknown event stream header type {}", other)),
+        };
+        let value = String::from_utf8_lossy(headers.get(pos..pos + value_len).ok_or_else(truncated)?).to_string();
+        pos += value_len;
+
+        match name {
+            b":message-type" => message_type = value,
+            b":event-type" | b":exception-type" => event_type = value,
+            _ => {}
+        }
+    }
+
+    Ok((message_type, event_type))
+}
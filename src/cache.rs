@@ -0,0 +1,186 @@
+use serde_json::{json, Map, Value};
+use tracing::debug;
+
+use crate::proxy::AnthropicMessageRequest;
+
+/// Maximum number of cache_control breakpoints Anthropic accepts per request
+pub const MAX_CACHE_BREAKPOINTS: usize = 4;
+
+fn ephemeral() -> Value {
+    json!({"type": "ephemeral"})
+}
+
+/// Number of blocks carrying a cache_control marker anywhere in the request
+pub fn count_breakpoints(request: &AnthropicMessageRequest) -> usize {
+    let in_blocks = |blocks: &[Value]| blocks.iter().filter(|b| b.get("cache_control").is_some()).count();
+
+    let tools = request.tools.as_deref().map(in_blocks).unwrap_or(0);
+    let system = match &request.system {
+        Some(Value::Array(blocks)) => in_blocks(blocks),
+        _ => 0,
+    };
+    let messages: usize = request
+        .messages
+        .iter()
+        .filter_map(|m| m.get("content").and_then(|c| c.as_array()))
+        .map(|blocks| in_blocks(blocks))
+        .sum();
+
+    tools + system + messages
+}
+
+fn serialized_len<T: serde::Serialize + ?Sized>(value: &T) -> usize {
+    serde_json::to_string(value).map(|s| s.len()).unwrap_or(0)
+}
+
+/// Mark the last content block of a message, converting string content to a text block
+fn mark_message(message: &mut Value) -> bool {
+    let Some(content) = message.get_mut("content") else {
+        return false;
+    };
+
+    if let Value::String(text) = content {
+        *content = json!([{"type": "text", "text": text.clone()}]);
+    }
+
+    match content.as_array_mut().and_then(|blocks| blocks.last_mut()) {
+        Some(Value::Object(block)) if !block.contains_key("cache_control") => {
+            block.insert("cache_control".to_string(), ephemeral());
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Attach ephemeral cache breakpoints to large, stable prefixes of the request:
+/// the tool definitions, the system prompt and the conversation before the latest turn.
+///
+/// Never exceeds the breakpoint budget left by markers already present in the request.
+pub fn inject_prompt_cache(request: &mut AnthropicMessageRequest, min_chars: usize) {
+    let existing = count_breakpoints(request);
+    let mut budget = MAX_CACHE_BREAKPOINTS.saturating_sub(existing);
+    let mut added = 0;
+
+    // Tool definitions come first in the cached prefix
+    if budget > 0 {
+        if let Some(tools) = request.tools.as_mut() {
+            if serialized_len(tools) >= min_chars {
+                if let Some(Value::Object(last)) = tools.last_mut() {
+                    if !last.contains_key("cache_control") {
+                        last.insert("cache_control".to_string(), ephemeral());
+                        budget -= 1;
+                        added += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // Then the system prompt
+    if budget > 0 {
+        if let Some(Value::Array(blocks)) = request.system.as_mut() {
+            if serialized_len(blocks) >= min_chars {
+                if let Some(Value::Object(last)) = blocks.last_mut() {
+                    if !last.contains_key("cache_control") {
+                        last.insert("cache_control".to_string(), ephemeral());
+                        budget -= 1;
+                        added += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // Then everything before the latest turn
+    if budget > 0 && request.messages.len() >= 2 {
+        let prefix_end = request.messages.len() - 2;
+        if serialized_len(&request.messages[..=prefix_end]) >= min_chars
+            && mark_message(&mut request.messages[prefix_end])
+        {
+            added += 1;
+        }
+    }
+
+    if added > 0 {
+        debug!("Injected {} automatic prompt cache breakpoint(s)", added);
+    }
+}
+
+/// All content blocks of the request in cache-prefix order: tools, system, messages
+fn blocks_in_prefix_order(request: &mut AnthropicMessageRequest) -> Vec<&mut Map<String, Value>> {
+    let mut blocks = Vec::new();
+
+    if let Some(tools) = request.tools.as_mut() {
+        blocks.extend(tools.iter_mut().filter_map(|t| t.as_object_mut()));
+    }
+    if let Some(Value::Array(system)) = request.system.as_mut() {
+        blocks.extend(system.iter_mut().filter_map(|b| b.as_object_mut()));
+    }
+    for message in request.messages.iter_mut() {
+        if let Some(Value::Array(content)) = message.get_mut("content") {
+            blocks.extend(content.iter_mut().filter_map(|b| b.as_object_mut()));
+        }
+    }
+
+    blocks
+}
+
+/// Whether a block can legally carry a cache_control marker
+fn accepts_cache_control(block: &Map<String, Value>) -> bool {
+    match block.get("type").and_then(|t| t.as_str()) {
+        Some("thinking") | Some("redacted_thinking") => false,
+        Some("text") => block
+            .get("text")
+            .and_then(|t| t.as_str())
+            .map(|t| !t.is_empty())
+            .unwrap_or(false),
+        _ => true,
+    }
+}
+
+/// Normalize cache_control markers so the request is accepted upstream:
+/// drops markers on blocks that can't carry them, rewrites malformed markers to
+/// `{"type": "ephemeral"}` (keeping a valid `ttl`), and trims the total to
+/// [`MAX_CACHE_BREAKPOINTS`] by removing the earliest ones, since later breakpoints
+/// cover longer prefixes.
+pub fn sanitize_cache_control(request: &mut AnthropicMessageRequest) {
+    let mut marked: Vec<&mut Map<String, Value>> = Vec::new();
+
+    for block in blocks_in_prefix_order(request) {
+        let Some(marker) = block.get("cache_control") else {
+            continue;
+        };
+
+        if !accepts_cache_control(block) {
+            debug!("Removing cache_control from block that cannot be cached");
+            block.remove("cache_control");
+            continue;
+        }
+
+        let mut normalized = ephemeral();
+        if let Some(ttl) = marker.get("ttl").and_then(|t| t.as_str()) {
+            if ttl == "5m" || ttl == "1h" {
+                normalized["ttl"] = Value::String(ttl.to_string());
+            }
+        }
+        if *marker != normalized {
+            debug!("Normalizing malformed cache_control marker: {}", marker);
+            block.insert("cache_control".to_string(), normalized);
+        }
+
+        marked.push(block);
+    }
+
+    if marked.len() > MAX_CACHE_BREAKPOINTS {
+        let excess = marked.len() - MAX_CACHE_BREAKPOINTS;
+        debug!(
+            "Request has {} cache breakpoints, removing the {} earliest (limit {})",
+            marked.len(),
+            excess,
+            MAX_CACHE_BREAKPOINTS
+        );
+        for block in marked.into_iter().take(excess) {
+            block.remove("cache_control");
+        }
+    }
+}
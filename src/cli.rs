@@ -7,8 +7,12 @@ use std::thread;
 use std::time::Duration;
 use tokio::runtime::Runtime;
 
-use crate::oauth::OAuthManager;
-use crate::proxy::{create_router, AppState};
+use crate::alerts;
+use crate::connection;
+use crate::oauth::{Authorization, AuthorizationError, OAuthManager};
+use crate::profile::AccountIdentity;
+use crate::proxy::{announce_listener, bind_listener, create_router, AppState};
+use crate::refresh_audit::RefreshReason;
 use crate::settings::Settings;
 
 pub struct Cli {
@@ -20,7 +24,7 @@ pub struct Cli {
 
 impl Cli {
     pub fn new(settings: Settings) -> Result<Self> {
-        let oauth_manager = Arc::new(OAuthManager::new(&settings.token_file)?);
+        let oauth_manager = Arc::new(OAuthManager::new(&settings.token_file, settings.oauth.clone(), connection::http_client(&settings))?);
         let settings = Arc::new(settings);
         let rt = Runtime::new()?;
 
@@ -49,6 +53,10 @@ impl Cli {
             return ("NO AUTH".to_string(), "No tokens available".to_string());
         }
 
+        if status.is_revoked {
+            return ("REVOKED".to_string(), "Refresh token revoked, please login again".to_string());
+        }
+
         if status.is_expired {
             return ("EXPIRED".to_string(), format!("Expired {}", status.time_until_expiry));
         }
@@ -88,6 +96,9 @@ impl Cli {
         println!("{}", "-".repeat(50));
         println!("Has Tokens: {}", if status.has_tokens { "Yes" } else { "No" });
         println!("Is Expired: {}", if status.is_expired { "Yes" } else { "No" });
+        if status.is_revoked {
+            println!("Is Revoked: {}", style("Yes - login again to restore access").red());
+        }
 
         if let Some(expires_at) = status.expires_at {
             println!("Expires At: {}", expires_at);
@@ -95,6 +106,23 @@ impl Cli {
         }
 
         println!("Token File: {}", self.oauth_manager.storage().token_file().display());
+
+        if status.has_tokens && !status.is_revoked {
+            match self.rt.block_on(self.oauth_manager.account_profile(false)) {
+                Ok(Some(profile)) => {
+                    let account = AccountIdentity::from_profile(&profile);
+                    let unknown = || "unknown".to_string();
+                    println!("\n{}", style("Account").cyan().bold());
+                    println!("{}", "-".repeat(50));
+                    println!("Email: {}", account.email.unwrap_or_else(unknown));
+                    println!("Organization: {}", account.organization_name.unwrap_or_else(unknown));
+                    println!("Plan: {}", account.plan.unwrap_or_else(unknown));
+                }
+                Ok(None) => {}
+                Err(e) => println!("\nAccount: {}", style(format!("could not fetch profile: {}", e)).yellow()),
+            }
+        }
+
         println!("\nPress Enter to continue...");
         let _ = io::stdin().read_line(&mut String::new());
     }
@@ -162,42 +190,63 @@ impl Cli {
 
         let oauth_manager = Arc::clone(&self.oauth_manager);
         let settings = Arc::clone(&self.settings);
-        let bind_addr = format!("{}:{}", settings.bind_address, settings.port);
+        // The server thread reports the address it bound, which differs with port 0 or a
+        // fallback port, or why it couldn't bind
+        let (bound_tx, bound_rx) = std::sync::mpsc::channel();
 
         let handle = thread::spawn(move || {
             let rt = Runtime::new().expect("Failed to create runtime");
             rt.block_on(async {
-                let state = AppState {
-                    oauth_manager,
-                    settings: settings.clone(),
-                    api_key: settings.api_key.clone(),
-                };
+                oauth_manager.spawn_background_refresh();
+                alerts::spawn_monitor(&settings, oauth_manager.clone());
 
-                let app = create_router(state);
-                let listener = tokio::net::TcpListener::bind(&bind_addr)
+                let bound = bind_listener(&settings)
                     .await
-                    .expect("Failed to bind");
+                    .and_then(|listener| Ok((listener.local_addr()?, listener)));
+                let (local_addr, listener) = match bound {
+                    Ok(bound) => bound,
+                    Err(e) => {
+                        tracing::error!("{}", e);
+                        let _ = bound_tx.send(Err(e.to_string()));
+                        return;
+                    }
+                };
+                let state = AppState::new(oauth_manager, settings.clone()).with_local_addr(local_addr);
+                let app = create_router(state);
 
-                tracing::info!("Proxy server listening on {}", bind_addr);
+                tracing::info!("Proxy server listening on {}", local_addr);
+                announce_listener(&settings, local_addr);
+                let _ = bound_tx.send(Ok(local_addr));
 
                 axum::serve(listener, app)
+                    .tcp_nodelay(true)
                     .await
                     .expect("Server error");
             });
         });
 
-        self.server_handle = Some(handle);
-
         // Wait for server to start
-        thread::sleep(Duration::from_secs(1));
+        let port = match bound_rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Ok(addr)) => addr.port(),
+            Ok(Err(e)) => {
+                let _ = handle.join();
+                println!("{} {}", style("✗").red(), e);
+                println!("Set a free PORT, or PORT_FALLBACK to try the following ports.");
+                println!("\nPress Enter to continue...");
+                let _ = io::stdin().read_line(&mut String::new());
+                return Ok(false);
+            }
+            Err(_) => self.settings.port,
+        };
+        self.server_handle = Some(handle);
 
         println!(
             "{} Proxy running at http://{}:{}",
             style("✓").green(),
             self.settings.bind_address,
-            self.settings.port
+            port
         );
-        println!("\nBase URL: http://{}:{}", self.settings.bind_address, self.settings.port);
+        println!("\nBase URL: http://{}:{}", self.settings.bind_address, port);
         println!("API Key: any-placeholder-string");
         println!("Endpoint: /v1/messages");
 
@@ -218,6 +267,14 @@ impl Cli {
             );
         }
 
+        if status.is_expired && status.is_revoked {
+            return (
+                false,
+                "REVOKED".to_string(),
+                "Refresh token was revoked. Please login again (option 2)".to_string(),
+            );
+        }
+
         if !status.is_expired {
             return (
                 true,
@@ -237,7 +294,7 @@ impl Cli {
 
         println!("{} Token expired, attempting automatic refresh...", style("⚠").yellow());
 
-        match self.rt.block_on(self.oauth_manager.refresh_tokens()) {
+        match self.rt.block_on(self.oauth_manager.refresh_tokens(RefreshReason::Manual)) {
             Ok(true) => {
                 let new_status = self.oauth_manager.storage().get_status();
                 (
@@ -307,23 +364,35 @@ impl Cli {
         println!("  3. You will see an authorization code on the Anthropic page");
 
         println!("\n{} Paste the authorization code below", style("Step 2:").bold());
-        println!("{}", style("The code should look like: CODE#STATE").dim());
+        println!("{}", style("The code should look like CODE#STATE; a callback URL works too").dim());
 
         let code: String = Input::new()
             .with_prompt("\nAuthorization code")
             .interact_text()
             .unwrap_or_default();
 
-        if code.trim().is_empty() || code.trim().len() < 10 {
-            println!("{} Invalid or missing code. Please paste the complete code from the browser.", style("✗").red());
-            println!("\nPress Enter to continue...");
-            let _ = io::stdin().read_line(&mut String::new());
-            return;
+        let mut parsed = Authorization::parse(&code, None);
+        if parsed == Err(AuthorizationError::MissingState) {
+            println!("{}", style("The code has no state part; paste the state separately").dim());
+            let state: String = Input::new()
+                .with_prompt("State")
+                .interact_text()
+                .unwrap_or_default();
+            parsed = Authorization::parse(&code, Some(&state));
         }
+        let authorization = match parsed {
+            Ok(authorization) => authorization,
+            Err(e) => {
+                println!("{} Invalid authorization code: {}", style("✗").red(), e);
+                println!("\nPress Enter to continue...");
+                let _ = io::stdin().read_line(&mut String::new());
+                return;
+            }
+        };
 
         println!("\n{} Exchanging code for tokens...", style("Step 3:").bold());
 
-        match self.rt.block_on(self.oauth_manager.exchange_code(&code.trim())) {
+        match self.rt.block_on(self.oauth_manager.exchange_code(&authorization)) {
             Ok(_) => {
                 println!("{} Tokens obtained successfully", style("✓").green());
                 let status = self.oauth_manager.storage().get_status();
@@ -333,6 +402,9 @@ impl Cli {
             }
             Err(e) => {
                 println!("{} Failed to exchange code for tokens: {}", style("✗").red(), e);
+                if self.oauth_manager.storage().get_status().has_tokens {
+                    println!("{}", style("Your existing tokens were left unchanged").dim());
+                }
             }
         }
 
@@ -350,7 +422,7 @@ impl Cli {
             return;
         }
 
-        match self.rt.block_on(self.oauth_manager.refresh_tokens()) {
+        match self.rt.block_on(self.oauth_manager.refresh_tokens(RefreshReason::Manual)) {
             Ok(true) => {
                 println!("{} Token refreshed successfully!", style("✓").green());
                 let (auth_status, auth_detail) = self.get_auth_status();
@@ -434,10 +506,8 @@ impl Cli {
                     if server_running {
                         self.stop_proxy_server();
                         server_running = false;
-                    } else {
-                        if self.start_proxy_server(0)? {
-                            server_running = true;
-                        }
+                    } else if self.start_proxy_server(0)? {
+                        server_running = true;
                     }
                 }
                 1 => self.login(),
@@ -0,0 +1,92 @@
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::proxy::AnthropicMessageRequest;
+use crate::tokenizer;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionSettings {
+    /// Estimated prompt size above which old turns are dropped
+    pub max_tokens: u64,
+    /// Number of most recent messages that are never dropped
+    pub keep_recent: usize,
+}
+
+fn role(message: &Value) -> Option<&str> {
+    message.get("role").and_then(|r| r.as_str())
+}
+
+/// A valid conversation start: a user message that isn't answering a tool call
+fn is_valid_start(message: &Value) -> bool {
+    if role(message) != Some("user") {
+        return false;
+    }
+
+    match message.get("content") {
+        Some(Value::Array(blocks)) => !blocks
+            .iter()
+            .any(|b| b.get("type").and_then(|t| t.as_str()) == Some("tool_result")),
+        _ => true,
+    }
+}
+
+/// Prepend a note to the first message telling the model earlier turns were removed
+fn add_truncation_note(message: &mut Value, dropped: usize) {
+    let note = json!({
+        "type": "text",
+        "text": format!("[{} earlier messages were omitted to fit the context window]", dropped)
+    });
+
+    match message.get_mut("content") {
+        Some(Value::Array(blocks)) => blocks.insert(0, note),
+        Some(content) => {
+            let text = json!({"type": "text", "text": content.as_str().unwrap_or_default()});
+            *content = json!([note, text]);
+        }
+        None => {}
+    }
+}
+
+/// Drop the oldest turns of an oversized conversation, keeping the system prompt,
+/// tools and the most recent messages. The remaining history always starts with a
+/// plain user turn so tool_use/tool_result pairs are never split.
+///
+/// Returns the number of messages removed.
+pub fn compact(request: &mut AnthropicMessageRequest, settings: &CompactionSettings) -> usize {
+    if tokenizer::count_request(request) <= settings.max_tokens {
+        return 0;
+    }
+
+    let total = request.messages.len();
+    let latest_start = total.saturating_sub(settings.keep_recent);
+    let mut cut = 0;
+
+    // Only cut at positions that leave a well-formed conversation
+    for start in 1..=latest_start {
+        if start >= total || !is_valid_start(&request.messages[start]) {
+            continue;
+        }
+
+        cut = start;
+        let remaining = &request.messages[start..];
+        if tokenizer::count_prompt(remaining, request.system.as_ref(), request.tools.as_deref()) <= settings.max_tokens {
+            break;
+        }
+    }
+
+    if cut == 0 {
+        debug!("Conversation exceeds compaction threshold but has no safe cut point");
+        return 0;
+    }
+
+    request.messages.drain(..cut);
+    add_truncation_note(&mut request.messages[0], cut);
+    debug!(
+        "Compacted conversation: dropped {} of {} messages (~{} tokens remaining)",
+        cut,
+        total,
+        tokenizer::count_request(request)
+    );
+
+    cut
+}
@@ -0,0 +1,292 @@
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+
+use crate::config_loader::{describe_origin, ConfigLoader};
+use crate::quota::{QuotaPeriod, ResetSchedule};
+use crate::settings::Settings;
+use crate::validation::ValidationMode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The value was ignored, or the server can't work with it
+    Error,
+    /// Accepted, but likely not what was meant
+    Warning,
+}
+
+/// One problem with the effective configuration
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Where the value was set, such as "env PORT" or "config.json server.port"
+    pub origin: String,
+    pub message: String,
+}
+
+struct Findings<'a> {
+    loader: &'a ConfigLoader,
+    items: Vec<Finding>,
+}
+
+impl Findings<'_> {
+    fn push(&mut self, severity: Severity, env_var: &str, config_path: &str, message: String) {
+        let origin = describe_origin(env_var, config_path, self.loader.source(env_var, config_path));
+        self.items.push(Finding { severity, origin, message });
+    }
+
+    fn error(&mut self, env_var: &str, config_path: &str, message: String) {
+        self.push(Severity::Error, env_var, config_path, message);
+    }
+
+    fn warning(&mut self, env_var: &str, config_path: &str, message: String) {
+        self.push(Severity::Warning, env_var, config_path, message);
+    }
+}
+
+/// Values the loader had to skip, then paths, the session backend, policies passthrough
+/// would skip, model names and rate limit rules of the settings it produced
+pub fn check(loader: &ConfigLoader, settings: &Settings) -> Vec<Finding> {
+    let items = loader
+        .issues()
+        .into_iter()
+        .map(|issue| Finding {
+            severity: Severity::Error,
+            origin: issue.origin(),
+            message: issue.message,
+        })
+        .collect();
+    let mut findings = Findings { loader, items };
+
+    check_paths(&mut findings, settings);
+    check_sessions(&mut findings, settings);
+    check_passthrough(&mut findings, settings);
+    check_models(&mut findings, settings);
+    check_rate_limits(&mut findings, settings);
+
+    findings.items
+}
+
+fn check_paths(findings: &mut Findings, settings: &Settings) {
+    if let Some(problem) = token_file_problem(&settings.token_file) {
+        findings.error("TOKEN_FILE", "storage.token_file", problem);
+    }
+    for (name, token_file) in &settings.accounts.profiles {
+        if let Some(problem) = token_file_problem(token_file) {
+            findings.error("MAXIMIZE_ACCOUNTS", "accounts.profiles", format!("account '{}': {}", name, problem));
+        }
+    }
+    for name in &settings.accounts.pool {
+        if !settings.accounts.profiles.contains_key(name) {
+            findings.error("MAXIMIZE_ACCOUNT_POOL", "accounts.pool", format!("unknown account '{}'", name));
+        }
+    }
+    for (name, tenant) in &settings.tenants {
+        if let Some(problem) = token_file_problem(&tenant.token_file) {
+            findings.error("MAXIMIZE_TENANTS", "tenants", format!("tenant '{}': {}", name, problem));
+        }
+    }
+
+    if let Some(script) = &settings.transform_script {
+        if !Path::new(script).is_file() {
+            findings.error("TRANSFORM_SCRIPT", "scripting.transform_script", format!("'{}' not found", script));
+        }
+    }
+    for filter in &settings.wasm_filters {
+        if !Path::new(filter).is_file() {
+            findings.error("WASM_FILTERS", "scripting.wasm_filters", format!("'{}' not found", filter));
+        }
+    }
+}
+
+/// Why tokens couldn't be saved to `token_file`, if they can't
+fn token_file_problem(token_file: &str) -> Option<String> {
+    let path = Path::new(token_file);
+    if path.is_dir() {
+        return Some(format!("{} is a directory", token_file));
+    }
+    if path.exists() {
+        return OpenOptions::new()
+            .append(true)
+            .open(path)
+            .err()
+            .map(|e| format!("{} is not writable ({})", token_file, e));
+    }
+
+    // Missing directories are created on first save, under the closest one that exists
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let existing = parent.ancestors().find(|dir| dir.exists())?;
+    if !existing.is_dir() {
+        return Some(format!("cannot create {}: {} is not a directory", token_file, existing.display()));
+    }
+    let probe = existing.join(format!(".maximize-check-{}", std::process::id()));
+    match fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            None
+        }
+        Err(e) => Some(format!("cannot create {}: {} is not writable ({})", token_file, existing.display(), e)),
+    }
+}
+
+fn check_sessions(findings: &mut Findings, settings: &Settings) {
+    if !matches!(settings.session_backend.as_str(), "memory" | "sqlite") {
+        findings.error(
+            "SESSION_BACKEND",
+            "sessions.backend",
+            format!("unknown backend '{}'; expected memory or sqlite", settings.session_backend),
+        );
+    }
+}
+
+/// Configured request policies that raw passthrough mode skips, since they need the
+/// parsed request; empty when passthrough is off
+pub fn passthrough_bypasses(settings: &Settings) -> Vec<&'static str> {
+    if !settings.passthrough {
+        return Vec::new();
+    }
+    let guardrails = &settings.guardrails;
+    [
+        (
+            "guardrails",
+            !guardrails.patterns.is_empty() || !guardrails.keywords.is_empty() || guardrails.detect_secrets,
+        ),
+        ("moderation", settings.moderation.url.is_some() || !settings.moderation.rules.is_empty()),
+        ("tool allowlists", !settings.tool_allowlists.is_empty()),
+        ("tenant system prompts", settings.tenants.values().any(|t| t.system_prompt.is_some())),
+        ("key presets", !settings.presets.keys.is_empty()),
+        ("strict validation", settings.validation == ValidationMode::Strict),
+    ]
+    .into_iter()
+    .filter_map(|(feature, configured)| configured.then_some(feature))
+    .collect()
+}
+
+fn check_passthrough(findings: &mut Findings, settings: &Settings) {
+    let bypassed = passthrough_bypasses(settings);
+    if !bypassed.is_empty() {
+        findings.error(
+            "PASSTHROUGH",
+            "api.passthrough",
+            format!("skips the configured {}; Messages requests go upstream unchecked", bypassed.join(", ")),
+        );
+    }
+}
+
+fn check_models(findings: &mut Findings, settings: &Settings) {
+    let unknown = |name: &str| {
+        let model = settings.resolve_model(name);
+        settings.context_window(&model).is_none() && settings.price(&model).is_none()
+    };
+
+    if unknown(&settings.default_model) {
+        findings.warning(
+            "DEFAULT_MODEL",
+            "models.default",
+            format!("'{}' is neither a nickname nor a known model; it is sent upstream as-is", settings.default_model),
+        );
+    }
+    for model in settings.allowed_models.iter().filter(|m| unknown(m)) {
+        findings.warning(
+            "ALLOWED_MODELS",
+            "models.allowed",
+            format!("'{}' is neither a nickname nor a known model", model),
+        );
+    }
+    for (rollout, canary) in &settings.canaries {
+        if unknown(&canary.model) {
+            findings.warning(
+                "MODEL_CANARIES",
+                "canaries",
+                format!("canary model '{}' of '{}' is neither a nickname nor a known model", canary.model, rollout),
+            );
+        }
+        if !(0.0..=100.0).contains(&canary.percent) {
+            findings.error(
+                "MODEL_CANARIES",
+                "canaries",
+                format!("percent of '{}' is {}; expected 0 to 100", rollout, canary.percent),
+            );
+        }
+    }
+    if let Some(model) = settings.shadow.model.as_deref().filter(|m| unknown(m)) {
+        findings.warning(
+            "SHADOW_MODEL",
+            "shadow.model",
+            format!("'{}' is neither a nickname nor a known model", model),
+        );
+    }
+}
+
+fn check_rate_limits(findings: &mut Findings, settings: &Settings) {
+    let rate_limits = &settings.rate_limits;
+    if !(rate_limits.throttle_below > 0.0 && rate_limits.throttle_below <= 1.0) {
+        findings.error(
+            "RATE_LIMIT_THROTTLE_BELOW",
+            "rate_limits.throttle_below",
+            format!("{} is not a share of the window; expected more than 0 and at most 1", rate_limits.throttle_below),
+        );
+    }
+    if settings.max_concurrent_requests > 0 && rate_limits.min_concurrent as usize > settings.max_concurrent_requests {
+        findings.warning(
+            "RATE_LIMIT_MIN_CONCURRENT",
+            "rate_limits.min_concurrent",
+            format!(
+                "{} is above MAX_CONCURRENT_REQUESTS ({}), so auto-throttling never lowers concurrency",
+                rate_limits.min_concurrent, settings.max_concurrent_requests
+            ),
+        );
+    }
+
+    for (key_id, quota) in &settings.quotas {
+        let windows = [
+            (QuotaPeriod::Daily, "daily", &quota.daily),
+            (QuotaPeriod::Weekly, "weekly", &quota.weekly),
+        ];
+        for (period, label, window) in windows {
+            let Some(window) = window else {
+                continue;
+            };
+            if window.tokens == 0 {
+                findings.warning(
+                    "KEY_QUOTAS",
+                    "quotas",
+                    format!("{} quota of key {} is 0 tokens, which rejects every request", label, key_id),
+                );
+            }
+            if let Some(Err(e)) = window.reset.as_deref().map(|reset| ResetSchedule::parse(reset, period)) {
+                findings.error("KEY_QUOTAS", "quotas", format!("{} quota reset of key {}: {}", label, key_id, e));
+            }
+        }
+    }
+
+    for (i, rule) in settings.routing.rules.iter().enumerate() {
+        if rule.targets.is_empty() {
+            findings.error("ROUTING", "routing.rules", format!("rule {} has no targets", i + 1));
+        }
+        if let Some(below) = rule.quota_remaining_below.filter(|b| !(0.0..=1.0).contains(b)) {
+            findings.error(
+                "ROUTING",
+                "routing.rules",
+                format!("quota_remaining_below of rule {} is {}; expected 0 to 1", i + 1, below),
+            );
+        }
+    }
+}
+
+/// Print findings; returns whether there were no errors
+pub fn report(findings: &[Finding]) -> bool {
+    for finding in findings {
+        let mark = match finding.severity {
+            Severity::Error => "❌",
+            Severity::Warning => "⚠️ ",
+        };
+        println!("{} {}: {}", mark, finding.origin, finding.message);
+    }
+    let errors = findings.iter().filter(|f| f.severity == Severity::Error).count();
+    if findings.is_empty() {
+        println!("✅ Configuration is valid");
+    } else {
+        println!("\n{} error(s), {} warning(s)", errors, findings.len() - errors);
+    }
+    errors == 0
+}
@@ -1,10 +1,43 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs;
 use std::path::Path;
 
-use crate::settings::{ApiConfig, Config, ModelConfig, ServerConfig, StorageConfig};
+use crate::admission::Priority;
+use crate::connection::DnsStrategy;
+use crate::guardrails::GuardrailAction;
+use crate::request_log::LogDetail;
+use crate::upstream::UpstreamKind;
+use crate::validation::ValidationMode;
+use crate::settings::{
+    AccountsConfig, AdminConfig, AdmissionConfig, AlertConfig, AnthropicApiConfig, ApiConfig,
+    BedrockConfig, CacheConfig, CompactionConfig, Config, GuardrailConfig, ImageConfig,
+    LoggingConfig, MetadataConfig, ModelConfig, ModerationConfig, OAuthConfig, OverloadConfig,
+    PricingConfig, RateLimitConfig, RoutingConfig, SanitizationConfig, ScriptingConfig,
+    ServerConfig, SessionConfig, ShadowConfig, StorageConfig, StreamingConfig, TenantConfig,
+    ThinkingConfig, ToolConfig, VertexConfig,
+};
+
+/// Expand tilde (~) in a token file path, and point directories at a tokens.json inside
+fn resolve_token_file(raw: &str) -> String {
+    let mut token_file = expand_tilde(raw);
+
+    // Validate token file path - if it's a directory, append default filename
+    let token_path = Path::new(&token_file);
+    if token_path.exists() && token_path.is_dir() {
+        eprintln!("Warning: TOKEN_FILE '{}' is a directory. Using '{}/tokens.json' instead.", token_file, token_file);
+        token_file = token_path.join("tokens.json").to_string_lossy().to_string();
+    } else if token_file.ends_with('/') || token_file.ends_with('\\') {
+        eprintln!("Warning: TOKEN_FILE '{}' appears to be a directory path. Using '{}tokens.json' instead.", token_file, token_file);
+        token_file.push_str("tokens.json");
+    }
+    token_file
+}
 
 /// Expand tilde (~) in paths to home directory
 fn expand_tilde(path: &str) -> String {
@@ -17,8 +50,116 @@ fn expand_tilde(path: &str) -> String {
     path.to_string()
 }
 
+/// Where a setting's value came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueSource {
+    /// Command line flag, given as an override of the env var
+    Flag,
+    Env,
+    ConfigFile,
+    Default,
+}
+
+/// A configured value that couldn't be used; the loader fell back to the next source
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    pub env_var: String,
+    pub config_path: String,
+    pub source: ValueSource,
+    pub message: String,
+}
+
+/// Where a value was set, as the user would look for it: "env PORT", "config.json server.port"
+pub fn describe_origin(env_var: &str, config_path: &str, source: ValueSource) -> String {
+    match source {
+        ValueSource::Flag => format!("command line ({})", env_var),
+        ValueSource::Env => format!("env {}", env_var),
+        ValueSource::ConfigFile => format!("config.json {}", config_path),
+        ValueSource::Default => format!("default (set {} or {})", env_var, config_path),
+    }
+}
+
+/// Where one setting was taken from
+#[derive(Debug, Clone, Serialize)]
+pub struct SettingSource {
+    pub env_var: String,
+    pub source: ValueSource,
+}
+
+impl ConfigIssue {
+    pub fn origin(&self) -> String {
+        describe_origin(&self.env_var, &self.config_path, self.source)
+    }
+}
+
+/// Config paths holding credentials, masked when the configuration is shown; `*` matches
+/// any key or list index
+const SECRET_PATHS: &[&str] = &[
+    "api.keys",
+    "tenants.*.keys",
+    "anthropic_api.api_key",
+    "bedrock.access_key_id",
+    "bedrock.secret_access_key",
+    "bedrock.session_token",
+    "vertex.access_token",
+    "metadata.user_id_salt",
+    "alerts.webhook_url",
+    "alerts.webhooks.*.url",
+];
+
+/// Replace credentials in a serialized `Config` with masked versions that are still
+/// recognizable: the last characters of a key, the host of a webhook URL
+pub fn mask_secrets(config: &mut Value) {
+    for path in SECRET_PATHS {
+        let segments: Vec<&str> = path.split('.').collect();
+        mask_path(config, &segments);
+    }
+}
+
+fn mask_path(value: &mut Value, segments: &[&str]) {
+    let Some((first, rest)) = segments.split_first() else {
+        mask_value(value);
+        return;
+    };
+    match (value, *first) {
+        (Value::Object(map), "*") => map.values_mut().for_each(|v| mask_path(v, rest)),
+        (Value::Array(items), "*") => items.iter_mut().for_each(|v| mask_path(v, rest)),
+        (Value::Object(map), key) => {
+            if let Some(v) = map.get_mut(key) {
+                mask_path(v, rest);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn mask_value(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(mask_value),
+        Value::String(s) if s.is_empty() => {}
+        Value::String(s) => {
+            let masked = match s.split_once("://") {
+                Some((scheme, rest)) => format!("{}://{}/****", scheme, rest.split('/').next().unwrap_or_default()),
+                None if s.chars().count() > 12 => {
+                    let tail: String = s.chars().skip(s.chars().count() - 4).collect();
+                    format!("****{}", tail)
+                }
+                None => "****".to_string(),
+            };
+            *s = masked;
+        }
+        _ => {}
+    }
+}
+
 pub struct ConfigLoader {
     config_data: Value,
+    /// Values given on the command line, keyed by the env var they stand in for
+    overrides: HashMap<String, String>,
+    issues: RefCell<Vec<ConfigIssue>>,
+    /// Source of each setting read so far, by config path
+    sources: RefCell<BTreeMap<String, SettingSource>>,
 }
 
 impl ConfigLoader {
@@ -26,7 +167,93 @@ impl ConfigLoader {
         let path = config_path.unwrap_or("config.json");
         let config_data = Self::load_config_file(path)?;
 
-        Ok(Self { config_data })
+        Ok(Self {
+            config_data,
+            overrides: HashMap::new(),
+            issues: RefCell::new(Vec::new()),
+            sources: RefCell::new(BTreeMap::new()),
+        })
+    }
+
+    /// Values that take precedence over the env vars they are keyed by
+    pub fn with_overrides(mut self, overrides: HashMap<String, String>) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Values that were set but couldn't be used, in the order they were read
+    pub fn issues(&self) -> Vec<ConfigIssue> {
+        self.issues.borrow().clone()
+    }
+
+    /// Where each setting read so far was taken from, by config path
+    pub fn sources(&self) -> BTreeMap<String, SettingSource> {
+        self.sources.borrow().clone()
+    }
+
+    /// Where the setting read from `env_var` or `config_path` is taken from
+    pub fn source(&self, env_var: &str, config_path: &str) -> ValueSource {
+        if let Some(setting) = self.sources.borrow().get(config_path) {
+            return setting.source;
+        }
+        match self.env_value(env_var) {
+            Some((_, source)) => source,
+            None if self.get_nested_value(config_path).is_some_and(|v| !v.is_null()) => ValueSource::ConfigFile,
+            None => ValueSource::Default,
+        }
+    }
+
+    fn env_value(&self, env_var: &str) -> Option<(String, ValueSource)> {
+        if let Some(value) = self.overrides.get(env_var) {
+            return Some((value.clone(), ValueSource::Flag));
+        }
+        env::var(env_var).ok().map(|value| (value, ValueSource::Env))
+    }
+
+    /// Record where a setting's value came from and pass the value on
+    fn took<T>(&self, env_var: &str, config_path: &str, source: ValueSource, value: T) -> T {
+        let setting = SettingSource {
+            env_var: env_var.to_string(),
+            source,
+        };
+        self.sources.borrow_mut().insert(config_path.to_string(), setting);
+        value
+    }
+
+    fn invalid(&self, env_var: &str, config_path: &str, source: ValueSource, message: impl Into<String>) {
+        self.issues.borrow_mut().push(ConfigIssue {
+            env_var: env_var.to_string(),
+            config_path: config_path.to_string(),
+            source,
+            message: message.into(),
+        });
+    }
+
+    /// Record an unusable value of the setting and warn about it on stderr
+    fn warn(&self, env_var: &str, config_path: &str, message: String) {
+        eprintln!("Warning: {}", message);
+        self.invalid(env_var, config_path, self.source(env_var, config_path), message);
+    }
+
+    /// Number from the env var or config.json; values that aren't one are recorded and skipped
+    fn get_number<T: std::str::FromStr + TryFrom<u64>>(&self, env_var: &str, config_path: &str, expected: &str) -> Option<T> {
+        if let Some((value, source)) = self.env_value(env_var) {
+            match value.trim().parse() {
+                Ok(num) => return self.took(env_var, config_path, source, Some(num)),
+                Err(_) => self.invalid(env_var, config_path, source, format!("expected {}, got '{}'", expected, value)),
+            }
+        }
+
+        let Some(value) = self.get_nested_value(config_path) else {
+            return self.took(env_var, config_path, ValueSource::Default, None);
+        };
+        match value.as_u64().and_then(|n| T::try_from(n).ok()) {
+            Some(num) => self.took(env_var, config_path, ValueSource::ConfigFile, Some(num)),
+            None => {
+                self.invalid(env_var, config_path, ValueSource::ConfigFile, format!("expected {}, got {}", expected, value));
+                self.took(env_var, config_path, ValueSource::Default, None)
+            }
+        }
     }
 
     fn load_config_file(path: &str) -> Result<Value> {
@@ -42,8 +269,8 @@ impl ConfigLoader {
             return Ok(Value::Object(serde_json::Map::new()));
         }
 
-        let contents = fs::read_to_string(path)?;
-        let json: Value = serde_json::from_str(&contents)?;
+        let contents = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        let json: Value = serde_json::from_str(&contents).with_context(|| format!("{} is not valid JSON", path))?;
         Ok(json)
     }
 
@@ -65,99 +292,532 @@ impl ConfigLoader {
 
     pub fn get_string(&self, env_var: &str, config_path: &str, default: &str) -> String {
         // 1. Check environment variable
-        if let Ok(value) = env::var(env_var) {
-            return value;
+        if let Some((value, source)) = self.env_value(env_var) {
+            return self.took(env_var, config_path, source, value);
         }
 
         // 2. Check config.json
         if let Some(value) = self.get_nested_value(config_path) {
             if let Some(s) = value.as_str() {
-                return s.to_string();
+                return self.took(env_var, config_path, ValueSource::ConfigFile, s.to_string());
             }
+            self.invalid(env_var, config_path, ValueSource::ConfigFile, format!("expected a string, got {}", value));
         }
 
         // 3. Return default
-        default.to_string()
+        self.took(env_var, config_path, ValueSource::Default, default.to_string())
     }
 
-    pub fn get_u16(&self, env_var: &str, config_path: &str, default: u16) -> u16 {
+    pub fn get_optional_string(&self, env_var: &str, config_path: &str) -> Option<String> {
+        // 1. Check environment variable
+        if let Some((value, source)) = self.env_value(env_var) {
+            if !value.trim().is_empty() {
+                return self.took(env_var, config_path, source, Some(value));
+            }
+        }
+
+        // 2. Check config.json
+        let Some(value) = self.get_nested_value(config_path) else {
+            return self.took(env_var, config_path, ValueSource::Default, None);
+        };
+        if !value.is_null() && !value.is_string() {
+            self.invalid(env_var, config_path, ValueSource::ConfigFile, format!("expected a string, got {}", value));
+        }
+        let value = value
+            .as_str()
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.to_string());
+        let source = if value.is_some() { ValueSource::ConfigFile } else { ValueSource::Default };
+        self.took(env_var, config_path, source, value)
+    }
+
+    pub fn get_list(&self, env_var: &str, config_path: &str) -> Vec<String> {
+        // 1. Check environment variable (comma-separated)
+        if let Some((value, source)) = self.env_value(env_var) {
+            let items = value
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+            return self.took(env_var, config_path, source, items);
+        }
+
+        // 2. Check config.json
+        match self.get_nested_value(config_path) {
+            Some(Value::Array(items)) => {
+                let items = items
+                    .iter()
+                    .filter_map(|v| {
+                        if !v.is_string() {
+                            self.invalid(env_var, config_path, ValueSource::ConfigFile, format!("ignoring {}, not a string", v));
+                        }
+                        v.as_str()
+                    })
+                    .map(|s| s.to_string())
+                    .collect();
+                return self.took(env_var, config_path, ValueSource::ConfigFile, items);
+            }
+            Some(Value::Null) | None => {}
+            Some(value) => {
+                self.invalid(env_var, config_path, ValueSource::ConfigFile, format!("expected a list of strings, got {}", value))
+            }
+        }
+
+        // 3. Return default
+        self.took(env_var, config_path, ValueSource::Default, Vec::new())
+    }
+
+    pub fn get_bool(&self, env_var: &str, config_path: &str, default: bool) -> bool {
         // 1. Check environment variable
-        if let Ok(value) = env::var(env_var) {
-            if let Ok(num) = value.parse() {
-                return num;
+        if let Some((value, source)) = self.env_value(env_var) {
+            match value.trim().to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => return self.took(env_var, config_path, source, true),
+                "0" | "false" | "no" | "off" => return self.took(env_var, config_path, source, false),
+                _ => self.invalid(env_var, config_path, source, format!("expected true or false, got '{}'", value)),
             }
         }
 
         // 2. Check config.json
         if let Some(value) = self.get_nested_value(config_path) {
-            if let Some(num) = value.as_u64() {
-                return num as u16;
+            if let Some(b) = value.as_bool() {
+                return self.took(env_var, config_path, ValueSource::ConfigFile, b);
             }
+            self.invalid(env_var, config_path, ValueSource::ConfigFile, format!("expected true or false, got {}", value));
         }
 
         // 3. Return default
-        default
+        self.took(env_var, config_path, ValueSource::Default, default)
+    }
+
+    /// Structured value: environment variable holding JSON, then the config.json subtree
+    pub fn get_json<T: DeserializeOwned>(&self, env_var: &str, config_path: &str) -> Option<T> {
+        // 1. Check environment variable
+        if let Some((value, source)) = self.env_value(env_var) {
+            match serde_json::from_str(&value) {
+                Ok(parsed) => return self.took(env_var, config_path, source, Some(parsed)),
+                Err(e) => {
+                    eprintln!("Warning: {} is not valid JSON ({}). Ignoring.", env_var, e);
+                    self.invalid(env_var, config_path, source, format!("not valid JSON ({})", e));
+                }
+            }
+        }
+
+        // 2. Check config.json
+        let Some(value) = self.get_nested_value(config_path) else {
+            return self.took(env_var, config_path, ValueSource::Default, None);
+        };
+        match serde_json::from_value(value.clone()) {
+            Ok(parsed) => self.took(env_var, config_path, ValueSource::ConfigFile, Some(parsed)),
+            Err(e) => {
+                eprintln!("Warning: invalid '{}' in config.json ({}). Ignoring.", config_path, e);
+                self.invalid(env_var, config_path, ValueSource::ConfigFile, e.to_string());
+                self.took(env_var, config_path, ValueSource::Default, None)
+            }
+        }
+    }
+
+    pub fn get_log_detail(&self, env_var: &str, config_path: &str, default: LogDetail) -> LogDetail {
+        let value = self.get_string(env_var, config_path, "");
+        if value.is_empty() {
+            return default;
+        }
+
+        LogDetail::parse(&value).unwrap_or_else(|| {
+            self.warn(
+                env_var,
+                config_path,
+                format!("invalid value '{}' for {} (expected off, summary or full). Using default.", value, env_var),
+            );
+            default
+        })
+    }
+
+    pub fn get_u16(&self, env_var: &str, config_path: &str, default: u16) -> u16 {
+        self.get_number(env_var, config_path, "a number from 0 to 65535").unwrap_or(default)
     }
 
     pub fn get_u64(&self, env_var: &str, config_path: &str, default: u64) -> u64 {
+        self.get_number(env_var, config_path, "a whole number").unwrap_or(default)
+    }
+
+    pub fn get_f64(&self, env_var: &str, config_path: &str, default: f64) -> f64 {
         // 1. Check environment variable
-        if let Ok(value) = env::var(env_var) {
-            if let Ok(num) = value.parse() {
-                return num;
+        if let Some((value, source)) = self.env_value(env_var) {
+            match value.trim().parse() {
+                Ok(num) => return self.took(env_var, config_path, source, num),
+                Err(_) => self.invalid(env_var, config_path, source, format!("expected a number, got '{}'", value)),
             }
         }
 
         // 2. Check config.json
         if let Some(value) = self.get_nested_value(config_path) {
-            if let Some(num) = value.as_u64() {
-                return num;
+            if let Some(num) = value.as_f64() {
+                return self.took(env_var, config_path, ValueSource::ConfigFile, num);
             }
+            self.invalid(env_var, config_path, ValueSource::ConfigFile, format!("expected a number, got {}", value));
         }
 
         // 3. Return default
-        default
+        self.took(env_var, config_path, ValueSource::Default, default)
     }
 
     pub fn load() -> Result<Config> {
-        let loader = Self::new(None)?;
+        Ok(Self::new(None)?.config())
+    }
 
+    /// Resolve every setting; values that can't be used are skipped and kept in `issues`
+    pub fn config(&self) -> Config {
         let server = ServerConfig {
-            port: loader.get_u16("PORT", "server.port", 8081),
-            log_level: loader.get_string("LOG_LEVEL", "server.log_level", "info"),
-            bind_address: loader.get_string("BIND_ADDRESS", "server.bind_address", "0.0.0.0"),
+            port: self.get_u16("PORT", "server.port", 8081),
+            log_level: self.get_string("LOG_LEVEL", "server.log_level", "info"),
+            bind_address: self.get_string("BIND_ADDRESS", "server.bind_address", "0.0.0.0"),
+            startup_self_test: self.get_bool("STARTUP_SELF_TEST", "server.startup_self_test", false),
+            discovery_file: self
+                .get_optional_string("DISCOVERY_FILE", "server.discovery_file")
+                .map(|p| expand_tilde(&p)),
+            port_fallback: self.get_u16("PORT_FALLBACK", "server.port_fallback", 0),
         };
 
         let models = ModelConfig {
-            default: loader.get_string("DEFAULT_MODEL", "models.default", "l"),
+            default: self.get_string("DEFAULT_MODEL", "models.default", "l"),
+            context_windows: self
+                .get_json("MODEL_CONTEXT_WINDOWS", "models.context_windows")
+                .unwrap_or_default(),
+            allowed: self.get_list("ALLOWED_MODELS", "models.allowed"),
+            echo_requested: self.get_bool("ECHO_REQUESTED_MODEL", "models.echo_requested", false),
+            output_limits: self.get_json("MODEL_OUTPUT_LIMITS", "models.output_limits").unwrap_or_default(),
+            extended_output: self
+                .get_json("MODEL_EXTENDED_OUTPUT", "models.extended_output")
+                .unwrap_or_default(),
         };
 
+        let api_default = ApiConfig::default();
+        let dns_strategy = self.get_string("DNS_STRATEGY", "api.dns_strategy", "system");
+        let validation = self.get_string("VALIDATION", "api.validation", "permissive");
         let api = ApiConfig {
-            request_timeout: loader.get_u64("REQUEST_TIMEOUT", "api.request_timeout", 120),
+            request_timeout: self.get_u64("REQUEST_TIMEOUT", "api.request_timeout", 120),
+            max_request_timeout: self.get_u64("MAX_REQUEST_TIMEOUT", "api.max_request_timeout", 3600),
+            connect_timeout_ms: self.get_u64("CONNECT_TIMEOUT_MS", "api.connect_timeout_ms", api_default.connect_timeout_ms),
+            tcp_keepalive_secs: self.get_u64("TCP_KEEPALIVE_SECS", "api.tcp_keepalive_secs", api_default.tcp_keepalive_secs),
+            dns_strategy: DnsStrategy::parse(&dns_strategy).unwrap_or_else(|| {
+                self.warn(
+                    "DNS_STRATEGY",
+                    "api.dns_strategy",
+                    format!("invalid DNS_STRATEGY '{}' (expected system, ipv4, ipv6 or ipv4_first). Using system.", dns_strategy),
+                );
+                DnsStrategy::System
+            }),
+            dns_overrides: self.get_json("DNS_OVERRIDES", "api.dns_overrides").unwrap_or_default(),
+            keys: self.get_list("MAXIMIZE_API_KEY", "api.keys"),
+            passthrough: self.get_bool("PASSTHROUGH", "api.passthrough", api_default.passthrough),
+            stream_body_min_kb: self.get_u64("STREAM_BODY_MIN_KB", "api.stream_body_min_kb", api_default.stream_body_min_kb),
+            validation: ValidationMode::parse(&validation).unwrap_or_else(|| {
+                self.warn(
+                    "VALIDATION",
+                    "api.validation",
+                    format!("invalid VALIDATION '{}' (expected strict or permissive). Using permissive.", validation),
+                );
+                ValidationMode::Permissive
+            }),
+            service_tiers: self.get_json("SERVICE_TIERS", "api.service_tiers").unwrap_or_default(),
         };
 
         let storage_default = StorageConfig::default();
-        let token_file_raw = loader.get_string("TOKEN_FILE", "storage.token_file", &storage_default.token_file);
-        let mut token_file = expand_tilde(&token_file_raw);
-        
-        // Validate token file path - if it's a directory, append default filename
-        let token_path = Path::new(&token_file);
-        if token_path.exists() && token_path.is_dir() {
-            eprintln!("Warning: TOKEN_FILE '{}' is a directory. Using '{}/tokens.json' instead.", token_file, token_file);
-            token_file = token_path.join("tokens.json").to_string_lossy().to_string();
-        } else if token_file.ends_with('/') || token_file.ends_with('\\') {
-            eprintln!("Warning: TOKEN_FILE '{}' appears to be a directory path. Using '{}tokens.json' instead.", token_file, token_file);
-            token_file.push_str("tokens.json");
-        }
-        
+        let token_file_raw = self.get_string("TOKEN_FILE", "storage.token_file", &storage_default.token_file);
         let storage = StorageConfig {
-            token_file,
+            token_file: resolve_token_file(&token_file_raw),
+        };
+
+        let oauth_default = OAuthConfig::default();
+        let oauth = OAuthConfig {
+            client_id: self.get_string("OAUTH_CLIENT_ID", "oauth.client_id", &oauth_default.client_id),
+            redirect_uri: self.get_string("OAUTH_REDIRECT_URI", "oauth.redirect_uri", &oauth_default.redirect_uri),
+            scopes: self.get_string("OAUTH_SCOPES", "oauth.scopes", &oauth_default.scopes),
+        };
+
+        let account_profiles: HashMap<String, String> =
+            self.get_json("MAXIMIZE_ACCOUNTS", "accounts.profiles").unwrap_or_default();
+        let accounts = AccountsConfig {
+            profiles: account_profiles
+                .into_iter()
+                .map(|(name, path)| (name, expand_tilde(&path)))
+                .collect(),
+            keys: self.get_json("MAXIMIZE_ACCOUNT_KEYS", "accounts.keys").unwrap_or_default(),
+            pool: self.get_list("MAXIMIZE_ACCOUNT_POOL", "accounts.pool"),
+        };
+
+        let tenants: HashMap<String, TenantConfig> = self.get_json("MAXIMIZE_TENANTS", "tenants").unwrap_or_default();
+        let tenants = tenants
+            .into_iter()
+            .map(|(name, mut tenant)| {
+                tenant.token_file = expand_tilde(&tenant.token_file);
+                (name, tenant)
+            })
+            .collect();
+
+        let scripting = ScriptingConfig {
+            transform_script: self
+                .get_optional_string("TRANSFORM_SCRIPT", "scripting.transform_script")
+                .map(|p| expand_tilde(&p)),
+            wasm_filters: self
+                .get_list("WASM_FILTERS", "scripting.wasm_filters")
+                .iter()
+                .map(|p| expand_tilde(p))
+                .collect(),
+        };
+
+        let admin = AdminConfig {
+            history_size: self.get_u64("REQUEST_HISTORY_SIZE", "admin.history_size", 100),
+        };
+
+        let cache = CacheConfig {
+            auto_inject: self.get_bool("AUTO_PROMPT_CACHE", "cache.auto_inject", false),
+            min_prefix_chars: self.get_u64("AUTO_PROMPT_CACHE_MIN_CHARS", "cache.min_prefix_chars", 4096),
+        };
+
+        let thinking: ThinkingConfig = self.get_json("THINKING_POLICY", "thinking").unwrap_or_default();
+
+        let sanitization = SanitizationConfig {
+            rules: self.get_json("SANITIZATION_RULES", "sanitization.rules"),
+        };
+
+        let images = ImageConfig {
+            max_bytes: self.get_u64("IMAGE_MAX_BYTES", "images.max_bytes", 5 * 1024 * 1024),
+            max_dimension: self.get_u64("IMAGE_MAX_DIMENSION", "images.max_dimension", 8000),
+            downscale: self.get_bool("IMAGE_DOWNSCALE", "images.downscale", false),
+        };
+
+        let tools = ToolConfig {
+            max_tools: self.get_u64("TOOL_MAX_COUNT", "tools.max_tools", 0),
+            max_schema_bytes: self.get_u64("TOOL_MAX_SCHEMA_BYTES", "tools.max_schema_bytes", 0),
+            max_result_chars: self.get_u64("TOOL_RESULT_MAX_CHARS", "tools.max_result_chars", 0),
+            truncate_results: self.get_bool("TOOL_RESULT_TRUNCATE", "tools.truncate_results", false),
+            allowed: self.get_json("TOOL_ALLOWLIST", "tools.allowed").unwrap_or_default(),
         };
 
-        Ok(Config {
+        let compaction = CompactionConfig {
+            enabled: self.get_bool("COMPACTION_ENABLED", "compaction.enabled", false),
+            max_tokens: self.get_u64("COMPACTION_MAX_TOKENS", "compaction.max_tokens", 150_000),
+            keep_recent: self.get_u64("COMPACTION_KEEP_RECENT", "compaction.keep_recent", 6),
+        };
+
+        let session_default = SessionConfig::default();
+        let sessions = SessionConfig {
+            enabled: self.get_bool("SESSIONS_ENABLED", "sessions.enabled", false),
+            backend: self.get_string("SESSION_BACKEND", "sessions.backend", &session_default.backend),
+            sqlite_path: expand_tilde(&self.get_string(
+                "SESSION_DB",
+                "sessions.sqlite_path",
+                &session_default.sqlite_path,
+            )),
+            ttl_seconds: self.get_u64("SESSION_TTL", "sessions.ttl_seconds", session_default.ttl_seconds),
+        };
+
+        let metadata = MetadataConfig {
+            inject_user_id: self.get_bool("INJECT_USER_ID", "metadata.inject_user_id", true),
+            user_id_salt: self.get_string("USER_ID_SALT", "metadata.user_id_salt", ""),
+        };
+
+        let logging = LoggingConfig {
+            redact_defaults: self.get_bool("REDACT_DEFAULTS", "logging.redact_defaults", true),
+            redaction_patterns: self.get_list("REDACTION_PATTERNS", "logging.redaction_patterns"),
+            request_bodies: self.get_log_detail("LOG_REQUEST_BODIES", "logging.request_bodies", LogDetail::Off),
+            headers: self.get_log_detail("LOG_HEADERS", "logging.headers", LogDetail::Off),
+            response_bodies: self.get_log_detail("LOG_RESPONSE_BODIES", "logging.response_bodies", LogDetail::Off),
+            debug_keys: self.get_list("DEBUG_KEYS", "logging.debug_keys"),
+            sample_every: self.get_u64("LOG_SAMPLE_EVERY", "logging.sample_every", 1),
+        };
+
+        let alerts = AlertConfig {
+            webhook_url: self.get_optional_string("ALERT_WEBHOOK_URL", "alerts.webhook_url"),
+            webhooks: self.get_json("ALERT_WEBHOOKS", "alerts.webhooks").unwrap_or_default(),
+            expiry_warning_hours: self.get_u64("ALERT_EXPIRY_WARNING_HOURS", "alerts.expiry_warning_hours", 6),
+            unauthorized_threshold: self.get_u64("ALERT_UNAUTHORIZED_THRESHOLD", "alerts.unauthorized_threshold", 5),
+        };
+
+        let quotas = self.get_json("KEY_QUOTAS", "quotas").unwrap_or_default();
+
+        let default_priority = self.get_string("DEFAULT_PRIORITY", "admission.default_priority", "interactive");
+        let admission = AdmissionConfig {
+            max_concurrent: self.get_u64("MAX_CONCURRENT_REQUESTS", "admission.max_concurrent", 0),
+            max_queue_depth: self.get_u64("MAX_QUEUE_DEPTH", "admission.max_queue_depth", 0),
+            max_wait_secs: self.get_u64("MAX_QUEUE_WAIT_SECS", "admission.max_wait_secs", 0),
+            default_priority: Priority::parse(&default_priority).unwrap_or_else(|| {
+                self.warn(
+                    "DEFAULT_PRIORITY",
+                    "admission.default_priority",
+                    format!("invalid DEFAULT_PRIORITY '{}' (expected urgent, interactive or batch). Using interactive.", default_priority),
+                );
+                Priority::Interactive
+            }),
+            priorities: self.get_json("KEY_PRIORITIES", "admission.priorities").unwrap_or_default(),
+            override_keys: self.get_list("PRIORITY_OVERRIDE_KEYS", "admission.override_keys"),
+        };
+
+        let overload_default = OverloadConfig::default();
+        let shed_priorities = self.get_list("SHED_PRIORITIES", "overload.shed_priorities");
+        let overload = OverloadConfig {
+            max_in_flight: self.get_u64("SHED_MAX_IN_FLIGHT", "overload.max_in_flight", 0),
+            max_memory_mb: self.get_u64("SHED_MAX_MEMORY_MB", "overload.max_memory_mb", 0),
+            shed_priorities: if shed_priorities.is_empty() {
+                overload_default.shed_priorities
+            } else {
+                shed_priorities
+                    .iter()
+                    .filter_map(|p| {
+                        let priority = Priority::parse(p);
+                        if priority.is_none() {
+                            self.warn(
+                                "SHED_PRIORITIES",
+                                "overload.shed_priorities",
+                                format!("unknown priority '{}' in SHED_PRIORITIES. Ignoring.", p),
+                            );
+                        }
+                        priority
+                    })
+                    .collect()
+            },
+            shed_non_streaming: self.get_bool("SHED_NON_STREAMING", "overload.shed_non_streaming", false),
+        };
+
+        let rate_limits_default = RateLimitConfig::default();
+        let rate_limits = RateLimitConfig {
+            auto_throttle: self.get_bool("RATE_LIMIT_AUTO_THROTTLE", "rate_limits.auto_throttle", rate_limits_default.auto_throttle),
+            throttle_below: self.get_f64("RATE_LIMIT_THROTTLE_BELOW", "rate_limits.throttle_below", rate_limits_default.throttle_below),
+            min_concurrent: self
+                .get_u64("RATE_LIMIT_MIN_CONCURRENT", "rate_limits.min_concurrent", rate_limits_default.min_concurrent)
+                .max(1),
+        };
+
+        let streaming = StreamingConfig {
+            normalize_events: self.get_bool("SSE_NORMALIZE", "streaming.normalize_events", false),
+            repair_tool_input: self.get_bool("SSE_REPAIR_TOOL_INPUT", "streaming.repair_tool_input", false),
+        };
+
+        let anthropic_api = AnthropicApiConfig {
+            api_key: self.get_optional_string("ANTHROPIC_API_KEY", "anthropic_api.api_key"),
+            fallback_on_auth_failure: self.get_bool(
+                "API_KEY_FALLBACK_ON_AUTH_FAILURE",
+                "anthropic_api.fallback_on_auth_failure",
+                false,
+            ),
+            fallback_on_rate_limit: self.get_bool(
+                "API_KEY_FALLBACK_ON_RATE_LIMIT",
+                "anthropic_api.fallback_on_rate_limit",
+                false,
+            ),
+        };
+
+        let bedrock_default = BedrockConfig::default();
+        let bedrock = BedrockConfig {
+            region: self.get_string("AWS_REGION", "bedrock.region", &bedrock_default.region),
+            access_key_id: self.get_optional_string("AWS_ACCESS_KEY_ID", "bedrock.access_key_id"),
+            secret_access_key: self.get_optional_string("AWS_SECRET_ACCESS_KEY", "bedrock.secret_access_key"),
+            session_token: self.get_optional_string("AWS_SESSION_TOKEN", "bedrock.session_token"),
+            model_ids: self.get_json("BEDROCK_MODEL_IDS", "bedrock.model_ids").unwrap_or_default(),
+        };
+
+        let vertex_default = VertexConfig::default();
+        let vertex = VertexConfig {
+            project_id: self.get_optional_string("VERTEX_PROJECT_ID", "vertex.project_id"),
+            region: self.get_string("VERTEX_REGION", "vertex.region", &vertex_default.region),
+            access_token: self.get_optional_string("VERTEX_ACCESS_TOKEN", "vertex.access_token"),
+            credentials_file: self.get_optional_string("GOOGLE_APPLICATION_CREDENTIALS", "vertex.credentials_file"),
+            model_ids: self.get_json("VERTEX_MODEL_IDS", "vertex.model_ids").unwrap_or_default(),
+        };
+
+        let shadow_upstream = self.get_optional_string("SHADOW_UPSTREAM", "shadow.upstream");
+        let shadow = ShadowConfig {
+            percent: self.get_f64("SHADOW_PERCENT", "shadow.percent", 0.0),
+            upstream: shadow_upstream.and_then(|u| {
+                let upstream = UpstreamKind::parse(&u);
+                if upstream.is_none() {
+                    self.warn(
+                        "SHADOW_UPSTREAM",
+                        "shadow.upstream",
+                        format!("unknown upstream '{}' in SHADOW_UPSTREAM. Mirroring to the request's own upstream.", u),
+                    );
+                }
+                upstream
+            }),
+            model: self.get_optional_string("SHADOW_MODEL", "shadow.model"),
+            record: self.get_bool("SHADOW_RECORD", "shadow.record", false),
+        };
+
+        let canaries = self.get_json("MODEL_CANARIES", "canaries").unwrap_or_default();
+
+        let prompt_experiment = self.get_json("PROMPT_EXPERIMENT", "prompt_experiment");
+
+        let presets = self.get_json("SYSTEM_PRESETS", "presets").unwrap_or_default();
+
+        let guardrail_action = self.get_string("GUARDRAIL_ACTION", "guardrails.action", "block");
+        let guardrails = GuardrailConfig {
+            patterns: self.get_list("GUARDRAIL_PATTERNS", "guardrails.patterns"),
+            keywords: self.get_list("GUARDRAIL_KEYWORDS", "guardrails.keywords"),
+            detect_secrets: self.get_bool("GUARDRAIL_DETECT_SECRETS", "guardrails.detect_secrets", false),
+            max_secrets: self.get_u64("GUARDRAIL_MAX_SECRETS", "guardrails.max_secrets", 0),
+            action: GuardrailAction::parse(&guardrail_action).unwrap_or_else(|| {
+                self.warn(
+                    "GUARDRAIL_ACTION",
+                    "guardrails.action",
+                    format!("unknown guardrail action '{}'. Blocking instead.", guardrail_action),
+                );
+                GuardrailAction::Block
+            }),
+            response_patterns: self.get_list("GUARDRAIL_RESPONSE_PATTERNS", "guardrails.response_patterns"),
+        };
+
+        let moderation_default = ModerationConfig::default();
+        let moderation = ModerationConfig {
+            url: self.get_optional_string("MODERATION_URL", "moderation.url"),
+            timeout_ms: self.get_u64("MODERATION_TIMEOUT_MS", "moderation.timeout_ms", moderation_default.timeout_ms),
+            fail_open: self.get_bool("MODERATION_FAIL_OPEN", "moderation.fail_open", moderation_default.fail_open),
+            rules: self.get_json("MODERATION_RULES", "moderation.rules").unwrap_or_default(),
+        };
+
+        let pricing = PricingConfig {
+            models: self.get_json("MODEL_PRICING", "pricing.models").unwrap_or_default(),
+            max_request_cost: self.get_f64("MAX_REQUEST_COST", "pricing.max_request_cost", 0.0),
+        };
+
+        let routing: RoutingConfig = self.get_json("ROUTING", "routing").unwrap_or_default();
+
+        Config {
             server,
             models,
             api,
             storage,
-        })
+            oauth,
+            accounts,
+            tenants,
+            scripting,
+            admin,
+            cache,
+            thinking,
+            sanitization,
+            images,
+            tools,
+            compaction,
+            sessions,
+            metadata,
+            logging,
+            alerts,
+            quotas,
+            admission,
+            overload,
+            rate_limits,
+            streaming,
+            anthropic_api,
+            bedrock,
+            vertex,
+            routing,
+            shadow,
+            canaries,
+            prompt_experiment,
+            presets,
+            guardrails,
+            moderation,
+            pricing,
+        }
     }
 }
@@ -0,0 +1,80 @@
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::settings::Settings;
+
+/// Which addresses of a host to connect to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsStrategy {
+    /// Whatever the system resolver returns, in its order
+    #[default]
+    System,
+    /// IPv4 addresses only
+    Ipv4,
+    /// IPv6 addresses only
+    Ipv6,
+    /// IPv4 addresses first, IPv6 as a fallback
+    Ipv4First,
+}
+
+impl DnsStrategy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "system" => Some(DnsStrategy::System),
+            "ipv4" => Some(DnsStrategy::Ipv4),
+            "ipv6" => Some(DnsStrategy::Ipv6),
+            "ipv4_first" => Some(DnsStrategy::Ipv4First),
+            _ => None,
+        }
+    }
+}
+
+/// System lookups, filtered and ordered by address family
+struct FamilyResolver(DnsStrategy);
+
+impl Resolve for FamilyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let strategy = self.0;
+        Box::pin(async move {
+            let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            match strategy {
+                DnsStrategy::System => {}
+                DnsStrategy::Ipv4 => addrs.retain(SocketAddr::is_ipv4),
+                DnsStrategy::Ipv6 => addrs.retain(SocketAddr::is_ipv6),
+                DnsStrategy::Ipv4First => addrs.sort_by_key(SocketAddr::is_ipv6),
+            }
+            if addrs.is_empty() {
+                return Err(format!("No address of {} matches the {:?} DNS strategy", name.as_str(), strategy).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// HTTP client for Anthropic and the other upstreams, with the connection settings of
+/// the `api` section: connect timeout, TCP keepalive, DNS strategy and static addresses
+pub fn http_client(settings: &Settings) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder();
+    if settings.connect_timeout_ms > 0 {
+        builder = builder.connect_timeout(Duration::from_millis(settings.connect_timeout_ms));
+    }
+    builder = builder.tcp_keepalive((settings.tcp_keepalive_secs > 0).then(|| Duration::from_secs(settings.tcp_keepalive_secs)));
+    if settings.dns_strategy != DnsStrategy::System {
+        builder = builder.dns_resolver(Arc::new(FamilyResolver(settings.dns_strategy)));
+    }
+    for (host, ips) in &settings.dns_overrides {
+        // The port is taken from the URL
+        let addrs: Vec<SocketAddr> = ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect();
+        builder = builder.resolve_to_addrs(host, &addrs);
+    }
+
+    builder.build().unwrap_or_else(|e| {
+        tracing::error!("Failed to apply the connection settings, using defaults: {}", e);
+        reqwest::Client::new()
+    })
+}
@@ -0,0 +1,116 @@
+use std::path::Path;
+
+use crate::config_check::{self, Severity};
+use crate::config_loader::ConfigLoader;
+use crate::oauth::OAuthManager;
+use crate::proxy;
+use crate::settings::Settings;
+
+/// Outcome of one self-test check
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, passed: true, detail: detail.into() }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, passed: false, detail: detail.into() }
+    }
+}
+
+/// Validate config, token file permissions and tokens, then confirm the upstream path
+/// with a one-token request. Later checks are skipped once the tokens are unusable.
+pub async fn run_checks(loader: &ConfigLoader, settings: &Settings, oauth_manager: &OAuthManager) -> Vec<Check> {
+    let mut checks = vec![check_config(loader, settings), check_token_file(&settings.token_file)];
+
+    let access_token = match oauth_manager.get_valid_token().await {
+        Ok(Some(token)) => {
+            checks.push(Check::pass("tokens", "access token valid (refreshed if it had expired)"));
+            token
+        }
+        Ok(None) if oauth_manager.storage().is_revoked() => {
+            checks.push(Check::fail("tokens", "refresh token was revoked; log in again"));
+            return checks;
+        }
+        Ok(None) => {
+            checks.push(Check::fail("tokens", "no valid tokens; log in first"));
+            return checks;
+        }
+        Err(e) => {
+            checks.push(Check::fail("tokens", format!("token refresh failed: {}", e)));
+            return checks;
+        }
+    };
+
+    let model = settings.resolve_model(&settings.default_model);
+    checks.push(match proxy::probe_upstream(oauth_manager.http(), &model, &access_token).await {
+        Ok((status, _)) if status.is_success() => Check::pass("upstream", format!("{} answered a test request", model)),
+        Ok((status, body)) => Check::fail("upstream", format!("HTTP {}: {}", status, body)),
+        Err(e) => Check::fail("upstream", format!("request failed: {}", e)),
+    });
+
+    checks
+}
+
+/// Continues a check's detail on the next line, under the first line's detail
+const FINDING_SEPARATOR: &str = "\n                ";
+
+/// What `config check` finds; only its errors fail the check
+fn check_config(loader: &ConfigLoader, settings: &Settings) -> Check {
+    let findings = config_check::check(loader, settings);
+    if findings.is_empty() {
+        return Check::pass("config", "configuration loaded");
+    }
+
+    let detail = findings
+        .iter()
+        .map(|finding| match finding.severity {
+            Severity::Error => format!("{}: {}", finding.origin, finding.message),
+            Severity::Warning => format!("warning, {}: {}", finding.origin, finding.message),
+        })
+        .collect::<Vec<_>>()
+        .join(FINDING_SEPARATOR);
+    if findings.iter().any(|finding| finding.severity == Severity::Error) {
+        Check::fail("config", detail)
+    } else {
+        Check::pass("config", detail)
+    }
+}
+
+fn check_token_file(token_file: &str) -> Check {
+    let path = Path::new(token_file);
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return Check::pass("token file", format!("{} does not exist yet", token_file));
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            return Check::fail(
+                "token file",
+                format!("{} is accessible by other users (mode {:o}); run chmod 600", token_file, mode),
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+
+    Check::pass("token file", format!("{} has private permissions", token_file))
+}
+
+/// Print check results; returns whether all passed
+pub fn report(checks: &[Check]) -> bool {
+    for check in checks {
+        let mark = if check.passed { "✅" } else { "❌" };
+        println!("{} {:<12} {}", mark, check.name, check.detail);
+    }
+    checks.iter().all(|c| c.passed)
+}
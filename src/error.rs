@@ -0,0 +1,241 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Value};
+
+use crate::admission::QueueRejection;
+use crate::quota::QuotaExceeded;
+
+/// Status recorded for requests the client gave up on, as nginx does
+pub const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+/// Every way the proxy fails a request. Each variant has a fixed status code, Anthropic
+/// error type and metrics category, and is sent in Anthropic's error shape:
+/// `{"type": "error", "error": {"type": ..., "message": ...}}`.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ProxyError {
+    /// Missing or unknown client API key, or no usable OAuth token
+    #[error("{0}")]
+    Authentication(String),
+    /// The key or tenant may not use what it asked for
+    #[error("{0}")]
+    Permission(String),
+    /// A malformed request, or one over a configured limit
+    #[error("{0}")]
+    InvalidRequest(String),
+    /// Content refused by the guardrails or a moderator
+    #[error("{0}")]
+    PolicyViolation(String),
+    #[error("{0}")]
+    NotFound(String),
+    /// A token quota of the key or its tenant is used up
+    #[error("{message}")]
+    QuotaExceeded { message: String, quota: Value, retry_after: u64 },
+    /// The admission queue is full, or no slot freed up in time
+    #[error("{message}")]
+    QueueFull { message: String, retry_after: u64 },
+    /// A low-priority request shed under load
+    #[error("Proxy is overloaded; low-priority requests are temporarily rejected")]
+    Overloaded,
+    /// The upstream did not respond before the request's deadline
+    #[error("{0}")]
+    Timeout(String),
+    /// The client's own timeout passed first, so the upstream call was cancelled
+    #[error("{0}")]
+    ClientClosed(String),
+    /// An error response of the upstream, relayed with its status and body
+    #[error("{message}")]
+    Upstream { status: StatusCode, message: String, body: Value },
+    /// The upstream could not be reached, or its response could not be read
+    #[error("{0}")]
+    BadGateway(String),
+    /// Something the request depends on is unavailable, such as a tenant's token store
+    #[error("{0}")]
+    Unavailable(String),
+    /// A failure inside the proxy: a hook, the session store, a missing upstream setting
+    #[error("{0}")]
+    Internal(String),
+}
+
+/// Category of an error response, left in its extensions for `maximize_errors_total`
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorCategory(pub &'static str);
+
+impl ProxyError {
+    /// Every value of `category()`, exported from the start so alerts work before the first error
+    pub const CATEGORIES: &'static [&'static str] = &[
+        "authentication",
+        "permission",
+        "validation",
+        "policy",
+        "not_found",
+        "quota",
+        "queue_full",
+        "overloaded",
+        "timeout",
+        "cancelled",
+        "upstream_4xx",
+        "upstream_5xx",
+        "upstream_unreachable",
+        "unavailable",
+        "internal",
+    ];
+
+    /// Relay an upstream error response. Bodies already in Anthropic's shape are kept;
+    /// anything else becomes the message of an error typed by the status.
+    pub fn upstream(status: u16, body: &str) -> Self {
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+        let body = serde_json::from_str::<Value>(body)
+            .ok()
+            .filter(|v| v.get("error").is_some_and(Value::is_object))
+            .unwrap_or_else(|| {
+                json!({
+                    "type": "error",
+                    "error": {"type": error_type_for(status.as_u16()), "message": body}
+                })
+            });
+        let message = body["error"]["message"].as_str().unwrap_or_default().to_string();
+        ProxyError::Upstream { status, message, body }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::Authentication(_) => StatusCode::UNAUTHORIZED,
+            ProxyError::Permission(_) => StatusCode::FORBIDDEN,
+            ProxyError::InvalidRequest(_) | ProxyError::PolicyViolation(_) => StatusCode::BAD_REQUEST,
+            ProxyError::NotFound(_) => StatusCode::NOT_FOUND,
+            ProxyError::QuotaExceeded { .. } | ProxyError::QueueFull { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ProxyError::Overloaded => StatusCode::from_u16(529).expect("valid status code"),
+            ProxyError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::ClientClosed(_) => StatusCode::from_u16(CLIENT_CLOSED_REQUEST).expect("valid status code"),
+            ProxyError::Upstream { status, .. } => *status,
+            ProxyError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// `error.type` of the body
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            ProxyError::Authentication(_) => "authentication_error",
+            ProxyError::Permission(_) => "permission_error",
+            ProxyError::InvalidRequest(_) => "invalid_request_error",
+            ProxyError::PolicyViolation(_) => "policy_violation",
+            ProxyError::NotFound(_) => "not_found_error",
+            ProxyError::QuotaExceeded { .. } => "quota_exceeded",
+            ProxyError::QueueFull { .. } => "rate_limit_error",
+            ProxyError::Overloaded => "overloaded_error",
+            ProxyError::Timeout(_) | ProxyError::ClientClosed(_) => "timeout_error",
+            ProxyError::Upstream { status, .. } => error_type_for(status.as_u16()),
+            ProxyError::BadGateway(_) | ProxyError::Unavailable(_) | ProxyError::Internal(_) => "api_error",
+        }
+    }
+
+    /// Label of the error in `maximize_errors_total`
+    pub fn category(&self) -> &'static str {
+        match self {
+            ProxyError::Authentication(_) => "authentication",
+            ProxyError::Permission(_) => "permission",
+            ProxyError::InvalidRequest(_) => "validation",
+            ProxyError::PolicyViolation(_) => "policy",
+            ProxyError::NotFound(_) => "not_found",
+            ProxyError::QuotaExceeded { .. } => "quota",
+            ProxyError::QueueFull { .. } => "queue_full",
+            ProxyError::Overloaded => "overloaded",
+            ProxyError::Timeout(_) => "timeout",
+            ProxyError::ClientClosed(_) => "cancelled",
+            ProxyError::Upstream { status, .. } if status.is_server_error() => "upstream_5xx",
+            ProxyError::Upstream { .. } => "upstream_4xx",
+            ProxyError::BadGateway(_) => "upstream_unreachable",
+            ProxyError::Unavailable(_) => "unavailable",
+            ProxyError::Internal(_) => "internal",
+        }
+    }
+
+    /// Seconds the client should wait before retrying, sent in the body and as Retry-After
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            ProxyError::QuotaExceeded { retry_after, .. } | ProxyError::QueueFull { retry_after, .. } => {
+                Some(*retry_after)
+            }
+            ProxyError::Overloaded => Some(1),
+            _ => None,
+        }
+    }
+
+    pub fn body(&self) -> Value {
+        if let ProxyError::Upstream { body, .. } = self {
+            return body.clone();
+        }
+        let mut error = json!({"type": self.error_type(), "message": self.to_string()});
+        if let ProxyError::QuotaExceeded { quota, .. } = self {
+            error["quota"] = quota.clone();
+        }
+        if let Some(seconds) = self.retry_after() {
+            error["retry_after"] = json!(seconds);
+        }
+        json!({"type": "error", "error": error})
+    }
+}
+
+impl IntoResponse for ProxyError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(self.body())).into_response();
+        if let Some(seconds) = self.retry_after() {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response.extensions_mut().insert(ErrorCategory(self.category()));
+        response
+    }
+}
+
+impl From<QuotaExceeded> for ProxyError {
+    fn from(exceeded: QuotaExceeded) -> Self {
+        let window = exceeded.window;
+        let key = exceeded
+            .name
+            .as_deref()
+            .map(|name| format!(" for key '{}'", name))
+            .unwrap_or_default();
+        ProxyError::QuotaExceeded {
+            message: format!(
+                "{:?} quota of {} tokens exhausted{}; resets at {}",
+                window.period,
+                window.limit,
+                key,
+                window.resets_at.to_rfc3339()
+            ),
+            retry_after: (window.resets_at - chrono::Utc::now()).num_seconds().max(1) as u64,
+            quota: json!(window),
+        }
+    }
+}
+
+impl From<QueueRejection> for ProxyError {
+    fn from(rejection: QueueRejection) -> Self {
+        let message = match rejection {
+            QueueRejection::Full { .. } => "Too many requests are waiting on this proxy",
+            QueueRejection::Timeout { .. } => "Timed out waiting for a free slot on this proxy",
+        };
+        ProxyError::QueueFull {
+            message: message.to_string(),
+            retry_after: rejection.retry_after(),
+        }
+    }
+}
+
+/// Anthropic error type for an HTTP status, for error bodies that don't carry one
+pub fn error_type_for(status: u16) -> &'static str {
+    match status {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        429 => "rate_limit_error",
+        503 | 529 => "overloaded_error",
+        _ => "api_error",
+    }
+}
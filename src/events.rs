@@ -0,0 +1,90 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Buffered events per subscriber before slow subscribers start missing events
+const EVENT_BUFFER: usize = 256;
+
+/// Proxy activity pushed to live subscribers (e.g. /admin/events)
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProxyEvent {
+    RequestStarted {
+        request_id: String,
+        model: String,
+        stream: bool,
+    },
+    RequestFinished {
+        request_id: String,
+        status: u16,
+        duration_ms: u64,
+    },
+    RequestFailed {
+        request_id: String,
+        status: u16,
+        duration_ms: u64,
+        message: String,
+    },
+    TokenRefreshed {
+        expires_in: i64,
+    },
+    TokenRefreshFailed {
+        message: String,
+    },
+    /// The refresh token was rejected as invalid; a re-login is required
+    TokenRevoked {
+        message: String,
+    },
+}
+
+impl ProxyEvent {
+    /// Event name used for the SSE `event:` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProxyEvent::RequestStarted { .. } => "request_started",
+            ProxyEvent::RequestFinished { .. } => "request_finished",
+            ProxyEvent::RequestFailed { .. } => "request_failed",
+            ProxyEvent::TokenRefreshed { .. } => "token_refreshed",
+            ProxyEvent::TokenRefreshFailed { .. } => "token_refresh_failed",
+            ProxyEvent::TokenRevoked { .. } => "token_revoked",
+        }
+    }
+}
+
+/// Event with the time it was published
+#[derive(Debug, Clone, Serialize)]
+pub struct EventEnvelope {
+    pub timestamp: i64,
+    #[serde(flatten)]
+    pub event: ProxyEvent,
+}
+
+/// Fan-out channel for proxy events. Cloning shares the same channel.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<EventEnvelope>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+
+    /// Publish an event; a no-op when nobody is subscribed
+    pub fn publish(&self, event: ProxyEvent) {
+        let _ = self.sender.send(EventEnvelope {
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            event,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<EventEnvelope> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
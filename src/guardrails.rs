@@ -0,0 +1,379 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::proxy::AnthropicMessageRequest;
+use crate::sse::SseEvent;
+
+/// Credential shapes counted by the secrets heuristic: cloud and SaaS keys, private keys,
+/// JWTs and password-like assignments
+const SECRET_PATTERNS: &[&str] = &[
+    r"\b(AKIA|ASIA)[0-9A-Z]{16}\b",
+    r"-----BEGIN [A-Z ]*PRIVATE KEY-----",
+    r"sk-ant-[A-Za-z0-9_-]{20,}",
+    r"\bsk-[A-Za-z0-9_-]{20,}",
+    r"\bgh[pousr]_[A-Za-z0-9]{36,}",
+    r"\bxox[abprs]-[A-Za-z0-9-]{10,}",
+    r"\bAIza[0-9A-Za-z_-]{35}",
+    r"\beyJ[A-Za-z0-9_-]{10,}\.[A-Za-z0-9_-]{10,}\.[A-Za-z0-9_-]{10,}",
+    r#"(?i)\b(password|passwd|secret|api[_-]?key|access[_-]?token)\s*[:=]\s*['"]?[^\s'"]{8,}"#,
+];
+
+const REPLACEMENT: &str = "[REDACTED]";
+
+/// Keys whose string values are never inspected: signed thinking and identifiers
+const SKIPPED_KEYS: &[&str] = &[
+    "signature",
+    "thinking",
+    "type",
+    "media_type",
+    "id",
+    "tool_use_id",
+    "file_id",
+    "name",
+    "role",
+];
+
+/// What happens to a request with forbidden content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuardrailAction {
+    /// Reject the request with a policy_violation error
+    #[default]
+    Block,
+    /// Replace the forbidden content and forward the request
+    Scrub,
+}
+
+impl GuardrailAction {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "block" => Some(GuardrailAction::Block),
+            "scrub" => Some(GuardrailAction::Scrub),
+            _ => None,
+        }
+    }
+}
+
+/// Deny-list filter applied to request content before it leaves the network
+#[derive(Debug, Clone, Default)]
+pub struct Guardrails {
+    /// Patterns and keywords, with the description reported when one matches
+    deny: Vec<(String, Regex)>,
+    secrets: Vec<Regex>,
+    max_secrets: usize,
+    action: GuardrailAction,
+}
+
+impl Guardrails {
+    /// Keywords match case-insensitively anywhere. Invalid patterns are skipped with a
+    /// warning.
+    pub fn new(
+        patterns: &[String],
+        keywords: &[String],
+        detect_secrets: bool,
+        max_secrets: usize,
+        action: GuardrailAction,
+    ) -> Self {
+        let patterns = patterns.iter().enumerate().filter_map(|(i, pattern)| match Regex::new(pattern) {
+            Ok(regex) => Some((format!("deny pattern #{}", i + 1), regex)),
+            Err(e) => {
+                warn!("Ignoring invalid guardrail pattern '{}': {}", pattern, e);
+                None
+            }
+        });
+        let keywords = keywords.iter().enumerate().filter_map(|(i, keyword)| {
+            Regex::new(&format!("(?i){}", regex::escape(keyword)))
+                .ok()
+                .map(|regex| (format!("deny keyword #{}", i + 1), regex))
+        });
+        let secrets = SECRET_PATTERNS
+            .iter()
+            .filter(|_| detect_secrets)
+            .map(|p| Regex::new(p).expect("built-in secret patterns are valid"))
+            .collect();
+
+        Self {
+            deny: patterns.chain(keywords).collect(),
+            secrets,
+            max_secrets,
+            action,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.deny.is_empty() || !self.secrets.is_empty()
+    }
+
+    /// Check the system prompt and messages. When blocking, returns what the request
+    /// violated; when scrubbing, how many matches were replaced.
+    pub fn apply(&self, request: &mut AnthropicMessageRequest) -> Result<usize, String> {
+        let mut texts = inspected_strings(request);
+
+        let secret_count: usize = texts
+            .iter()
+            .map(|text| self.secrets.iter().map(|r| r.find_iter(text).count()).sum::<usize>())
+            .sum();
+        let too_many_secrets = secret_count > self.max_secrets;
+
+        if self.action == GuardrailAction::Block {
+            if let Some((description, _)) = self.deny.iter().find(|(_, r)| texts.iter().any(|text| r.is_match(text))) {
+                return Err(format!("request content matches {}", description));
+            }
+            if too_many_secrets {
+                return Err(format!("request contains {} likely credentials", secret_count));
+            }
+            return Ok(0);
+        }
+
+        let mut replaced = 0;
+        let scrubbed = self.deny.iter().map(|(_, r)| r).chain(self.secrets.iter().filter(|_| too_many_secrets));
+        for regex in scrubbed {
+            for text in texts.iter_mut() {
+                let count = regex.find_iter(text).count();
+                if count > 0 {
+                    replaced += count;
+                    **text = regex.replace_all(text, REPLACEMENT).into_owned();
+                }
+            }
+        }
+        Ok(replaced)
+    }
+}
+
+/// Whether the member `key` of an object with this `type` is inspected. Redacted thinking
+/// is opaque, and so is the `data` of a base64 source; a text document source keeps its
+/// text there.
+fn is_inspected(object_type: Option<&str>, key: &str) -> bool {
+    match key {
+        _ if object_type == Some("redacted_thinking") => false,
+        "data" => object_type != Some("base64"),
+        _ => !SKIPPED_KEYS.contains(&key),
+    }
+}
+
+/// Every inspected string in a JSON value
+fn collect_strings<'a>(value: &'a mut Value, out: &mut Vec<&'a mut String>) {
+    match value {
+        Value::String(s) => out.push(s),
+        Value::Array(items) => items.iter_mut().for_each(|item| collect_strings(item, out)),
+        Value::Object(map) => {
+            let object_type = map.get("type").and_then(|t| t.as_str()).map(str::to_owned);
+            for (key, item) in map.iter_mut() {
+                if is_inspected(object_type.as_deref(), key) {
+                    collect_strings(item, out);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Strings in the system prompt and messages, which guardrails and moderation inspect
+fn inspected_strings(request: &mut AnthropicMessageRequest) -> Vec<&mut String> {
+    let mut texts = Vec::new();
+    if let Some(system) = request.system.as_mut() {
+        collect_strings(system, &mut texts);
+    }
+    for message in request.messages.iter_mut() {
+        collect_strings(message, &mut texts);
+    }
+    texts
+}
+
+/// Every inspected string in a JSON value, read-only
+fn collect_text<'a>(value: &'a Value, out: &mut Vec<&'a str>) {
+    match value {
+        Value::String(s) => out.push(s),
+        Value::Array(items) => items.iter().for_each(|item| collect_text(item, out)),
+        Value::Object(map) => {
+            let object_type = map.get("type").and_then(|t| t.as_str());
+            for (key, item) in map {
+                if is_inspected(object_type, key) {
+                    collect_text(item, out);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The text guardrails and moderation inspect, for moderators that only read it
+pub fn inspected_text(request: &AnthropicMessageRequest) -> Vec<&str> {
+    let mut texts = Vec::new();
+    if let Some(system) = &request.system {
+        collect_text(system, &mut texts);
+    }
+    for message in &request.messages {
+        collect_text(message, &mut texts);
+    }
+    texts
+}
+
+/// Masks matches of configured patterns in generated text before it reaches the client
+#[derive(Debug, Clone, Default)]
+pub struct ResponseScrubber {
+    patterns: Vec<Regex>,
+}
+
+impl ResponseScrubber {
+    /// Invalid patterns are skipped with a warning
+    pub fn new(patterns: &[String]) -> Self {
+        let patterns = patterns
+            .iter()
+            .filter_map(|pattern| match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    warn!("Ignoring invalid response guardrail pattern '{}': {}", pattern, e);
+                    None
+                }
+            })
+            .collect();
+        Self { patterns }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.patterns.is_empty()
+    }
+
+    pub fn scrub<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut result = Cow::Borrowed(text);
+        for regex in &self.patterns {
+            if let Cow::Owned(replaced) = regex.replace_all(&result, REPLACEMENT) {
+                result = Cow::Owned(replaced);
+            }
+        }
+        result
+    }
+
+    /// Scrub the text blocks of a non-streaming response
+    pub fn scrub_message(&self, message: &mut Value) {
+        let Some(Value::Array(blocks)) = message.get_mut("content") else {
+            return;
+        };
+        for block in blocks {
+            if block.get("type").and_then(|t| t.as_str()) != Some("text") {
+                continue;
+            }
+            if let Some(Value::String(text)) = block.get_mut("text") {
+                if let Cow::Owned(scrubbed) = self.scrub(text) {
+                    *text = scrubbed;
+                }
+            }
+        }
+    }
+}
+
+/// Scrubs text deltas of a streamed response. Each delta is sent up to its last
+/// whitespace and the rest held for the next one, so matches within a word split
+/// across deltas are still caught.
+pub struct StreamScrubber {
+    scrubber: Arc<ResponseScrubber>,
+    /// Text held back, per block index
+    pending: HashMap<u64, String>,
+}
+
+impl StreamScrubber {
+    pub fn new(scrubber: Arc<ResponseScrubber>) -> Self {
+        Self {
+            scrubber,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// The events to send in place of this one
+    pub fn process(&mut self, event: SseEvent) -> Vec<SseEvent> {
+        let Some(data) = event.json() else {
+            return vec![event];
+        };
+        let index = data.get("index").and_then(|i| i.as_u64()).unwrap_or(0);
+
+        match data.get("type").and_then(|t| t.as_str()) {
+            Some("content_block_delta") => {
+                let Some(text) = data.pointer("/delta/text").and_then(|t| t.as_str()) else {
+                    return vec![event];
+                };
+                if data.pointer("/delta/type").and_then(|t| t.as_str()) != Some("text_delta") {
+                    return vec![event];
+                }
+                let pending = self.pending.entry(index).or_default();
+                pending.push_str(text);
+                let Some(split) = pending.rfind(char::is_whitespace) else {
+                    return Vec::new();
+                };
+                let split = split + pending[split..].chars().next().map_or(1, char::len_utf8);
+                let ready: String = pending.drain(..split).collect();
+                vec![self.text_delta(index, &ready)]
+            }
+            Some("content_block_stop") => match self.pending.remove(&index).filter(|p| !p.is_empty()) {
+                Some(rest) => vec![self.text_delta(index, &rest), event],
+                None => vec![event],
+            },
+            _ => vec![event],
+        }
+    }
+
+    fn text_delta(&self, index: u64, text: &str) -> SseEvent {
+        SseEvent {
+            event: Some("content_block_delta".to_string()),
+            data: json!({
+                "type": "content_block_delta",
+                "index": index,
+                "delta": {"type": "text_delta", "text": self.scrubber.scrub(text)}
+            })
+            .to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(content: Value) -> AnthropicMessageRequest {
+        serde_json::from_value(json!({
+            "model": "l",
+            "max_tokens": 100,
+            "messages": [{"role": "user", "content": content}]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn text_document_sources_are_inspected() {
+        let guardrails = Guardrails::new(&[], &["forbidden".to_string()], false, 0, GuardrailAction::Block);
+        let mut document = request(json!([{
+            "type": "document",
+            "source": {"type": "text", "media_type": "text/plain", "data": "a forbidden plan"}
+        }]));
+        assert!(guardrails.apply(&mut document).is_err());
+
+        // Base64 media is left alone, whatever its encoding happens to spell
+        let mut image = request(json!([{
+            "type": "image",
+            "source": {"type": "base64", "media_type": "image/png", "data": "forbidden+Zm9yYmlkZGVu"}
+        }]));
+        assert_eq!(guardrails.apply(&mut image), Ok(0));
+    }
+
+    #[test]
+    fn read_only_and_scrubbing_traversals_agree() {
+        let mut request = request(json!([
+            {"type": "text", "text": "hello"},
+            {"type": "document", "source": {"type": "text", "media_type": "text/plain", "data": "notes"}},
+            {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0K"}},
+            {"type": "redacted_thinking", "data": "opaque"},
+            {"type": "tool_use", "id": "tu_1", "name": "lookup", "input": {"query": "weather", "data": "raw"}}
+        ]));
+        request.system = Some(json!("be brief"));
+
+        let read: Vec<String> = inspected_text(&request).into_iter().map(str::to_owned).collect();
+        assert_eq!(read, ["be brief", "hello", "notes", "raw", "weather"].map(str::to_owned));
+        let scrubbed: Vec<String> = inspected_strings(&mut request).into_iter().map(|s| s.clone()).collect();
+        assert_eq!(read, scrubbed);
+    }
+}
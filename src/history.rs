@@ -0,0 +1,96 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::moderation::ModerationResult;
+use crate::settings::{CanaryArm, ExperimentArm};
+use crate::usage::TokenUsage;
+
+/// Summary of a single proxied request kept for quick debugging
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestRecord {
+    pub request_id: String,
+    pub timestamp: i64,
+    pub model: String,
+    pub stream: bool,
+    /// Short fingerprint of the client API key, never the key itself
+    pub key_id: Option<String>,
+    /// Tenant of the client key, in multi-tenant mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub status: u16,
+    pub latency_ms: u64,
+    pub usage: Option<TokenUsage>,
+    /// Truncated SHA-256 of the request messages, to spot repeated prompts
+    pub prompt_hash: String,
+    /// Local approximation of input tokens, useful when upstream usage is unavailable
+    pub estimated_input_tokens: u64,
+    pub error: Option<String>,
+    /// Handled with per-request debugging enabled
+    pub debug: bool,
+    /// For mirrored (shadow) requests, the ID of the request they mirror
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shadow_of: Option<String>,
+    /// Canary rollout arm the request was assigned to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canary: Option<CanaryArm>,
+    /// Prompt experiment variant the request was assigned to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<ExperimentArm>,
+    /// Verdicts of the moderators consulted
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub moderation: Vec<ModerationResult>,
+}
+
+/// Bounded in-memory history of the most recent requests
+pub struct RequestHistory {
+    capacity: usize,
+    records: Mutex<VecDeque<RequestRecord>>,
+}
+
+impl RequestHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, record: RequestRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Fill in usage reported after the request was recorded, at the end of a stream
+    pub fn set_usage(&self, request_id: &str, usage: TokenUsage) {
+        let mut records = self.records.lock().unwrap();
+        if let Some(record) = records.iter_mut().rev().find(|r| r.request_id == request_id) {
+            record.usage = Some(usage);
+        }
+    }
+
+    /// Most recent records first, at most `limit` of them
+    pub fn recent(&self, limit: usize) -> Vec<RequestRecord> {
+        let records = self.records.lock().unwrap();
+        records.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// Most recent requests of one tenant, newest first
+    pub fn recent_for_tenant(&self, tenant: &str, limit: usize) -> Vec<RequestRecord> {
+        let records = self.records.lock().unwrap();
+        records
+            .iter()
+            .rev()
+            .filter(|r| r.tenant.as_deref() == Some(tenant))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
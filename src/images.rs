@@ -0,0 +1,164 @@
+use base64::{engine::general_purpose, Engine};
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageReader};
+use serde_json::{Map, Value};
+use std::io::Cursor;
+use tracing::debug;
+
+use crate::proxy::AnthropicMessageRequest;
+
+/// Media types Anthropic accepts for image blocks
+const SUPPORTED_MEDIA_TYPES: &[&str] = &["image/jpeg", "image/png", "image/gif", "image/webp"];
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImageLimits {
+    pub max_bytes: usize,
+    pub max_dimension: u32,
+    /// Downscale oversized images instead of rejecting them
+    pub downscale: bool,
+}
+
+fn media_type_of(format: ImageFormat) -> Option<&'static str> {
+    match format {
+        ImageFormat::Jpeg => Some("image/jpeg"),
+        ImageFormat::Png => Some("image/png"),
+        ImageFormat::Gif => Some("image/gif"),
+        ImageFormat::WebP => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Shrink an image until it fits both limits, re-encoding JPEGs as JPEG and everything else as PNG
+fn downscale(image: DynamicImage, format: ImageFormat, limits: &ImageLimits) -> Result<(Vec<u8>, &'static str), String> {
+    let output_format = if format == ImageFormat::Jpeg { ImageFormat::Jpeg } else { ImageFormat::Png };
+    let (width, height) = image.dimensions();
+    let mut target = limits.max_dimension.min(width.max(height));
+
+    loop {
+        let resized = if width.max(height) > target {
+            image.resize(target, target, image::imageops::FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+        // JPEG has no alpha channel
+        let resized = if output_format == ImageFormat::Jpeg {
+            DynamicImage::ImageRgb8(resized.to_rgb8())
+        } else {
+            resized
+        };
+
+        let mut encoded = Cursor::new(Vec::new());
+        resized
+            .write_to(&mut encoded, output_format)
+            .map_err(|e| format!("failed to re-encode image: {}", e))?;
+        let encoded = encoded.into_inner();
+
+        if encoded.len() <= limits.max_bytes {
+            let media_type = media_type_of(output_format).unwrap_or("image/png");
+            return Ok((encoded, media_type));
+        }
+        if target <= 64 {
+            return Err("image could not be downscaled below the size limit".to_string());
+        }
+        target = target * 3 / 4;
+    }
+}
+
+/// Validate (and if allowed, downscale) a single base64 image source in place
+fn process_source(source: &mut Map<String, Value>, limits: &ImageLimits) -> Result<(), String> {
+    if source.get("type").and_then(|t| t.as_str()) != Some("base64") {
+        return Ok(());
+    }
+
+    let declared = source.get("media_type").and_then(|m| m.as_str()).unwrap_or_default().to_string();
+    if !SUPPORTED_MEDIA_TYPES.contains(&declared.as_str()) {
+        return Err(format!(
+            "unsupported image media_type '{}' (expected one of: {})",
+            declared,
+            SUPPORTED_MEDIA_TYPES.join(", ")
+        ));
+    }
+
+    let data = source.get("data").and_then(|d| d.as_str()).unwrap_or_default();
+    let bytes = general_purpose::STANDARD
+        .decode(data.trim())
+        .map_err(|e| format!("image data is not valid base64: {}", e))?;
+
+    let reader = ImageReader::new(Cursor::new(&bytes))
+        .with_guessed_format()
+        .map_err(|e| format!("unreadable image data: {}", e))?;
+    let format = reader
+        .format()
+        .ok_or_else(|| "image data is not a recognized image format".to_string())?;
+    let actual = media_type_of(format)
+        .ok_or_else(|| format!("unsupported image format {:?}", format))?;
+
+    if actual != declared {
+        debug!("Correcting image media_type from {} to {}", declared, actual);
+        source.insert("media_type".to_string(), Value::String(actual.to_string()));
+    }
+
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|e| format!("unreadable image data: {}", e))?;
+    let too_large = bytes.len() > limits.max_bytes;
+    let too_wide = width.max(height) > limits.max_dimension;
+    if !too_large && !too_wide {
+        return Ok(());
+    }
+
+    if !limits.downscale {
+        return Err(format!(
+            "image of {} bytes and {}x{} pixels exceeds the limit of {} bytes and {} pixels per side",
+            bytes.len(),
+            width,
+            height,
+            limits.max_bytes,
+            limits.max_dimension
+        ));
+    }
+
+    let image = image::load_from_memory_with_format(&bytes, format)
+        .map_err(|e| format!("failed to decode image: {}", e))?;
+    let (encoded, media_type) = downscale(image, format, limits)?;
+    debug!(
+        "Downscaled {}x{} image from {} to {} bytes",
+        width,
+        height,
+        bytes.len(),
+        encoded.len()
+    );
+
+    source.insert("media_type".to_string(), Value::String(media_type.to_string()));
+    source.insert("data".to_string(), Value::String(general_purpose::STANDARD.encode(encoded)));
+    Ok(())
+}
+
+fn process_blocks(blocks: &mut [Value], limits: &ImageLimits) -> Result<(), String> {
+    for block in blocks.iter_mut() {
+        match block.get("type").and_then(|t| t.as_str()) {
+            Some("image") => {
+                if let Some(Value::Object(source)) = block.get_mut("source") {
+                    process_source(source, limits)?;
+                }
+            }
+            Some("tool_result") => {
+                if let Some(Value::Array(nested)) = block.get_mut("content") {
+                    process_blocks(nested, limits)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check every image in the request against the configured limits.
+/// Returns a client-facing message describing the first invalid image.
+pub fn validate_images(request: &mut AnthropicMessageRequest, limits: &ImageLimits) -> Result<(), String> {
+    for (index, message) in request.messages.iter_mut().enumerate() {
+        if let Some(Value::Array(blocks)) = message.get_mut("content") {
+            process_blocks(blocks, limits).map_err(|e| format!("messages.{}: {}", index, e))?;
+        }
+    }
+    Ok(())
+}
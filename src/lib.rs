@@ -0,0 +1,54 @@
+// The OpenAPI document is one large json! literal
+#![recursion_limit = "256"]
+
+pub mod admin;
+pub mod admission;
+pub mod alerts;
+pub mod bedrock;
+pub mod affinity;
+pub mod cache;
+pub mod cli;
+pub mod compaction;
+pub mod config_check;
+pub mod config_loader;
+pub mod connection;
+pub mod doctor;
+pub mod error;
+pub mod events;
+pub mod guardrails;
+pub mod history;
+pub mod images;
+pub mod metrics;
+pub mod moderation;
+pub mod oauth;
+pub mod openai;
+pub mod openapi;
+pub mod passthrough;
+pub mod pkce;
+pub mod pricing;
+pub mod profile;
+pub mod proxy;
+pub mod quota;
+pub mod ratelimit;
+pub mod redaction;
+pub mod refresh_audit;
+pub mod request_log;
+pub mod routing;
+pub mod sanitize;
+pub mod scripting;
+pub mod sessions;
+pub mod settings;
+pub mod sse;
+pub mod stats;
+pub mod storage;
+pub mod tenants;
+pub mod tokenizer;
+pub mod tools;
+pub mod upstream;
+pub mod usage;
+pub mod usage_export;
+pub mod usage_report;
+pub mod validation;
+pub mod vertex;
+#[cfg(feature = "wasm")]
+pub mod wasm_filter;
@@ -1,12 +1,7 @@
-mod cli;
-mod config_loader;
-mod oauth;
-mod proxy;
-mod settings;
-mod storage;
-
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use maximize::{alerts, cli, config_check, config_loader, connection, doctor, oauth, proxy, settings, usage, usage_export, usage_report};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::runtime::Runtime;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -23,23 +18,226 @@ struct Args {
     #[arg(short, long)]
     bind: Option<String>,
 
+    /// Override listening port, 0 for any free port (default: from config or PORT)
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Override token file (default: from config or TOKEN_FILE)
+    #[arg(long)]
+    token_file: Option<String>,
+
+    /// Override default model (default: from config or DEFAULT_MODEL)
+    #[arg(long)]
+    model_default: Option<String>,
+
+    /// Override accepted client API keys, comma-separated or repeated (default: from config or MAXIMIZE_API_KEY)
+    #[arg(long, value_delimiter = ',')]
+    api_key: Vec<String>,
+
     /// Run in server-only mode (no CLI, for production/containers)
     #[arg(long)]
     server_only: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Args {
+    /// Flags given, keyed by the env var each one overrides
+    fn overrides(&self) -> HashMap<String, String> {
+        let api_keys = (!self.api_key.is_empty()).then(|| self.api_key.join(","));
+        [
+            ("BIND_ADDRESS", self.bind.clone()),
+            ("PORT", self.port.map(|port| port.to_string())),
+            ("TOKEN_FILE", self.token_file.clone()),
+            ("DEFAULT_MODEL", self.model_default.clone()),
+            ("MAXIMIZE_API_KEY", api_keys),
+        ]
+        .into_iter()
+        .filter_map(|(env_var, value)| Some((env_var.to_string(), value?)))
+        .collect()
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check config, token file permissions and tokens, then send a test request upstream
+    Doctor,
+    /// Inspect the effective configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Show recent token refresh attempts
+    RefreshLog {
+        /// Number of entries to show
+        #[arg(short, long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Show tokens and cost per key and model for today and this week
+    Usage {
+        #[command(subcommand)]
+        command: Option<UsageCommand>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Validate types, paths, model names and rate limit rules, showing where each bad value was set
+    Check,
+    /// Print every resolved setting, secrets masked, and whether it came from a flag, env, config.json or default
+    Show {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum UsageCommand {
+    /// Dump usage records for offline analysis and chargeback
+    Export {
+        /// Start of the range, inclusive (RFC 3339 time or YYYY-MM-DD)
+        #[arg(long)]
+        from: Option<String>,
+        /// End of the range, exclusive (RFC 3339 time or YYYY-MM-DD)
+        #[arg(long)]
+        to: Option<String>,
+        /// csv or parquet
+        #[arg(long, default_value = "csv")]
+        format: String,
+        /// File to write; standard output if omitted
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+    },
+}
+
+fn show_config(loader: &config_loader::ConfigLoader, config: &settings::Config, json: bool) -> Result<()> {
+    let mut effective = serde_json::to_value(config)?;
+    config_loader::mask_secrets(&mut effective);
+    let sources = loader.sources();
+    if json {
+        let report = serde_json::json!({ "config": effective, "sources": sources });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    let rows: Vec<(&String, String, &serde_json::Value)> = sources
+        .iter()
+        .map(|(path, setting)| {
+            let origin = match setting.source {
+                config_loader::ValueSource::Flag => format!("flag ({})", setting.env_var),
+                config_loader::ValueSource::Env => format!("env {}", setting.env_var),
+                config_loader::ValueSource::ConfigFile => "config.json".to_string(),
+                config_loader::ValueSource::Default => "default".to_string(),
+            };
+            let value = path
+                .split('.')
+                .try_fold(&effective, |value, key| value.get(key))
+                .unwrap_or(&serde_json::Value::Null);
+            (path, origin, value)
+        })
+        .collect();
+    let path_width = rows.iter().map(|(path, _, _)| path.len()).max().unwrap_or(0);
+    let origin_width = rows.iter().map(|(_, origin, _)| origin.len()).max().unwrap_or(0);
+    for (path, origin, value) in rows {
+        println!("{:<path_width$}  {:<origin_width$}  {}", path, origin, value);
+    }
+    Ok(())
+}
+
+fn show_refresh_log(settings: &settings::Settings, limit: usize) -> Result<()> {
+    let oauth_manager = oauth::OAuthManager::new(&settings.token_file, settings.oauth.clone(), connection::http_client(settings))?;
+    let audit = oauth_manager.refresh_audit();
+    let entries = audit.recent(limit)?;
+    if entries.is_empty() {
+        println!("No refresh attempts recorded in {}", audit.path().display());
+        return Ok(());
+    }
+
+    for entry in entries {
+        let when = chrono::DateTime::from_timestamp(entry.timestamp, 0)
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default();
+        let detail = match (entry.expires_at, entry.error) {
+            (Some(expires_at), _) => chrono::DateTime::from_timestamp(expires_at, 0)
+                .map(|t| format!("expires {}", t.to_rfc3339()))
+                .unwrap_or_default(),
+            (None, Some(error)) => error,
+            (None, None) => String::new(),
+        };
+        println!(
+            "{}  {:<22} {:<18} {}",
+            when,
+            format!("{:?}", entry.reason),
+            format!("{:?}", entry.outcome),
+            detail
+        );
+    }
+    Ok(())
 }
 
-async fn run_server_only(settings: settings::Settings) -> Result<()> {
+fn show_usage(settings: &settings::Settings) -> Result<()> {
+    let log = usage::UsageLog::for_token_file(std::path::Path::new(&settings.token_file));
+    let records = log.read(None, None)?;
+    if records.is_empty() {
+        println!("No usage recorded in {}", log.path().display());
+        return Ok(());
+    }
+    usage_report::print(&records, chrono::Utc::now());
+    Ok(())
+}
+
+fn export_usage(
+    settings: &settings::Settings,
+    from: Option<&str>,
+    to: Option<&str>,
+    format: &str,
+    output: Option<&std::path::Path>,
+) -> Result<()> {
+    let format = usage_export::ExportFormat::parse(format)
+        .ok_or_else(|| anyhow::anyhow!("unknown export format '{}', expected csv or parquet", format))?;
+    let (from, to) = usage_export::parse_range(from, to).map_err(anyhow::Error::msg)?;
+    let log = usage::UsageLog::for_token_file(std::path::Path::new(&settings.token_file));
+    let records = log.read(from, to)?;
+    let body = usage_export::export(&records, format)?;
+    match output {
+        Some(path) => {
+            std::fs::write(path, body)?;
+            eprintln!("Exported {} usage records to {}", records.len(), path.display());
+        }
+        None => std::io::Write::write_all(&mut std::io::stdout(), &body)?,
+    }
+    Ok(())
+}
+
+async fn run_doctor(loader: &config_loader::ConfigLoader, settings: settings::Settings) -> Result<()> {
+    let oauth_manager = oauth::OAuthManager::new(&settings.token_file, settings.oauth.clone(), connection::http_client(&settings))?;
+    let checks = doctor::run_checks(loader, &settings, &oauth_manager).await;
+    if !doctor::report(&checks) {
+        anyhow::bail!("self-test failed");
+    }
+    Ok(())
+}
+
+async fn run_server_only(loader: &config_loader::ConfigLoader, settings: settings::Settings) -> Result<()> {
     use tracing::info;
 
     let settings = Arc::new(settings);
-    let oauth_manager = Arc::new(oauth::OAuthManager::new(&settings.token_file)?);
+    let oauth_manager = Arc::new(oauth::OAuthManager::new(&settings.token_file, settings.oauth.clone(), connection::http_client(&settings))?);
 
     // Check for authorization code in environment and exchange it automatically
     if let Ok(auth_code) = std::env::var("MAXIMIZE_AUTHENTICATION_CODE") {
         info!("🔄 Found MAXIMIZE_AUTHENTICATION_CODE, exchanging for tokens...");
         info!("📝 Using code: {}...{}", &auth_code[..20.min(auth_code.len())], if auth_code.len() > 40 { "..." } else { "" });
         
-        match oauth_manager.exchange_code(&auth_code).await {
+        // The state may also be given on its own when the code was copied without it
+        let auth_state = std::env::var("MAXIMIZE_AUTHENTICATION_STATE").ok();
+        let exchanged = match oauth::Authorization::parse(&auth_code, auth_state.as_deref()) {
+            Ok(authorization) => oauth_manager.exchange_code(&authorization).await,
+            Err(e) => Err(e.into()),
+        };
+        match exchanged {
             Ok(_) => {
                 info!("✅ Successfully exchanged authorization code for tokens!");
                 info!("💡 Tokens saved to: {}", settings.token_file);
@@ -66,7 +264,10 @@ async fn run_server_only(settings: settings::Settings) -> Result<()> {
                 tracing::error!("Common issues:");
                 tracing::error!("  1. Code has expired (they expire in ~5 minutes)");
                 tracing::error!("  2. Code was already used (single-use only)");
-                tracing::error!("  3. Code format is wrong (must be: CODE#STATE)");
+                tracing::error!("  3. Code format is wrong; accepted formats are:");
+                tracing::error!("       CODE#STATE, as shown by Anthropic");
+                tracing::error!("       the full callback URL, with its code and state parameters");
+                tracing::error!("       CODE alone, with the state in MAXIMIZE_AUTHENTICATION_STATE");
                 tracing::error!("");
                 tracing::error!("Solution: Get a FRESH code from the OAuth URL below and use it immediately");
                 tracing::error!("");
@@ -95,7 +296,7 @@ async fn run_server_only(settings: settings::Settings) -> Result<()> {
         tracing::warn!("📋 After authorizing at the URL above, you can either:");
         tracing::warn!("");
         tracing::warn!("   Option 1 (Easiest - Auto exchange):");
-        tracing::warn!("   export MAXIMIZE_AUTHENTICATION_CODE=\"CODE#STATE\"");
+        tracing::warn!("   export MAXIMIZE_AUTHENTICATION_CODE=\"CODE#STATE\"  (or the callback URL)");
         tracing::warn!("   (Server will auto-exchange on restart)");
         tracing::warn!("");
         tracing::warn!("   Option 2 (Manual - Set tokens directly):");
@@ -110,28 +311,50 @@ async fn run_server_only(settings: settings::Settings) -> Result<()> {
     }
 
     // Log API key status
-    if settings.api_key.is_some() {
-        info!("🔐 API key authentication: ENABLED");
+    if !settings.api_keys.is_empty() {
+        info!("🔐 API key authentication: ENABLED ({} key(s) accepted)", settings.api_keys.len());
     } else {
         tracing::warn!("⚠️  API key authentication: DISABLED (set MAXIMIZE_API_KEY to enable)");
     }
 
-    let state = proxy::AppState {
-        oauth_manager,
-        settings: settings.clone(),
-        api_key: settings.api_key.clone(),
-    };
+    let bypassed = config_check::passthrough_bypasses(&settings);
+    if !bypassed.is_empty() {
+        tracing::warn!(
+            "⚠️  Passthrough mode skips the configured {}: Messages requests go upstream unchecked",
+            bypassed.join(", ")
+        );
+    }
+
+    if settings.startup_self_test {
+        info!("🩺 Running startup self-test...");
+        let checks = doctor::run_checks(loader, &settings, &oauth_manager).await;
+        for check in &checks {
+            if check.passed {
+                info!("   ✅ {}: {}", check.name, check.detail);
+            } else {
+                tracing::error!("   ❌ {}: {}", check.name, check.detail);
+            }
+        }
+        if checks.iter().any(|c| !c.passed) {
+            anyhow::bail!("Startup self-test failed; not accepting traffic");
+        }
+    }
+
+    oauth_manager.spawn_background_refresh();
+    alerts::spawn_monitor(&settings, oauth_manager.clone());
+    let listener = proxy::bind_listener(&settings).await?;
+    // With port 0 the OS picked the port
+    let local_addr = listener.local_addr()?;
+    let state = proxy::AppState::new(oauth_manager, settings.clone()).with_local_addr(local_addr);
 
     let app = proxy::create_router(state);
-    let bind_addr = format!("{}:{}", settings.bind_address, settings.port);
 
     info!("🚀 Maximize server starting in SERVER-ONLY mode");
-    info!("📍 Listening on: {}", bind_addr);
-    info!("🔗 Base URL: http://{}", bind_addr);
     info!("📡 Endpoint: /v1/messages");
+    proxy::announce_listener(&settings, local_addr);
 
-    let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
-    axum::serve(listener, app).await?;
+    // Write streamed events out immediately instead of letting Nagle's algorithm batch them
+    axum::serve(listener, app).tcp_nodelay(true).await?;
 
     Ok(())
 }
@@ -162,23 +385,38 @@ fn main() -> Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    // Load settings
-    let mut settings = settings::Settings::load()?;
-
-    // Override bind address if provided
-    if let Some(bind) = args.bind {
-        settings.bind_address = bind;
-    }
+    // Load settings; command line flags take the place of the env vars they mirror
+    let loader = config_loader::ConfigLoader::new(None)?.with_overrides(args.overrides());
+    let config = loader.config();
+    let settings = settings::Settings::from_config(&config);
 
-    if args.server_only {
-        // Run in server-only mode (no CLI)
-        tracing::info!("Starting in server-only mode...");
-        let rt = Runtime::new()?;
-        rt.block_on(run_server_only(settings))?;
-    } else {
-        // Create and run CLI (CLI manages its own Tokio runtime)
-        let mut cli = cli::Cli::new(settings)?;
-        cli.run()?;
+    match args.command {
+        Some(Command::Doctor) => {
+            let rt = Runtime::new()?;
+            rt.block_on(run_doctor(&loader, settings))?;
+        }
+        Some(Command::Config { command: ConfigCommand::Check }) => {
+            if !config_check::report(&config_check::check(&loader, &settings)) {
+                anyhow::bail!("configuration check failed");
+            }
+        }
+        Some(Command::Config { command: ConfigCommand::Show { json } }) => show_config(&loader, &config, json)?,
+        Some(Command::RefreshLog { limit }) => show_refresh_log(&settings, limit)?,
+        Some(Command::Usage { command: None }) => show_usage(&settings)?,
+        Some(Command::Usage { command: Some(UsageCommand::Export { from, to, format, output }) }) => {
+            export_usage(&settings, from.as_deref(), to.as_deref(), &format, output.as_deref())?
+        }
+        None if args.server_only => {
+            // Run in server-only mode (no CLI)
+            tracing::info!("Starting in server-only mode...");
+            let rt = Runtime::new()?;
+            rt.block_on(run_server_only(&loader, settings))?;
+        }
+        None => {
+            // Create and run CLI (CLI manages its own Tokio runtime)
+            let mut cli = cli::Cli::new(settings)?;
+            cli.run()?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,480 @@
+use prometheus::core::Metric;
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::admission::{Priority, QueueSnapshot};
+use crate::error::ProxyError;
+use crate::events::{EventEnvelope, ProxyEvent};
+use crate::moderation::ModerationResult;
+use crate::settings::{CanaryArm, ExperimentArm};
+use crate::usage::{self, TokenUsage};
+
+/// Stages of a proxied request, timed separately to tell proxy overhead from upstream latency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Client API key check
+    Auth,
+    /// Fetching the OAuth access token, including any refresh
+    Token,
+    /// Validation and rewriting of the request before it is sent upstream
+    Transform,
+    /// From sending the request upstream until response headers arrive
+    UpstreamTtfb,
+    /// From response headers until the last byte of the body (the whole stream when streaming)
+    Response,
+}
+
+impl Phase {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Phase::Auth => "auth",
+            Phase::Token => "token",
+            Phase::Transform => "transform",
+            Phase::UpstreamTtfb => "upstream_ttfb",
+            Phase::Response => "response",
+        }
+    }
+}
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// Time a streamed chunk spends inside the proxy, which should stay well under a millisecond
+const CHUNK_DELAY_BUCKETS: &[f64] = &[0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.1];
+
+/// Tokens per request, from a few tokens up to a million-token context
+const TOKEN_BUCKETS: &[f64] = &[
+    16.0, 64.0, 256.0, 1024.0, 4096.0, 16384.0, 32768.0, 65536.0, 131072.0, 262144.0, 524288.0, 1048576.0,
+];
+
+/// Prometheus metrics exported on /metrics
+pub struct Metrics {
+    registry: Registry,
+    phase_seconds: HistogramVec,
+    token_expires_in: IntGauge,
+    refreshes: IntCounterVec,
+    tokens: IntCounterVec,
+    request_tokens: HistogramVec,
+    cache_requests: IntCounterVec,
+    cache_hit_ratio: Gauge,
+    canary_requests: IntCounterVec,
+    canary_tokens: IntCounterVec,
+    experiment_requests: IntCounterVec,
+    experiment_tokens: IntCounterVec,
+    moderation_verdicts: IntCounterVec,
+    cancelled_requests: IntCounterVec,
+    errors: IntCounterVec,
+    in_flight: IntGauge,
+    queue_depth: IntGaugeVec,
+    concurrency_limit: IntGauge,
+    queue_wait_seconds: HistogramVec,
+    stream_first_byte_seconds: Histogram,
+    stream_chunk_delay_seconds: Histogram,
+}
+
+/// Queue waits of one priority class since startup, for /stats
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueWaits {
+    pub count: u64,
+    pub total_seconds: f64,
+    /// Cumulative like Prometheus buckets
+    pub buckets: Vec<WaitBucket>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WaitBucket {
+    /// Upper bound in seconds
+    pub le: f64,
+    /// Requests admitted within `le`
+    pub count: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let phase_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "maximize_phase_duration_seconds",
+                "Time spent in each phase of a proxied request",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["phase"],
+        )
+        .expect("valid histogram definition");
+        let token_expires_in = IntGauge::new(
+            "maximize_token_expires_in_seconds",
+            "Seconds until the OAuth access token expires (negative once expired)",
+        )
+        .expect("valid gauge definition");
+
+        let refreshes = IntCounterVec::new(
+            Opts::new("maximize_token_refresh_total", "OAuth token refresh attempts by outcome"),
+            &["outcome"],
+        )
+        .expect("valid counter definition");
+        // Export both outcomes from the start so rate() and alerts work before the first refresh
+        for outcome in ["success", "failure"] {
+            refreshes.with_label_values(&[outcome]);
+        }
+
+        let tokens = IntCounterVec::new(
+            Opts::new("maximize_tokens_total", "Tokens reported by upstream usage blocks, by type"),
+            &["type"],
+        )
+        .expect("valid counter definition");
+        for kind in ["input", "output", "cache_creation", "cache_read"] {
+            tokens.with_label_values(&[kind]);
+        }
+
+        let request_tokens = HistogramVec::new(
+            HistogramOpts::new(
+                "maximize_request_tokens",
+                "Tokens per request by model and type; input includes prompt cache reads and writes",
+            )
+            .buckets(TOKEN_BUCKETS.to_vec()),
+            &["model", "type"],
+        )
+        .expect("valid histogram definition");
+
+        let cache_requests = IntCounterVec::new(
+            Opts::new(
+                "maximize_prompt_cache_requests_total",
+                "Requests by prompt cache result: hit (read from cache), write (only wrote to it) or miss",
+            ),
+            &["result"],
+        )
+        .expect("valid counter definition");
+        for result in ["hit", "write", "miss"] {
+            cache_requests.with_label_values(&[result]);
+        }
+        let cache_hit_ratio = Gauge::new(
+            "maximize_prompt_cache_hit_ratio",
+            "Share of input tokens read from the prompt cache since the proxy started",
+        )
+        .expect("valid gauge definition");
+
+        let canary_requests = IntCounterVec::new(
+            Opts::new("maximize_canary_requests_total", "Requests in canary rollouts, by arm and outcome"),
+            &["rollout", "arm", "outcome"],
+        )
+        .expect("valid counter definition");
+        let canary_tokens = IntCounterVec::new(
+            Opts::new("maximize_canary_tokens_total", "Tokens used in canary rollouts, by arm and type"),
+            &["rollout", "arm", "type"],
+        )
+        .expect("valid counter definition");
+
+        let experiment_requests = IntCounterVec::new(
+            Opts::new(
+                "maximize_experiment_requests_total",
+                "Requests in the prompt experiment, by variant and outcome",
+            ),
+            &["experiment", "variant", "outcome"],
+        )
+        .expect("valid counter definition");
+        let experiment_tokens = IntCounterVec::new(
+            Opts::new(
+                "maximize_experiment_tokens_total",
+                "Tokens used in the prompt experiment, by variant and type",
+            ),
+            &["experiment", "variant", "type"],
+        )
+        .expect("valid counter definition");
+
+        let moderation_verdicts = IntCounterVec::new(
+            Opts::new("maximize_moderation_verdicts_total", "Moderation verdicts, by moderator and action"),
+            &["moderator", "action"],
+        )
+        .expect("valid counter definition");
+
+        let cancelled_requests = IntCounterVec::new(
+            Opts::new(
+                "maximize_requests_cancelled_total",
+                "Requests whose upstream call was abandoned because the client gave up, by reason",
+            ),
+            &["reason"],
+        )
+        .expect("valid counter definition");
+        for reason in ["disconnect", "client_timeout"] {
+            cancelled_requests.with_label_values(&[reason]);
+        }
+
+        let errors = IntCounterVec::new(
+            Opts::new("maximize_errors_total", "Error responses sent to clients, by category"),
+            &["category"],
+        )
+        .expect("valid counter definition");
+        for category in ProxyError::CATEGORIES {
+            errors.with_label_values(&[category]);
+        }
+
+        let in_flight = IntGauge::new(
+            "maximize_in_flight_requests",
+            "Requests holding an admission slot; streaming ones until their last byte is sent",
+        )
+        .expect("valid gauge definition");
+        let queue_depth = IntGaugeVec::new(
+            Opts::new("maximize_queue_depth", "Requests waiting for an admission slot, by priority"),
+            &["priority"],
+        )
+        .expect("valid gauge definition");
+        let concurrency_limit = IntGauge::new(
+            "maximize_concurrency_limit",
+            "Concurrency limit in effect after auto-throttling; 0 when unlimited",
+        )
+        .expect("valid gauge definition");
+        let queue_wait_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "maximize_queue_wait_seconds",
+                "Time admitted requests waited for a slot, by priority",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["priority"],
+        )
+        .expect("valid histogram definition");
+        for priority in Priority::ALL {
+            queue_depth.with_label_values(&[priority.name()]);
+            queue_wait_seconds.with_label_values(&[priority.name()]);
+        }
+
+        let stream_first_byte_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "maximize_stream_first_byte_seconds",
+                "Time from a streaming request's arrival until its first byte is handed to the client",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+        )
+        .expect("valid histogram definition");
+        let stream_chunk_delay_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "maximize_stream_chunk_delay_seconds",
+                "Time from receiving a chunk of an upstream stream until it is handed to the client",
+            )
+            .buckets(CHUNK_DELAY_BUCKETS.to_vec()),
+        )
+        .expect("valid histogram definition");
+
+        for metric in [
+            Box::new(phase_seconds.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(token_expires_in.clone()),
+            Box::new(refreshes.clone()),
+            Box::new(tokens.clone()),
+            Box::new(request_tokens.clone()),
+            Box::new(cache_requests.clone()),
+            Box::new(cache_hit_ratio.clone()),
+            Box::new(canary_requests.clone()),
+            Box::new(canary_tokens.clone()),
+            Box::new(experiment_requests.clone()),
+            Box::new(experiment_tokens.clone()),
+            Box::new(moderation_verdicts.clone()),
+            Box::new(cancelled_requests.clone()),
+            Box::new(errors.clone()),
+            Box::new(in_flight.clone()),
+            Box::new(queue_depth.clone()),
+            Box::new(concurrency_limit.clone()),
+            Box::new(queue_wait_seconds.clone()),
+            Box::new(stream_first_byte_seconds.clone()),
+            Box::new(stream_chunk_delay_seconds.clone()),
+        ] {
+            registry.register(metric).expect("metric registered once");
+        }
+
+        Self {
+            registry,
+            phase_seconds,
+            token_expires_in,
+            refreshes,
+            tokens,
+            request_tokens,
+            cache_requests,
+            cache_hit_ratio,
+            canary_requests,
+            canary_tokens,
+            experiment_requests,
+            experiment_tokens,
+            moderation_verdicts,
+            cancelled_requests,
+            errors,
+            in_flight,
+            queue_depth,
+            concurrency_limit,
+            queue_wait_seconds,
+            stream_first_byte_seconds,
+            stream_chunk_delay_seconds,
+        }
+    }
+
+    /// A request cancelled by a client `disconnect` or its `client_timeout`
+    pub fn record_cancelled(&self, reason: &str) {
+        self.cancelled_requests.with_label_values(&[reason]).inc();
+    }
+
+    /// An error response of the given `ProxyError::category`
+    pub fn record_error(&self, category: &str) {
+        self.errors.with_label_values(&[category]).inc();
+    }
+
+    pub fn observe_phase(&self, phase: Phase, duration: Duration) {
+        self.phase_seconds
+            .with_label_values(&[phase.label()])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn set_token_expires_in(&self, seconds: i64) {
+        self.token_expires_in.set(seconds);
+    }
+
+    pub fn set_admission(&self, snapshot: &QueueSnapshot) {
+        self.in_flight.set(snapshot.in_flight as i64);
+        for (priority, queued) in &snapshot.queued {
+            self.queue_depth.with_label_values(&[priority.name()]).set(*queued as i64);
+        }
+        self.concurrency_limit.set(snapshot.limit.unwrap_or(0) as i64);
+    }
+
+    /// Time a request waited for its admission slot, zero if it got one right away
+    pub fn observe_queue_wait(&self, priority: Priority, wait: Duration) {
+        self.queue_wait_seconds
+            .with_label_values(&[priority.name()])
+            .observe(wait.as_secs_f64());
+    }
+
+    pub fn observe_stream_first_byte(&self, elapsed: Duration) {
+        self.stream_first_byte_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_stream_chunk_delay(&self, delay: Duration) {
+        self.stream_chunk_delay_seconds.observe(delay.as_secs_f64());
+    }
+
+    pub fn queue_waits(&self) -> BTreeMap<Priority, QueueWaits> {
+        Priority::ALL
+            .into_iter()
+            .map(|priority| {
+                let metric = self.queue_wait_seconds.with_label_values(&[priority.name()]).metric();
+                let histogram = metric.get_histogram();
+                let buckets = histogram
+                    .get_bucket()
+                    .iter()
+                    .map(|bucket| WaitBucket {
+                        le: bucket.get_upper_bound(),
+                        count: bucket.get_cumulative_count(),
+                    })
+                    .collect();
+                let waits = QueueWaits {
+                    count: histogram.get_sample_count(),
+                    total_seconds: histogram.get_sample_sum(),
+                    buckets,
+                };
+                (priority, waits)
+            })
+            .collect()
+    }
+
+    /// Count the tokens of a completed request, streamed or not
+    pub fn record_usage(&self, model: &str, usage: &TokenUsage) {
+        for (kind, count) in [
+            ("input", usage.input_tokens),
+            ("output", usage.output_tokens),
+            ("cache_creation", usage.cache_creation_input_tokens),
+            ("cache_read", usage.cache_read_input_tokens),
+        ] {
+            self.tokens.with_label_values(&[kind]).inc_by(count);
+        }
+
+        let result = if usage.cache_read_input_tokens > 0 {
+            "hit"
+        } else if usage.cache_creation_input_tokens > 0 {
+            "write"
+        } else {
+            "miss"
+        };
+        self.cache_requests.with_label_values(&[result]).inc();
+
+        let input = usage.input_tokens + usage.cache_creation_input_tokens + usage.cache_read_input_tokens;
+        for (kind, count) in [("input", input), ("output", usage.output_tokens)] {
+            self.request_tokens.with_label_values(&[model, kind]).observe(count as f64);
+        }
+        let total = |kind: &str| self.tokens.with_label_values(&[kind]).get();
+        if let Some(ratio) = usage::cache_hit_rate(total("input"), total("cache_creation"), total("cache_read")) {
+            self.cache_hit_ratio.set(ratio);
+        }
+    }
+
+    /// Count a finished request of a canary rollout; errors are responses with status >= 400
+    pub fn record_canary_request(&self, arm: &CanaryArm, success: bool) {
+        let outcome = if success { "success" } else { "error" };
+        self.canary_requests
+            .with_label_values(&[&arm.rollout, arm.label(), outcome])
+            .inc();
+    }
+
+    pub fn record_canary_usage(&self, arm: &CanaryArm, usage: &TokenUsage) {
+        for (kind, count) in [("input", usage.input_tokens), ("output", usage.output_tokens)] {
+            self.canary_tokens
+                .with_label_values(&[&arm.rollout, arm.label(), kind])
+                .inc_by(count);
+        }
+    }
+
+    /// Count a finished request of the prompt experiment; errors are responses with status >= 400
+    pub fn record_experiment_request(&self, arm: &ExperimentArm, success: bool) {
+        let outcome = if success { "success" } else { "error" };
+        self.experiment_requests
+            .with_label_values(&[&arm.experiment, &arm.variant, outcome])
+            .inc();
+    }
+
+    pub fn record_experiment_usage(&self, arm: &ExperimentArm, usage: &TokenUsage) {
+        for (kind, count) in [("input", usage.input_tokens), ("output", usage.output_tokens)] {
+            self.experiment_tokens
+                .with_label_values(&[&arm.experiment, &arm.variant, kind])
+                .inc_by(count);
+        }
+    }
+
+    pub fn record_moderation(&self, result: &ModerationResult) {
+        self.moderation_verdicts
+            .with_label_values(&[&result.moderator, result.verdict.action.name()])
+            .inc();
+    }
+
+    /// Count token refreshes from the proxy event stream until it closes
+    pub async fn track_events(self: std::sync::Arc<Self>, mut events: Receiver<EventEnvelope>) {
+        loop {
+            match events.recv().await {
+                Ok(envelope) => match envelope.event {
+                    ProxyEvent::TokenRefreshed { .. } => self.refreshes.with_label_values(&["success"]).inc(),
+                    ProxyEvent::TokenRefreshFailed { .. } => self.refreshes.with_label_values(&["failure"]).inc(),
+                    _ => {}
+                },
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Metrics event tracking lagged, skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,185 @@
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::guardrails;
+use crate::proxy::AnthropicMessageRequest;
+
+/// What a moderator decided about a request, mildest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    #[default]
+    Allow,
+    /// Forward the request but mark it for review
+    Flag,
+    /// Reject the request with a policy_violation error
+    Block,
+}
+
+impl ModerationAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ModerationAction::Allow => "allow",
+            ModerationAction::Flag => "flag",
+            ModerationAction::Block => "block",
+        }
+    }
+}
+
+/// A moderator's verdict; also the response expected from an HTTP classifier
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModerationVerdict {
+    #[serde(default)]
+    pub action: ModerationAction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Annotations such as categories, kept with the request record whatever the action
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+/// A verdict and the moderator that gave it, as recorded for the request
+#[derive(Debug, Clone, Serialize)]
+pub struct ModerationResult {
+    pub moderator: String,
+    #[serde(flatten)]
+    pub verdict: ModerationVerdict,
+}
+
+/// What a moderator sees of a request
+pub struct ModerationInput<'a> {
+    pub request_id: &'a str,
+    /// Fingerprint of the client key
+    pub key_id: Option<&'a str>,
+    pub request: &'a AnthropicMessageRequest,
+}
+
+/// Decides whether a request may be forwarded, before the proxy processes it
+pub trait Moderator: Send + Sync {
+    fn name(&self) -> &str;
+    fn moderate<'a>(&'a self, input: &'a ModerationInput<'a>) -> BoxFuture<'a, ModerationVerdict>;
+}
+
+/// A local moderation rule: when the pattern matches the request's text, the rule's
+/// action and label apply
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationRule {
+    pub pattern: String,
+    #[serde(default = "default_rule_action")]
+    pub action: ModerationAction,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+fn default_rule_action() -> ModerationAction {
+    ModerationAction::Flag
+}
+
+/// Moderates with regular expressions over the request's text
+pub struct RuleModerator {
+    rules: Vec<(Regex, ModerationRule)>,
+}
+
+impl RuleModerator {
+    /// Invalid patterns are skipped with a warning
+    pub fn new(rules: &[ModerationRule]) -> Self {
+        let rules = rules
+            .iter()
+            .filter_map(|rule| match Regex::new(&rule.pattern) {
+                Ok(regex) => Some((regex, rule.clone())),
+                Err(e) => {
+                    warn!("Ignoring invalid moderation rule '{}': {}", rule.pattern, e);
+                    None
+                }
+            })
+            .collect();
+        Self { rules }
+    }
+}
+
+impl Moderator for RuleModerator {
+    fn name(&self) -> &str {
+        "rules"
+    }
+
+    fn moderate<'a>(&'a self, input: &'a ModerationInput<'a>) -> BoxFuture<'a, ModerationVerdict> {
+        let texts = guardrails::inspected_text(input.request);
+        let mut verdict = ModerationVerdict::default();
+        for (index, (regex, rule)) in self.rules.iter().enumerate() {
+            if !texts.iter().any(|text| regex.is_match(text)) {
+                continue;
+            }
+            if let Some(label) = &rule.label {
+                verdict.labels.push(label.clone());
+            }
+            if rule.action > verdict.action {
+                verdict.action = rule.action;
+                verdict.reason = Some(format!("matched moderation rule #{}", index + 1));
+            }
+        }
+        async move { verdict }.boxed()
+    }
+}
+
+/// Moderates by POSTing the request to an external classifier, which answers with a
+/// `ModerationVerdict`
+pub struct HttpModerator {
+    url: String,
+    /// Allow requests when the classifier can't be reached or answers badly
+    fail_open: bool,
+    client: reqwest::Client,
+}
+
+impl HttpModerator {
+    pub fn new(url: &str, timeout: Duration, fail_open: bool) -> Self {
+        Self {
+            url: url.to_string(),
+            fail_open,
+            client: reqwest::Client::builder().timeout(timeout).build().unwrap_or_default(),
+        }
+    }
+
+    async fn classify(&self, input: &ModerationInput<'_>) -> Result<ModerationVerdict, String> {
+        let body = json!({
+            "request_id": input.request_id,
+            "key_id": input.key_id,
+            "model": input.request.model,
+            "system": input.request.system,
+            "messages": input.request.messages,
+        });
+        let response = self.client.post(&self.url).json(&body).send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("classifier returned {}", status));
+        }
+        response.json().await.map_err(|e| format!("unreadable verdict: {}", e))
+    }
+}
+
+impl Moderator for HttpModerator {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn moderate<'a>(&'a self, input: &'a ModerationInput<'a>) -> BoxFuture<'a, ModerationVerdict> {
+        async move {
+            match self.classify(input).await {
+                Ok(verdict) => verdict,
+                Err(e) => {
+                    warn!("[{}] Moderation classifier failed: {}", input.request_id, e);
+                    ModerationVerdict {
+                        action: if self.fail_open { ModerationAction::Allow } else { ModerationAction::Block },
+                        reason: Some("moderation unavailable".to_string()),
+                        labels: vec!["moderation_unavailable".to_string()],
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+}
@@ -3,17 +3,122 @@ use base64::{engine::general_purpose, Engine};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::fs;
-use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use url::Url;
 
-use crate::settings::Settings;
+use crate::events::{EventBus, ProxyEvent};
+use crate::pkce::PkceStore;
+use crate::profile;
+use crate::refresh_audit::{RefreshAuditEntry, RefreshAuditLog, RefreshOutcome, RefreshReason};
+use crate::settings::{OAuthConfig, Settings};
 use crate::storage::TokenStorage;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct PkceData {
-    code_verifier: String,
-    state: String,
+/// Refresh requests made before a transient failure is returned to the caller
+const REFRESH_ATTEMPTS: u32 = 3;
+/// Delay before the first refresh retry, doubled for each further retry
+const REFRESH_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// How often the background refresher checks token expiry
+const BACKGROUND_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Upper bound on the background refresher's backoff after failures
+const BACKGROUND_MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+/// Tokens expiring within this many seconds are refreshed in the background
+const REFRESH_AHEAD_SECONDS: i64 = 5 * 60;
+/// How long a fetched account profile is reused for the same access token
+const PROFILE_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+enum RefreshAttempt {
+    Refreshed,
+    /// The token endpoint refused the refresh token
+    Rejected,
+    /// Network error, rate limit or server error worth retrying
+    Transient(String),
+}
+
+/// Whether a token endpoint error says the refresh token itself is no longer valid
+fn is_invalid_grant(error_text: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(error_text)
+        .ok()
+        .and_then(|body| {
+            body.get("error")
+                .and_then(|e| e.as_str().or_else(|| e.get("type").and_then(|t| t.as_str())))
+                .map(|e| e == "invalid_grant")
+        })
+        .unwrap_or_else(|| error_text.contains("invalid_grant"))
+}
+
+/// Add up to 50% random jitter so concurrent retries don't line up
+fn with_jitter(delay: Duration) -> Duration {
+    delay + delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+}
+
+/// Why pasted login input couldn't be split into an authorization code and a state
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AuthorizationError {
+    #[error("the authorization code is empty")]
+    EmptyCode,
+    #[error("the state is missing: paste the full CODE#STATE string, or give the state separately")]
+    MissingState,
+    #[error("nothing follows the '#': copy the whole CODE#STATE string shown by Anthropic")]
+    EmptyState,
+    #[error("the {0} contains whitespace; it was probably copied across a line break")]
+    Whitespace(&'static str),
+    #[error("the callback URL could not be parsed: {0}")]
+    InvalidUrl(String),
+    #[error("the callback URL has no code parameter")]
+    UrlWithoutCode,
+    #[error("the state given separately does not match the state in the pasted code")]
+    StateMismatch,
+}
+
+/// An authorization code and the state of the login flow it completes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authorization {
+    pub code: String,
+    pub state: String,
+}
+
+impl Authorization {
+    /// Accepts CODE#STATE, a pasted callback URL with `code` and `state` parameters, or a
+    /// bare code with the state given separately
+    pub fn parse(input: &str, state: Option<&str>) -> Result<Self, AuthorizationError> {
+        let input = input.trim();
+        let given_state = state.map(str::trim).filter(|s| !s.is_empty());
+
+        let (raw_code, mut pasted_state) = if input.starts_with("http://") || input.starts_with("https://") {
+            let url = Url::parse(input).map_err(|e| AuthorizationError::InvalidUrl(e.to_string()))?;
+            let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.into_owned());
+            let code = param("code").ok_or(AuthorizationError::UrlWithoutCode)?;
+            (code, param("state").or_else(|| url.fragment().map(str::to_string)))
+        } else {
+            (input.to_string(), None)
+        };
+        let code = match raw_code.split_once('#') {
+            Some((code, state)) => {
+                pasted_state = Some(state.to_string());
+                code.to_string()
+            }
+            None => raw_code,
+        };
+
+        if code.is_empty() {
+            return Err(AuthorizationError::EmptyCode);
+        }
+        if code.contains(char::is_whitespace) {
+            return Err(AuthorizationError::Whitespace("authorization code"));
+        }
+        let state = match (pasted_state, given_state) {
+            (Some(pasted), _) if pasted.trim().is_empty() => return Err(AuthorizationError::EmptyState),
+            (Some(pasted), Some(given)) if pasted != given => return Err(AuthorizationError::StateMismatch),
+            (Some(pasted), _) => pasted,
+            (None, Some(given)) => given.to_string(),
+            (None, None) => return Err(AuthorizationError::MissingState),
+        };
+        if state.contains(char::is_whitespace) {
+            return Err(AuthorizationError::Whitespace("state"));
+        }
+        Ok(Self { code, state })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,54 +143,56 @@ struct TokenResponse {
     access_token: String,
     refresh_token: String,
     expires_in: Option<i64>,
+    scope: Option<String>,
+}
+
+/// Account profile fetched for an access token
+struct CachedProfile {
+    access_token: String,
+    fetched_at: Instant,
+    profile: serde_json::Value,
 }
 
 pub struct OAuthManager {
     storage: TokenStorage,
-    pkce_file: PathBuf,
+    client: OAuthConfig,
+    pkce: PkceStore,
+    events: EventBus,
+    audit: RefreshAuditLog,
+    profile_cache: Mutex<Option<CachedProfile>>,
+    /// Held for the whole of a refresh, so a rotating refresh token is only spent once
+    refresh_lock: tokio::sync::Mutex<()>,
+    /// Client for the token and profile endpoints, with the configured connection settings
+    http: reqwest::Client,
 }
 
 impl OAuthManager {
-    pub fn new(token_file: &str) -> Result<Self> {
+    pub fn new(token_file: &str, client: OAuthConfig, http: reqwest::Client) -> Result<Self> {
         let storage = TokenStorage::new(token_file)?;
-        let temp_dir = std::env::temp_dir();
-        let pkce_file = temp_dir.join("maximize_oauth_pkce.json");
-
-        Ok(Self { storage, pkce_file })
-    }
-
-    fn save_pkce(&self, code_verifier: &str, state: &str) -> Result<()> {
-        let data = PkceData {
-            code_verifier: code_verifier.to_string(),
-            state: state.to_string(),
-        };
-        let json = serde_json::to_string(&data)?;
-        fs::write(&self.pkce_file, json)?;
-        Ok(())
-    }
-
-    fn load_pkce(&self) -> Result<Option<(String, String)>> {
-        if !self.pkce_file.exists() {
-            return Ok(None);
-        }
-
-        let contents = fs::read_to_string(&self.pkce_file)?;
-        let data: PkceData = serde_json::from_str(&contents)?;
-        Ok(Some((data.code_verifier, data.state)))
+        let audit = RefreshAuditLog::for_token_file(storage.token_file());
+        let pkce = PkceStore::for_token_file(storage.token_file());
+
+        Ok(Self {
+            storage,
+            client,
+            pkce,
+            events: EventBus::new(),
+            audit,
+            profile_cache: Mutex::new(None),
+            refresh_lock: tokio::sync::Mutex::new(()),
+            http,
+        })
     }
 
-    fn clear_pkce(&self) -> Result<()> {
-        if self.pkce_file.exists() {
-            fs::remove_file(&self.pkce_file)?;
-        }
-        Ok(())
+    fn random_token() -> String {
+        let mut rng = rand::thread_rng();
+        let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen::<u8>()).collect();
+        general_purpose::URL_SAFE_NO_PAD.encode(&random_bytes)
     }
 
     fn generate_pkce(&self) -> (String, String) {
         // Generate high-entropy code_verifier (43-128 chars)
-        let mut rng = rand::thread_rng();
-        let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen::<u8>()).collect();
-        let code_verifier = general_purpose::URL_SAFE_NO_PAD.encode(&random_bytes);
+        let code_verifier = Self::random_token();
 
         // Create code_challenge using SHA-256
         let mut hasher = Sha256::new();
@@ -98,11 +205,9 @@ impl OAuthManager {
 
     pub fn get_authorize_url(&self) -> Result<String> {
         let (code_verifier, code_challenge) = self.generate_pkce();
-        // OpenCode uses the verifier as the state
-        let state = code_verifier.clone();
-
-        // Save PKCE values for later use
-        self.save_pkce(&code_verifier, &state)?;
+        // Each flow gets its own state, so concurrent logins keep their own verifiers
+        let state = Self::random_token();
+        self.pkce.save(&state, &code_verifier)?;
 
         let mut url = Url::parse(&format!(
             "{}/oauth/authorize",
@@ -111,10 +216,10 @@ impl OAuthManager {
 
         url.query_pairs_mut()
             .append_pair("code", "true")
-            .append_pair("client_id", Settings::client_id())
+            .append_pair("client_id", &self.client.client_id)
             .append_pair("response_type", "code")
-            .append_pair("redirect_uri", Settings::redirect_uri())
-            .append_pair("scope", Settings::scopes())
+            .append_pair("redirect_uri", &self.client.redirect_uri)
+            .append_pair("scope", &self.client.scopes)
             .append_pair("code_challenge", &code_challenge)
             .append_pair("code_challenge_method", "S256")
             .append_pair("state", &state);
@@ -133,39 +238,33 @@ impl OAuthManager {
         Ok(auth_url)
     }
 
-    pub async fn exchange_code(&self, code: &str) -> Result<()> {
-        // Split the code and state (they come as "code#state")
-        let parts: Vec<&str> = code.split('#').collect();
-        if parts.len() < 2 {
-            anyhow::bail!("Invalid code format. Expected: CODE#STATE");
-        }
-        
-        let actual_code = parts[0];
-        let state = parts[1].to_string();
-
-        // Try to load saved PKCE verifier (for CLI flow)
-        // If not found, use the state as the verifier (for env var flow)
-        let code_verifier = match self.load_pkce()? {
-            Some((verifier, _)) => {
-                tracing::debug!("Using PKCE verifier from file (CLI flow)");
+    /// Exchange an authorization code for tokens. Stored tokens are only replaced once the
+    /// exchange has fully succeeded, so a failed re-login keeps the previous ones working.
+    pub async fn exchange_code(&self, authorization: &Authorization) -> Result<()> {
+        let state = &authorization.state;
+
+        // Use the verifier of the flow this host started with that state. Flows started
+        // elsewhere (OpenCode style) use the verifier as the state.
+        let code_verifier = match self.pkce.verifier(state)? {
+            Some(verifier) => {
+                tracing::debug!("Using PKCE verifier saved for this login flow");
                 verifier
             }
             None => {
-                tracing::debug!("Using state as PKCE verifier (environment variable flow)");
-                // In the OAuth flow, the state IS the code_verifier
+                tracing::debug!("No saved login flow for this state; using the state as PKCE verifier");
                 state.clone()
             }
         };
 
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let response = client
-            .post(&format!("{}/v1/oauth/token", Settings::auth_base_token()))
+            .post(format!("{}/v1/oauth/token", Settings::auth_base_token()))
             .json(&TokenRequest {
-                code: actual_code.to_string(),
-                state,
+                code: authorization.code.clone(),
+                state: state.clone(),
                 grant_type: "authorization_code".to_string(),
-                client_id: Settings::client_id().to_string(),
-                redirect_uri: Settings::redirect_uri().to_string(),
+                client_id: self.client.client_id.clone(),
+                redirect_uri: self.client.redirect_uri.clone(),
                 code_verifier,
             })
             .header("Content-Type", "application/json")
@@ -177,26 +276,42 @@ impl OAuthManager {
             anyhow::bail!("Token exchange failed: {}", error_text);
         }
 
-        let token_data: TokenResponse = response.json().await?;
+        let token_data: TokenResponse = response
+            .json()
+            .await
+            .map_err(|e| anyhow::anyhow!("Token exchange returned an unexpected response: {}", e))?;
+        if token_data.access_token.trim().is_empty() || token_data.refresh_token.trim().is_empty() {
+            anyhow::bail!("Token exchange returned an empty access or refresh token");
+        }
 
         // Log what we received from Anthropic
         let expires_in = token_data.expires_in.unwrap_or(86400); // Default to 24 hours
         tracing::info!("Token exchange successful. Expires in: {} seconds (~{} hours)", expires_in, expires_in / 3600);
 
-        // Store tokens securely
+        // Swap in the new tokens in one step
         self.storage.save_tokens(
             &token_data.access_token,
             &token_data.refresh_token,
             expires_in,
+            token_data.scope.as_deref(),
         )?;
 
-        // Clear PKCE values after successful exchange
-        self.clear_pkce()?;
+        // The flow is complete; its verifier is no longer needed
+        if let Err(e) = self.pkce.remove(state) {
+            tracing::warn!("Failed to remove PKCE entry of the completed login: {}", e);
+        }
 
         Ok(())
     }
 
-    pub async fn refresh_tokens(&self) -> Result<bool> {
+    /// Refresh the OAuth tokens. Transient failures (network errors, 429 and 5xx) are retried
+    /// with jittered exponential backoff; returns Ok(false) if the refresh was rejected.
+    /// Refreshes run one at a time: a caller that waited for another one to finish uses
+    /// the tokens it saved instead of spending the refresh token again.
+    pub async fn refresh_tokens(&self, reason: RefreshReason) -> Result<bool> {
+        let seen = self.storage.get_refresh_token();
+        let _flight = self.refresh_lock.lock().await;
+
         let refresh_token = match self.storage.get_refresh_token() {
             Some(token) => token,
             None => {
@@ -204,25 +319,103 @@ impl OAuthManager {
                 return Ok(false);
             }
         };
+        if self.storage.is_revoked() {
+            tracing::warn!("Refresh token was revoked; log in again to get new tokens");
+            return Ok(false);
+        }
+        if seen.as_deref() != Some(refresh_token.as_str()) || !self.refresh_needed(reason) {
+            tracing::debug!("Tokens were refreshed while waiting; using them");
+            return Ok(true);
+        }
+
+        let mut delay = REFRESH_RETRY_BASE_DELAY;
+        for attempt in 1..=REFRESH_ATTEMPTS {
+            match self.try_refresh(&refresh_token, reason).await? {
+                RefreshAttempt::Refreshed => return Ok(true),
+                RefreshAttempt::Rejected => return Ok(false),
+                RefreshAttempt::Transient(message) if attempt < REFRESH_ATTEMPTS => {
+                    let wait = with_jitter(delay);
+                    tracing::warn!(
+                        "Token refresh attempt {}/{} failed ({}), retrying in {}ms",
+                        attempt,
+                        REFRESH_ATTEMPTS,
+                        message,
+                        wait.as_millis()
+                    );
+                    tokio::time::sleep(wait).await;
+                    delay *= 2;
+                }
+                RefreshAttempt::Transient(message) => {
+                    anyhow::bail!("Token refresh failed after {} attempts: {}", REFRESH_ATTEMPTS, message)
+                }
+            }
+        }
+        unreachable!("the last attempt always returns")
+    }
+
+    /// Whether the stored tokens still call for a refresh of this kind. The upstream
+    /// rejected an unexpired token on 401, so that and manual refreshes always proceed.
+    fn refresh_needed(&self, reason: RefreshReason) -> bool {
+        match reason {
+            RefreshReason::Expired => self.storage.is_token_expired(),
+            RefreshReason::Background => match self.storage.load_tokens() {
+                Ok(Some(tokens)) => tokens.expires_at - chrono::Utc::now().timestamp() < REFRESH_AHEAD_SECONDS,
+                _ => false,
+            },
+            RefreshReason::UpstreamUnauthorized | RefreshReason::Manual => true,
+        }
+    }
 
+    /// One refresh request against the token endpoint
+    async fn try_refresh(&self, refresh_token: &str, reason: RefreshReason) -> Result<RefreshAttempt> {
         tracing::info!("Attempting to refresh OAuth tokens...");
 
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&format!("{}/v1/oauth/token", Settings::auth_base_token()))
+        let client = &self.http;
+        let sent = client
+            .post(format!("{}/v1/oauth/token", Settings::auth_base_token()))
             .json(&RefreshRequest {
                 grant_type: "refresh_token".to_string(),
-                refresh_token,
-                client_id: Settings::client_id().to_string(),
+                refresh_token: refresh_token.to_string(),
+                client_id: self.client.client_id.clone(),
             })
             .header("Content-Type", "application/json")
             .send()
-            .await?;
+            .await;
+
+        let response = match sent {
+            Ok(response) => response,
+            Err(e) => {
+                self.audit_attempt(reason, RefreshOutcome::TransientFailure, None, Some(e.to_string()));
+                self.events.publish(ProxyEvent::TokenRefreshFailed { message: e.to_string() });
+                return Ok(RefreshAttempt::Transient(e.to_string()));
+            }
+        };
 
-        if !response.status().is_success() {
-            let error_text = response.text().await?;
-            tracing::error!("Token refresh failed: {}", error_text);
-            return Ok(false);
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            tracing::error!("Token refresh failed ({}): {}", status, error_text);
+            self.events.publish(ProxyEvent::TokenRefreshFailed { message: error_text.clone() });
+            let error_summary = format!("HTTP {}: {}", status, error_text);
+            if status.is_server_error() || status.as_u16() == 429 {
+                self.audit_attempt(reason, RefreshOutcome::TransientFailure, None, Some(error_summary));
+                return Ok(RefreshAttempt::Transient(format!("HTTP {}", status)));
+            }
+
+            // A revoked or expired refresh token will never work again: stop retrying it
+            if is_invalid_grant(&error_text) {
+                self.audit_attempt(reason, RefreshOutcome::Revoked, None, Some(error_summary));
+                if !self.storage.mark_revoked(refresh_token)? {
+                    // Another process logged in or refreshed meanwhile; its tokens are good
+                    tracing::warn!("Rejected refresh token was already replaced; using the stored tokens");
+                    return Ok(RefreshAttempt::Refreshed);
+                }
+                tracing::error!("Refresh token revoked; quarantining tokens until the next login");
+                self.events.publish(ProxyEvent::TokenRevoked { message: error_text });
+            } else {
+                self.audit_attempt(reason, RefreshOutcome::Rejected, None, Some(error_summary));
+            }
+            return Ok(RefreshAttempt::Rejected);
         }
 
         let token_data: TokenResponse = response.json().await?;
@@ -236,10 +429,71 @@ impl OAuthManager {
             &token_data.access_token,
             &token_data.refresh_token,
             expires_in,
+            token_data.scope.as_deref(),
         )?;
 
         tracing::info!("Successfully refreshed OAuth tokens");
-        Ok(true)
+        let expires_at = chrono::Utc::now().timestamp() + expires_in;
+        self.audit_attempt(reason, RefreshOutcome::Success, Some(expires_at), None);
+        self.events.publish(ProxyEvent::TokenRefreshed { expires_in });
+        Ok(RefreshAttempt::Refreshed)
+    }
+
+    fn audit_attempt(&self, reason: RefreshReason, outcome: RefreshOutcome, expires_at: Option<i64>, error: Option<String>) {
+        self.audit.record(RefreshAuditEntry {
+            timestamp: chrono::Utc::now().timestamp(),
+            reason,
+            outcome,
+            expires_at,
+            error,
+        });
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// Log of past refresh attempts
+    pub fn refresh_audit(&self) -> &RefreshAuditLog {
+        &self.audit
+    }
+
+    /// Keep tokens fresh in the background: refresh shortly before expiry and, when that
+    /// fails, keep retrying with growing backoff instead of waiting for the next request.
+    /// Must be called from within a Tokio runtime.
+    pub fn spawn_background_refresh(self: &Arc<Self>) {
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut delay = BACKGROUND_CHECK_INTERVAL;
+            loop {
+                tokio::time::sleep(delay).await;
+
+                let needs_refresh = match manager.storage.load_tokens() {
+                    Ok(Some(tokens)) => {
+                        !tokens.refresh_token.is_empty()
+                            && tokens.revoked_at.is_none()
+                            && tokens.expires_at - chrono::Utc::now().timestamp() < REFRESH_AHEAD_SECONDS
+                    }
+                    _ => false,
+                };
+                if !needs_refresh {
+                    delay = BACKGROUND_CHECK_INTERVAL;
+                    continue;
+                }
+
+                match manager.refresh_tokens(RefreshReason::Background).await {
+                    Ok(true) => delay = BACKGROUND_CHECK_INTERVAL,
+                    Ok(false) => {
+                        delay = (delay * 2).min(BACKGROUND_MAX_BACKOFF);
+                        tracing::warn!("Background token refresh rejected, next try in {}s", delay.as_secs());
+                    }
+                    Err(e) => {
+                        delay = (delay * 2).min(BACKGROUND_MAX_BACKOFF);
+                        tracing::warn!("Background token refresh failed: {}, next try in {}s", e, delay.as_secs());
+                    }
+                }
+            }
+        });
     }
 
     pub async fn get_valid_token(&self) -> Result<Option<String>> {
@@ -249,7 +503,7 @@ impl OAuthManager {
 
         tracing::info!("Token expired, attempting automatic refresh...");
 
-        if self.refresh_tokens().await? {
+        if self.refresh_tokens(RefreshReason::Expired).await? {
             Ok(self.storage.get_access_token())
         } else {
             tracing::error!("Failed to refresh token automatically");
@@ -257,7 +511,37 @@ impl OAuthManager {
         }
     }
 
+    /// Profile of the account the tokens belong to, or None without an unexpired token
+    /// (this never refreshes tokens). Reused for the same access token for a while unless
+    /// `refresh` is set.
+    pub async fn account_profile(&self, refresh: bool) -> Result<Option<serde_json::Value>> {
+        let Some(access_token) = self.storage.get_access_token() else {
+            return Ok(None);
+        };
+        if !refresh {
+            let cache = self.profile_cache.lock().unwrap();
+            if let Some(cached) = cache.as_ref() {
+                if cached.access_token == access_token && cached.fetched_at.elapsed() < PROFILE_CACHE_TTL {
+                    return Ok(Some(cached.profile.clone()));
+                }
+            }
+        }
+
+        let profile = profile::fetch_profile(&self.http, &access_token).await?;
+        *self.profile_cache.lock().unwrap() = Some(CachedProfile {
+            access_token,
+            fetched_at: Instant::now(),
+            profile: profile.clone(),
+        });
+        Ok(Some(profile))
+    }
+
     pub fn storage(&self) -> &TokenStorage {
         &self.storage
     }
+
+    /// Event bus shared with the proxy for live activity streaming
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
 }
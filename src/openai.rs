@@ -0,0 +1,378 @@
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+use crate::proxy::AnthropicMessageRequest;
+use crate::sse::SseEvent;
+
+/// Output limit for chat completions that don't set one; Anthropic requires max_tokens
+pub const DEFAULT_MAX_TOKENS: i32 = 4096;
+
+/// The closing frame of an OpenAI chat completion stream
+pub const DONE_FRAME: &str = "data: [DONE]\n\n";
+
+/// Translate an OpenAI chat completion request into a Messages request. `deployment`
+/// replaces the body's model, for Azure-style routes that name it in the path. Parameters
+/// with no Messages equivalent (penalties, logit_bias, seed, response_format...) are ignored.
+pub fn to_messages_request(body: &Value, deployment: Option<&str>) -> Result<AnthropicMessageRequest, String> {
+    let body = body.as_object().ok_or("Request body is not a JSON object")?;
+    if body.get("n").and_then(Value::as_u64).is_some_and(|n| n != 1) {
+        return Err("n: only one choice per request is supported".to_string());
+    }
+
+    let mut request = Map::new();
+    let model = deployment.or_else(|| body.get("model").and_then(Value::as_str)).unwrap_or_default();
+    request.insert("model".to_string(), json!(model));
+
+    let (system, messages) = convert_messages(body.get("messages").ok_or("messages: required")?)?;
+    if let Some(system) = system {
+        request.insert("system".to_string(), json!(system));
+    }
+    request.insert("messages".to_string(), Value::Array(messages));
+
+    let max_tokens = body
+        .get("max_completion_tokens")
+        .or_else(|| body.get("max_tokens"))
+        .and_then(Value::as_i64)
+        .unwrap_or(DEFAULT_MAX_TOKENS as i64);
+    request.insert("max_tokens".to_string(), json!(max_tokens));
+
+    for field in ["temperature", "top_p", "stream", "stream_options"] {
+        if let Some(value) = body.get(field).filter(|v| !v.is_null()) {
+            request.insert(field.to_string(), value.clone());
+        }
+    }
+    match body.get("stop") {
+        Some(Value::String(stop)) => {
+            request.insert("stop_sequences".to_string(), json!([stop]));
+        }
+        Some(Value::Array(stops)) => {
+            request.insert("stop_sequences".to_string(), Value::Array(stops.clone()));
+        }
+        _ => {}
+    }
+    if let Some(user) = body.get("user").and_then(Value::as_str) {
+        request.insert("metadata".to_string(), json!({"user_id": user}));
+    }
+
+    if let Some(tools) = body.get("tools").and_then(Value::as_array) {
+        let tools = tools.iter().enumerate().map(|(i, tool)| convert_tool(i, tool)).collect::<Result<Vec<_>, _>>()?;
+        if !tools.is_empty() {
+            request.insert("tools".to_string(), Value::Array(tools));
+        }
+    }
+    let mut tool_choice = match body.get("tool_choice") {
+        None | Some(Value::Null) => None,
+        Some(choice) => Some(convert_tool_choice(choice)?),
+    };
+    if body.get("parallel_tool_calls") == Some(&Value::Bool(false)) && request.contains_key("tools") {
+        let choice = tool_choice.get_or_insert_with(|| json!({"type": "auto"}));
+        if choice["type"] != "none" {
+            choice["disable_parallel_tool_use"] = json!(true);
+        }
+    }
+    if let Some(choice) = tool_choice {
+        request.insert("tool_choice".to_string(), choice);
+    }
+
+    serde_json::from_value(Value::Object(request)).map_err(|e| format!("Invalid request: {}", e))
+}
+
+/// System text and Messages turns of an OpenAI conversation. Tool results become
+/// tool_result blocks, and consecutive turns of one role are merged as Anthropic expects.
+fn convert_messages(messages: &Value) -> Result<(Option<String>, Vec<Value>), String> {
+    let messages = messages.as_array().ok_or("messages: must be a list")?;
+    let mut system = Vec::new();
+    let mut turns: Vec<Value> = Vec::new();
+
+    for (index, message) in messages.iter().enumerate() {
+        let role = message.get("role").and_then(Value::as_str).unwrap_or_default();
+        let content = message.get("content").unwrap_or(&Value::Null);
+        let (role, blocks) = match role {
+            "system" | "developer" => {
+                system.push(text_of(content).ok_or_else(|| format!("messages.{}.content: must be text", index))?);
+                continue;
+            }
+            "user" => ("user", convert_user_content(index, content)?),
+            "assistant" => ("assistant", convert_assistant_message(index, message)?),
+            "tool" => {
+                let id = message
+                    .get("tool_call_id")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| format!("messages.{}.tool_call_id: required", index))?;
+                let result = json!({"type": "tool_result", "tool_use_id": id, "content": text_of(content).unwrap_or_default()});
+                ("user", vec![result])
+            }
+            other => return Err(format!("messages.{}.role: '{}' is not supported", index, other)),
+        };
+
+        match turns.last_mut() {
+            Some(last) if last["role"] == role => {
+                if let Some(content) = last["content"].as_array_mut() {
+                    content.extend(blocks);
+                }
+            }
+            _ => turns.push(json!({"role": role, "content": blocks})),
+        }
+    }
+
+    let system = (!system.is_empty()).then(|| system.join("\n\n"));
+    Ok((system, turns))
+}
+
+/// Text of a string or a list of text parts
+fn text_of(content: &Value) -> Option<String> {
+    match content {
+        Value::String(text) => Some(text.clone()),
+        Value::Array(parts) => Some(
+            parts
+                .iter()
+                .filter_map(|part| part.get("text").and_then(Value::as_str))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+        Value::Null => Some(String::new()),
+        _ => None,
+    }
+}
+
+fn convert_user_content(index: usize, content: &Value) -> Result<Vec<Value>, String> {
+    let parts = match content {
+        Value::String(text) => return Ok(vec![json!({"type": "text", "text": text})]),
+        Value::Array(parts) => parts,
+        _ => return Err(format!("messages.{}.content: must be a string or a list of parts", index)),
+    };
+
+    parts
+        .iter()
+        .map(|part| match part.get("type").and_then(Value::as_str) {
+            Some("text") => Ok(json!({"type": "text", "text": part.get("text").cloned().unwrap_or_default()})),
+            Some("image_url") => {
+                let url = part
+                    .get("image_url")
+                    .and_then(|image| image.get("url").or(Some(image)))
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| format!("messages.{}.content: image_url needs a url", index))?;
+                Ok(json!({"type": "image", "source": image_source(url)}))
+            }
+            Some(other) => Err(format!("messages.{}.content: '{}' parts are not supported", index, other)),
+            None => Err(format!("messages.{}.content: part without a type", index)),
+        })
+        .collect()
+}
+
+/// Image source of an image_url: inline data URLs become base64 sources
+fn image_source(url: &str) -> Value {
+    let inline = url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"));
+    match inline {
+        Some((media_type, data)) => json!({"type": "base64", "media_type": media_type, "data": data}),
+        None => json!({"type": "url", "url": url}),
+    }
+}
+
+fn convert_assistant_message(index: usize, message: &Value) -> Result<Vec<Value>, String> {
+    let mut blocks = Vec::new();
+    let text = text_of(message.get("content").unwrap_or(&Value::Null))
+        .ok_or_else(|| format!("messages.{}.content: must be text", index))?;
+    if !text.is_empty() {
+        blocks.push(json!({"type": "text", "text": text}));
+    }
+
+    for call in message.get("tool_calls").and_then(Value::as_array).into_iter().flatten() {
+        let function = call.get("function").unwrap_or(&Value::Null);
+        let arguments = function.get("arguments").and_then(Value::as_str).unwrap_or("{}");
+        let input: Value = serde_json::from_str(if arguments.trim().is_empty() { "{}" } else { arguments })
+            .map_err(|e| format!("messages.{}.tool_calls: arguments are not JSON: {}", index, e))?;
+        blocks.push(json!({
+            "type": "tool_use",
+            "id": call.get("id").cloned().unwrap_or_default(),
+            "name": function.get("name").cloned().unwrap_or_default(),
+            "input": input,
+        }));
+    }
+    Ok(blocks)
+}
+
+fn convert_tool(index: usize, tool: &Value) -> Result<Value, String> {
+    if tool.get("type").and_then(Value::as_str) != Some("function") {
+        return Err(format!("tools.{}: only function tools are supported", index));
+    }
+    let function = tool.get("function").ok_or_else(|| format!("tools.{}.function: required", index))?;
+    let mut converted = json!({
+        "name": function.get("name").cloned().unwrap_or_default(),
+        "input_schema": function.get("parameters").cloned().unwrap_or_else(|| json!({"type": "object"})),
+    });
+    if let Some(description) = function.get("description") {
+        converted["description"] = description.clone();
+    }
+    Ok(converted)
+}
+
+fn convert_tool_choice(choice: &Value) -> Result<Value, String> {
+    match choice {
+        Value::String(mode) => match mode.as_str() {
+            "auto" => Ok(json!({"type": "auto"})),
+            "none" => Ok(json!({"type": "none"})),
+            "required" => Ok(json!({"type": "any"})),
+            other => Err(format!("tool_choice: '{}' is not auto, none or required", other)),
+        },
+        _ => choice
+            .get("function")
+            .and_then(|f| f.get("name"))
+            .and_then(Value::as_str)
+            .map(|name| json!({"type": "tool", "name": name}))
+            .ok_or_else(|| "tool_choice: expected a mode or a function to call".to_string()),
+    }
+}
+
+fn finish_reason(stop_reason: Option<&str>) -> Option<&'static str> {
+    match stop_reason? {
+        "max_tokens" | "model_context_window_exceeded" => Some("length"),
+        "tool_use" => Some("tool_calls"),
+        "refusal" => Some("content_filter"),
+        _ => Some("stop"),
+    }
+}
+
+/// OpenAI usage counts from Messages usage; cached input counts as prompt tokens
+fn usage(usage: &Value) -> Value {
+    let count = |field: &str| usage.get(field).and_then(Value::as_u64).unwrap_or(0);
+    let prompt = count("input_tokens") + count("cache_creation_input_tokens") + count("cache_read_input_tokens");
+    let completion = count("output_tokens");
+    json!({
+        "prompt_tokens": prompt,
+        "completion_tokens": completion,
+        "total_tokens": prompt + completion,
+        "prompt_tokens_details": {"cached_tokens": count("cache_read_input_tokens")},
+    })
+}
+
+/// A Messages response as an OpenAI chat completion. Thinking blocks are left out.
+pub fn to_chat_completion(message: &Value) -> Value {
+    let mut text = String::new();
+    let mut tool_calls = Vec::new();
+    for block in message.get("content").and_then(Value::as_array).into_iter().flatten() {
+        match block.get("type").and_then(Value::as_str) {
+            Some("text") => text.push_str(block.get("text").and_then(Value::as_str).unwrap_or_default()),
+            Some("tool_use") => tool_calls.push(json!({
+                "id": block.get("id"),
+                "type": "function",
+                "function": {
+                    "name": block.get("name"),
+                    "arguments": block.get("input").map(Value::to_string).unwrap_or_else(|| "{}".to_string()),
+                },
+            })),
+            _ => {}
+        }
+    }
+
+    let mut reply = json!({
+        "role": "assistant",
+        "content": if text.is_empty() && !tool_calls.is_empty() { Value::Null } else { json!(text) },
+    });
+    if !tool_calls.is_empty() {
+        reply["tool_calls"] = Value::Array(tool_calls);
+    }
+    json!({
+        "id": completion_id(message.get("id")),
+        "object": "chat.completion",
+        "created": chrono::Utc::now().timestamp(),
+        "model": message.get("model"),
+        "choices": [{
+            "index": 0,
+            "message": reply,
+            "finish_reason": finish_reason(message.get("stop_reason").and_then(Value::as_str)),
+            "logprobs": null,
+        }],
+        "usage": usage(message.get("usage").unwrap_or(&Value::Null)),
+    })
+}
+
+fn completion_id(message_id: Option<&Value>) -> String {
+    format!("chatcmpl-{}", message_id.and_then(Value::as_str).unwrap_or_default())
+}
+
+/// Turns the events of a streamed Messages response into OpenAI chat completion chunks
+#[derive(Debug, Default)]
+pub struct ChunkTranslator {
+    id: String,
+    model: Value,
+    created: i64,
+    /// OpenAI tool call index of each tool_use content block, by block index
+    tool_calls: HashMap<u64, usize>,
+}
+
+impl ChunkTranslator {
+    pub fn new() -> Self {
+        Self {
+            created: chrono::Utc::now().timestamp(),
+            ..Self::default()
+        }
+    }
+
+    /// `data:` frames for one upstream event; most events have none
+    pub fn translate(&mut self, event: &SseEvent) -> Vec<String> {
+        let Some(data) = event.json() else {
+            return Vec::new();
+        };
+        let index = data.get("index").and_then(Value::as_u64).unwrap_or(0);
+        match data.get("type").and_then(Value::as_str) {
+            Some("message_start") => {
+                let message = &data["message"];
+                self.id = completion_id(message.get("id"));
+                self.model = message.get("model").cloned().unwrap_or_default();
+                vec![self.chunk(json!({"role": "assistant", "content": ""}), None)]
+            }
+            Some("content_block_start") if data["content_block"]["type"] == "tool_use" => {
+                let call = self.tool_calls.len();
+                self.tool_calls.insert(index, call);
+                let block = &data["content_block"];
+                let delta = json!({"tool_calls": [{
+                    "index": call,
+                    "id": block.get("id"),
+                    "type": "function",
+                    "function": {"name": block.get("name"), "arguments": ""},
+                }]});
+                vec![self.chunk(delta, None)]
+            }
+            Some("content_block_delta") => match data["delta"]["type"].as_str() {
+                Some("text_delta") => vec![self.chunk(json!({"content": data["delta"]["text"]}), None)],
+                Some("input_json_delta") => match self.tool_calls.get(&index) {
+                    Some(&call) => {
+                        let arguments = &data["delta"]["partial_json"];
+                        vec![self.chunk(json!({"tool_calls": [{"index": call, "function": {"arguments": arguments}}]}), None)]
+                    }
+                    None => Vec::new(),
+                },
+                _ => Vec::new(),
+            },
+            Some("message_delta") => {
+                let reason = finish_reason(data["delta"]["stop_reason"].as_str());
+                vec![self.chunk(json!({}), reason)]
+            }
+            // The proxy's own final usage event, sent when stream_options.include_usage is set
+            Some("usage") => {
+                let mut chunk = self.chunk(json!({}), None);
+                chunk["choices"] = json!([]);
+                chunk["usage"] = usage(&data["usage"]);
+                vec![chunk]
+            }
+            Some("error") => vec![json!({"error": data["error"]})],
+            _ => Vec::new(),
+        }
+        .iter()
+        .map(|chunk| format!("data: {}\n\n", chunk))
+        .collect()
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason, "logprobs": null}],
+        })
+    }
+}
@@ -0,0 +1,509 @@
+use serde_json::{json, Value};
+
+/// OpenAPI 3 description of the proxy's routes, served at /openapi.json for client
+/// generators and API gateways
+pub fn document() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Maximize",
+            "description": "Anthropic Messages API proxy for Claude Max subscriptions",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": "/" }],
+        "security": [{ "apiKey": [] }, { "bearer": [] }],
+        "tags": [
+            { "name": "messages", "description": "Anthropic Messages API" },
+            { "name": "chat", "description": "OpenAI-compatible chat completions, translated to Messages" },
+            { "name": "auth", "description": "Subscription tokens and the account behind them" },
+            { "name": "admin", "description": "Request history, usage and statistics" },
+            { "name": "health", "description": "Liveness and monitoring" },
+        ],
+        "paths": {
+            "/v1/messages": { "post": messages_operation("createMessage", "Create a message") },
+            "/api/v1/messages": {
+                "post": messages_operation("createMessageGateway", "Create a message (OpenRouter-style base path)")
+            },
+            "/v1/messages/{route}": { "post": routed_messages_operation() },
+            "/api/v1/chat/completions": {
+                "post": chat_completions_operation("createChatCompletion", "Create a chat completion (OpenRouter-style base path)")
+            },
+            "/openai/deployments/{deployment}/chat/completions": { "post": azure_chat_completions_operation() },
+            "/v1/tokenize": {
+                "post": {
+                    "tags": ["messages"],
+                    "operationId": "tokenize",
+                    "summary": "Estimate the input tokens of a request locally",
+                    "requestBody": json_body("#/components/schemas/MessagesRequest"),
+                    "responses": {
+                        "200": json_response("Approximate token count", json!({
+                            "type": "object",
+                            "properties": {
+                                "input_tokens": { "type": "integer" },
+                                "approximate": { "type": "boolean" }
+                            }
+                        })),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/debug/preview": {
+                "post": {
+                    "tags": ["messages"],
+                    "operationId": "previewRequest",
+                    "summary": "Show the upstream request a message would produce, without sending it",
+                    "parameters": [query_parameter("format", "\"json\" (default) or \"curl\" for the curl command as plain text")],
+                    "requestBody": json_body("#/components/schemas/MessagesRequest"),
+                    "responses": {
+                        "200": object_response("Upstream URL, headers and body"),
+                        "400": error_response("Invalid request, or routed to Bedrock or Vertex, which can't be previewed"),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/v1/sessions/{id}": {
+                "delete": {
+                    "tags": ["messages"],
+                    "operationId": "deleteSession",
+                    "summary": "Forget a server-side conversation session",
+                    "parameters": [{
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "description": "Session id sent in X-Session-Id",
+                        "schema": { "type": "string" }
+                    }],
+                    "responses": {
+                        "200": json_response("Whether the session existed", json!({
+                            "type": "object",
+                            "properties": {
+                                "id": { "type": "string" },
+                                "deleted": { "type": "boolean" }
+                            }
+                        })),
+                        "400": error_response("Sessions are not enabled"),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/auth/status": {
+                "get": {
+                    "tags": ["auth"],
+                    "operationId": "authStatus",
+                    "summary": "Whether tokens are loaded and when they expire",
+                    "security": [],
+                    "responses": { "200": object_response("Token status") }
+                }
+            },
+            "/auth/whoami": {
+                "get": {
+                    "tags": ["auth"],
+                    "operationId": "authWhoami",
+                    "summary": "Account and plan behind the selected tokens",
+                    "parameters": [account_parameter()],
+                    "responses": {
+                        "200": object_response("Account identity"),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/auth/introspect": {
+                "get": {
+                    "tags": ["auth"],
+                    "operationId": "authIntrospect",
+                    "summary": "Token expiry, scopes, age, source and last refresh",
+                    "parameters": [account_parameter()],
+                    "responses": {
+                        "200": object_response("Token details"),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/auth/exchange": {
+                "post": {
+                    "tags": ["auth"],
+                    "operationId": "authExchange",
+                    "summary": "Complete a login by exchanging its authorization code for tokens",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": {
+                            "type": "object",
+                            "required": ["code"],
+                            "properties": {
+                                "code": { "type": "string", "description": "CODE#STATE, a callback URL, or the bare code" },
+                                "state": { "type": "string", "description": "Required when `code` carries no state" }
+                            }
+                        } } }
+                    },
+                    "responses": {
+                        "200": object_response("Tokens saved"),
+                        "400": error_response("Invalid or expired code"),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/usage": {
+                "get": {
+                    "tags": ["admin"],
+                    "operationId": "usage",
+                    "summary": "Token usage, quotas and rate limit estimates of the calling key",
+                    "responses": {
+                        "200": object_response("Usage report"),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/stats": {
+                "get": {
+                    "tags": ["admin"],
+                    "operationId": "stats",
+                    "summary": "Rolling latency, error rate and throughput, with admission queue load and wait times",
+                    "responses": {
+                        "200": object_response("Statistics"),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/admin/requests": {
+                "get": {
+                    "tags": ["admin"],
+                    "operationId": "adminRequests",
+                    "summary": "Most recent requests handled by the proxy, newest first",
+                    "parameters": history_parameters(),
+                    "responses": {
+                        "200": json_response("Request records", json!({ "type": "array", "items": { "type": "object" } })),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/admin/refreshes": {
+                "get": {
+                    "tags": ["admin"],
+                    "operationId": "adminRefreshes",
+                    "summary": "Recent token refresh attempts, newest first",
+                    "parameters": history_parameters(),
+                    "responses": {
+                        "200": json_response("Refresh attempts", json!({ "type": "array", "items": { "type": "object" } })),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/admin/events": {
+                "get": {
+                    "tags": ["admin"],
+                    "operationId": "adminEvents",
+                    "summary": "Live request events as server-sent events",
+                    "responses": {
+                        "200": {
+                            "description": "Event stream",
+                            "content": { "text/event-stream": { "schema": { "type": "string" } } }
+                        },
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/admin/usage/export": {
+                "get": {
+                    "tags": ["admin"],
+                    "operationId": "usageExport",
+                    "summary": "Usage records for offline analysis and chargeback",
+                    "parameters": [
+                        query_parameter("from", "Start of the range, inclusive (RFC 3339 time or YYYY-MM-DD)"),
+                        query_parameter("to", "End of the range, exclusive (RFC 3339 time or YYYY-MM-DD)"),
+                        query_parameter("format", "csv (default) or parquet, when built with the parquet feature"),
+                        query_parameter("tenant", "Only this usage bucket; tenant keys always get just their own"),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Usage records",
+                            "content": {
+                                "text/csv": { "schema": { "type": "string" } },
+                                "application/vnd.apache.parquet": { "schema": { "type": "string", "format": "binary" } }
+                            }
+                        },
+                        "400": error_response("Invalid range or format"),
+                        "401": error_response("Missing or invalid API key"),
+                    }
+                }
+            },
+            "/healthz": {
+                "get": {
+                    "tags": ["health"],
+                    "operationId": "health",
+                    "summary": "Liveness check",
+                    "security": [],
+                    "responses": {
+                        "200": json_response("Server is up", json!({
+                            "type": "object",
+                            "properties": {
+                                "status": { "type": "string" },
+                                "timestamp": { "type": "integer" },
+                                "port": { "type": "integer", "nullable": true }
+                            }
+                        }))
+                    }
+                }
+            },
+            "/metrics": {
+                "get": {
+                    "tags": ["health"],
+                    "operationId": "metrics",
+                    "summary": "Prometheus metrics",
+                    "security": [],
+                    "responses": {
+                        "200": {
+                            "description": "Metrics in the Prometheus text format",
+                            "content": { "text/plain": { "schema": { "type": "string" } } }
+                        }
+                    }
+                }
+            },
+            "/openapi.json": {
+                "get": {
+                    "tags": ["health"],
+                    "operationId": "openapi",
+                    "summary": "This document",
+                    "security": [],
+                    "responses": { "200": object_response("OpenAPI 3 document") }
+                }
+            },
+        },
+        "components": {
+            "securitySchemes": {
+                "apiKey": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "x-api-key",
+                    "description": "One of `api.keys` or a tenant key; Azure-style `api-key` works too"
+                },
+                "bearer": { "type": "http", "scheme": "bearer" }
+            },
+            "schemas": {
+                "MessagesRequest": {
+                    "type": "object",
+                    "required": ["messages", "max_tokens"],
+                    "additionalProperties": true,
+                    "properties": {
+                        "model": {
+                            "type": "string",
+                            "description": "Full model name, nickname (xs to xxl) or gateway id; the default model when omitted"
+                        },
+                        "messages": { "type": "array", "items": { "type": "object" } },
+                        "max_tokens": { "type": "integer" },
+                        "system": {
+                            "oneOf": [{ "type": "string" }, { "type": "array", "items": { "type": "object" } }]
+                        },
+                        "stream": { "type": "boolean" },
+                        "temperature": { "type": "number" },
+                        "top_p": { "type": "number" },
+                        "top_k": { "type": "integer" },
+                        "stop_sequences": { "type": "array", "items": { "type": "string" } },
+                        "thinking": { "type": "object" },
+                        "tools": { "type": "array", "items": { "type": "object" } },
+                        "tool_choice": { "type": "object" },
+                        "metadata": { "type": "object" },
+                        "service_tier": {
+                            "type": "string",
+                            "description": "Capacity tier, such as auto or standard_only; the key's `api.service_tiers` default when omitted"
+                        },
+                        "stream_options": {
+                            "type": "object",
+                            "description": "Handled by the proxy, never forwarded",
+                            "properties": { "include_usage": { "type": "boolean" } }
+                        }
+                    }
+                },
+                "ChatCompletionRequest": {
+                    "type": "object",
+                    "required": ["messages"],
+                    "additionalProperties": true,
+                    "properties": {
+                        "model": {
+                            "type": "string",
+                            "description": "Full model name, nickname or alias; replaced by the deployment on Azure-style paths"
+                        },
+                        "messages": { "type": "array", "items": { "type": "object" } },
+                        "max_completion_tokens": { "type": "integer" },
+                        "max_tokens": { "type": "integer", "description": "Deprecated by OpenAI; 4096 when neither is set" },
+                        "temperature": { "type": "number" },
+                        "top_p": { "type": "number" },
+                        "stop": { "oneOf": [{ "type": "string" }, { "type": "array", "items": { "type": "string" } }] },
+                        "stream": { "type": "boolean" },
+                        "stream_options": { "type": "object", "properties": { "include_usage": { "type": "boolean" } } },
+                        "tools": { "type": "array", "items": { "type": "object" } },
+                        "tool_choice": { "oneOf": [{ "type": "string" }, { "type": "object" }] },
+                        "parallel_tool_calls": { "type": "boolean" },
+                        "user": { "type": "string" }
+                    }
+                },
+                "Message": {
+                    "type": "object",
+                    "additionalProperties": true,
+                    "properties": {
+                        "id": { "type": "string" },
+                        "type": { "type": "string", "enum": ["message"] },
+                        "role": { "type": "string", "enum": ["assistant"] },
+                        "model": { "type": "string" },
+                        "content": { "type": "array", "items": { "type": "object" } },
+                        "stop_reason": { "type": "string", "nullable": true },
+                        "usage": { "type": "object" }
+                    }
+                },
+                "Error": {
+                    "type": "object",
+                    "properties": {
+                        "type": { "type": "string", "enum": ["error"] },
+                        "error": {
+                            "type": "object",
+                            "properties": {
+                                "type": { "type": "string" },
+                                "message": { "type": "string" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn messages_operation(operation_id: &str, summary: &str) -> Value {
+    json!({
+        "tags": ["messages"],
+        "operationId": operation_id,
+        "summary": summary,
+        "parameters": [
+            query_parameter("format", "\"ndjson\" to stream newline-delimited JSON instead of server-sent events"),
+            account_parameter(),
+            header_parameter("x-maximize-priority", "Admission priority of this request: urgent, interactive or batch"),
+            header_parameter("x-maximize-timeout", "Upstream timeout of this request in seconds"),
+            header_parameter("x-maximize-max-output", "Upper bound on the output tokens of this request"),
+            header_parameter("x-maximize-debug", "Log this request verbosely"),
+            header_parameter("x-session-id", "Server-side conversation session to continue"),
+        ],
+        "requestBody": json_body("#/components/schemas/MessagesRequest"),
+        "responses": {
+            "200": {
+                "description": "The message, or its events when `stream` is true",
+                "content": {
+                    "application/json": { "schema": { "$ref": "#/components/schemas/Message" } },
+                    "text/event-stream": { "schema": { "type": "string" } },
+                    "application/x-ndjson": { "schema": { "type": "string" } }
+                }
+            },
+            "400": error_response("Invalid request"),
+            "401": error_response("Missing or invalid API key"),
+            "403": error_response("Model, account or feature not allowed for this key"),
+            "429": error_response("Rate limited, quota exceeded or queue full"),
+            "499": error_response("Client closed the connection; upstream call cancelled"),
+            "502": error_response("Upstream unreachable or its response unreadable"),
+            "504": error_response("Upstream timed out"),
+            "529": error_response("Proxy overloaded; low-priority request shed"),
+        }
+    })
+}
+
+fn chat_completions_operation(operation_id: &str, summary: &str) -> Value {
+    json!({
+        "tags": ["chat"],
+        "operationId": operation_id,
+        "summary": summary,
+        "parameters": [
+            account_parameter(),
+            header_parameter("x-maximize-priority", "Admission priority of this request: urgent, interactive or batch"),
+            header_parameter("x-maximize-timeout", "Upstream timeout of this request in seconds"),
+        ],
+        "requestBody": json_body("#/components/schemas/ChatCompletionRequest"),
+        "responses": {
+            "200": {
+                "description": "The chat completion, or its chunks when `stream` is true",
+                "content": {
+                    "application/json": { "schema": { "type": "object" } },
+                    "text/event-stream": { "schema": { "type": "string" } }
+                }
+            },
+            "400": error_response("Invalid request"),
+            "401": error_response("Missing or invalid API key"),
+            "429": error_response("Rate limited, quota exceeded or queue full"),
+            "502": error_response("Upstream unreachable or its response unreadable"),
+        }
+    })
+}
+
+/// Chat completions on an Azure OpenAI-style path, which names the model as a deployment
+fn azure_chat_completions_operation() -> Value {
+    let mut operation = chat_completions_operation("createAzureChatCompletion", "Create a chat completion (Azure OpenAI-style path)");
+    if let Some(parameters) = operation["parameters"].as_array_mut() {
+        parameters.insert(
+            0,
+            json!({
+                "name": "deployment",
+                "in": "path",
+                "required": true,
+                "description": "Model name, nickname or tenant model alias",
+                "schema": { "type": "string" }
+            }),
+        );
+        parameters.insert(1, query_parameter("api-version", "Accepted and ignored"));
+    }
+    operation
+}
+
+/// Messages operation on a virtual route, which prepends the route's preset system prompt
+fn routed_messages_operation() -> Value {
+    let mut operation = messages_operation("createRoutedMessage", "Create a message through a virtual route");
+    if let Some(parameters) = operation["parameters"].as_array_mut() {
+        parameters.insert(
+            0,
+            json!({
+                "name": "route",
+                "in": "path",
+                "required": true,
+                "description": "Virtual route configured in `presets.routes`",
+                "schema": { "type": "string" }
+            }),
+        );
+    }
+    operation
+}
+
+fn json_body(schema_ref: &str) -> Value {
+    json!({
+        "required": true,
+        "content": { "application/json": { "schema": { "$ref": schema_ref } } }
+    })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } }
+    })
+}
+
+fn object_response(description: &str) -> Value {
+    json_response(description, json!({ "type": "object" }))
+}
+
+fn error_response(description: &str) -> Value {
+    json_response(description, json!({ "$ref": "#/components/schemas/Error" }))
+}
+
+fn query_parameter(name: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "query", "description": description, "schema": { "type": "string" } })
+}
+
+fn header_parameter(name: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "header", "description": description, "schema": { "type": "string" } })
+}
+
+fn account_parameter() -> Value {
+    header_parameter("x-maximize-account", "Account profile whose tokens to use")
+}
+
+fn history_parameters() -> Value {
+    json!([
+        { "name": "limit", "in": "query", "schema": { "type": "integer" } },
+        query_parameter("tenant", "Only this tenant's entries; tenant keys always get just their own"),
+    ])
+}
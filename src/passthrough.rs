@@ -0,0 +1,478 @@
+use axum::body::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::proxy::StreamOptions;
+
+/// A Messages request in raw passthrough mode. Only the top level of the body is parsed;
+/// every value stays as the client serialized it, so fields the proxy doesn't know are
+/// never dropped. The few the proxy touches are read and rewritten one at a time.
+pub struct RawMessagesRequest {
+    body: Bytes,
+    fields: BTreeMap<String, Box<RawValue>>,
+    modified: bool,
+}
+
+impl RawMessagesRequest {
+    pub fn parse(body: Bytes) -> serde_json::Result<Self> {
+        let fields = serde_json::from_slice(&body)?;
+        Ok(Self {
+            body,
+            fields,
+            modified: false,
+        })
+    }
+
+    fn get<T: DeserializeOwned>(&self, field: &str) -> Option<T> {
+        self.fields.get(field).and_then(|value| serde_json::from_str(value.get()).ok())
+    }
+
+    fn set(&mut self, field: &str, value: &impl Serialize) {
+        if let Ok(raw) = serde_json::value::to_raw_value(value) {
+            self.fields.insert(field.to_string(), raw);
+            self.modified = true;
+        }
+    }
+
+    /// Empty when the client sent none
+    pub fn model(&self) -> String {
+        self.get("model").unwrap_or_default()
+    }
+
+    pub fn set_model(&mut self, model: &str) {
+        self.set("model", &model);
+    }
+
+    pub fn stream(&self) -> bool {
+        self.get("stream").unwrap_or(false)
+    }
+
+    /// The conversation exactly as the client serialized it
+    pub fn raw_messages(&self) -> Option<&str> {
+        self.fields.get("messages").map(|value| value.get())
+    }
+
+    pub fn service_tier(&self) -> Option<String> {
+        self.get("service_tier")
+    }
+
+    pub fn set_service_tier(&mut self, tier: &str) {
+        self.set("service_tier", &tier);
+    }
+
+    pub fn system(&self) -> Option<Value> {
+        self.get("system")
+    }
+
+    pub fn set_system(&mut self, system: &Value) {
+        self.set("system", system);
+    }
+
+    /// Remove the proxy-only `stream_options`, which Anthropic rejects. Returns whether a
+    /// final usage event was asked for.
+    pub fn take_stream_options(&mut self) -> bool {
+        let Some(raw) = self.fields.remove("stream_options") else {
+            return false;
+        };
+        self.modified = true;
+        serde_json::from_str::<StreamOptions>(raw.get()).is_ok_and(|options| options.include_usage)
+    }
+
+    /// The body to send upstream: the client's own bytes unless a field was rewritten
+    pub fn into_body(self) -> Bytes {
+        if !self.modified {
+            return self.body;
+        }
+        serde_json::to_vec(&self.fields).map(Bytes::from).unwrap_or(self.body)
+    }
+}
+
+/// Top-level fields a `BodyRewriter` reads and hands to its callback; all others stream
+/// through untouched
+pub const REWRITTEN_FIELDS: &[&str] = &["model", "service_tier", "stream", "stream_options", "system"];
+
+const NOT_AN_OBJECT: &str = "Request body is not a JSON object";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scan {
+    Start,
+    /// After the opening brace, where the object may also end
+    FirstKey,
+    /// After a comma, where only a field may follow
+    BeforeKey,
+    Key,
+    BeforeColon,
+    BeforeValue,
+    Value,
+    Done,
+}
+
+/// Rewrites a Messages request body as it streams in, for bodies too large to buffer.
+/// Values of `REWRITTEN_FIELDS` are buffered and passed to the callback, which returns
+/// what to write instead (None drops the field); at the end it is called with None for
+/// each of them the body didn't have. Everything else, the conversation included, is
+/// forwarded chunk by chunk, so memory stays bounded by those few fields.
+pub struct BodyRewriter<F> {
+    rewrite: F,
+    scan: Scan,
+    /// Current key as sent, quotes included
+    key: Vec<u8>,
+    /// Value of the current field, when it is one to rewrite
+    value: Vec<u8>,
+    buffering: bool,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// Whether the value has begun, and whether it is whole, so only ',' or '}' may follow
+    value_started: bool,
+    value_complete: bool,
+    /// Members written so far, to place the commas
+    written: usize,
+    seen: Vec<&'static str>,
+}
+
+impl<F: FnMut(&str, Option<Value>) -> Option<Value>> BodyRewriter<F> {
+    pub fn new(rewrite: F) -> Self {
+        Self {
+            rewrite,
+            scan: Scan::Start,
+            key: Vec::new(),
+            value: Vec::new(),
+            buffering: false,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            value_started: false,
+            value_complete: false,
+            written: 0,
+            seen: Vec::new(),
+        }
+    }
+
+    /// Take the next chunk of the body; returns the pieces to send upstream
+    pub fn feed(&mut self, chunk: &Bytes) -> Result<Vec<Bytes>, String> {
+        let mut out = Vec::new();
+        // Start of the part of this chunk that is forwarded as is
+        let mut span = (self.scan == Scan::Value && !self.buffering).then_some(0);
+
+        for (i, &b) in chunk.iter().enumerate() {
+            match self.scan {
+                Scan::Start => match b {
+                    b'{' => {
+                        out.push(Bytes::from_static(b"{"));
+                        self.scan = Scan::FirstKey;
+                    }
+                    _ if b.is_ascii_whitespace() => {}
+                    _ => return Err(NOT_AN_OBJECT.to_string()),
+                },
+                Scan::FirstKey | Scan::BeforeKey => match b {
+                    b'"' => {
+                        self.key.clear();
+                        self.key.push(b);
+                        self.escaped = false;
+                        self.scan = Scan::Key;
+                    }
+                    b'}' if self.scan == Scan::FirstKey => {
+                        self.close(&mut out);
+                    }
+                    _ if b.is_ascii_whitespace() => {}
+                    _ if self.scan == Scan::FirstKey => {
+                        return Err(format!("{}: expected a field name or '}}'", NOT_AN_OBJECT))
+                    }
+                    _ => return Err(format!("{}: expected a field name after ','", NOT_AN_OBJECT)),
+                },
+                Scan::Key => {
+                    self.key.push(b);
+                    if self.escaped {
+                        self.escaped = false;
+                    } else if b == b'\\' {
+                        self.escaped = true;
+                    } else if b == b'"' {
+                        self.scan = Scan::BeforeColon;
+                    }
+                }
+                Scan::BeforeColon => match b {
+                    b':' => self.scan = Scan::BeforeValue,
+                    _ if b.is_ascii_whitespace() => {}
+                    _ => return Err(format!("{}: expected ':' after a field name", NOT_AN_OBJECT)),
+                },
+                Scan::BeforeValue if b.is_ascii_whitespace() => {}
+                Scan::BeforeValue | Scan::Value => {
+                    if self.scan == Scan::BeforeValue {
+                        self.scan = Scan::Value;
+                        self.buffering = self.field().is_some();
+                        self.value.clear();
+                        self.depth = 0;
+                        self.in_string = false;
+                        self.escaped = false;
+                        self.value_started = false;
+                        self.value_complete = false;
+                        if !self.buffering {
+                            let mut member = self.separator();
+                            member.extend_from_slice(&self.key);
+                            member.push(b':');
+                            out.push(Bytes::from(member));
+                            span = Some(i);
+                        }
+                    }
+                    if !self.value_ends_at(b)? {
+                        if self.buffering {
+                            self.value.push(b);
+                        }
+                        continue;
+                    }
+                    if let Some(start) = span.take() {
+                        out.push(chunk.slice(start..i));
+                    } else {
+                        self.rewrite_field(&mut out)?;
+                    }
+                    if b == b'}' {
+                        self.close(&mut out);
+                    } else {
+                        self.scan = Scan::BeforeKey;
+                    }
+                }
+                Scan::Done if b.is_ascii_whitespace() => {}
+                Scan::Done => return Err(format!("{}: unexpected data after the object", NOT_AN_OBJECT)),
+            }
+        }
+
+        if let Some(start) = span {
+            out.push(chunk.slice(start..));
+        }
+        Ok(out)
+    }
+
+    /// Check the body was complete once it has all been fed
+    pub fn finish(&self) -> Result<(), String> {
+        match self.scan {
+            Scan::Done => Ok(()),
+            _ => Err(format!("{}: the body ended before the object was closed", NOT_AN_OBJECT)),
+        }
+    }
+
+    /// The current key, if it names a field to rewrite
+    fn field(&self) -> Option<&'static str> {
+        let name = self.key.get(1..self.key.len().saturating_sub(1))?;
+        REWRITTEN_FIELDS.iter().copied().find(|field| field.as_bytes() == name)
+    }
+
+    fn separator(&mut self) -> Vec<u8> {
+        self.written += 1;
+        if self.written > 1 {
+            vec![b',']
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Whether the value being scanned ends just before this byte. Only the value's extent
+    /// is checked here: rewritten values are parsed, and the rest is left to Anthropic.
+    fn value_ends_at(&mut self, b: u8) -> Result<bool, String> {
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if b == b'\\' {
+                self.escaped = true;
+            } else if b == b'"' {
+                self.in_string = false;
+                self.value_complete = self.depth == 0;
+            }
+            return Ok(false);
+        }
+        if self.depth > 0 {
+            match b {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth -= 1;
+                    self.value_complete = self.depth == 0;
+                }
+                _ => {}
+            }
+            return Ok(false);
+        }
+
+        match b {
+            b',' | b'}' if self.value_started => return Ok(true),
+            b',' | b'}' => return Err(format!("{}: expected a value", NOT_AN_OBJECT)),
+            // Whitespace ends a number or literal
+            _ if b.is_ascii_whitespace() => {
+                self.value_complete = self.value_started;
+                return Ok(false);
+            }
+            _ if self.value_complete => {
+                return Err(format!("{}: expected ',' or '}}' after a value", NOT_AN_OBJECT))
+            }
+            b'"' => self.in_string = true,
+            b'{' | b'[' => self.depth += 1,
+            _ => {}
+        }
+        self.value_started = true;
+        Ok(false)
+    }
+
+    fn rewrite_field(&mut self, out: &mut Vec<Bytes>) -> Result<(), String> {
+        let Some(field) = self.field() else {
+            return Ok(());
+        };
+        let value: Value =
+            serde_json::from_slice(&self.value).map_err(|e| format!("Invalid value for '{}': {}", field, e))?;
+        if !self.seen.contains(&field) {
+            self.seen.push(field);
+        }
+        if let Some(value) = (self.rewrite)(field, Some(value)) {
+            self.write_member(field, &value, out);
+        }
+        Ok(())
+    }
+
+    /// Add the fields the body didn't have, then end the object
+    fn close(&mut self, out: &mut Vec<Bytes>) {
+        for field in REWRITTEN_FIELDS {
+            if self.seen.contains(field) {
+                continue;
+            }
+            if let Some(value) = (self.rewrite)(field, None) {
+                self.write_member(field, &value, out);
+            }
+        }
+        out.push(Bytes::from_static(b"}"));
+        self.scan = Scan::Done;
+    }
+
+    fn write_member(&mut self, field: &str, value: &Value, out: &mut Vec<Bytes>) {
+        let mut member = self.separator();
+        member.extend_from_slice(Value::String(field.to_string()).to_string().as_bytes());
+        member.push(b':');
+        member.extend_from_slice(value.to_string().as_bytes());
+        out.push(Bytes::from(member));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Resolves the model, keeps or adds a system prompt and service tier, and drops
+    /// `stream_options`, like the passthrough handler
+    fn rewrite_field(field: &str, value: Option<Value>) -> Option<Value> {
+        match field {
+            "model" => Some(json!("claude-sonnet-4-20250514")),
+            "system" => value.or_else(|| Some(json!("injected"))),
+            "service_tier" => value.or_else(|| Some(json!("standard_only"))),
+            "stream_options" => None,
+            _ => value,
+        }
+    }
+
+    fn rewrite(chunks: &[&[u8]]) -> Result<Value, String> {
+        let mut rewriter = BodyRewriter::new(rewrite_field);
+        let mut body = Vec::new();
+        for chunk in chunks {
+            for piece in rewriter.feed(&Bytes::copy_from_slice(chunk))? {
+                body.extend_from_slice(&piece);
+            }
+        }
+        rewriter.finish()?;
+        Ok(serde_json::from_slice(&body).expect("rewritten body is JSON"))
+    }
+
+    /// The body rewritten whole, after checking every two-chunk split and byte-by-byte
+    /// feeding give the same result
+    fn rewrite_split(body: &str) -> Value {
+        let body = body.as_bytes();
+        let whole = rewrite(&[body]).unwrap();
+        for i in 1..body.len() {
+            assert_eq!(rewrite(&[&body[..i], &body[i..]]).unwrap(), whole, "split at byte {}", i);
+        }
+        let bytes: Vec<&[u8]> = body.chunks(1).collect();
+        assert_eq!(rewrite(&bytes).unwrap(), whole, "fed byte by byte");
+        whole
+    }
+
+    #[test]
+    fn keys_and_values_split_across_chunks() {
+        let body = r#"{ "model" : "l", "max_tokens": 1024, "system": "Be brief.", "messages": [{"role": "user", "content": "hi"}], "stream": true }"#;
+        assert_eq!(
+            rewrite_split(body),
+            json!({
+                "model": "claude-sonnet-4-20250514",
+                "max_tokens": 1024,
+                "system": "Be brief.",
+                "messages": [{"role": "user", "content": "hi"}],
+                "stream": true,
+                "service_tier": "standard_only"
+            })
+        );
+    }
+
+    #[test]
+    fn escaped_quotes_in_keys_and_strings() {
+        let body = r#"{"a\"model\"":"x\"}, \"y","system":"say \"hi\" }, {","messages":[{"role":"user","content":"\\\"]"}]}"#;
+        let rewritten = rewrite_split(body);
+        assert_eq!(rewritten["a\"model\""], json!("x\"}, \"y"));
+        assert_eq!(rewritten["system"], json!("say \"hi\" }, {"));
+        assert_eq!(rewritten["messages"][0]["content"], json!("\\\"]"));
+        assert_eq!(rewritten["model"], json!("claude-sonnet-4-20250514"));
+    }
+
+    #[test]
+    fn nested_values_rewritten_and_passed_through() {
+        let body = r#"{"system":[{"type":"text","text":"{[","cache_control":{"type":"ephemeral"}}],"stream_options":{"include_usage":true},"metadata":{"tags":[["a"],{"b":[]}]},"tools":[{"name":"t","input_schema":{"type":"object","properties":{}}}]}"#;
+        let rewritten = rewrite_split(body);
+        assert_eq!(
+            rewritten["system"],
+            json!([{"type": "text", "text": "{[", "cache_control": {"type": "ephemeral"}}])
+        );
+        assert_eq!(rewritten["metadata"], json!({"tags": [["a"], {"b": []}]}));
+        assert_eq!(rewritten["tools"][0]["input_schema"]["properties"], json!({}));
+        assert!(rewritten.get("stream_options").is_none());
+    }
+
+    #[test]
+    fn missing_fields_are_injected() {
+        assert_eq!(
+            rewrite_split(r#"{"messages":[]}"#),
+            json!({
+                "messages": [],
+                "model": "claude-sonnet-4-20250514",
+                "system": "injected",
+                "service_tier": "standard_only"
+            })
+        );
+        assert_eq!(
+            rewrite_split("{}"),
+            json!({"model": "claude-sonnet-4-20250514", "system": "injected", "service_tier": "standard_only"})
+        );
+        // A dropped first member leaves no stray comma before the next
+        assert_eq!(
+            rewrite_split(r#"{"stream_options":{"include_usage":true},"model":"s","service_tier":"auto","system":"x"}"#),
+            json!({"model": "claude-sonnet-4-20250514", "service_tier": "auto", "system": "x"})
+        );
+    }
+
+    #[test]
+    fn malformed_bodies_are_rejected() {
+        for body in [
+            r#"{,"a":1}"#,
+            r#"{"a":1,}"#,
+            r#"{"a":1,,"b":2}"#,
+            r#"{"a":1 "b":2}"#,
+            r#"{"a":[1] "b":2}"#,
+            r#"{"a":"x""b":2}"#,
+            r#"{"model":"s" "b":2}"#,
+            r#"{"a":}"#,
+            r#"{"a":,"b":2}"#,
+            r#"{"a" 1}"#,
+            r#"{"a":1"#,
+            r#"{"a":1} x"#,
+            r#"[1]"#,
+        ] {
+            assert!(rewrite(&[body.as_bytes()]).is_err(), "accepted {}", body);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose, Engine};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+/// How long a started login may take before its verifier is discarded
+const FLOW_TTL_SECONDS: i64 = 15 * 60;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PkceEntry {
+    code_verifier: String,
+    created_at: i64,
+}
+
+/// PKCE verifiers of the login flows in progress, one file per flow keyed by its state, in a
+/// private directory next to the token file
+pub struct PkceStore {
+    dir: PathBuf,
+}
+
+impl PkceStore {
+    pub fn for_token_file(token_path: &Path) -> Self {
+        let dir = token_path.parent().unwrap_or_else(|| Path::new("."));
+        Self { dir: dir.join("pkce") }
+    }
+
+    /// Entries are named after a hash of the state, which comes from user input
+    fn entry_path(&self, state: &str) -> PathBuf {
+        let digest = Sha256::digest(state.as_bytes());
+        self.dir
+            .join(format!("{}.json", general_purpose::URL_SAFE_NO_PAD.encode(digest)))
+    }
+
+    fn ensure_dir(&self) -> Result<()> {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(0o700);
+        builder
+            .create(&self.dir)
+            .context(format!("Failed to create PKCE directory {}", self.dir.display()))
+    }
+
+    /// Remember the verifier of a new flow, discarding flows that expired
+    pub fn save(&self, state: &str, code_verifier: &str) -> Result<()> {
+        self.ensure_dir()?;
+        self.prune();
+
+        let entry = PkceEntry {
+            code_verifier: code_verifier.to_string(),
+            created_at: Utc::now().timestamp(),
+        };
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(self.entry_path(state))?;
+        file.write_all(serde_json::to_string(&entry)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Verifier of the flow started with `state`; None if this host never started it
+    pub fn verifier(&self, state: &str) -> Result<Option<String>> {
+        let path = self.entry_path(state);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let entry: PkceEntry = serde_json::from_str(&fs::read_to_string(&path)?)
+            .context(format!("Failed to parse PKCE entry {}", path.display()))?;
+        if Utc::now().timestamp() - entry.created_at > FLOW_TTL_SECONDS {
+            let _ = fs::remove_file(&path);
+            anyhow::bail!(
+                "This login was started more than {} minutes ago and has expired; start the login again",
+                FLOW_TTL_SECONDS / 60
+            );
+        }
+        Ok(Some(entry.code_verifier))
+    }
+
+    /// Forget a flow once its code was exchanged
+    pub fn remove(&self, state: &str) -> Result<()> {
+        let path = self.entry_path(state);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Delete expired entries; failures only leave stale files behind
+    fn prune(&self) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let now = Utc::now().timestamp();
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let expired = fs::read_to_string(&path)
+                .ok()
+                .and_then(|contents| serde_json::from_str::<PkceEntry>(&contents).ok())
+                .is_some_and(|entry| now - entry.created_at > FLOW_TTL_SECONDS);
+            if expired {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::usage::TokenUsage;
+
+/// A model's list prices in USD per million tokens
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+    /// Writing to the prompt cache; 1.25x the input price when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_write: Option<f64>,
+    /// Reading from the prompt cache; 0.1x the input price when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_read: Option<f64>,
+}
+
+impl ModelPrice {
+    const fn new(input: f64, output: f64) -> Self {
+        Self {
+            input,
+            output,
+            cache_write: None,
+            cache_read: None,
+        }
+    }
+
+    /// Cost in USD of the tokens a request used
+    pub fn cost(&self, usage: &TokenUsage) -> f64 {
+        let cache_write = self.cache_write.unwrap_or(self.input * 1.25);
+        let cache_read = self.cache_read.unwrap_or(self.input * 0.1);
+        (usage.input_tokens as f64 * self.input
+            + usage.output_tokens as f64 * self.output
+            + usage.cache_creation_input_tokens as f64 * cache_write
+            + usage.cache_read_input_tokens as f64 * cache_read)
+            / 1_000_000.0
+    }
+
+    /// Most a request can cost: its estimated input plus every allowed output token
+    pub fn worst_case_cost(&self, input_tokens: u64, max_tokens: u64) -> f64 {
+        self.cost(&TokenUsage {
+            input_tokens,
+            output_tokens: max_tokens,
+            ..TokenUsage::default()
+        })
+    }
+}
+
+/// List prices of the known models, keyed by full model name
+pub fn default_prices() -> HashMap<String, ModelPrice> {
+    [
+        ("claude-3-5-haiku-20241022", ModelPrice::new(0.8, 4.0)),
+        ("claude-3-5-sonnet-20241022", ModelPrice::new(3.0, 15.0)),
+        ("claude-3-7-sonnet-20250219", ModelPrice::new(3.0, 15.0)),
+        ("claude-sonnet-4-20250514", ModelPrice::new(3.0, 15.0)),
+        ("claude-opus-4-20250514", ModelPrice::new(15.0, 75.0)),
+        ("claude-opus-4-1-20250805", ModelPrice::new(15.0, 75.0)),
+    ]
+    .into_iter()
+    .map(|(model, price)| (model.to_string(), price))
+    .collect()
+}
@@ -0,0 +1,61 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+use std::time::Duration;
+
+use crate::settings::Settings;
+
+const PROFILE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Who an OAuth token belongs to, summarized from the profile the `user:profile` scope grants
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AccountIdentity {
+    pub email: Option<String>,
+    pub display_name: Option<String>,
+    pub account_uuid: Option<String>,
+    pub organization_name: Option<String>,
+    pub organization_uuid: Option<String>,
+    /// Subscription tier, such as "max" or "pro"
+    pub plan: Option<String>,
+    pub rate_limit_tier: Option<String>,
+}
+
+impl AccountIdentity {
+    pub fn from_profile(profile: &Value) -> Self {
+        let field = |path: &str| profile.pointer(path).and_then(|v| v.as_str()).map(str::to_string);
+        let flag = |path: &str| profile.pointer(path).and_then(|v| v.as_bool()).unwrap_or(false);
+
+        let plan = field("/organization/organization_type")
+            .map(|kind| kind.strip_prefix("claude_").map(str::to_string).unwrap_or(kind))
+            .or_else(|| flag("/account/has_claude_max").then(|| "max".to_string()))
+            .or_else(|| flag("/account/has_claude_pro").then(|| "pro".to_string()));
+
+        Self {
+            email: field("/account/email").or_else(|| field("/account/email_address")),
+            display_name: field("/account/display_name").or_else(|| field("/account/full_name")),
+            account_uuid: field("/account/uuid"),
+            organization_name: field("/organization/name"),
+            organization_uuid: field("/organization/uuid"),
+            plan,
+            rate_limit_tier: field("/organization/rate_limit_tier"),
+        }
+    }
+}
+
+/// Fetch the account and organization profile of an OAuth access token
+pub async fn fetch_profile(http: &reqwest::Client, access_token: &str) -> Result<Value> {
+    let response = http
+        .get(format!("{}/api/oauth/profile", Settings::api_base()))
+        .timeout(PROFILE_TIMEOUT)
+        .header("Authorization", format!("Bearer {}", access_token))
+        .header("anthropic-beta", "oauth-2025-04-20")
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        anyhow::bail!("Profile request failed ({}): {}", status, error_text);
+    }
+    Ok(response.json().await?)
+}
@@ -1,21 +1,63 @@
 use axum::{
-    extract::{Request, State},
-    http::{HeaderMap, StatusCode},
+    body::{Body, Bytes},
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use std::sync::Arc;
-use std::time::Instant;
+use futures::StreamExt;
+use rand::Rng;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tower_http::trace::TraceLayer;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, warn, Instrument};
 use uuid::Uuid;
 
-use crate::oauth::OAuthManager;
-use crate::settings::Settings;
+use crate::admin;
+use crate::admission::{AdmissionPermit, AdmissionQueue, Priority};
+use crate::affinity;
+use crate::bedrock::BedrockClient;
+use crate::cache;
+use crate::compaction;
+use crate::connection;
+use crate::error::{ErrorCategory, ProxyError, CLIENT_CLOSED_REQUEST};
+use crate::events::{EventBus, ProxyEvent};
+use crate::guardrails::{Guardrails, ResponseScrubber, StreamScrubber};
+use crate::history::{RequestHistory, RequestRecord};
+use crate::images;
+use crate::metrics::{Metrics, Phase};
+use crate::moderation::{HttpModerator, ModerationAction, ModerationInput, ModerationResult, Moderator, RuleModerator};
+use crate::oauth::{Authorization, OAuthManager};
+use crate::openai::{self, ChunkTranslator};
+use crate::openapi;
+use crate::passthrough::{BodyRewriter, RawMessagesRequest};
+use crate::profile::AccountIdentity;
+use crate::quota::{QuotaTracker, RateLimitStyle, WindowStatus};
+use crate::ratelimit::{self, RateLimitTracker};
+use crate::scripting::ScriptTransform;
+use crate::redaction::Redactor;
+use crate::refresh_audit::RefreshReason;
+use crate::request_log::{self, BodyLogging, LogDetail, LogSampler};
+use crate::routing::{self, UpstreamHealth};
+use crate::sanitize::{self, SanitizeRule};
+use crate::sessions::{self, MemorySessionStore, SessionStore, SessionTurn};
+use crate::tenants::{self, Tenant, Tenants};
+use crate::settings::{CanaryArm, ExperimentArm, Settings, ThinkingMode, ThinkingPolicy};
+use crate::sse::{MessageAssembler, SseEvent, SseParser, StreamFormat, ToolInputRepair};
+use crate::stats::RollingStats;
+use crate::tokenizer;
+use crate::tools;
+use crate::upstream::{UpstreamKind, UpstreamResponse};
+use crate::validation::{self, ValidationMode};
+use crate::vertex::VertexClient;
+use crate::usage::{StreamUsage, TokenUsage, UsageLog, UsageRecord, UsageTracker};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ThinkingParameter {
@@ -31,6 +73,8 @@ fn default_budget() -> i32 {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AnthropicMessageRequest {
+    /// Empty when the client sent none; replaced by the default model
+    #[serde(default)]
     pub model: String,
     pub messages: Vec<Value>,
     pub max_tokens: i32,
@@ -42,337 +86,2923 @@ pub struct AnthropicMessageRequest {
     pub top_k: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub system: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
     #[serde(default)]
     pub stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub thinking: Option<ThinkingParameter>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tools: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<Value>,
+    /// Code execution container to reuse
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mcp_servers: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    /// Capacity tier, e.g. "auto" or "standard_only"; kept as a string so new tiers pass
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+    /// Proxy-side streaming options; never forwarded upstream
+    #[serde(default, skip_serializing)]
+    pub stream_options: Option<StreamOptions>,
+    /// Fields the proxy doesn't know, forwarded as sent unless validation is strict
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// OpenAI-style `stream_options`, handled by the proxy
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamOptions {
+    /// Send a final `usage` event with the request's token usage once the stream ends
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+/// How a streamed response is delivered to the client
+#[derive(Debug, Clone, Copy)]
+struct StreamOutput {
+    format: StreamFormat,
+    include_usage: bool,
+    sampled: bool,
+}
+
+/// Per-request handling decided before a Messages request is processed
+struct RequestOptions {
+    /// Verbose logging asked for with X-Maximize-Debug
+    debug: bool,
+    /// Progress of the request is logged at info rather than debug (see `sampled_info!`)
+    sampled: bool,
+    /// Format for a streamed response
+    format: StreamFormat,
+    canary: Option<CanaryArm>,
+    experiment: Option<ExperimentArm>,
+    /// Model as the client named it; None when the default model was substituted
+    requested_model: Option<String>,
+    /// Preset system prompt to prepend
+    preset: Option<String>,
+}
+
+/// Log at info for requests picked by the log sampler, at debug for the rest
+macro_rules! sampled_info {
+    ($sampled:expr, $($arg:tt)+) => {
+        if $sampled {
+            info!($($arg)+)
+        } else {
+            debug!($($arg)+)
+        }
+    };
+}
+
+/// Header carrying the request ID: honored when sent by the client, generated otherwise,
+/// forwarded upstream and echoed on every response
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+fn generate_request_id() -> String {
+    Uuid::new_v4().to_string()[..8].to_string()
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+/// The request ID assigned by `request_id_layer`
+fn request_id_from(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|id| id.to_string())
+        .unwrap_or_else(generate_request_id)
+}
+
+/// Assign each request an ID, run it inside a span carrying that ID so every log line
+/// includes it, and return it as a response header on success and error alike
+async fn request_id_layer(mut request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(|id| id.to_string())
+        .unwrap_or_else(generate_request_id);
+    let value = HeaderValue::from_str(&request_id).expect("request IDs are visible ASCII");
+    request.headers_mut().insert(REQUEST_ID_HEADER, value.clone());
+
+    let span = tracing::info_span!("request", id = %request_id);
+    let mut response = next.run(request).instrument(span).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
+
+/// Header requesting verbose logging for a single request
+const DEBUG_HEADER: &str = "x-maximize-debug";
+
+/// Header requesting a different admission priority for a single request
+const PRIORITY_HEADER: &str = "x-maximize-priority";
+
+/// Header bounding the output tokens of a single request below its body's values
+const MAX_OUTPUT_HEADER: &str = "x-maximize-max-output";
+
+/// Header setting the upstream timeout of a single request, in seconds
+const TIMEOUT_HEADER: &str = "x-maximize-timeout";
+
+/// Timeout the Anthropic SDKs declare on every request, in seconds
+const STAINLESS_TIMEOUT_HEADER: &str = "x-stainless-timeout";
+
+/// Deadline of this request's upstream calls: the client's timeout capped at
+/// `api.max_request_timeout`, else `api.request_timeout`. None means no limit.
+fn upstream_deadline(
+    settings: &Settings,
+    headers: &HeaderMap,
+    stream: bool,
+    start_time: Instant,
+    request_id: &str,
+) -> Option<UpstreamDeadline> {
+    let requested = [TIMEOUT_HEADER, STAINLESS_TIMEOUT_HEADER].into_iter().find_map(|name| {
+        let value = headers.get(name)?.to_str().ok()?;
+        let seconds = value.trim().parse::<f64>().ok().filter(|s| s.is_finite() && *s > 0.0);
+        if seconds.is_none() {
+            warn!("[{}] Ignoring invalid {} value '{}'", request_id, name, value);
+        }
+        seconds
+    });
+    let Some(seconds) = requested else {
+        return (settings.request_timeout > 0).then(|| UpstreamDeadline {
+            at: tokio::time::Instant::now() + Duration::from_secs(settings.request_timeout),
+            timeout: Duration::from_secs(settings.request_timeout),
+            client_gone: false,
+        });
+    };
+
+    let max = settings.max_request_timeout as f64;
+    let capped = max > 0.0 && seconds > max;
+    if capped {
+        debug!("[{}] Capping requested timeout of {}s at {}s", request_id, seconds, max);
+    }
+    let timeout = Duration::from_secs_f64(if capped { max } else { seconds });
+    // The client's clock started when it sent the request, queueing included
+    Some(UpstreamDeadline {
+        at: tokio::time::Instant::from_std(start_time) + timeout,
+        timeout,
+        client_gone: !stream && !capped,
+    })
+}
+
+/// Point by which a request's upstream calls, retries included, must have responded
+#[derive(Debug, Clone, Copy)]
+struct UpstreamDeadline {
+    at: tokio::time::Instant,
+    timeout: Duration,
+    /// The client stops waiting at this point too, so a call still running is cancelled
+    /// rather than answered with a timeout
+    client_gone: bool,
+}
+
+/// An upstream call still running at its request's deadline
+#[derive(Debug, thiserror::Error)]
+enum UpstreamTimeout {
+    #[error("Upstream did not respond within the {}s timeout", .0.as_secs_f64())]
+    Elapsed(Duration),
+    #[error("The client's {}s timeout passed; upstream call cancelled", .0.as_secs_f64())]
+    ClientGaveUp(Duration),
+}
+
+/// Run an upstream call, giving up at the deadline if there is one
+async fn before_deadline<T>(deadline: Option<UpstreamDeadline>, call: impl std::future::Future<Output = T>) -> Result<T, UpstreamTimeout> {
+    let Some(deadline) = deadline else {
+        return Ok(call.await);
+    };
+    tokio::time::timeout_at(deadline.at, call).await.map_err(|_| {
+        if deadline.client_gone {
+            UpstreamTimeout::ClientGaveUp(deadline.timeout)
+        } else {
+            UpstreamTimeout::Elapsed(deadline.timeout)
+        }
+    })
+}
+
+/// Output token ceiling requested by the client for this call, if any
+fn requested_max_output(headers: &HeaderMap) -> Option<i32> {
+    let value = headers.get(MAX_OUTPUT_HEADER)?.to_str().ok()?;
+    let max_output = value.trim().parse().ok().filter(|n: &i32| *n > 0);
+    if max_output.is_none() {
+        warn!("Ignoring invalid {} value '{}'", MAX_OUTPUT_HEADER, value);
+    }
+    max_output
+}
+
+/// Lower max_tokens to the ceiling, shrinking the thinking budget so at least 1024
+/// response tokens remain. Thinking is turned off when its minimum budget no longer fits.
+fn clamp_output(request: &mut AnthropicMessageRequest, max_output: i32, request_id: &str) {
+    if request.max_tokens > max_output {
+        debug!("[{}] Clamping max_tokens from {} to {}", request_id, request.max_tokens, max_output);
+        request.max_tokens = max_output;
+    }
+    let Some(thinking) = request.thinking.as_mut().filter(|t| t.type_ == "enabled") else {
+        return;
+    };
+    let budget = max_output - 1024;
+    if budget < 1024 {
+        info!("[{}] Disabling thinking: {} output tokens leave no room for its budget", request_id, max_output);
+        request.thinking = None;
+    } else if thinking.budget_tokens > budget {
+        debug!("[{}] Clamping thinking budget from {} to {}", request_id, thinking.budget_tokens, budget);
+        thinking.budget_tokens = budget;
+    }
+}
+
+/// Priority class requested by the client for this call, if any
+fn requested_priority(headers: &HeaderMap) -> Option<Priority> {
+    let value = headers.get(PRIORITY_HEADER)?.to_str().ok()?;
+    let priority = Priority::parse(value);
+    if priority.is_none() {
+        warn!("Ignoring invalid {} value '{}'", PRIORITY_HEADER, value);
+    }
+    priority
+}
+
+/// Whether the client asked for per-request debugging and its key is allowed to
+fn debug_requested(state: &AppState, headers: &HeaderMap) -> bool {
+    let requested = headers
+        .get(DEBUG_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false);
+    if !requested {
+        return false;
+    }
+
+    let key_id = extract_client_key(headers).map(key_fingerprint);
+    let allowed = key_id
+        .as_deref()
+        .is_some_and(|id| state.settings.debug_keys.iter().any(|k| k == id));
+    if !allowed {
+        warn!("Ignoring {} from a key not listed in logging.debug_keys", DEBUG_HEADER);
+    }
+    allowed
+}
+
+/// Header selecting which configured OAuth account serves a request
+const ACCOUNT_HEADER: &str = "x-maximize-account";
+
+/// OAuth identity serving a request: a tenant's own account, the account named in the
+/// account header if the client key may select it, otherwise the pool account the
+/// request's `affinity` key maps to, or the main token file
+fn select_account(
+    state: &AppState,
+    headers: &HeaderMap,
+    key_id: Option<&str>,
+    affinity: Option<&str>,
+) -> Result<Arc<OAuthManager>, ProxyError> {
+    let name = match headers.get(ACCOUNT_HEADER) {
+        Some(value) => value
+            .to_str()
+            .map(str::trim)
+            .map_err(|_| ProxyError::InvalidRequest(format!("Invalid {} header", ACCOUNT_HEADER)))?,
+        None => "",
+    };
+
+    if let Some(tenant) = state.tenants.for_key(key_id) {
+        if !name.is_empty() {
+            warn!("Tenant '{}' tried to select account '{}'", tenant.name, name);
+            return Err(ProxyError::Permission("Tenant keys are always served by their own account".to_string()));
+        }
+        return tenant.oauth.clone().ok_or_else(|| {
+            error!("Token store of tenant '{}' is unavailable", tenant.name);
+            ProxyError::Unavailable("This tenant's account is unavailable".to_string())
+        });
+    }
+    if name.is_empty() {
+        return Ok(pooled_account(state, affinity).unwrap_or_else(|| state.oauth_manager.clone()));
+    }
+
+    // Checked first, so keys without access can't probe which accounts exist
+    if !state.settings.account_allowed(key_id, name) {
+        warn!("Key {} may not select account '{}'", key_id.unwrap_or("(none)"), name);
+        return Err(ProxyError::Permission(format!("This API key may not use account '{}'", name)));
+    }
+    let account = state
+        .accounts
+        .get(name)
+        .cloned()
+        .ok_or_else(|| ProxyError::InvalidRequest(format!("Unknown account '{}' in {}", name, ACCOUNT_HEADER)))?;
+    debug!("Serving with account '{}'", name);
+    Ok(account)
+}
+
+/// Account of `accounts.pool` a conversation sticks to, so Anthropic's prompt cache keeps
+/// hitting instead of every turn landing on another account
+fn pooled_account(state: &AppState, affinity: Option<&str>) -> Option<Arc<OAuthManager>> {
+    let key = affinity?;
+    let pool: Vec<String> = state
+        .settings
+        .accounts
+        .pool
+        .iter()
+        .filter(|name| state.accounts.contains_key(*name))
+        .cloned()
+        .collect();
+    let name = affinity::pick_account(key, &pool)?;
+    debug!("Serving {} with pool account '{}'", key, name);
+    state.accounts.get(name).cloned()
+}
+
+/// Token managers of the accounts under `accounts.profiles`, each refreshed in the background
+fn open_accounts(settings: &Settings) -> HashMap<String, Arc<OAuthManager>> {
+    let mut accounts = HashMap::new();
+    for (name, token_file) in &settings.accounts.profiles {
+        match OAuthManager::new(token_file, settings.oauth.clone(), connection::http_client(settings)) {
+            Ok(manager) => {
+                let manager = Arc::new(manager);
+                manager.spawn_background_refresh();
+                info!("Account '{}': tokens in {}", name, token_file);
+                accounts.insert(name.clone(), manager);
+            }
+            Err(e) => error!("Account '{}' is unavailable: {}", name, e),
+        }
+    }
+    accounts
+}
+
+/// Header carrying the client's session ID in stateful mode
+const SESSION_HEADER: &str = "x-session-id";
+
+/// Session store configured in settings, or `None` when sessions are disabled
+fn open_session_store(settings: &Settings) -> Option<Arc<dyn SessionStore>> {
+    if !settings.sessions_enabled {
+        return None;
+    }
+
+    let ttl = std::time::Duration::from_secs(settings.session_ttl);
+    match settings.session_backend.as_str() {
+        #[cfg(feature = "sqlite")]
+        "sqlite" => match sessions::SqliteSessionStore::open(&settings.session_db, ttl) {
+            Ok(store) => {
+                info!("Session store: SQLite at {}", settings.session_db);
+                Some(Arc::new(store))
+            }
+            Err(e) => {
+                error!("Failed to open session database {}: {}", settings.session_db, e);
+                None
+            }
+        },
+        backend => {
+            if backend != "memory" {
+                warn!("Session backend '{}' is not available, using in-memory sessions", backend);
+            }
+            info!("Session store: in-memory");
+            Some(Arc::new(MemorySessionStore::new(ttl)))
+        }
+    }
+}
+
+/// Per-request information handed to every hook
+#[derive(Debug, Clone)]
+pub struct HookContext {
+    pub request_id: String,
+    pub headers: HeaderMap,
+    /// Set when the client asked for verbose handling of this request (X-Maximize-Debug)
+    pub debug: bool,
+    /// Canary rollout arm the request was assigned to, if its model has a rollout
+    pub canary: Option<CanaryArm>,
+    /// Prompt experiment variant the request was assigned to
+    pub experiment: Option<ExperimentArm>,
+    /// Model as the client named it, unless the default model was substituted
+    pub requested_model: Option<String>,
+    /// Model the request is sent with
+    pub model: String,
+}
+
+/// Inspect or mutate a request after the proxy's own transforms, right before it is sent upstream.
+/// Returning an error short-circuits the request and sends that error to the client.
+pub trait RequestHook: Send + Sync {
+    fn on_request(&self, ctx: &HookContext, request: &mut AnthropicMessageRequest) -> Result<(), ProxyError>;
+}
+
+/// Inspect or mutate upstream responses before they reach the client.
+/// Both methods default to passing data through untouched.
+pub trait ResponseHook: Send + Sync {
+    /// Called with the parsed JSON body of a successful non-streaming response
+    fn on_response(&self, _ctx: &HookContext, _response: &mut Value) {}
+
+    /// Called for every raw chunk of a streaming (SSE) response
+    fn on_stream_chunk(&self, _ctx: &HookContext, chunk: Bytes) -> Bytes {
+        chunk
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    pub oauth_manager: Arc<OAuthManager>,
+    /// Additional accounts clients may select per request, by name
+    pub accounts: Arc<HashMap<String, Arc<OAuthManager>>>,
+    pub tenants: Arc<Tenants>,
+    pub settings: Arc<Settings>,
+    /// Accepted client API keys; empty disables API key authentication
+    pub api_keys: Arc<Vec<String>>,
+    pub request_hooks: Vec<Arc<dyn RequestHook>>,
+    pub response_hooks: Vec<Arc<dyn ResponseHook>>,
+    pub moderators: Vec<Arc<dyn Moderator>>,
+    pub events: EventBus,
+    pub history: Arc<RequestHistory>,
+    pub sessions: Option<Arc<dyn SessionStore>>,
+    pub redactor: Arc<Redactor>,
+    pub log_sampler: Arc<LogSampler>,
+    pub guardrails: Arc<Guardrails>,
+    pub response_scrubber: Arc<ResponseScrubber>,
+    pub metrics: Arc<Metrics>,
+    pub stats: Arc<RollingStats>,
+    pub quotas: Arc<QuotaTracker>,
+    pub admission: Arc<AdmissionQueue>,
+    /// Limits and remaining capacity of each OAuth account, from its responses
+    pub rate_limits: Arc<RateLimitTracker>,
+    pub usage: Arc<UsageTracker>,
+    pub usage_log: Arc<UsageLog>,
+    /// Bedrock upstream, when AWS credentials are configured
+    pub bedrock: Option<Arc<BedrockClient>>,
+    /// Vertex AI upstream, when a Google Cloud project is configured
+    pub vertex: Option<Arc<VertexClient>>,
+    pub upstream_health: Arc<UpstreamHealth>,
+    /// Client for the upstreams, with the configured connection settings
+    pub http: reqwest::Client,
+    /// Address the server listens on, once bound
+    pub local_addr: Option<SocketAddr>,
+}
+
+impl AppState {
+    pub fn new(oauth_manager: Arc<OAuthManager>, settings: Arc<Settings>) -> Self {
+        let http = connection::http_client(&settings);
+        let mut state = Self {
+            events: oauth_manager.events().clone(),
+            oauth_manager,
+            accounts: Arc::new(open_accounts(&settings)),
+            tenants: Arc::new(Tenants::open(&settings)),
+            api_keys: Arc::new(settings.api_keys.clone()),
+            settings: settings.clone(),
+            request_hooks: Vec::new(),
+            response_hooks: Vec::new(),
+            moderators: Vec::new(),
+            history: Arc::new(RequestHistory::new(settings.history_size)),
+            sessions: open_session_store(&settings),
+            redactor: Arc::new(Redactor::new(&settings.redaction_patterns, settings.redact_defaults)),
+            log_sampler: Arc::new(LogSampler::new(settings.log_sample_every)),
+            guardrails: Arc::new(Guardrails::new(
+                &settings.guardrails.patterns,
+                &settings.guardrails.keywords,
+                settings.guardrails.detect_secrets,
+                settings.guardrails.max_secrets as usize,
+                settings.guardrails.action,
+            )),
+            response_scrubber: Arc::new(ResponseScrubber::new(&settings.guardrails.response_patterns)),
+            metrics: Arc::new(Metrics::new()),
+            stats: Arc::new(RollingStats::new()),
+            quotas: Arc::new(QuotaTracker::new(&tenants::quotas_with_tenants(&settings))),
+            usage: Arc::new(UsageTracker::new()),
+            usage_log: Arc::new(UsageLog::for_token_file(std::path::Path::new(&settings.token_file))),
+            bedrock: BedrockClient::new(&settings.bedrock, http.clone()).map(Arc::new),
+            vertex: VertexClient::new(&settings.vertex, http.clone()).map(Arc::new),
+            upstream_health: Arc::new(UpstreamHealth::new(&settings.routing.health)),
+            admission: AdmissionQueue::new(
+                settings.max_concurrent_requests,
+                settings.max_queue_depth,
+                settings.max_queue_wait,
+            ),
+            rate_limits: Arc::new(RateLimitTracker::new()),
+            http,
+            local_addr: None,
+        };
+
+        // Count token refreshes for /metrics; AppState is always built inside the server's runtime
+        tokio::spawn(state.metrics.clone().track_events(state.events.subscribe()));
+
+        if let Some(path) = &settings.transform_script {
+            let script = Arc::new(ScriptTransform::new(path));
+            state = state
+                .with_request_hook(script.clone())
+                .with_response_hook(script);
+        }
+
+        if !settings.moderation.rules.is_empty() {
+            state = state.with_moderator(Arc::new(RuleModerator::new(&settings.moderation.rules)));
+        }
+        if let Some(url) = &settings.moderation.url {
+            let timeout = Duration::from_millis(settings.moderation.timeout_ms);
+            state = state.with_moderator(Arc::new(HttpModerator::new(url, timeout, settings.moderation.fail_open)));
+        }
+
+        for path in &settings.wasm_filters {
+            #[cfg(feature = "wasm")]
+            match crate::wasm_filter::WasmFilter::load(path) {
+                Ok(filter) => {
+                    info!("Loaded WASM filter: {}", path);
+                    let filter = Arc::new(filter);
+                    state = state
+                        .with_request_hook(filter.clone())
+                        .with_response_hook(filter);
+                }
+                Err(e) => error!("Skipping WASM filter: {:#}", e),
+            }
+
+            #[cfg(not(feature = "wasm"))]
+            warn!("Ignoring WASM filter '{}': built without the 'wasm' feature", path);
+        }
+
+        state
+    }
+
+    /// Tenant of the calling client key, if it belongs to one
+    pub fn tenant_of(&self, headers: &HeaderMap) -> Option<&Arc<Tenant>> {
+        self.tenants.for_key(extract_client_key(headers).map(key_fingerprint).as_deref())
+    }
+
+    /// Count a completed request's tokens in metrics, the key's and its tenant's quotas and
+    /// its /usage totals
+    fn account_usage(&self, request_id: &str, key_id: Option<&str>, model: &str, usage: &TokenUsage) {
+        self.metrics.record_usage(model, usage);
+        self.usage.record(key_id, usage);
+        let tokens = usage.input_tokens + usage.output_tokens;
+        if let Some(key_id) = key_id {
+            self.quotas.record(key_id, tokens);
+        }
+        let tenant = self.tenants.for_key(key_id);
+        if let Some(tenant) = tenant {
+            self.quotas.record(&Tenant::quota_id(&tenant.name), tokens);
+            self.usage.record_bucket(&tenant.usage_bucket, usage);
+        }
+        self.usage_log.record(&UsageRecord {
+            timestamp: chrono::Utc::now().timestamp(),
+            request_id: request_id.to_string(),
+            key_id: key_id.map(str::to_string),
+            tenant: tenant.map(|tenant| tenant.usage_bucket.clone()),
+            model: model.to_string(),
+            usage: *usage,
+            cost: self.settings.price(model).map(|price| price.cost(usage)),
+        });
+    }
+
+    /// Record the address the server was bound to, which differs from the settings with port 0
+    pub fn with_local_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// Register a hook that runs on every request before it is forwarded
+    pub fn with_request_hook(mut self, hook: Arc<dyn RequestHook>) -> Self {
+        self.request_hooks.push(hook);
+        self
+    }
+
+    /// Register a hook that runs on every upstream response
+    pub fn with_response_hook(mut self, hook: Arc<dyn ResponseHook>) -> Self {
+        self.response_hooks.push(hook);
+        self
+    }
+
+    /// Register a moderator consulted on every request before it is processed
+    pub fn with_moderator(mut self, moderator: Arc<dyn Moderator>) -> Self {
+        self.moderators.push(moderator);
+        self
+    }
+}
+
+fn log_request(request_id: &str, request_data: &AnthropicMessageRequest, headers: &HeaderMap) {
+    debug!("[{}] RAW REQUEST CAPTURE", request_id);
+    debug!("[{}] Endpoint: /v1/messages", request_id);
+    debug!("[{}] Model: {}", request_id, request_data.model);
+    debug!("[{}] Stream: {}", request_id, request_data.stream);
+    debug!("[{}] Max Tokens: {}", request_id, request_data.max_tokens);
+
+    if let Some(beta) = headers.get("anthropic-beta") {
+        if let Ok(v) = beta.to_str() {
+            debug!("[{}] *** ANTHROPIC-BETA HEADER FOUND: {} ***", request_id, v);
+        }
+    }
+
+    if let Some(thinking) = &request_data.thinking {
+        debug!("[{}] THINKING FIELDS DETECTED: {:?}", request_id, thinking);
+    }
+}
+
+fn sanitize_anthropic_request(
+    mut request_data: AnthropicMessageRequest,
+    thinking_policy: Option<&ThinkingPolicy>,
+    rules: &[SanitizeRule],
+) -> AnthropicMessageRequest {
+    // Operator-configured thinking policy overrides what the client asked for
+    if let Some(policy) = thinking_policy {
+        match policy.mode {
+            ThinkingMode::Force => {
+                let enabled = request_data
+                    .thinking
+                    .as_ref()
+                    .map(|t| t.type_ == "enabled")
+                    .unwrap_or(false);
+                if !enabled {
+                    let budget_tokens = policy.budget_tokens.unwrap_or_else(default_budget);
+                    debug!("Forcing thinking on with budget {} (policy)", budget_tokens);
+                    request_data.thinking = Some(ThinkingParameter {
+                        type_: "enabled".to_string(),
+                        budget_tokens,
+                    });
+                }
+            }
+            ThinkingMode::Deny => {
+                if request_data.thinking.take().is_some() {
+                    debug!("Removing thinking parameter (policy)");
+                }
+            }
+        }
+    }
+
+    // Configured rules: clamping, dropping and thinking-dependent adjustments
+    let stream_options = request_data.stream_options.take();
+    let Ok(Value::Object(mut map)) = serde_json::to_value(&request_data) else {
+        return request_data;
+    };
+    sanitize::apply(&mut map, rules);
+    match serde_json::from_value::<AnthropicMessageRequest>(Value::Object(map)) {
+        Ok(sanitized) => request_data = sanitized,
+        Err(e) => warn!("Sanitization rules produced an invalid request, sending it unsanitized: {}", e),
+    }
+    request_data.stream_options = stream_options;
+
+    request_data
+}
+
+/// System prompt the OAuth token is only accepted with
+const CLAUDE_CODE_SYSTEM_PROMPT: &str = "You are Claude Code, Anthropic's official CLI for Claude.";
+
+fn inject_claude_code_system_message(mut request_data: AnthropicMessageRequest) -> AnthropicMessageRequest {
+    request_data.system = Some(with_claude_code_system(request_data.system.take()));
+
+    debug!("Injected Claude Code system message array for Anthropic authentication bypass");
+    if let Some(Value::Array(arr)) = &request_data.system {
+        debug!("Final system message array length: {}", arr.len());
+    }
+
+    request_data
+}
+
+/// A system prompt with the Claude Code block spliced in front
+fn with_claude_code_system(system: Option<Value>) -> Value {
+    let claude_code_spoof_element = json!({
+        "type": "text",
+        "text": CLAUDE_CODE_SYSTEM_PROMPT,
+        "cache_control": {"type": "ephemeral"}
+    });
+
+    match system {
+        Some(Value::Array(arr)) => {
+            let mut new_arr = vec![claude_code_spoof_element];
+            new_arr.extend(arr);
+            Value::Array(new_arr)
+        }
+        Some(Value::String(s)) => {
+            let existing_element = json!({
+                "type": "text",
+                "text": s,
+                "cache_control": {"type": "ephemeral"}
+            });
+            Value::Array(vec![claude_code_spoof_element, existing_element])
+        }
+        // Anything else is left for the upstream to reject
+        Some(other) => other,
+        None => Value::Array(vec![claude_code_spoof_element]),
+    }
+}
+
+/// Whether a system prompt already starts with the Claude Code block, as Claude Code's own are
+fn has_claude_code_system(system: Option<&Value>) -> bool {
+    system
+        .and_then(|s| s.as_array())
+        .and_then(|blocks| blocks.first())
+        .and_then(|b| b.get("text"))
+        .and_then(|t| t.as_str())
+        == Some(CLAUDE_CODE_SYSTEM_PROMPT)
+}
+
+/// Put a text block in front of the request's system prompt
+fn prepend_system_text(request_data: &mut AnthropicMessageRequest, text: &str) {
+    let block = json!({"type": "text", "text": text});
+    request_data.system = Some(match request_data.system.take() {
+        Some(Value::Array(mut blocks)) => {
+            blocks.insert(0, block);
+            Value::Array(blocks)
+        }
+        Some(Value::String(existing)) => Value::Array(vec![block, json!({"type": "text", "text": existing})]),
+        _ => Value::Array(vec![block]),
+    });
+}
+
+/// Undo `inject_claude_code_system_message` for a request that goes elsewhere after all
+fn strip_claude_code_system_message(mut request_data: AnthropicMessageRequest) -> AnthropicMessageRequest {
+    let spoofed = has_claude_code_system(request_data.system.as_ref());
+    if let Some(Value::Array(blocks)) = &mut request_data.system {
+        if spoofed {
+            blocks.remove(0);
+        }
+        if blocks.is_empty() {
+            request_data.system = None;
+        }
+    }
+    request_data
+}
+
+/// Whether any message (or nested tool result) contains a block of the given type
+fn has_content_block(request: &AnthropicMessageRequest, block_type: &str) -> bool {
+    fn search(blocks: &[Value], block_type: &str) -> bool {
+        blocks.iter().any(|block| {
+            block.get("type").and_then(|t| t.as_str()) == Some(block_type)
+                || block
+                    .get("content")
+                    .and_then(|c| c.as_array())
+                    .is_some_and(|nested| search(nested, block_type))
+        })
+    }
+
+    request
+        .messages
+        .iter()
+        .filter_map(|m| m.get("content").and_then(|c| c.as_array()))
+        .any(|blocks| search(blocks, block_type))
+}
+
+/// Beta flags the request's content needs, so clients don't have to send them
+fn content_betas(request: &AnthropicMessageRequest) -> Vec<&'static str> {
+    let mut betas = Vec::new();
+
+    if has_content_block(request, "document") {
+        betas.push("pdfs-2024-09-25");
+    }
+    // Files uploaded for code execution
+    if has_content_block(request, "container_upload") {
+        betas.push("files-api-2025-04-14");
+    }
+    betas.extend(tools::tool_betas(request));
+
+    betas
+}
+
+const UPSTREAM_MESSAGES_URL: &str = "https://api.anthropic.com/v1/messages?beta=true";
+const API_KEY_MESSAGES_URL: &str = "https://api.anthropic.com/v1/messages";
+
+/// Headers sent with every upstream Messages request, in order
+fn upstream_headers(
+    content_betas: Vec<&'static str>,
+    access_token: &str,
+    client_beta_headers: Option<&str>,
+    request_id: &str,
+) -> Vec<(&'static str, String)> {
+    let mut required_betas = vec![
+        "claude-code-20250219",
+        "oauth-2025-04-20",
+        "fine-grained-tool-streaming-2025-05-14",
+    ];
+    required_betas.extend(content_betas);
+
+    let all_betas = if let Some(client_betas) = client_beta_headers {
+        let client_beta_list: Vec<&str> = client_betas.split(',').map(|s| s.trim()).collect();
+        let mut combined: Vec<&str> = required_betas.clone();
+        combined.extend(client_beta_list);
+        combined.sort();
+        combined.dedup();
+        combined
+    } else {
+        required_betas
+    };
+
+    let beta_header_value = all_betas.join(",");
+
+    vec![
+        ("host", "api.anthropic.com".to_string()),
+        ("Accept", "application/json".to_string()),
+        ("X-Stainless-Retry-Count", "0".to_string()),
+        ("X-Stainless-Timeout", "600".to_string()),
+        ("X-Stainless-Lang", "js".to_string()),
+        ("X-Stainless-Package-Version", "0.60.0".to_string()),
+        ("X-Stainless-OS", "Windows".to_string()),
+        ("X-Stainless-Arch", "x64".to_string()),
+        ("X-Stainless-Runtime", "node".to_string()),
+        ("X-Stainless-Runtime-Version", "v22.19.0".to_string()),
+        ("anthropic-dangerous-direct-browser-access", "true".to_string()),
+        ("anthropic-version", "2023-06-01".to_string()),
+        ("authorization", format!("Bearer {}", access_token)),
+        ("x-app", "cli".to_string()),
+        ("User-Agent", "claude-cli/1.0.113 (external, cli)".to_string()),
+        ("content-type", "application/json".to_string()),
+        ("anthropic-beta", beta_header_value),
+        ("x-stainless-helper-method", "stream".to_string()),
+        ("accept-language", "*".to_string()),
+        ("sec-fetch-mode", "cors".to_string()),
+        (REQUEST_ID_HEADER, request_id.to_string()),
+    ]
+}
+
+/// How a prepared request reaches its upstream
+enum UpstreamAuth {
+    /// Anthropic's API with this OAuth access token
+    OAuth(String),
+    /// Anthropic's API with this standard API key
+    ApiKey(String),
+    Bedrock(Arc<BedrockClient>),
+    Vertex(Arc<VertexClient>),
+}
+
+impl UpstreamAuth {
+    fn kind(&self) -> UpstreamKind {
+        match self {
+            UpstreamAuth::OAuth(_) => UpstreamKind::Anthropic,
+            UpstreamAuth::ApiKey(_) => UpstreamKind::ApiKey,
+            UpstreamAuth::Bedrock(_) => UpstreamKind::Bedrock,
+            UpstreamAuth::Vertex(_) => UpstreamKind::Vertex,
+        }
+    }
+}
+
+/// Send a request upstream, recording the outcome for health-based routing
+async fn send_upstream(
+    state: &AppState,
+    auth: &UpstreamAuth,
+    request_data: &AnthropicMessageRequest,
+    client_beta_headers: Option<&str>,
+    request_id: &str,
+    deadline: Option<UpstreamDeadline>,
+) -> anyhow::Result<UpstreamResponse> {
+    let result = before_deadline(deadline, send_with_auth(&state.http, auth, request_data, client_beta_headers, request_id))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result);
+    let success = result.as_ref().is_ok_and(|response| !routing::is_failure(response.status()));
+    state.upstream_health.record(auth.kind(), success);
+    result
 }
 
-#[derive(Clone)]
-pub struct AppState {
-    pub oauth_manager: Arc<OAuthManager>,
-    pub settings: Arc<Settings>,
-    pub api_key: Option<String>,
-}
+/// Send a passthrough body to the OAuth upstream unchanged, recording the outcome like
+/// `send_upstream`
+async fn send_raw_upstream(
+    state: &AppState,
+    body: Bytes,
+    access_token: &str,
+    client_beta_headers: Option<&str>,
+    request_id: &str,
+    deadline: Option<UpstreamDeadline>,
+) -> anyhow::Result<UpstreamResponse> {
+    let result = send_raw(state, body, access_token, client_beta_headers, request_id, deadline).await;
+    let success = result.as_ref().is_ok_and(|response| !routing::is_failure(response.status()));
+    state.upstream_health.record(UpstreamKind::Anthropic, success);
+    result
+}
+
+/// POST a passthrough body to the OAuth upstream. Betas the content needs aren't inferred,
+/// as the body isn't parsed.
+async fn send_raw(
+    state: &AppState,
+    body: impl Into<reqwest::Body>,
+    access_token: &str,
+    client_beta_headers: Option<&str>,
+    request_id: &str,
+    deadline: Option<UpstreamDeadline>,
+) -> anyhow::Result<UpstreamResponse> {
+    let mut builder = state.http.post(UPSTREAM_MESSAGES_URL).body(body);
+    for (name, value) in upstream_headers(Vec::new(), access_token, client_beta_headers, request_id) {
+        builder = builder.header(name, value);
+    }
+    before_deadline(deadline, builder.send())
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| Ok(UpstreamResponse::anthropic(result?)))
+}
+
+async fn send_with_auth(
+    http: &reqwest::Client,
+    auth: &UpstreamAuth,
+    request_data: &AnthropicMessageRequest,
+    client_beta_headers: Option<&str>,
+    request_id: &str,
+) -> anyhow::Result<UpstreamResponse> {
+    match auth {
+        UpstreamAuth::OAuth(access_token) => {
+            let response = make_anthropic_request(http, request_data, access_token, client_beta_headers, request_id).await?;
+            Ok(UpstreamResponse::anthropic(response))
+        }
+        UpstreamAuth::ApiKey(api_key) => {
+            let mut builder = http.post(API_KEY_MESSAGES_URL);
+            for (name, value) in api_key_headers(request_data, api_key, client_beta_headers, request_id) {
+                builder = builder.header(name, value);
+            }
+            Ok(UpstreamResponse::anthropic(builder.json(request_data).send().await?))
+        }
+        UpstreamAuth::Bedrock(client) => Ok(client.send(request_data, &standard_betas(request_data, client_beta_headers)).await?),
+        UpstreamAuth::Vertex(client) => client.send(request_data, &standard_betas(request_data, client_beta_headers)).await,
+    }
+}
+
+/// Headers sent with Messages requests authenticated by a standard API key
+fn api_key_headers(
+    request_data: &AnthropicMessageRequest,
+    api_key: &str,
+    client_beta_headers: Option<&str>,
+    request_id: &str,
+) -> Vec<(&'static str, String)> {
+    let mut headers = vec![
+        ("x-api-key", api_key.to_string()),
+        ("anthropic-version", "2023-06-01".to_string()),
+        ("content-type", "application/json".to_string()),
+        (REQUEST_ID_HEADER, request_id.to_string()),
+    ];
+    let betas = standard_betas(request_data, client_beta_headers);
+    if !betas.is_empty() {
+        headers.push(("anthropic-beta", betas.join(",")));
+    }
+    headers
+}
+
+/// Betas for upstreams not using the OAuth token: the client's and those the content
+/// needs; the OAuth ones don't apply
+fn standard_betas<'a>(request_data: &AnthropicMessageRequest, client_beta_headers: Option<&'a str>) -> Vec<&'a str> {
+    let mut betas = content_betas(request_data);
+    betas.extend(client_beta_headers.into_iter().flat_map(|b| b.split(',')).map(str::trim));
+    betas.sort();
+    betas.dedup();
+    betas
+}
+
+async fn make_anthropic_request(
+    http: &reqwest::Client,
+    request_data: &AnthropicMessageRequest,
+    access_token: &str,
+    client_beta_headers: Option<&str>,
+    request_id: &str,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let mut builder = http.post(UPSTREAM_MESSAGES_URL).json(request_data);
+    for (name, value) in upstream_headers(content_betas(request_data), access_token, client_beta_headers, request_id) {
+        builder = builder.header(name, value);
+    }
+    builder.send().await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessagesQuery {
+    /// "ndjson" to stream newline-delimited JSON instead of server-sent events
+    pub format: Option<String>,
+}
+
+/// Streaming format asked for by the client, via `?format=ndjson` or an NDJSON Accept header
+fn stream_format(query: &MessagesQuery, headers: &HeaderMap) -> StreamFormat {
+    let accepts_ndjson = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains(StreamFormat::Ndjson.content_type()));
+    if query.format.as_deref() == Some("ndjson") || accepts_ndjson {
+        StreamFormat::Ndjson
+    } else {
+        StreamFormat::Sse
+    }
+}
+
+/// Placeholder shown instead of the OAuth access token in previews
+const TOKEN_PLACEHOLDER: &str = "$ACCESS_TOKEN";
+/// Placeholder shown instead of the standard API key in previews
+const API_KEY_PLACEHOLDER: &str = "$ANTHROPIC_API_KEY";
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewQuery {
+    /// "json" (default) or "curl" for just the curl command as plain text
+    pub format: Option<String>,
+}
+
+/// Quote a value for a POSIX shell
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Equivalent curl command for an upstream request. Credential placeholders are left
+/// outside single quotes so the shell expands them from the environment.
+fn curl_command(url: &str, headers: &[(&'static str, String)], body: &str) -> String {
+    let mut lines = vec![format!("curl {}", shell_quote(url))];
+    for (name, value) in headers {
+        let header = format!("{}: {}", name, value);
+        if value.contains(TOKEN_PLACEHOLDER) || value.contains(API_KEY_PLACEHOLDER) {
+            lines.push(format!("-H \"{}\"", header));
+        } else {
+            lines.push(format!("-H {}", shell_quote(&header)));
+        }
+    }
+    lines.push(format!("--data-raw {}", shell_quote(body)));
+    lines.join(" \\\n  ")
+}
+
+/// Run the transformation pipeline on a request and return exactly what would be sent
+/// upstream (URL, headers, body and an equivalent curl command), without sending it.
+/// Only Anthropic's API can be previewed: Bedrock and Vertex requests are signed and
+/// reshaped by their clients.
+pub async fn preview_request(
+    State(state): State<AppState>,
+    Query(query): Query<PreviewQuery>,
+    headers: HeaderMap,
+    Json(mut request): Json<AnthropicMessageRequest>,
+) -> Result<Response, ProxyError> {
+    let request_id = request_id_from(&headers);
+    let key_id = extract_client_key(&headers).map(key_fingerprint);
+    let tenant = state.tenants.for_key(key_id.as_deref());
+    let substituted = apply_default_model(&state.settings, tenant.map(Arc::as_ref), &mut request.model, &request_id);
+    let options = RequestOptions {
+        debug: false,
+        sampled: false,
+        format: StreamFormat::Sse,
+        canary: state.settings.canary_arm(&request.model),
+        experiment: state.settings.experiment_arm(),
+        requested_model: (!substituted).then(|| request.model.clone()),
+        preset: state
+            .settings
+            .preset_name(None, key_id.as_deref())
+            .map(str::to_string),
+    };
+    let prepared = prepare_request(&state, &headers, request, &request_id, &options)?;
+
+    let beta_header = prepared.beta_header.as_deref();
+    let (url, upstream) = match prepared.upstream {
+        UpstreamKind::Anthropic => (
+            UPSTREAM_MESSAGES_URL,
+            upstream_headers(content_betas(&prepared.request), TOKEN_PLACEHOLDER, beta_header, &request_id),
+        ),
+        UpstreamKind::ApiKey => (
+            API_KEY_MESSAGES_URL,
+            api_key_headers(&prepared.request, API_KEY_PLACEHOLDER, beta_header, &request_id),
+        ),
+        UpstreamKind::Bedrock | UpstreamKind::Vertex => {
+            return Err(ProxyError::InvalidRequest(format!(
+                "Requests routed to {} can't be previewed; only the anthropic and api_key upstreams can",
+                prepared.upstream.name()
+            )));
+        }
+    };
+    let body = serde_json::to_string(&prepared.request).unwrap_or_default();
+    let curl = curl_command(url, &upstream, &body);
+
+    if query.format.as_deref() == Some("curl") {
+        return Ok(([(axum::http::header::CONTENT_TYPE, "text/plain")], curl + "\n").into_response());
+    }
+
+    let header_map: serde_json::Map<String, Value> = upstream
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), Value::String(value)))
+        .collect();
+
+    Ok(Json(json!({
+        "upstream": prepared.upstream.name(),
+        "method": "POST",
+        "url": url,
+        "headers": header_map,
+        "body": prepared.request,
+        "curl": curl,
+    }))
+    .into_response())
+}
+
+/// Send a minimal one-token request upstream to confirm the token and request path work.
+/// Returns the upstream status and, on failure, the response body.
+pub async fn probe_upstream(http: &reqwest::Client, model: &str, access_token: &str) -> Result<(reqwest::StatusCode, String), reqwest::Error> {
+    let request: AnthropicMessageRequest = serde_json::from_value(json!({
+        "model": model,
+        "max_tokens": 1,
+        "messages": [{"role": "user", "content": "ping"}]
+    }))
+    .expect("static probe request");
+    let request = inject_claude_code_system_message(request);
+
+    let response = make_anthropic_request(http, &request, access_token, None, &generate_request_id()).await?;
+    let status = response.status();
+    let body = if status.is_success() { String::new() } else { response.text().await.unwrap_or_default() };
+    Ok((status, body))
+}
+
+pub async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
+    Json(json!({
+        "status": "ok",
+        "timestamp": chrono::Utc::now().timestamp(),
+        "port": state.local_addr.map(|addr| addr.port()),
+    }))
+}
+
+/// Bind the configured address, moving on to the next of up to `port_fallback` ports while
+/// the port is in use
+pub async fn bind_listener(settings: &Settings) -> std::io::Result<tokio::net::TcpListener> {
+    let mut port = settings.port;
+    // Port 0 never collides
+    let mut fallbacks_left = if port == 0 { 0 } else { settings.port_fallback };
+    loop {
+        match tokio::net::TcpListener::bind((settings.bind_address.as_str(), port)).await {
+            Ok(listener) => {
+                if port != settings.port {
+                    warn!("Port {} is in use, listening on port {} instead", settings.port, port);
+                }
+                return Ok(listener);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && fallbacks_left > 0 && port < u16::MAX => {
+                debug!("Port {} is in use, trying {}", port, port + 1);
+                port += 1;
+                fallbacks_left -= 1;
+            }
+            Err(e) => {
+                return Err(std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to bind {}:{}: {}", settings.bind_address, port, e),
+                ))
+            }
+        }
+    }
+}
+
+/// OpenAPI description of every route, for client generators and API gateways
+pub async fn openapi_document() -> impl IntoResponse {
+    Json(openapi::document())
+}
+
+/// Log where a bound server can be reached and write it to `server.discovery_file`, so
+/// scripts can find a server started on port 0
+pub fn announce_listener(settings: &Settings, addr: SocketAddr) {
+    // A wildcard address can't be connected to; point clients at loopback instead
+    let host = match addr.ip() {
+        ip if ip.is_unspecified() && ip.is_ipv6() => "[::1]".to_string(),
+        ip if ip.is_unspecified() => "127.0.0.1".to_string(),
+        ip if ip.is_ipv6() => format!("[{}]", ip),
+        ip => ip.to_string(),
+    };
+    let base_url = format!("http://{}:{}", host, addr.port());
+    info!("Listening on {} ({})", addr, base_url);
+
+    let Some(path) = &settings.discovery_file else {
+        return;
+    };
+    let discovery = json!({
+        "address": addr.to_string(),
+        "port": addr.port(),
+        "base_url": base_url,
+        "pid": std::process::id(),
+    });
+    match std::fs::write(path, format!("{:#}\n", discovery)) {
+        Ok(()) => info!("Wrote listening address to {}", path),
+        Err(e) => error!("Failed to write discovery file {}: {}", path, e),
+    }
+}
+
+/// Token status only: the account behind the tokens is personal data, served by the
+/// authenticated `/auth/whoami`
+pub async fn auth_status(State(state): State<AppState>) -> impl IntoResponse {
+    let status = state.oauth_manager.storage().get_status();
+    Json(status)
+}
+
+/// Account, organization and plan of the OAuth token, fetched fresh from Anthropic. The
+/// account header selects which configured account to describe.
+pub async fn auth_whoami(State(state): State<AppState>, headers: HeaderMap) -> Result<impl IntoResponse, ProxyError> {
+    let key_id = extract_client_key(&headers).map(key_fingerprint);
+    let account = select_account(&state, &headers, key_id.as_deref(), None)?;
+    let profile = match account.account_profile(true).await {
+        Ok(Some(profile)) => profile,
+        Ok(None) => return Err(ProxyError::Authentication("No valid OAuth token; log in first".to_string())),
+        Err(e) => return Err(ProxyError::BadGateway(format!("Failed to fetch account profile: {}", e))),
+    };
+
+    let identity = AccountIdentity::from_profile(&profile);
+    Ok(Json(json!({
+        "account": profile.get("account"),
+        "organization": profile.get("organization"),
+        "plan": identity.plan,
+        "rate_limit_tier": identity.rate_limit_tier,
+        "token_file": account.storage().token_file(),
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExchangeRequest {
+    /// CODE#STATE, a callback URL, or the bare code
+    pub code: String,
+    /// Required when `code` carries no state
+    pub state: Option<String>,
+}
+
+/// Complete a login by exchanging its authorization code for tokens
+pub async fn auth_exchange(
+    State(state): State<AppState>,
+    Json(body): Json<ExchangeRequest>,
+) -> Result<impl IntoResponse, ProxyError> {
+    let authorization = Authorization::parse(&body.code, body.state.as_deref())
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid authorization code: {}", e)))?;
+
+    if let Err(e) = state.oauth_manager.exchange_code(&authorization).await {
+        return Err(ProxyError::BadGateway(format!("Token exchange failed: {}", e)));
+    }
+    Ok(Json(json!(state.oauth_manager.storage().get_status())))
+}
+
+/// Metadata of the OAuth token in use: expiry, granted scopes, age, where it was loaded from
+/// and the last refresh attempt. The tokens themselves are never included.
+pub async fn auth_introspect(State(state): State<AppState>, headers: HeaderMap) -> Result<impl IntoResponse, ProxyError> {
+    let key_id = extract_client_key(&headers).map(key_fingerprint);
+    let account = select_account(&state, &headers, key_id.as_deref(), None)?;
+    let storage = account.storage();
+    let tokens = storage.load_tokens().ok().flatten();
+    let now = chrono::Utc::now().timestamp();
+    let rfc3339 = |timestamp: i64| chrono::DateTime::from_timestamp(timestamp, 0).map(|dt| dt.to_rfc3339());
+
+    let last_refresh = account
+        .refresh_audit()
+        .recent(1)
+        .unwrap_or_else(|e| {
+            warn!("Failed to read refresh audit log: {}", e);
+            Vec::new()
+        })
+        .pop();
+
+    Ok(Json(json!({
+        "has_tokens": tokens.is_some(),
+        "source": storage.source(),
+        "token_file": storage.token_file(),
+        "expires_at": tokens.as_ref().and_then(|t| rfc3339(t.expires_at)),
+        "expires_in_seconds": tokens.as_ref().map(|t| t.expires_at - now),
+        "is_expired": tokens.as_ref().is_none_or(|t| now >= t.expires_at),
+        "is_revoked": tokens.as_ref().is_some_and(|t| t.revoked_at.is_some()),
+        "revoked_at": tokens.as_ref().and_then(|t| t.revoked_at).and_then(rfc3339),
+        "obtained_at": tokens.as_ref().and_then(|t| t.obtained_at).and_then(rfc3339),
+        "age_seconds": tokens.as_ref().and_then(|t| t.obtained_at).map(|at| now - at),
+        "scopes": tokens.as_ref().and_then(|t| t.scope.as_ref()).map(|s| s.split_whitespace().collect::<Vec<_>>()),
+        "requested_scopes": state.settings.oauth.scopes.split_whitespace().collect::<Vec<_>>(),
+        "last_refresh": last_refresh.map(|entry| json!({
+            "at": rfc3339(entry.timestamp),
+            "reason": entry.reason,
+            "outcome": entry.outcome,
+            "error": entry.error,
+        })),
+    })))
+}
+
+pub async fn anthropic_messages(
+    State(state): State<AppState>,
+    Query(query): Query<MessagesQuery>,
+    headers: HeaderMap,
+    Json(request): Json<AnthropicMessageRequest>,
+) -> Result<Response, Response> {
+    handle_messages(state, query, headers, request, None, RateLimitStyle::Anthropic).await
+}
+
+/// Messages endpoint under the OpenRouter-style base path. Gateway clients there pace
+/// themselves with OpenAI-style rate limit headers.
+pub async fn gateway_messages(
+    State(state): State<AppState>,
+    Query(query): Query<MessagesQuery>,
+    headers: HeaderMap,
+    Json(request): Json<AnthropicMessageRequest>,
+) -> Result<Response, Response> {
+    handle_messages(state, query, headers, request, None, RateLimitStyle::OpenAi).await
+}
+
+/// Chat completions under the OpenRouter-style base path, naming the model in the body
+pub async fn gateway_chat_completions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Result<Response, Response> {
+    handle_chat_completions(state, headers, body, None).await
+}
+
+/// Azure OpenAI-style chat completions. The deployment name is resolved like a model name,
+/// so a tenant's `model_aliases` can name deployments; `api-version` is accepted and ignored.
+pub async fn azure_chat_completions(
+    State(state): State<AppState>,
+    Path(deployment): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Result<Response, Response> {
+    handle_chat_completions(state, headers, body, Some(deployment)).await
+}
+
+/// OpenAI chat completions, served by translating to a Messages request and translating
+/// the reply back. OpenAI clients pace themselves with OpenAI-style rate limit headers.
+async fn handle_chat_completions(
+    state: AppState,
+    mut headers: HeaderMap,
+    body: Value,
+    deployment: Option<String>,
+) -> Result<Response, Response> {
+    let request = openai::to_messages_request(&body, deployment.as_deref())
+        .map_err(|message| ProxyError::InvalidRequest(message).into_response())?;
+    // Chunks are translated from server-sent events, whatever format the client accepts
+    headers.remove(header::ACCEPT);
+    let query = MessagesQuery { format: None };
+    let response = handle_messages(state, query, headers, request, None, RateLimitStyle::OpenAi).await?;
+    Ok(chat_completion_response(response).await)
+}
+
+/// A successful Messages response as a chat completion, or its stream as completion chunks.
+/// Errors keep Anthropic's shape, whose `error.message` OpenAI clients read as well.
+async fn chat_completion_response(response: Response) -> Response {
+    if !response.status().is_success() {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let streamed = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|t| t.starts_with(StreamFormat::Sse.content_type()));
+
+    if streamed {
+        let mut incoming = body.into_data_stream();
+        let stream = async_stream::stream! {
+            let mut parser = SseParser::new();
+            let mut translator = ChunkTranslator::new();
+            while let Some(chunk) = incoming.next().await {
+                let bytes = match chunk {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                let frames: String = parser.feed(&bytes).iter().flat_map(|event| translator.translate(event)).collect();
+                if !frames.is_empty() {
+                    yield Ok(Bytes::from(frames));
+                }
+            }
+            yield Ok(Bytes::from_static(openai::DONE_FRAME.as_bytes()));
+        };
+        return Response::from_parts(parts, Body::from_stream(stream));
+    }
+
+    let message = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => serde_json::from_slice::<Value>(&bytes).unwrap_or_default(),
+        Err(e) => return ProxyError::BadGateway(format!("Failed to read response: {}", e)).into_response(),
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(openai::to_chat_completion(&message).to_string()))
+}
+
+/// Messages endpoint of a virtual route, which prepends the route's preset system prompt
+pub async fn route_messages(
+    State(state): State<AppState>,
+    Query(query): Query<MessagesQuery>,
+    Path(route): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<AnthropicMessageRequest>,
+) -> Result<Response, Response> {
+    if !state.settings.presets.routes.contains_key(&route) {
+        return Err(ProxyError::NotFound(format!("Unknown route '{}'", route)).into_response());
+    }
+    handle_messages(state, query, headers, request, Some(route), RateLimitStyle::Anthropic).await
+}
+
+/// `/v1/messages` in raw passthrough mode (`api.passthrough`)
+pub async fn passthrough_messages(
+    State(state): State<AppState>,
+    Query(query): Query<MessagesQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, Response> {
+    handle_raw_messages(state, query, headers, body, RateLimitStyle::Anthropic).await
+}
+
+/// `/api/v1/messages` in raw passthrough mode (`api.passthrough`)
+pub async fn passthrough_gateway_messages(
+    State(state): State<AppState>,
+    Query(query): Query<MessagesQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, Response> {
+    handle_raw_messages(state, query, headers, body, RateLimitStyle::OpenAi).await
+}
+
+/// Records a request as cancelled if its handler is dropped before it finished, which is
+/// how a client disconnect shows up; the upstream call in flight is dropped with it
+struct CancellationRecorder {
+    state: AppState,
+    record: Option<RequestRecord>,
+    start_time: Instant,
+}
+
+impl CancellationRecorder {
+    fn finished(mut self) {
+        self.record = None;
+    }
+}
+
+impl Drop for CancellationRecorder {
+    fn drop(&mut self) {
+        let Some(mut record) = self.record.take() else {
+            return;
+        };
+        let duration_ms = self.start_time.elapsed().as_millis() as u64;
+        let message = "Client closed the connection; upstream call cancelled".to_string();
+        info!("[{}] {} after {}ms", record.request_id, message, duration_ms);
+        record.status = CLIENT_CLOSED_REQUEST;
+        record.latency_ms = duration_ms;
+        record.error = Some(message.clone());
+        self.state.metrics.record_cancelled("disconnect");
+        self.state.events.publish(ProxyEvent::RequestFailed {
+            request_id: record.request_id.clone(),
+            status: record.status,
+            duration_ms,
+            message,
+        });
+        self.state.stats.record(duration_ms, true);
+        self.state.history.push(record);
+    }
+}
+
+async fn handle_messages(
+    state: AppState,
+    query: MessagesQuery,
+    headers: HeaderMap,
+    mut request: AnthropicMessageRequest,
+    route: Option<String>,
+    rate_limit_style: RateLimitStyle,
+) -> Result<Response, Response> {
+    let request_id = request_id_from(&headers);
+    let start_time = Instant::now();
+    let debug = debug_requested(&state, &headers);
+    let key_id = extract_client_key(&headers).map(key_fingerprint);
+    let tenant = state.tenants.for_key(key_id.as_deref());
+    let substituted = apply_default_model(&state.settings, tenant.map(Arc::as_ref), &mut request.model, &request_id);
+
+    state.events.publish(ProxyEvent::RequestStarted {
+        request_id: request_id.clone(),
+        model: request.model.clone(),
+        stream: request.stream,
+    });
+
+    let record = RequestRecord {
+        request_id: request_id.clone(),
+        timestamp: chrono::Utc::now().timestamp(),
+        model: request.model.clone(),
+        stream: request.stream,
+        key_id: key_id.clone(),
+        tenant: tenant.map(|t| t.name.clone()),
+        status: 0,
+        latency_ms: 0,
+        usage: None,
+        prompt_hash: prompt_hash(&request.messages),
+        estimated_input_tokens: tokenizer::count_request(&request),
+        debug,
+        error: None,
+        shadow_of: None,
+        canary: state.settings.canary_arm(&request.model),
+        experiment: state.settings.experiment_arm(),
+        moderation: moderate_request(&state, &request_id, &headers, &request).await,
+    };
+
+    let options = RequestOptions {
+        debug,
+        sampled: debug || state.log_sampler.sample(),
+        format: stream_format(&query, &headers),
+        canary: record.canary.clone(),
+        experiment: record.experiment.clone(),
+        requested_model: (!substituted).then(|| record.model.clone()),
+        preset: state
+            .settings
+            .preset_name(route.as_deref(), record.key_id.as_deref())
+            .map(str::to_string),
+    };
+    let blocked = record.moderation.iter().find(|r| r.verdict.action == ModerationAction::Block);
+    let cancellation = CancellationRecorder {
+        state: state.clone(),
+        record: Some(record.clone()),
+        start_time,
+    };
+    let result = match blocked {
+        Some(result) => Err(ProxyError::PolicyViolation(format!(
+            "Request blocked by moderation: {}",
+            result.verdict.reason.as_deref().unwrap_or("no reason given")
+        ))),
+        None => process_messages_request(&state, &headers, request, &request_id, start_time, options).await,
+    };
+    cancellation.finished();
+
+    Ok(finish_messages_request(&state, record, result, start_time, substituted, rate_limit_style))
+}
+
+/// Largest passthrough body buffered when streaming is off, axum's default for its extractors
+const BUFFERED_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Handle a Messages request in raw passthrough mode. The body goes to the OAuth upstream
+/// as the client sent it; only the model is resolved, `stream_options` removed and the Claude
+/// Code system block spliced in when missing. Quotas, admission, rate limit tracking and
+/// usage accounting apply as usual; the transformation pipeline and moderation don't.
+async fn handle_raw_messages(
+    state: AppState,
+    query: MessagesQuery,
+    headers: HeaderMap,
+    body: Body,
+    rate_limit_style: RateLimitStyle,
+) -> Result<Response, Response> {
+    let min_streamed = state.settings.stream_body_min_kb * 1024;
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if min_streamed > 0 && content_length.is_none_or(|length| length > min_streamed) {
+        return handle_streamed_messages(state, query, headers, body, rate_limit_style).await;
+    }
+
+    let request_id = request_id_from(&headers);
+    let start_time = Instant::now();
+    let limit = if min_streamed > 0 { min_streamed as usize } else { BUFFERED_BODY_LIMIT };
+    let body = axum::body::to_bytes(body, limit)
+        .await
+        .map_err(|e| ProxyError::InvalidRequest(format!("Failed to read the request body: {}", e)).into_response())?;
+    let request = RawMessagesRequest::parse(body)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Request body is not a JSON object: {}", e)).into_response())?;
+    let debug = debug_requested(&state, &headers);
+    let key_id = extract_client_key(&headers).map(key_fingerprint);
+    let tenant = state.tenants.for_key(key_id.as_deref());
+    let mut model = request.model();
+    let substituted = apply_default_model(&state.settings, tenant.map(Arc::as_ref), &mut model, &request_id);
+    let stream = request.stream();
+
+    state.events.publish(ProxyEvent::RequestStarted {
+        request_id: request_id.clone(),
+        model: model.clone(),
+        stream,
+    });
+
+    let record = RequestRecord {
+        request_id: request_id.clone(),
+        timestamp: chrono::Utc::now().timestamp(),
+        model: model.clone(),
+        stream,
+        key_id: key_id.clone(),
+        tenant: tenant.map(|t| t.name.clone()),
+        status: 0,
+        latency_ms: 0,
+        usage: None,
+        prompt_hash: raw_prompt_hash(request.raw_messages().unwrap_or_default().as_bytes()),
+        // Estimating would mean parsing the conversation, which passthrough exists to avoid
+        estimated_input_tokens: 0,
+        debug,
+        error: None,
+        shadow_of: None,
+        canary: None,
+        experiment: None,
+        moderation: Vec::new(),
+    };
+
+    let options = RequestOptions {
+        debug,
+        sampled: debug || state.log_sampler.sample(),
+        format: stream_format(&query, &headers),
+        canary: None,
+        experiment: None,
+        requested_model: (!substituted).then(|| model.clone()),
+        preset: None,
+    };
+    let cancellation = CancellationRecorder {
+        state: state.clone(),
+        record: Some(record.clone()),
+        start_time,
+    };
+    let result = forward_raw_request(&state, &headers, request, &model, &request_id, start_time, options).await;
+    cancellation.finished();
+
+    Ok(finish_messages_request(&state, record, result, start_time, substituted, rate_limit_style))
+}
+
+/// What a streamed passthrough body turned out to hold, filled in as it's read
+#[derive(Debug, Clone, Default)]
+struct StreamedFields {
+    /// Model after default substitution, as recorded in the history
+    model: String,
+    /// Model sent upstream
+    resolved_model: String,
+    substituted: bool,
+    stream: bool,
+    include_usage: bool,
+    /// Why the body was rejected while it was streamed, if it was
+    invalid: Option<String>,
+}
+
+/// Handle a passthrough request whose body is too large to buffer, or of unknown size. The
+/// body streams upstream as it arrives, rewritten on the way (see `streamed_body`), so
+/// its model and `stream` flag are only known once the response is in.
+async fn handle_streamed_messages(
+    state: AppState,
+    query: MessagesQuery,
+    headers: HeaderMap,
+    body: Body,
+    rate_limit_style: RateLimitStyle,
+) -> Result<Response, Response> {
+    let request_id = request_id_from(&headers);
+    let start_time = Instant::now();
+    let debug = debug_requested(&state, &headers);
+    let key_id = extract_client_key(&headers).map(key_fingerprint);
+    let tenant = state.tenants.for_key(key_id.as_deref());
+
+    let mut record = RequestRecord {
+        request_id: request_id.clone(),
+        timestamp: chrono::Utc::now().timestamp(),
+        model: String::new(),
+        stream: false,
+        key_id: key_id.clone(),
+        tenant: tenant.map(|t| t.name.clone()),
+        status: 0,
+        latency_ms: 0,
+        usage: None,
+        prompt_hash: String::new(),
+        estimated_input_tokens: 0,
+        debug,
+        error: None,
+        shadow_of: None,
+        canary: None,
+        experiment: None,
+        moderation: Vec::new(),
+    };
+
+    let options = RequestOptions {
+        debug,
+        sampled: debug || state.log_sampler.sample(),
+        format: stream_format(&query, &headers),
+        canary: None,
+        experiment: None,
+        requested_model: None,
+        preset: None,
+    };
+    let fields = Arc::new(Mutex::new(StreamedFields::default()));
+    let cancellation = CancellationRecorder {
+        state: state.clone(),
+        record: Some(record.clone()),
+        start_time,
+    };
+    let result = forward_streamed_request(&state, &headers, body, fields.clone(), &request_id, start_time, options).await;
+    cancellation.finished();
+
+    let fields = fields.lock().unwrap().clone();
+    record.model = fields.model;
+    record.stream = fields.stream;
+    Ok(finish_messages_request(&state, record, result, start_time, fields.substituted, rate_limit_style))
+}
+
+/// Account for a finished Messages request in the history, events, stats and usage, and
+/// tag its response with the proxy's headers
+fn finish_messages_request(
+    state: &AppState,
+    mut record: RequestRecord,
+    result: Result<Response, ProxyError>,
+    start_time: Instant,
+    substituted: bool,
+    rate_limit_style: RateLimitStyle,
+) -> Response {
+    let duration_ms = start_time.elapsed().as_millis() as u64;
+    record.latency_ms = duration_ms;
+    match &result {
+        Ok(response) => {
+            record.status = response.status().as_u16();
+            record.usage = response.extensions().get::<TokenUsage>().copied();
+            // Streamed responses are accounted when the stream ends
+            if let Some(usage) = &record.usage {
+                let model = match &record.canary {
+                    Some(arm) => arm.model.clone(),
+                    None => state.settings.resolve_model(&record.model),
+                };
+                state.account_usage(&record.request_id, record.key_id.as_deref(), &model, usage);
+            }
+            state.events.publish(ProxyEvent::RequestFinished {
+                request_id: record.request_id.clone(),
+                status: record.status,
+                duration_ms,
+            });
+        }
+        Err(e) => {
+            let message = e.to_string();
+            record.status = e.status().as_u16();
+            record.error = Some(message.clone());
+            if record.status == CLIENT_CLOSED_REQUEST {
+                state.metrics.record_cancelled("client_timeout");
+            }
+            state.events.publish(ProxyEvent::RequestFailed {
+                request_id: record.request_id.clone(),
+                status: record.status,
+                duration_ms,
+                message,
+            });
+        }
+    }
+    if let Some(arm) = &record.canary {
+        state.metrics.record_canary_request(arm, record.status < 400);
+        if let Some(usage) = &record.usage {
+            state.metrics.record_canary_usage(arm, usage);
+        }
+    }
+    if let Some(arm) = &record.experiment {
+        state.metrics.record_experiment_request(arm, record.status < 400);
+        if let Some(usage) = &record.usage {
+            state.metrics.record_experiment_usage(arm, usage);
+        }
+    }
+    let mut tags = Vec::new();
+    if let Some(arm) = &record.experiment {
+        tags.push((EXPERIMENT_HEADER, arm.experiment.clone()));
+        tags.push((VARIANT_HEADER, arm.variant.clone()));
+    }
+    if substituted {
+        tags.push((DEFAULT_MODEL_HEADER, record.model.clone()));
+    }
+    if let Some(action) = record.moderation.iter().map(|r| r.verdict.action).max().filter(|a| *a != ModerationAction::Allow) {
+        tags.push((MODERATION_HEADER, action.name().to_string()));
+    }
+    if let Some(window) = tightest_quota(state, record.key_id.as_deref()) {
+        tags.extend(window.rate_limit_headers(rate_limit_style));
+    }
+    state.stats.record(duration_ms, record.status >= 400);
+    state.history.push(record);
+
+    let mut response = result.unwrap_or_else(IntoResponse::into_response);
+    for (name, value) in tags {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// Quota window with the fewest tokens left among those of the key and its tenant
+fn tightest_quota(state: &AppState, key_id: Option<&str>) -> Option<WindowStatus> {
+    let tenant_id = state.tenants.for_key(key_id).map(|tenant| Tenant::quota_id(&tenant.name));
+    [key_id.map(str::to_string), tenant_id]
+        .into_iter()
+        .flatten()
+        .filter_map(|id| state.quotas.status(&id))
+        .flat_map(|status| status.windows)
+        .min_by_key(|window| window.remaining)
+}
+
+/// Response headers naming the prompt experiment and the variant a request got
+const EXPERIMENT_HEADER: &str = "x-maximize-experiment";
+const VARIANT_HEADER: &str = "x-maximize-variant";
+
+/// Response header with the strictest moderation verdict, when it isn't "allow"
+const MODERATION_HEADER: &str = "x-maximize-moderation";
+
+/// Run every moderator on the client's request, logging and counting the verdicts
+async fn moderate_request(
+    state: &AppState,
+    request_id: &str,
+    headers: &HeaderMap,
+    request: &AnthropicMessageRequest,
+) -> Vec<ModerationResult> {
+    if state.moderators.is_empty() {
+        return Vec::new();
+    }
+    let key_id = extract_client_key(headers).map(key_fingerprint);
+    let input = ModerationInput {
+        request_id,
+        key_id: key_id.as_deref(),
+        request,
+    };
+    let verdicts = futures::future::join_all(state.moderators.iter().map(|m| m.moderate(&input))).await;
+
+    let results: Vec<ModerationResult> = state
+        .moderators
+        .iter()
+        .zip(verdicts)
+        .map(|(moderator, verdict)| ModerationResult {
+            moderator: moderator.name().to_string(),
+            verdict,
+        })
+        .collect();
+    for result in &results {
+        state.metrics.record_moderation(result);
+        if result.verdict.action != ModerationAction::Allow || !result.verdict.labels.is_empty() {
+            info!(
+                "[{}] Moderation ({}): {}{} [{}]",
+                request_id,
+                result.moderator,
+                result.verdict.action.name(),
+                result.verdict.reason.as_deref().map(|r| format!(" ({})", r)).unwrap_or_default(),
+                result.verdict.labels.join(", ")
+            );
+        }
+    }
+    results
+}
+
+/// Response header naming the default model used in place of a missing or disallowed one
+const DEFAULT_MODEL_HEADER: &str = "x-maximize-default-model";
+
+/// Substitute the default model when the client sent none or one it, or its tenant, may
+/// not use. Returns whether it did.
+fn apply_default_model(
+    settings: &Settings,
+    tenant: Option<&Tenant>,
+    model: &mut String,
+    request_id: &str,
+) -> bool {
+    let requested = model.trim();
+    let allowed = match tenant {
+        Some(tenant) => tenant.model_allowed(settings, requested),
+        None => settings.model_allowed(requested),
+    };
+    if !requested.is_empty() && allowed {
+        return false;
+    }
+
+    let default_model = tenant
+        .and_then(|t| t.default_model.as_ref())
+        .unwrap_or(&settings.default_model);
+    if requested.is_empty() {
+        info!("[{}] No model requested, using default '{}'", request_id, default_model);
+    } else {
+        warn!("[{}] Model '{}' is not allowed, using default '{}'", request_id, requested, default_model);
+    }
+    *model = default_model.clone();
+    true
+}
+
+/// Stable opaque user ID for a client API key, suitable for metadata.user_id
+fn derive_user_id(key: &str, salt: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(salt.as_bytes())
+        .chain_update(key.as_bytes())
+        .finalize();
+    digest.iter().take(16).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Set metadata.user_id unless the client already provided one
+fn inject_user_id(request: &mut AnthropicMessageRequest, user_id: &str) {
+    let metadata = request.metadata.get_or_insert_with(|| json!({}));
+    if let Value::Object(map) = metadata {
+        if !map.contains_key("user_id") {
+            map.insert("user_id".to_string(), Value::String(user_id.to_string()));
+        }
+    }
+}
+
+/// Short, non-reversible identifier for a client API key
+pub fn key_fingerprint(key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    digest.iter().take(4).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Truncated hash of the conversation, to recognize repeated prompts without storing them
+fn prompt_hash(messages: &[Value]) -> String {
+    raw_prompt_hash(&serde_json::to_vec(messages).unwrap_or_default())
+}
+
+/// `prompt_hash` of a conversation as the client serialized it
+fn raw_prompt_hash(serialized: &[u8]) -> String {
+    let digest = Sha256::digest(serialized);
+    digest.iter().take(6).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A request after the proxy's transformations, ready to send upstream
+struct PreparedRequest {
+    request: AnthropicMessageRequest,
+    session_turn: Option<SessionTurn>,
+    hook_ctx: HookContext,
+    upstream: UpstreamKind,
+    /// anthropic-beta value to forward: the client's flags plus any the proxy adds
+    beta_header: Option<String>,
+}
+
+/// Run the transformation pipeline: model resolution, session replay, compaction, validation,
+/// sanitization, spoof and cache injection, then the registered request hooks
+fn prepare_request(
+    state: &AppState,
+    headers: &HeaderMap,
+    mut request: AnthropicMessageRequest,
+    request_id: &str,
+    options: &RequestOptions,
+) -> Result<PreparedRequest, ProxyError> {
+    // Check the request as the client sent it, before the proxy fixes anything up
+    if state.settings.validation == ValidationMode::Strict {
+        let problems = validation::check(&request);
+        if !problems.is_empty() {
+            warn!("[{}] Rejecting invalid request: {}", request_id, problems.join("; "));
+            return Err(ProxyError::InvalidRequest(format!("Invalid request: {}", problems.join("; "))));
+        }
+    }
+
+    let key_id = extract_client_key(headers).map(key_fingerprint);
+    let tenant = state.tenants.for_key(key_id.as_deref());
+
+    // Resolve model nickname (the tenant's first) to actual model name, or take the canary rollout's pick
+    let actual_model = match (&options.canary, tenant) {
+        (Some(arm), _) => {
+            debug!("[{}] Canary rollout '{}' assigned the {} arm ({})", request_id, arm.rollout, arm.label(), arm.model);
+            arm.model.clone()
+        }
+        (None, Some(tenant)) => tenant.resolve_model(&state.settings, &request.model),
+        (None, None) => state.settings.resolve_model(&request.model),
+    };
+    if actual_model != request.model {
+        debug!("[{}] Resolved model nickname '{}' to '{}'", request_id, request.model, actual_model);
+        request.model = actual_model;
+    }
+
+    let priority = state.settings.priority(key_id.as_deref(), requested_priority(headers));
+    let quota_remaining = key_id.as_deref().and_then(|k| state.quotas.remaining_fraction(k));
+    let targets = state
+        .settings
+        .routing_targets(&request.model, key_id.as_deref(), priority, quota_remaining);
+    let upstream = state.upstream_health.choose(&targets);
+    if upstream != UpstreamKind::Anthropic {
+        debug!("[{}] Routing {} to the {} upstream", request_id, request.model, upstream.name());
+    }
+
+    // Replay server-side history for stateful clients
+    let mut session_turn = None;
+    if let Some(session_id) = headers.get(SESSION_HEADER).and_then(|v| v.to_str().ok()) {
+        let Some(store) = &state.sessions else {
+            return Err(ProxyError::InvalidRequest("Sessions are not enabled on this proxy".to_string()));
+        };
+        if !sessions::is_valid_session_id(session_id) {
+            return Err(ProxyError::InvalidRequest(
+                "Invalid X-Session-Id: use up to 128 letters, digits, '-', '_', '.' or ':'".to_string(),
+            ));
+        }
+
+        let key = sessions::session_key(key_id.as_deref(), session_id);
+        let history = store.load(&key).map_err(|e| {
+            error!("[{}] Failed to load session '{}': {}", request_id, session_id, e);
+            ProxyError::Internal(format!("Failed to load session: {}", e))
+        })?;
+
+        debug!("[{}] Session '{}' has {} stored messages", request_id, session_id, history.len());
+        session_turn = Some(SessionTurn::new(store.clone(), key, request.messages.clone()));
+        if !history.is_empty() {
+            request.messages = history.into_iter().chain(request.messages).collect();
+        }
+    }
+
+    // Block or scrub forbidden content before anything is sent
+    if state.guardrails.is_enabled() {
+        match state.guardrails.apply(&mut request) {
+            Ok(0) => {}
+            Ok(replaced) => info!("[{}] Guardrails scrubbed {} matches", request_id, replaced),
+            Err(violation) => {
+                warn!("[{}] Guardrails blocked the request: {}", request_id, violation);
+                return Err(ProxyError::PolicyViolation(format!("Request blocked by policy: {}", violation)));
+            }
+        }
+    }
+
+    // Enforce tool limits before sizing the conversation, so truncated results count
+    if let Err(message) = tools::enforce_limits(&mut request, &state.settings.tool_limits) {
+        warn!("[{}] Rejecting request exceeding tool limits: {}", request_id, message);
+        return Err(ProxyError::InvalidRequest(message));
+    }
+
+    // Drop the oldest turns of oversized conversations
+    if let Some(compaction) = &state.settings.compaction {
+        let dropped = compaction::compact(&mut request, compaction);
+        if dropped > 0 {
+            info!("[{}] Compacted conversation, dropped {} oldest messages", request_id, dropped);
+        }
+    }
+
+    // Reject requests that can't fit in the model's context window
+    if let Some(window) = state.settings.context_window(&request.model) {
+        let estimated = tokenizer::count_request(&request);
+        if estimated > window {
+            warn!(
+                "[{}] Prompt of ~{} tokens exceeds the {} token context window of {}",
+                request_id, estimated, window, request.model
+            );
+            return Err(ProxyError::InvalidRequest(format!(
+                "prompt is too long: ~{} tokens (estimated) > {} maximum for {}",
+                estimated, window, request.model
+            )));
+        }
+    }
+
+    // Validate image blocks, downscaling them if configured
+    if let Err(message) = images::validate_images(&mut request, &state.settings.image_limits) {
+        warn!("[{}] Rejecting request with invalid image: {}", request_id, message);
+        return Err(ProxyError::InvalidRequest(message));
+    }
+
+    // Identify the calling client to Anthropic without revealing its key
+    if state.settings.inject_user_id {
+        if let Some(client_key) = extract_client_key(headers) {
+            inject_user_id(&mut request, &derive_user_id(client_key, &state.settings.user_id_salt));
+        }
+    }
+
+    // Fill in the key's default service tier; a tier the client asked for wins
+    if request.service_tier.is_none() {
+        if let Some(tier) = state.settings.service_tier(key_id.as_deref()) {
+            debug!("[{}] Using default service tier '{}'", request_id, tier);
+            request.service_tier = Some(tier.to_string());
+        }
+    }
+
+    // Sanitize request
+    let thinking_policy = state.settings.thinking_policy(&request.model, key_id.as_deref());
+    request = sanitize_anthropic_request(request, thinking_policy, &state.settings.sanitize_rules);
+
+    // Keep only the tools this key may declare
+    let removed = tools::filter_tools(&mut request, state.settings.allowed_tools(key_id.as_deref()));
+    if !removed.is_empty() {
+        info!("[{}] Removed tools not allowed for this key: {}", request_id, removed.join(", "));
+    }
+
+    // Ensure max_tokens is sufficient if thinking is enabled
+    if let Some(thinking) = &request.thinking {
+        if thinking.type_ == "enabled" {
+            let thinking_budget = thinking.budget_tokens;
+            let min_response_tokens = 1024;
+            let required_total = thinking_budget + min_response_tokens;
+            if request.max_tokens < required_total {
+                request.max_tokens = required_total;
+                debug!(
+                    "[{}] Increased max_tokens to {} (thinking: {} + response: {})",
+                    request_id, required_total, thinking_budget, min_response_tokens
+                );
+            }
+        }
+    }
+
+    // Bound output to the client's per-call ceiling
+    if let Some(max_output) = requested_max_output(headers) {
+        clamp_output(&mut request, max_output, request_id);
+    }
+
+    // Prepend the prompt experiment variant's system text, if it has one
+    if let Some(system) = options.experiment.as_ref().and_then(|arm| arm.system.as_deref()) {
+        prepend_system_text(&mut request, system);
+    }
+
+    // Prepend the key's or route's preset system prompt
+    if let Some(name) = &options.preset {
+        if let Some(text) = state.settings.presets.prompts.get(name) {
+            debug!("[{}] Prepending system prompt preset '{}'", request_id, name);
+            prepend_system_text(&mut request, text);
+        }
+    }
+
+    // Prepend the tenant's own system prompt
+    if let Some(tenant) = tenant.filter(|t| t.system_prompt.is_some()) {
+        debug!("[{}] Prepending the system prompt of tenant '{}'", request_id, tenant.name);
+        prepend_system_text(&mut request, tenant.system_prompt.as_deref().unwrap_or_default());
+    }
+
+    // Inject Claude Code system message; only the OAuth token needs it
+    let client_manages_cache = cache::count_breakpoints(&request) > 0;
+    if upstream == UpstreamKind::Anthropic {
+        request = inject_claude_code_system_message(request);
+    }
+
+    // Add prompt cache breakpoints for clients that don't set their own
+    if state.settings.auto_prompt_cache && !client_manages_cache {
+        cache::inject_prompt_cache(&mut request, state.settings.auto_cache_min_chars);
+    }
+
+    // Keep cache_control markers within Anthropic's limits
+    cache::sanitize_cache_control(&mut request);
+
+    // Run registered request hooks
+    let hook_ctx = HookContext {
+        request_id: request_id.to_string(),
+        headers: headers.clone(),
+        debug: options.debug,
+        canary: options.canary.clone(),
+        experiment: options.experiment.clone(),
+        requested_model: options.requested_model.clone(),
+        model: request.model.clone(),
+    };
+    for hook in &state.request_hooks {
+        hook.on_request(&hook_ctx, &mut request)?;
+    }
+
+    // Reject requests that could cost more than the per-request ceiling
+    if state.settings.max_request_cost > 0.0 {
+        if let Some(price) = state.settings.price(&request.model) {
+            let estimated = tokenizer::count_request(&request);
+            let worst_case = price.worst_case_cost(estimated, request.max_tokens as u64);
+            if worst_case > state.settings.max_request_cost {
+                warn!(
+                    "[{}] Worst-case cost ${:.4} (~{} input + {} output tokens) exceeds the ${:.4} limit",
+                    request_id, worst_case, estimated, request.max_tokens, state.settings.max_request_cost
+                );
+                return Err(ProxyError::InvalidRequest(format!(
+                    "request may cost up to ${:.4} (~{} input tokens estimated + max_tokens {} for {}), above the per-request limit of ${:.4}",
+                    worst_case, estimated, request.max_tokens, request.model, state.settings.max_request_cost
+                )));
+            }
+        }
+    }
+
+    let mut betas = client_betas(headers, tenant.map(Arc::as_ref), request_id);
+
+    // Ask for extended output rather than have the upstream reject max_tokens
+    let output_limit = state.settings.output_limit(&request.model);
+    if output_limit.is_some_and(|limit| request.max_tokens as u64 > limit) {
+        if let Some(beta) = state.settings.extended_output_beta(&request.model) {
+            debug!(
+                "[{}] max_tokens {} exceeds the standard output limit of {}, adding {}",
+                request_id,
+                request.max_tokens,
+                output_limit.unwrap_or_default(),
+                beta
+            );
+            if !betas.contains(&beta) {
+                betas.push(beta);
+            }
+        }
+    }
+    let beta_header = (!betas.is_empty()).then(|| betas.join(","));
+
+    Ok(PreparedRequest {
+        request,
+        session_turn,
+        hook_ctx,
+        upstream,
+        beta_header,
+    })
+}
+
+/// Betas the client asked for in anthropic-beta, less those its tenant may not use
+fn client_betas<'a>(headers: &'a HeaderMap, tenant: Option<&Tenant>, request_id: &str) -> Vec<&'a str> {
+    let betas: Vec<&str> = headers
+        .get("anthropic-beta")
+        .and_then(|v| v.to_str().ok())
+        .into_iter()
+        .flat_map(|b| b.split(','))
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .collect();
+    let Some(tenant) = tenant else {
+        return betas;
+    };
+    let (allowed, dropped): (Vec<&str>, Vec<&str>) = betas.into_iter().partition(|b| tenant.beta_allowed(b));
+    if !dropped.is_empty() {
+        warn!("[{}] Dropped betas tenant '{}' may not use: {}", request_id, tenant.name, dropped.join(", "));
+    }
+    allowed
+}
+
+async fn process_messages_request(
+    state: &AppState,
+    headers: &HeaderMap,
+    request: AnthropicMessageRequest,
+    request_id: &str,
+    start_time: Instant,
+    options: RequestOptions,
+) -> Result<Response, ProxyError> {
+    let RequestOptions { debug, sampled, format, .. } = options;
+    sampled_info!(sampled, "[{}] ===== NEW ANTHROPIC MESSAGES REQUEST =====", request_id);
+    let key_id = extract_client_key(headers).map(key_fingerprint);
+    // Keep the conversation on one pool account, so its cached prefix is reused
+    let affinity = (!state.settings.accounts.pool.is_empty()).then(|| {
+        let session_id = headers.get(SESSION_HEADER).and_then(|v| v.to_str().ok());
+        affinity::affinity_key(&request, session_id)
+    });
+    let (account, permit, queued) =
+        admit(state, headers, key_id.as_deref(), request.stream, affinity.as_deref(), request_id).await?;
+    let body_logging = if debug {
+        info!("[{}] Per-request debug enabled via {}", request_id, DEBUG_HEADER);
+        BodyLogging::full()
+    } else {
+        state.settings.body_logging
+    };
+
+    log_request(request_id, &request, headers);
+    request_log::log_headers(body_logging.headers, request_id, headers, &state.redactor);
+
+    // Read before request hooks run: they may rebuild the request without proxy-only fields
+    let include_usage = request.stream_options.as_ref().is_some_and(|o| o.include_usage);
+    let PreparedRequest {
+        mut request,
+        session_turn,
+        hook_ctx,
+        mut upstream,
+        beta_header,
+    } = prepare_request(state, headers, request, request_id, &options)?;
+
+    let deadline = upstream_deadline(&state.settings, headers, request.stream, start_time, request_id);
+    let token_start = Instant::now();
+    let auth = match upstream_auth(state, &account, upstream, request_id).await {
+        Ok(auth) => auth,
+        Err(e) if upstream == UpstreamKind::Anthropic => {
+            state.upstream_health.record(UpstreamKind::Anthropic, false);
+            let api_key = api_key_fallback(&state.settings, FallbackReason::AuthFailure).ok_or(e)?;
+            warn!("[{}] No usable OAuth token, falling back to the API key upstream", request_id);
+            request = strip_claude_code_system_message(request);
+            upstream = UpstreamKind::ApiKey;
+            UpstreamAuth::ApiKey(api_key)
+        }
+        Err(e) => return Err(e),
+    };
+    let token_elapsed = token_start.elapsed();
+    if let UpstreamAuth::OAuth(_) = &auth {
+        state.metrics.observe_phase(Phase::Token, token_elapsed);
+    }
+
+    let client_beta_headers = beta_header.as_deref();
+
+    request_log::log_request_body(body_logging.request_bodies, request_id, &request, &state.redactor);
+
+    let is_streaming = request.stream;
+
+    let transform_elapsed = start_time.elapsed().saturating_sub(token_elapsed + queued);
+    state.metrics.observe_phase(Phase::Transform, transform_elapsed);
+
+    spawn_shadow(state, &account, &request, upstream, client_beta_headers, request_id, key_id.clone());
+
+    let upstream_start = Instant::now();
+    let mut response = send_upstream(state, &auth, &request, client_beta_headers, request_id, deadline)
+        .await
+        .map_err(|e| upstream_request_failed(request_id, start_time, e))?;
+    let ttfb = upstream_start.elapsed();
+    state.metrics.observe_phase(Phase::UpstreamTtfb, ttfb);
+
+    sampled_info!(
+        sampled || !response.status().is_success(),
+        "[{}] {} responded status={} (transform={}ms token={}ms upstream_ttfb={}ms)",
+        request_id,
+        upstream.name(),
+        response.status(),
+        transform_elapsed.as_millis(),
+        token_elapsed.as_millis(),
+        ttfb.as_millis()
+    );
+
+    // A 401 despite a locally valid token (clock skew, early revocation): get a fresh token
+    // and retry ONCE before surfacing the error
+    if let (reqwest::StatusCode::UNAUTHORIZED, UpstreamAuth::OAuth(access_token)) = (response.status(), &auth) {
+        warn!("[{}] Got 401 Unauthorized - token might be expired, attempting refresh and retry", request_id);
+
+        if let Some(new_token) = token_after_unauthorized(&account, access_token, request_id).await {
+            let retry_start = Instant::now();
+            response = send_upstream(state, &UpstreamAuth::OAuth(new_token), &request, client_beta_headers, request_id, deadline)
+                .await
+                .map_err(|e| upstream_request_failed(request_id, start_time, e))?;
+            let retry_ttfb = retry_start.elapsed();
+            state.metrics.observe_phase(Phase::UpstreamTtfb, retry_ttfb);
+            info!(
+                "[{}] Retry completed with status={} (upstream_ttfb={}ms)",
+                request_id,
+                response.status(),
+                retry_ttfb.as_millis()
+            );
+        }
+    }
+
+    if let UpstreamAuth::OAuth(_) = &auth {
+        observe_rate_limits(state, &account, &response, request_id);
+    }
+
+    // Requests the OAuth token can't serve go to the API key upstream, if policy allows
+    if let UpstreamAuth::OAuth(_) = &auth {
+        let reason = match response.status() {
+            reqwest::StatusCode::UNAUTHORIZED => Some(FallbackReason::AuthFailure),
+            reqwest::StatusCode::TOO_MANY_REQUESTS => Some(FallbackReason::RateLimited),
+            _ => None,
+        };
+        if let Some(api_key) = reason.and_then(|reason| api_key_fallback(&state.settings, reason)) {
+            warn!(
+                "[{}] OAuth upstream returned {}, falling back to the API key upstream",
+                request_id,
+                response.status()
+            );
+            request = strip_claude_code_system_message(request);
+            upstream = UpstreamKind::ApiKey;
+            let retry_start = Instant::now();
+            response = send_upstream(state, &UpstreamAuth::ApiKey(api_key), &request, client_beta_headers, request_id, deadline)
+                .await
+                .map_err(|e| upstream_request_failed(request_id, start_time, e))?;
+            let retry_ttfb = retry_start.elapsed();
+            state.metrics.observe_phase(Phase::UpstreamTtfb, retry_ttfb);
+            info!(
+                "[{}] Fallback completed with status={} (upstream_ttfb={}ms)",
+                request_id,
+                response.status(),
+                retry_ttfb.as_millis()
+            );
+        }
+    }
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        error!("[{}] {} API error {}: {}", request_id, upstream.name(), status, state.redactor.redact(&error_text));
+
+        return Err(ProxyError::upstream(status.as_u16(), &error_text));
+    }
+
+    let stream_output = is_streaming.then_some(StreamOutput { format, include_usage, sampled });
+    let result = forward_response(state, hook_ctx, response, stream_output, session_turn, deadline, start_time)
+        .await
+        .map(|response| permit.hold_until_complete(response));
+    if result.is_ok() && !is_streaming {
+        let final_elapsed_ms = start_time.elapsed().as_millis();
+        sampled_info!(
+            sampled,
+            "[{}] ===== ANTHROPIC MESSAGES FINISHED ===== Total time: {}ms",
+            request_id, final_elapsed_ms
+        );
+    }
+    result
+}
+
+/// Let a Messages request in: check the key's and tenant's quotas, pick the account, shed
+/// it if overloaded and wait for an admission slot. Returns the account, the slot and how
+/// long the request queued for it.
+async fn admit(
+    state: &AppState,
+    headers: &HeaderMap,
+    key_id: Option<&str>,
+    stream: bool,
+    affinity: Option<&str>,
+    request_id: &str,
+) -> Result<(Arc<OAuthManager>, AdmissionPermit, Duration), ProxyError> {
+    if let Some(key_id) = key_id {
+        state.quotas.check(key_id).map_err(|exceeded| {
+            warn!("[{}] Rejected: {:?} quota exhausted for key {}", request_id, exceeded.window.period, key_id);
+            ProxyError::from(exceeded)
+        })?;
+    }
+    if let Some(tenant) = state.tenants.for_key(key_id) {
+        state.quotas.check(&Tenant::quota_id(&tenant.name)).map_err(|exceeded| {
+            warn!("[{}] Rejected: {:?} quota exhausted for tenant {}", request_id, exceeded.window.period, tenant.name);
+            ProxyError::from(exceeded)
+        })?;
+    }
+    let account = select_account(state, headers, key_id, affinity)?;
+
+    let requested = requested_priority(headers);
+    let priority = state.settings.priority(key_id, requested);
+    if requested.is_some_and(|r| r != priority) {
+        warn!("[{}] Ignoring {} from a key not listed in admission.override_keys", request_id, PRIORITY_HEADER);
+    }
+    if state.settings.overload.sheds(priority, stream) {
+        if let Some(reason) = state.settings.overload.overloaded(state.admission.in_flight()) {
+            warn!("[{}] Shedding {:?} request: overloaded ({})", request_id, priority, reason);
+            return Err(ProxyError::Overloaded);
+        }
+    }
+
+    let queue_start = Instant::now();
+    let permit = state
+        .admission
+        .acquire(key_id.unwrap_or_default(), priority)
+        .await
+        .map_err(|rejection| {
+            warn!("[{}] Rejected by admission queue: {:?}", request_id, rejection);
+            ProxyError::from(rejection)
+        })?;
+    let queued = queue_start.elapsed();
+    state.metrics.observe_queue_wait(priority, queued);
+    if queued.as_millis() > 0 {
+        debug!("[{}] Admitted after {}ms in the {:?} queue", request_id, queued.as_millis(), priority);
+    }
+    Ok((account, permit, queued))
+}
+
+/// Send a passthrough request upstream and relay the response
+async fn forward_raw_request(
+    state: &AppState,
+    headers: &HeaderMap,
+    mut request: RawMessagesRequest,
+    model: &str,
+    request_id: &str,
+    start_time: Instant,
+    options: RequestOptions,
+) -> Result<Response, ProxyError> {
+    let RequestOptions { debug, sampled, format, requested_model, .. } = options;
+    sampled_info!(sampled, "[{}] ===== NEW ANTHROPIC MESSAGES REQUEST (passthrough) =====", request_id);
+    let key_id = extract_client_key(headers).map(key_fingerprint);
+    let tenant = state.tenants.for_key(key_id.as_deref());
+    let stream = request.stream();
+    let (account, permit, queued) = admit(state, headers, key_id.as_deref(), stream, None, request_id).await?;
+    let body_logging = if debug {
+        info!("[{}] Per-request debug enabled via {}", request_id, DEBUG_HEADER);
+        BodyLogging::full()
+    } else {
+        state.settings.body_logging
+    };
+    request_log::log_headers(body_logging.headers, request_id, headers, &state.redactor);
+
+    let actual_model = match tenant {
+        Some(tenant) => tenant.resolve_model(&state.settings, model),
+        None => state.settings.resolve_model(model),
+    };
+    if actual_model != request.model() {
+        debug!("[{}] Resolved model nickname '{}' to '{}'", request_id, model, actual_model);
+        request.set_model(&actual_model);
+    }
+    let include_usage = request.take_stream_options();
+    if request.service_tier().is_none() {
+        if let Some(tier) = state.settings.service_tier(key_id.as_deref()) {
+            debug!("[{}] Using default service tier '{}'", request_id, tier);
+            request.set_service_tier(tier);
+        }
+    }
+    let system = request.system();
+    if !has_claude_code_system(system.as_ref()) {
+        request.set_system(&with_claude_code_system(system));
+    }
+
+    let betas = client_betas(headers, tenant.map(Arc::as_ref), request_id);
+    let beta_header = (!betas.is_empty()).then(|| betas.join(","));
+    let body = request.into_body();
+    request_log::log_raw_request_body(body_logging.request_bodies, request_id, &actual_model, &body, &state.redactor);
+    let hook_ctx = HookContext {
+        request_id: request_id.to_string(),
+        headers: headers.clone(),
+        debug,
+        canary: None,
+        experiment: None,
+        requested_model,
+        model: actual_model,
+    };
+
+    let deadline = upstream_deadline(&state.settings, headers, stream, start_time, request_id);
+    let token_start = Instant::now();
+    let access_token = oauth_access_token(&account, request_id).await?;
+    let token_elapsed = token_start.elapsed();
+    state.metrics.observe_phase(Phase::Token, token_elapsed);
+
+    let transform_elapsed = start_time.elapsed().saturating_sub(token_elapsed + queued);
+    state.metrics.observe_phase(Phase::Transform, transform_elapsed);
+
+    let upstream_start = Instant::now();
+    let mut response = send_raw_upstream(state, body.clone(), &access_token, beta_header.as_deref(), request_id, deadline)
+        .await
+        .map_err(|e| upstream_request_failed(request_id, start_time, e))?;
+    let ttfb = upstream_start.elapsed();
+    state.metrics.observe_phase(Phase::UpstreamTtfb, ttfb);
+
+    sampled_info!(
+        sampled || !response.status().is_success(),
+        "[{}] {} responded status={} (transform={}ms token={}ms upstream_ttfb={}ms)",
+        request_id,
+        UpstreamKind::Anthropic.name(),
+        response.status(),
+        transform_elapsed.as_millis(),
+        token_elapsed.as_millis(),
+        ttfb.as_millis()
+    );
 
-fn log_request(request_id: &str, request_data: &AnthropicMessageRequest, headers: &HeaderMap) {
-    debug!("[{}] RAW REQUEST CAPTURE", request_id);
-    debug!("[{}] Endpoint: /v1/messages", request_id);
-    debug!("[{}] Model: {}", request_id, request_data.model);
-    debug!("[{}] Stream: {}", request_id, request_data.stream);
-    debug!("[{}] Max Tokens: {}", request_id, request_data.max_tokens);
+    // Same single refresh-and-retry on 401 as the regular path
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        warn!("[{}] Got 401 Unauthorized - token might be expired, attempting refresh and retry", request_id);
 
-    debug!("[{}] ===== INCOMING HEADERS FROM CLIENT =====", request_id);
-    for (name, value) in headers.iter() {
-        let header_name = name.as_str();
-        if header_name.to_lowercase().contains("authorization")
-            || header_name.to_lowercase().contains("api-key")
-        {
-            debug!("[{}] {}: [REDACTED]", request_id, header_name);
-        } else if let Ok(v) = value.to_str() {
-            debug!("[{}] {}: {}", request_id, header_name, v);
+        if let Some(new_token) = token_after_unauthorized(&account, &access_token, request_id).await {
+            let retry_start = Instant::now();
+            response = send_raw_upstream(state, body, &new_token, beta_header.as_deref(), request_id, deadline)
+                .await
+                .map_err(|e| upstream_request_failed(request_id, start_time, e))?;
+            let retry_ttfb = retry_start.elapsed();
+            state.metrics.observe_phase(Phase::UpstreamTtfb, retry_ttfb);
+            info!(
+                "[{}] Retry completed with status={} (upstream_ttfb={}ms)",
+                request_id,
+                response.status(),
+                retry_ttfb.as_millis()
+            );
         }
     }
 
-    if let Some(beta) = headers.get("anthropic-beta") {
-        if let Ok(v) = beta.to_str() {
-            debug!("[{}] *** ANTHROPIC-BETA HEADER FOUND: {} ***", request_id, v);
-        }
+    observe_rate_limits(state, &account, &response, request_id);
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        error!("[{}] Anthropic API error {}: {}", request_id, status, state.redactor.redact(&error_text));
+
+        return Err(ProxyError::upstream(status.as_u16(), &error_text));
     }
 
-    if let Some(thinking) = &request_data.thinking {
-        debug!("[{}] THINKING FIELDS DETECTED: {:?}", request_id, thinking);
+    let stream_output = stream.then_some(StreamOutput { format, include_usage, sampled });
+    let result = forward_response(state, hook_ctx, response, stream_output, None, deadline, start_time)
+        .await
+        .map(|response| permit.hold_until_complete(response));
+    if result.is_ok() && !stream {
+        sampled_info!(
+            sampled,
+            "[{}] ===== ANTHROPIC MESSAGES FINISHED ===== Total time: {}ms",
+            request_id,
+            start_time.elapsed().as_millis()
+        );
     }
+    result
 }
 
-fn sanitize_anthropic_request(mut request_data: AnthropicMessageRequest) -> AnthropicMessageRequest {
-    // Universal parameter validation
-    if let Some(top_p) = request_data.top_p {
-        if top_p.is_nan() || !(0.0..=1.0).contains(&top_p) {
-            debug!("Removing invalid top_p value: {}", top_p);
-            request_data.top_p = None;
-        }
+/// Stream a passthrough body upstream and relay the response. The body can't be sent
+/// twice, so a 401 refreshes the token for the client's retry instead of retrying here.
+async fn forward_streamed_request(
+    state: &AppState,
+    headers: &HeaderMap,
+    body: Body,
+    fields: Arc<Mutex<StreamedFields>>,
+    request_id: &str,
+    start_time: Instant,
+    options: RequestOptions,
+) -> Result<Response, ProxyError> {
+    let RequestOptions { debug, sampled, format, .. } = options;
+    sampled_info!(sampled, "[{}] ===== NEW ANTHROPIC MESSAGES REQUEST (passthrough, streamed body) =====", request_id);
+    let key_id = extract_client_key(headers).map(key_fingerprint);
+    let tenant = state.tenants.for_key(key_id.as_deref()).cloned();
+    // Whether the client wants a stream isn't known yet, so it counts as non-streaming
+    let (account, permit, queued) = admit(state, headers, key_id.as_deref(), false, None, request_id).await?;
+    let body_logging = if debug {
+        info!("[{}] Per-request debug enabled via {}", request_id, DEBUG_HEADER);
+        BodyLogging::full()
+    } else {
+        state.settings.body_logging
+    };
+    request_log::log_headers(body_logging.headers, request_id, headers, &state.redactor);
+    if body_logging.request_bodies != LogDetail::Off {
+        info!("[{}] Request body: streamed upstream, not logged", request_id);
     }
 
-    if let Some(temp) = request_data.temperature {
-        if temp.is_nan() {
-            debug!("Removing invalid temperature value: {}", temp);
-            request_data.temperature = None;
-        }
+    let betas = client_betas(headers, tenant.as_deref(), request_id);
+    let beta_header = (!betas.is_empty()).then(|| betas.join(","));
+    let deadline = upstream_deadline(&state.settings, headers, false, start_time, request_id);
+    let token_start = Instant::now();
+    let access_token = oauth_access_token(&account, request_id).await?;
+    let token_elapsed = token_start.elapsed();
+    state.metrics.observe_phase(Phase::Token, token_elapsed);
+
+    let service_tier = state.settings.service_tier(key_id.as_deref()).map(str::to_string);
+    let body = streamed_body(state, body, tenant, service_tier, fields.clone(), request_id);
+    let upstream_start = Instant::now();
+    let result = send_raw(state, body, &access_token, beta_header.as_deref(), request_id, deadline).await;
+    // A malformed body is the client's fault, not the upstream's
+    if let Some(message) = fields.lock().unwrap().invalid.take() {
+        warn!("[{}] Rejecting streamed body: {}", request_id, message);
+        return Err(ProxyError::InvalidRequest(message));
     }
+    let success = result.as_ref().is_ok_and(|response| !routing::is_failure(response.status()));
+    state.upstream_health.record(UpstreamKind::Anthropic, success);
+    let response = result.map_err(|e| upstream_request_failed(request_id, start_time, e))?;
+    // Includes receiving the body from the client, so it isn't an upstream TTFB
+    let upstream_elapsed = upstream_start.elapsed();
+    state.metrics.observe_phase(Phase::Transform, start_time.elapsed().saturating_sub(token_elapsed + queued + upstream_elapsed));
 
-    if let Some(top_k) = request_data.top_k {
-        if top_k <= 0 {
-            debug!("Removing invalid top_k value: {}", top_k);
-            request_data.top_k = None;
-        }
+    sampled_info!(
+        sampled || !response.status().is_success(),
+        "[{}] {} responded status={} (token={}ms upload_and_ttfb={}ms)",
+        request_id,
+        UpstreamKind::Anthropic.name(),
+        response.status(),
+        token_elapsed.as_millis(),
+        upstream_elapsed.as_millis()
+    );
+
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        warn!("[{}] Got 401 Unauthorized for a streamed body; refreshing the token for the client's retry", request_id);
+        token_after_unauthorized(&account, &access_token, request_id).await;
     }
 
-    // Handle tools parameter
-    if let Some(tools) = &request_data.tools {
-        if tools.is_empty() {
-            debug!("Removing empty tools list");
-            request_data.tools = None;
-        }
+    observe_rate_limits(state, &account, &response, request_id);
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        error!("[{}] Anthropic API error {}: {}", request_id, status, state.redactor.redact(&error_text));
+
+        return Err(ProxyError::upstream(status.as_u16(), &error_text));
     }
 
-    // Handle thinking parameter
-    if let Some(thinking) = &request_data.thinking {
-        if thinking.type_ == "enabled" {
-            debug!("Thinking enabled - applying Anthropic API constraints");
+    let fields = fields.lock().unwrap().clone();
+    let hook_ctx = HookContext {
+        request_id: request_id.to_string(),
+        headers: headers.clone(),
+        debug,
+        canary: None,
+        experiment: None,
+        requested_model: (!fields.substituted).then(|| fields.model.clone()),
+        model: fields.resolved_model.clone(),
+    };
+    let stream_output = fields.stream.then_some(StreamOutput {
+        format,
+        include_usage: fields.include_usage,
+        sampled,
+    });
+    let result = forward_response(state, hook_ctx, response, stream_output, None, deadline, start_time)
+        .await
+        .map(|response| permit.hold_until_complete(response));
+    if result.is_ok() && !fields.stream {
+        sampled_info!(
+            sampled,
+            "[{}] ===== ANTHROPIC MESSAGES FINISHED ===== Total time: {}ms",
+            request_id,
+            start_time.elapsed().as_millis()
+        );
+    }
+    result
+}
 
-            if let Some(temp) = request_data.temperature {
-                if (temp - 1.0).abs() > f32::EPSILON {
-                    debug!("Adjusting temperature from {} to 1.0 (thinking enabled)", temp);
-                    request_data.temperature = Some(1.0);
+/// The client's body as a stream for reqwest, rewritten on the way like a buffered
+/// passthrough body: the model resolved, `stream_options` dropped, and the key's default
+/// service tier and the Claude Code system block added when missing. What it held is left
+/// in `fields`.
+fn streamed_body(
+    state: &AppState,
+    body: Body,
+    tenant: Option<Arc<Tenant>>,
+    service_tier: Option<String>,
+    fields: Arc<Mutex<StreamedFields>>,
+    request_id: &str,
+) -> reqwest::Body {
+    let settings = state.settings.clone();
+    let events = state.events.clone();
+    let request_id = request_id.to_string();
+    let rewrite = {
+        let fields = fields.clone();
+        let request_id = request_id.clone();
+        move |field: &str, value: Option<Value>| -> Option<Value> {
+            let mut fields = fields.lock().unwrap();
+            match field {
+                "model" => {
+                    let mut model = value.as_ref().and_then(Value::as_str).unwrap_or_default().to_string();
+                    fields.substituted = apply_default_model(&settings, tenant.as_deref(), &mut model, &request_id);
+                    fields.resolved_model = match &tenant {
+                        Some(tenant) => tenant.resolve_model(&settings, &model),
+                        None => settings.resolve_model(&model),
+                    };
+                    fields.model = model;
+                    Some(Value::String(fields.resolved_model.clone()))
                 }
-            }
-
-            if let Some(top_p) = request_data.top_p {
-                if !(0.95..=1.0).contains(&top_p) {
-                    let adjusted = top_p.max(0.95).min(1.0);
-                    debug!("Adjusting top_p from {} to {} (thinking constraints)", top_p, adjusted);
-                    request_data.top_p = Some(adjusted);
+                "stream" => {
+                    fields.stream = value.as_ref().and_then(Value::as_bool).unwrap_or(false);
+                    value
+                }
+                "stream_options" => {
+                    fields.include_usage = value
+                        .and_then(|v| serde_json::from_value::<StreamOptions>(v).ok())
+                        .is_some_and(|options| options.include_usage);
+                    None
                 }
+                "service_tier" => value.or_else(|| service_tier.clone().map(Value::String)),
+                "system" if has_claude_code_system(value.as_ref()) => value,
+                "system" => Some(with_claude_code_system(value)),
+                _ => value,
             }
+        }
+    };
 
-            if request_data.top_k.is_some() {
-                debug!("Removing top_k parameter (not allowed with thinking)");
-                request_data.top_k = None;
+    let mut incoming = body.into_data_stream();
+    let stream = async_stream::stream! {
+        let mut rewriter = BodyRewriter::new(rewrite);
+        while let Some(chunk) = incoming.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    yield Err(std::io::Error::other(e));
+                    return;
+                }
+            };
+            match rewriter.feed(&chunk) {
+                Ok(pieces) => {
+                    for piece in pieces {
+                        yield Ok(piece);
+                    }
+                }
+                Err(message) => {
+                    fields.lock().unwrap().invalid = Some(message.clone());
+                    yield Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+                    return;
+                }
             }
         }
-    }
+        if let Err(message) = rewriter.finish() {
+            fields.lock().unwrap().invalid = Some(message.clone());
+            yield Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+            return;
+        }
 
-    request_data
+        let fields = fields.lock().unwrap().clone();
+        debug!("[{}] Streamed request body for {} upstream", request_id, fields.resolved_model);
+        events.publish(ProxyEvent::RequestStarted {
+            request_id,
+            model: fields.model,
+            stream: fields.stream,
+        });
+    };
+    reqwest::Body::wrap_stream(stream)
 }
 
-fn inject_claude_code_system_message(mut request_data: AnthropicMessageRequest) -> AnthropicMessageRequest {
-    let claude_code_spoof_element = json!({
-        "type": "text",
-        "text": "You are Claude Code, Anthropic's official CLI for Claude.",
-        "cache_control": {"type": "ephemeral"}
-    });
-
-    match &mut request_data.system {
-        Some(system) => {
-            if let Value::Array(arr) = system {
-                let mut new_arr = vec![claude_code_spoof_element];
-                new_arr.extend(arr.clone());
-                *arr = new_arr;
-            } else if let Value::String(s) = system {
-                let existing_element = json!({
-                    "type": "text",
-                    "text": s.clone(),
-                    "cache_control": {"type": "ephemeral"}
-                });
-                *system = Value::Array(vec![claude_code_spoof_element, existing_element]);
-            }
-        }
-        None => {
-            request_data.system = Some(Value::Array(vec![claude_code_spoof_element]));
-        }
+fn upstream_request_failed(request_id: &str, start_time: Instant, e: anyhow::Error) -> ProxyError {
+    if let Some(timeout) = e.downcast_ref::<UpstreamTimeout>() {
+        return upstream_timed_out(request_id, timeout);
     }
+    let final_elapsed_ms = start_time.elapsed().as_millis();
+    error!(
+        "[{}] Request failed after {}ms: {}",
+        request_id, final_elapsed_ms, e
+    );
+    ProxyError::BadGateway(e.to_string())
+}
 
-    debug!("Injected Claude Code system message array for Anthropic authentication bypass");
-    if let Some(Value::Array(arr)) = &request_data.system {
-        debug!("Final system message array length: {}", arr.len());
+fn upstream_timed_out(request_id: &str, timeout: &UpstreamTimeout) -> ProxyError {
+    warn!("[{}] {}", request_id, timeout);
+    match timeout {
+        UpstreamTimeout::Elapsed(_) => ProxyError::Timeout(timeout.to_string()),
+        UpstreamTimeout::ClientGaveUp(_) => ProxyError::ClientClosed(timeout.to_string()),
     }
-
-    request_data
 }
 
-async fn make_anthropic_request(
-    request_data: &AnthropicMessageRequest,
-    access_token: &str,
-    client_beta_headers: Option<&str>,
-) -> Result<reqwest::Response, reqwest::Error> {
-    let required_betas = vec![
-        "claude-code-20250219",
-        "oauth-2025-04-20",
-        "fine-grained-tool-streaming-2025-05-14",
-    ];
+/// A request routed to an upstream that has no credentials configured
+fn upstream_not_configured(request_id: &str, upstream: UpstreamKind, settings: &str) -> ProxyError {
+    error!("[{}] Routed to {}, but that upstream is not configured", request_id, upstream.name());
+    ProxyError::Internal(format!("The {} upstream is not configured; set {}", upstream.name(), settings))
+}
 
-    let all_betas = if let Some(client_betas) = client_beta_headers {
-        let client_beta_list: Vec<&str> = client_betas.split(',').map(|s| s.trim()).collect();
-        let mut combined: Vec<&str> = required_betas.clone();
-        combined.extend(client_beta_list);
-        combined.sort();
-        combined.dedup();
-        combined
-    } else {
-        required_betas
+/// Update the account's rate limit estimate from an OAuth response. The default account's
+/// remaining capacity also tunes the admission queue, as configured under `rate_limits`.
+fn observe_rate_limits(state: &AppState, account: &Arc<OAuthManager>, response: &UpstreamResponse, request_id: &str) {
+    let Some(estimate) = state.rate_limits.observe(account.storage().token_file(), response.headers()) else {
+        return;
     };
+    if !Arc::ptr_eq(account, &state.oauth_manager) {
+        return;
+    }
 
-    let beta_header_value = all_betas.join(",");
-
-    let client = reqwest::Client::new();
-    client
-        .post("https://api.anthropic.com/v1/messages?beta=true")
-        .json(request_data)
-        .header("host", "api.anthropic.com")
-        .header("Accept", "application/json")
-        .header("X-Stainless-Retry-Count", "0")
-        .header("X-Stainless-Timeout", "600")
-        .header("X-Stainless-Lang", "js")
-        .header("X-Stainless-Package-Version", "0.60.0")
-        .header("X-Stainless-OS", "Windows")
-        .header("X-Stainless-Arch", "x64")
-        .header("X-Stainless-Runtime", "node")
-        .header("X-Stainless-Runtime-Version", "v22.19.0")
-        .header("anthropic-dangerous-direct-browser-access", "true")
-        .header("anthropic-version", "2023-06-01")
-        .header("authorization", format!("Bearer {}", access_token))
-        .header("x-app", "cli")
-        .header("User-Agent", "claude-cli/1.0.113 (external, cli)")
-        .header("content-type", "application/json")
-        .header("anthropic-beta", beta_header_value)
-        .header("x-stainless-helper-method", "stream")
-        .header("accept-language", "*")
-        .header("sec-fetch-mode", "cors")
-        .send()
-        .await
+    let tightest = estimate.tightest(chrono::Utc::now());
+    let throttle = tightest.and_then(|(_, remaining)| {
+        ratelimit::throttle_limit(&state.settings.rate_limits, state.settings.max_concurrent_requests, remaining)
+    });
+    if throttle == state.admission.throttle() {
+        return;
+    }
+    match (throttle, tightest) {
+        (Some(limit), Some((window, remaining))) => warn!(
+            "[{}] Rate limit window '{}' has {:.0}% left, throttling to {} concurrent requests",
+            request_id,
+            window,
+            remaining * 100.0,
+            limit
+        ),
+        _ => info!("[{}] Rate limit capacity recovered, lifting the throttle", request_id),
+    }
+    state.admission.set_throttle(throttle);
 }
 
-pub async fn health_check() -> impl IntoResponse {
-    Json(json!({
-        "status": "ok",
-        "timestamp": chrono::Utc::now().timestamp()
-    }))
+/// Credentials for sending to an upstream, refreshing the account's OAuth token if needed
+async fn upstream_auth(
+    state: &AppState,
+    account: &OAuthManager,
+    upstream: UpstreamKind,
+    request_id: &str,
+) -> Result<UpstreamAuth, ProxyError> {
+    Ok(match upstream {
+        UpstreamKind::Anthropic => UpstreamAuth::OAuth(oauth_access_token(account, request_id).await?),
+        UpstreamKind::ApiKey => UpstreamAuth::ApiKey(
+            state
+                .settings
+                .anthropic_api
+                .api_key
+                .clone()
+                .ok_or_else(|| upstream_not_configured(request_id, upstream, "anthropic_api.api_key"))?,
+        ),
+        UpstreamKind::Bedrock => UpstreamAuth::Bedrock(
+            state
+                .bedrock
+                .clone()
+                .ok_or_else(|| upstream_not_configured(request_id, upstream, "bedrock.access_key_id and bedrock.secret_access_key"))?,
+        ),
+        UpstreamKind::Vertex => UpstreamAuth::Vertex(
+            state
+                .vertex
+                .clone()
+                .ok_or_else(|| upstream_not_configured(request_id, upstream, "vertex.project_id and credentials"))?,
+        ),
+    })
 }
 
-pub async fn auth_status(State(state): State<AppState>) -> impl IntoResponse {
-    let status = state.oauth_manager.storage().get_status();
-    Json(status)
-}
+/// Mirror a share of requests, as configured under `shadow`, to another upstream and/or
+/// model in the background. Mirrors are sent non-streaming; their responses are logged
+/// and optionally recorded in the request history, but never returned to the client.
+fn spawn_shadow(
+    state: &AppState,
+    account: &Arc<OAuthManager>,
+    request: &AnthropicMessageRequest,
+    upstream: UpstreamKind,
+    client_beta_headers: Option<&str>,
+    request_id: &str,
+    key_id: Option<String>,
+) {
+    let shadow = &state.settings.shadow;
+    if shadow.percent <= 0.0 || rand::thread_rng().gen_range(0.0..100.0) >= shadow.percent {
+        return;
+    }
 
-pub async fn debug_token(State(state): State<AppState>) -> impl IntoResponse {
-    let storage = state.oauth_manager.storage();
-    
-    // Check if we have a token from environment
-    let env_token = std::env::var("MAXIMIZE_ACCESS_TOKEN").ok();
-    
-    if let Some(token) = env_token {
-        let preview = if token.len() > 20 {
-            format!("{}...{}", &token[..10], &token[token.len()-10..])
-        } else {
-            "***".to_string()
+    let upstream = shadow.upstream.unwrap_or(upstream);
+    let mut request = strip_claude_code_system_message(request.clone());
+    if let Some(model) = &shadow.model {
+        request.model = state.settings.resolve_model(model);
+    }
+    request.stream = false;
+    if upstream == UpstreamKind::Anthropic {
+        request = inject_claude_code_system_message(request);
+    }
+
+    let state = state.clone();
+    let account = account.clone();
+    let client_beta_headers = client_beta_headers.map(str::to_string);
+    let request_id = request_id.to_string();
+    let shadow_id = format!("{}-shadow", request_id);
+    let deadline = upstream_deadline(&state.settings, &HeaderMap::new(), false, Instant::now(), &shadow_id);
+    tokio::spawn(async move {
+        let start = Instant::now();
+        let outcome = match upstream_auth(&state, &account, upstream, &shadow_id).await {
+            Ok(auth) => send_upstream(&state, &auth, &request, client_beta_headers.as_deref(), &shadow_id, deadline)
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
         };
-        
-        let is_valid_format = token.starts_with("sk-ant-") || token.starts_with("sess-");
-        
-        Json(json!({
-            "has_token": true,
-            "source": "environment",
-            "starts_with": &token[..std::cmp::min(10, token.len())],
-            "token_length": token.len(),
-            "token_preview": preview,
-            "looks_valid": is_valid_format,
-            "warning": if !is_valid_format {
-                Some("Token format looks wrong! Anthropic tokens should start with 'sk-ant-'")
-            } else {
-                None
+        let (status, body) = match outcome {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                (status, response.text().await.map_err(|e| e.to_string()))
             }
-        }))
-    } else if let Some(token) = storage.get_access_token() {
-        let preview = if token.len() > 20 {
-            format!("{}...{}", &token[..10], &token[token.len()-10..])
-        } else {
-            "***".to_string()
+            Err(message) => (0, Err(message)),
         };
-        
-        let is_valid_format = token.starts_with("sk-ant-") || token.starts_with("sess-");
-        
-        Json(json!({
-            "has_token": true,
-            "source": "file",
-            "starts_with": &token[..std::cmp::min(10, token.len())],
-            "token_length": token.len(),
-            "token_preview": preview,
-            "looks_valid": is_valid_format,
-            "warning": if !is_valid_format {
-                Some("Token format looks wrong! Anthropic tokens should start with 'sk-ant-'")
-            } else {
-                None
-            }
-        }))
-    } else {
-        Json(json!({
-            "has_token": false,
-            "error": "No token found in environment or file"
-        }))
-    }
-}
+        let latency_ms = start.elapsed().as_millis() as u64;
 
-pub async fn token_debug(State(state): State<AppState>) -> impl IntoResponse {
-    let token = state.oauth_manager.storage().get_access_token();
-    
-    let token_info = if let Some(t) = token {
-        if t.len() > 16 {
-            json!({
-                "has_token": true,
-                "token_preview": format!("{}...{}", &t[..12], &t[t.len()-12..]),
-                "token_length": t.len(),
-                "starts_with": &t[..10],
-                "source": if std::env::var("MAXIMIZE_ACCESS_TOKEN").is_ok() { "environment" } else { "file" }
-            })
-        } else {
-            json!({
-                "has_token": true,
-                "token_preview": "[too short]",
-                "token_length": t.len(),
-                "warning": "Token is unusually short"
-            })
+        let parsed = body.as_ref().ok().and_then(|text| serde_json::from_str::<Value>(text).ok());
+        let error = match (&body, status) {
+            (Err(message), _) => Some(message.clone()),
+            (Ok(_), 200..=299) => None,
+            (Ok(text), _) => Some(
+                parsed
+                    .as_ref()
+                    .and_then(|v| v["error"]["message"].as_str())
+                    .map(str::to_string)
+                    .unwrap_or_else(|| text.clone()),
+            ),
+        };
+        info!(
+            "[{}] Shadow request to {} ({}) finished status={} in {}ms{}",
+            request_id,
+            upstream.name(),
+            request.model,
+            status,
+            latency_ms,
+            error.as_deref().map(|e| format!(": {}", state.redactor.redact(e))).unwrap_or_default()
+        );
+
+        if state.settings.shadow.record {
+            state.history.push(RequestRecord {
+                request_id: shadow_id,
+                timestamp: chrono::Utc::now().timestamp(),
+                model: request.model.clone(),
+                stream: false,
+                tenant: state.tenants.for_key(key_id.as_deref()).map(|t| t.name.clone()),
+                key_id,
+                status,
+                latency_ms,
+                usage: parsed.as_ref().and_then(TokenUsage::from_response),
+                prompt_hash: prompt_hash(&request.messages),
+                estimated_input_tokens: tokenizer::count_request(&request),
+                error,
+                debug: false,
+                shadow_of: Some(request_id),
+                canary: None,
+                experiment: None,
+                moderation: Vec::new(),
+            });
         }
-    } else {
-        json!({
-            "has_token": false,
-            "message": "No token loaded"
-        })
-    };
-    
-    Json(token_info)
+    });
 }
 
-pub async fn anthropic_messages(
-    State(state): State<AppState>,
-    headers: HeaderMap,
-    Json(mut request): Json<AnthropicMessageRequest>,
-) -> Result<Response, (StatusCode, Json<Value>)> {
-    let request_id = Uuid::new_v4().to_string()[..8].to_string();
-    let start_time = Instant::now();
-
-    info!("[{}] ===== NEW ANTHROPIC MESSAGES REQUEST =====", request_id);
-    log_request(&request_id, &request, &headers);
+/// Why a request meant for the OAuth upstream may go to the API key upstream instead
+#[derive(Debug, Clone, Copy)]
+enum FallbackReason {
+    /// No valid token, or upstream rejected it even after a refresh
+    AuthFailure,
+    /// The subscription's usage window is exhausted
+    RateLimited,
+}
 
-    // Resolve model nickname to actual model name
-    let actual_model = state.settings.resolve_model(&request.model);
-    if actual_model != request.model {
-        debug!("[{}] Resolved model nickname '{}' to '{}'", request_id, request.model, actual_model);
-        request.model = actual_model;
-    }
+/// API key to fall back to, if one is configured and the policy covers `reason`
+fn api_key_fallback(settings: &Settings, reason: FallbackReason) -> Option<String> {
+    let api = &settings.anthropic_api;
+    let allowed = match reason {
+        FallbackReason::AuthFailure => api.fallback_on_auth_failure,
+        FallbackReason::RateLimited => api.fallback_on_rate_limit,
+    };
+    api.api_key.clone().filter(|_| allowed)
+}
 
-    // Get valid access token with automatic refresh
-    let access_token = state
-        .oauth_manager
+/// Valid OAuth access token of an account, refreshed if needed
+async fn oauth_access_token(account: &OAuthManager, request_id: &str) -> Result<String, ProxyError> {
+    let access_token = account
         .get_valid_token()
         .await
         .map_err(|e| {
             error!("[{}] Token refresh error: {}", request_id, e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": {"message": format!("Token refresh error: {}", e)}})),
-            )
+            ProxyError::Internal(format!("Token refresh error: {}", e))
         })?
         .ok_or_else(|| {
             error!("[{}] No valid token available", request_id);
-            (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({"error": {"message": "OAuth expired; please authenticate using the CLI"}})),
-            )
+            ProxyError::Authentication("OAuth expired; please authenticate using the CLI".to_string())
         })?;
-    
+
     // Debug: Log token info (first/last 8 chars only for security)
     if access_token.len() > 16 {
         info!(
@@ -385,190 +3015,337 @@ pub async fn anthropic_messages(
     } else {
         warn!("[{}] Access token is unusually short: {} chars", request_id, access_token.len());
     }
+    Ok(access_token)
+}
 
-    // Ensure max_tokens is sufficient if thinking is enabled
-    if let Some(thinking) = &request.thinking {
-        if thinking.type_ == "enabled" {
-            let thinking_budget = thinking.budget_tokens;
-            let min_response_tokens = 1024;
-            let required_total = thinking_budget + min_response_tokens;
-            if request.max_tokens < required_total {
-                request.max_tokens = required_total;
-                debug!(
-                    "[{}] Increased max_tokens to {} (thinking: {} + response: {})",
-                    request_id, required_total, thinking_budget, min_response_tokens
-                );
-            }
+/// Token to retry with after upstream rejected `rejected_token`. If a concurrent request
+/// already refreshed, its token is reused: refresh tokens rotate, so refreshing again
+/// could invalidate the token the other request just obtained.
+async fn token_after_unauthorized(account: &OAuthManager, rejected_token: &str, request_id: &str) -> Option<String> {
+    if let Some(current) = account.storage().get_access_token() {
+        if current != rejected_token {
+            info!("[{}] Token was refreshed by another request, retrying with it", request_id);
+            return Some(current);
         }
     }
 
-    // Sanitize request
-    request = sanitize_anthropic_request(request);
+    match account.refresh_tokens(RefreshReason::UpstreamUnauthorized).await {
+        Ok(true) => {
+            info!("[{}] Token refresh successful, retrying request", request_id);
+            let token = account.storage().get_access_token();
+            if token.is_none() {
+                error!("[{}] No token available after refresh", request_id);
+            }
+            token
+        }
+        Ok(false) => {
+            error!("[{}] Token refresh failed", request_id);
+            None
+        }
+        Err(e) => {
+            error!("[{}] Error during token refresh: {}", request_id, e);
+            None
+        }
+    }
+}
 
-    // Inject Claude Code system message
-    request = inject_claude_code_system_message(request);
+/// Report the client's model name in a message_start event
+fn rewrite_event_model(event: &mut SseEvent, model: &str) {
+    if event.event.as_deref() != Some("message_start") {
+        return;
+    }
+    if let Some(mut data) = event.json() {
+        if let Some(field) = data.pointer_mut("/message/model") {
+            *field = json!(model);
+            event.data = data.to_string();
+        }
+    }
+}
 
-    // Extract client beta headers
-    let client_beta_headers = headers
-        .get("anthropic-beta")
-        .and_then(|v| v.to_str().ok());
+fn run_stream_hooks(state: &AppState, hook_ctx: &HookContext, chunk: Bytes) -> Bytes {
+    state
+        .response_hooks
+        .iter()
+        .fold(chunk, |chunk, hook| hook.on_stream_chunk(hook_ctx, chunk))
+}
 
-    debug!("[{}] FULL REQUEST BODY: {}", request_id, serde_json::to_string_pretty(&request).unwrap_or_default());
+fn response_log_detail(state: &AppState, hook_ctx: &HookContext) -> LogDetail {
+    if hook_ctx.debug {
+        LogDetail::Full
+    } else {
+        state.settings.body_logging.response_bodies
+    }
+}
 
-    let is_streaming = request.stream;
+/// Turn a successful upstream response into the client response, running response hooks
+/// and saving the assistant reply to the client's session, if any
+async fn forward_response(
+    state: &AppState,
+    hook_ctx: HookContext,
+    response: UpstreamResponse,
+    stream_output: Option<StreamOutput>,
+    session_turn: Option<SessionTurn>,
+    deadline: Option<UpstreamDeadline>,
+    start_time: Instant,
+) -> Result<Response, ProxyError> {
+    let request_id = hook_ctx.request_id.clone();
+    let body_start = Instant::now();
+    let echo_model = hook_ctx.requested_model.clone().filter(|_| state.settings.echo_requested_model);
 
-    match make_anthropic_request(&request, &access_token, client_beta_headers).await {
-        Ok(response) => {
-            let status = response.status();
-            let elapsed_ms = start_time.elapsed().as_millis();
+    if let Some(StreamOutput { format, include_usage, sampled }) = stream_output {
+        // Handle streaming response
+        let state = state.clone();
+        let key_id = extract_client_key(&hook_ctx.headers).map(key_fingerprint);
+        let response_detail = response_log_detail(&state, &hook_ctx);
+        let assemble = session_turn.is_some() || response_detail != LogDetail::Off;
+        let mut upstream = response.bytes_stream();
+        let stream = async_stream::stream! {
+            let mut session_turn = session_turn;
+            let mut parser = SseParser::new();
+            let mut assembler = MessageAssembler::new();
+            let mut stream_usage = StreamUsage::new();
 
-            info!(
-                "[{}] Anthropic request completed in {}ms status={}",
-                request_id, elapsed_ms, status
-            );
+            let mut tool_input_repair = state.settings.repair_tool_input.then(ToolInputRepair::new);
+            let mut scrubber = state
+                .response_scrubber
+                .is_enabled()
+                .then(|| StreamScrubber::new(state.response_scrubber.clone()));
 
-            if !status.is_success() {
-                let error_text = response.text().await.unwrap_or_default();
-                error!("[{}] Anthropic API error {}: {}", request_id, status, error_text);
-
-                // If we got 401 Unauthorized, try to refresh token and retry ONCE
-                if status.as_u16() == 401 {
-                    warn!("[{}] Got 401 Unauthorized - token might be expired, attempting refresh and retry", request_id);
-                    
-                    // Try to refresh the token
-                    match state.oauth_manager.refresh_tokens().await {
-                        Ok(true) => {
-                            info!("[{}] Token refresh successful, retrying request", request_id);
-                            
-                            // Get the new token
-                            let new_token = state.oauth_manager.storage().get_access_token()
-                                .ok_or_else(|| {
-                                    error!("[{}] No token available after refresh", request_id);
-                                    (
-                                        StatusCode::UNAUTHORIZED,
-                                        Json(json!({"error": {"message": "Token refresh succeeded but no token available"}})),
-                                    )
-                                })?;
-                            
-                            // Retry the request with new token
-                            match make_anthropic_request(&request, &new_token, client_beta_headers).await {
-                                Ok(retry_response) => {
-                                    let retry_status = retry_response.status();
-                                    info!("[{}] Retry completed with status={}", request_id, retry_status);
-                                    
-                                    if !retry_status.is_success() {
-                                        let retry_error = retry_response.text().await.unwrap_or_default();
-                                        error!("[{}] Retry also failed: {}", request_id, retry_error);
-                                        let error_json: Value = serde_json::from_str(&retry_error)
-                                            .unwrap_or_else(|_| json!({"error": {"type": "api_error", "message": retry_error}}));
-                                        return Err((StatusCode::from_u16(retry_status.as_u16()).unwrap(), Json(error_json)));
-                                    }
-                                    
-                                    // Retry succeeded! Process the response
-                                    if is_streaming {
-                                        let stream = retry_response.bytes_stream();
-                                        let body = axum::body::Body::from_stream(stream);
-                                        return Ok(Response::builder()
-                                            .status(StatusCode::OK)
-                                            .header("Content-Type", "text/event-stream")
-                                            .header("Cache-Control", "no-cache")
-                                            .header("Connection", "keep-alive")
-                                            .body(body)
-                                            .unwrap());
-                                    } else {
-                                        let body_text = retry_response.text().await.map_err(|e| {
-                                            error!("[{}] Failed to read retry response body: {}", request_id, e);
-                                            (
-                                                StatusCode::INTERNAL_SERVER_ERROR,
-                                                Json(json!({"error": {"message": format!("Failed to read response: {}", e)}})),
-                                            )
-                                        })?;
-                                        let anthropic_response: Value = serde_json::from_str(&body_text).map_err(|e| {
-                                            error!("[{}] Failed to parse retry response JSON: {}", request_id, e);
-                                            (
-                                                StatusCode::INTERNAL_SERVER_ERROR,
-                                                Json(json!({"error": {"message": format!("Failed to parse response: {}", e)}})),
-                                            )
-                                        })?;
-                                        let final_elapsed_ms = start_time.elapsed().as_millis();
-                                        info!("[{}] ===== ANTHROPIC MESSAGES FINISHED (after retry) ===== Total time: {}ms", request_id, final_elapsed_ms);
-                                        return Ok(Json(anthropic_response).into_response());
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("[{}] Retry request failed: {}", request_id, e);
-                                    return Err((
-                                        StatusCode::INTERNAL_SERVER_ERROR,
-                                        Json(json!({"error": {"message": format!("Retry failed: {}", e)}})),
-                                    ));
-                                }
-                            }
+            // Upstream bytes are inspected, never altered, unless normalization is on, another
+            // format was asked for, the model is echoed, tool input is repaired or text is
+            // scrubbed: then only complete events are sent, re-encoded, one per chunk
+            let reencode = state.settings.normalize_sse
+                || format != StreamFormat::Sse
+                || echo_model.is_some()
+                || tool_input_repair.is_some()
+                || scrubber.is_some();
+            let mut first_byte_sent = false;
+            while let Some(chunk) = upstream.next().await {
+                let received = Instant::now();
+                let mut frames = Vec::new();
+                if let Ok(bytes) = &chunk {
+                    let mut events = parser.feed(bytes);
+                    if let Some(repair) = tool_input_repair.as_mut() {
+                        events = events.into_iter().flat_map(|event| repair.process(event)).collect();
+                    }
+                    if let Some(scrubber) = scrubber.as_mut() {
+                        events = events.into_iter().flat_map(|event| scrubber.process(event)).collect();
+                    }
+                    for mut event in events {
+                        if let Some(model) = &echo_model {
+                            rewrite_event_model(&mut event, model);
                         }
-                        Ok(false) => {
-                            error!("[{}] Token refresh failed", request_id);
+                        stream_usage.push(&event);
+                        if assemble {
+                            assembler.push(&event);
                         }
-                        Err(e) => {
-                            error!("[{}] Error during token refresh: {}", request_id, e);
+                        if reencode {
+                            frames.push(Bytes::from(format.encode(&event)));
                         }
                     }
                 }
 
-                let error_json: Value = serde_json::from_str(&error_text)
-                    .unwrap_or_else(|_| json!({"error": {"type": "api_error", "message": error_text}}));
+                let chunks = match chunk {
+                    Ok(_) if reencode => frames.into_iter().map(Ok).collect(),
+                    chunk => vec![chunk],
+                };
+                // Nothing is held back for later: the body returns to hyper after each upstream
+                // read, and hyper flushes whatever it was given before waiting for more
+                let chunks: Vec<_> = chunks
+                    .into_iter()
+                    .map(|chunk| chunk.map(|bytes| run_stream_hooks(&state, &hook_ctx, bytes)))
+                    .collect();
+                state.metrics.observe_stream_chunk_delay(received.elapsed());
+                if !first_byte_sent && chunks.iter().any(|chunk| chunk.as_ref().is_ok_and(|bytes| !bytes.is_empty())) {
+                    first_byte_sent = true;
+                    state.metrics.observe_stream_first_byte(start_time.elapsed());
+                }
+                for chunk in chunks {
+                    yield chunk;
+                }
+            }
 
-                return Err((StatusCode::from_u16(status.as_u16()).unwrap(), Json(error_json)));
+            if let (true, Some(usage)) = (include_usage, stream_usage.usage()) {
+                let event = SseEvent {
+                    event: Some("usage".to_string()),
+                    data: json!({"type": "usage", "usage": usage}).to_string(),
+                };
+                yield Ok(run_stream_hooks(&state, &hook_ctx, Bytes::from(format.encode(&event))));
             }
 
-            if is_streaming {
-                // Handle streaming response
-                let stream = response.bytes_stream();
-                let body = axum::body::Body::from_stream(stream);
-
-                Ok(Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Content-Type", "text/event-stream")
-                    .header("Cache-Control", "no-cache")
-                    .header("Connection", "keep-alive")
-                    .body(body)
-                    .unwrap())
-            } else {
-                // Handle non-streaming response
-                let body_text = response.text().await.map_err(|e| {
-                    error!("[{}] Failed to read response body: {}", request_id, e);
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(json!({"error": {"message": format!("Failed to read response: {}", e)}})),
-                    )
-                })?;
-
-                let anthropic_response: Value = serde_json::from_str(&body_text).map_err(|e| {
-                    error!("[{}] Failed to parse response JSON: {}", request_id, e);
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(json!({"error": {"message": format!("Failed to parse response: {}", e)}})),
-                    )
-                })?;
-
-                let final_elapsed_ms = start_time.elapsed().as_millis();
-                info!(
-                    "[{}] ===== ANTHROPIC MESSAGES FINISHED ===== Total time: {}ms",
-                    request_id, final_elapsed_ms
-                );
+            let stream_elapsed = body_start.elapsed();
+            state.metrics.observe_phase(Phase::Response, stream_elapsed);
+            sampled_info!(sampled, "[{}] Stream finished in {}ms", hook_ctx.request_id, stream_elapsed.as_millis());
+
+            if let Some(usage) = stream_usage.usage() {
+                state.account_usage(&hook_ctx.request_id, key_id.as_deref(), &hook_ctx.model, &usage);
+                if let Some(arm) = &hook_ctx.canary {
+                    state.metrics.record_canary_usage(arm, &usage);
+                }
+                if let Some(arm) = &hook_ctx.experiment {
+                    state.metrics.record_experiment_usage(arm, &usage);
+                }
+                state.history.set_usage(&hook_ctx.request_id, usage);
+            }
+
+            if assembler.is_complete() {
+                request_log::log_response_body(response_detail, &hook_ctx.request_id, &assembler.message(), &state.redactor);
+            }
 
-                Ok(Json(anthropic_response).into_response())
+            // Only complete replies become part of the session
+            if let Some(turn) = session_turn.take() {
+                if assembler.is_complete() {
+                    turn.commit(&assembler.message());
+                } else {
+                    warn!("[{}] Stream ended early, session not updated", hook_ctx.request_id);
+                }
             }
+        };
+        let body = axum::body::Body::from_stream(stream);
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", format.content_type())
+            .header("Cache-Control", "no-cache")
+            .header("Connection", "keep-alive")
+            // Keep nginx and similar reverse proxies from buffering the stream
+            .header("X-Accel-Buffering", "no")
+            .body(body)
+            .unwrap())
+    } else {
+        // Handle non-streaming response
+        let body_text = before_deadline(deadline, response.text())
+            .await
+            .map_err(|timeout| upstream_timed_out(&request_id, &timeout))?
+            .map_err(|e| {
+                error!("[{}] Failed to read response body: {}", request_id, e);
+                ProxyError::BadGateway(format!("Failed to read response: {}", e))
+            })?;
+        state.metrics.observe_phase(Phase::Response, body_start.elapsed());
+
+        let mut anthropic_response: Value = serde_json::from_str(&body_text).map_err(|e| {
+            error!("[{}] Failed to parse response JSON: {}", request_id, e);
+            ProxyError::BadGateway(format!("Failed to parse response: {}", e))
+        })?;
+
+        request_log::log_response_body(
+            response_log_detail(state, &hook_ctx),
+            &request_id,
+            &anthropic_response,
+            &state.redactor,
+        );
+
+        for hook in &state.response_hooks {
+            hook.on_response(&hook_ctx, &mut anthropic_response);
         }
-        Err(e) => {
-            let final_elapsed_ms = start_time.elapsed().as_millis();
-            error!(
-                "[{}] Request failed after {}ms: {}",
-                request_id, final_elapsed_ms, e
-            );
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": {"message": format!("{}", e)}})),
-            ))
+
+        if let (Some(model), Some(field)) = (&echo_model, anthropic_response.get_mut("model")) {
+            *field = json!(model);
         }
+
+        state.response_scrubber.scrub_message(&mut anthropic_response);
+
+        if let Some(turn) = session_turn {
+            turn.commit(&anthropic_response);
+        }
+
+        let usage = TokenUsage::from_response(&anthropic_response);
+        let mut response = Json(anthropic_response).into_response();
+        if let Some(usage) = usage {
+            response.extensions_mut().insert(usage);
+        }
+        Ok(response)
+    }
+}
+
+/// Tokens used by the calling key since startup, and its quota, if it has one
+pub async fn usage(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let key_id = extract_client_key(&headers).map(key_fingerprint);
+    let quota = key_id.as_deref().and_then(|k| state.quotas.status(k));
+    let totals = state.usage.totals(key_id.as_deref());
+    // Tenants also see their bucket's totals, never another tenant's
+    let tenant = state.tenants.for_key(key_id.as_deref()).map(|tenant| {
+        let totals = state.usage.bucket_totals(&tenant.usage_bucket);
+        json!({
+            "name": tenant.name,
+            "usage_bucket": tenant.usage_bucket,
+            "usage": totals,
+            "cache_hit_rate": totals.cache_hit_rate(),
+            "quota": state.quotas.status(&Tenant::quota_id(&tenant.name)),
+        })
+    });
+    // Limits of the account serving the caller, as its latest response reported them
+    let rate_limits = select_account(&state, &headers, key_id.as_deref(), None)
+        .ok()
+        .and_then(|account| state.rate_limits.estimate(account.storage().token_file()))
+        .map(|estimate| {
+            let tightest = estimate.tightest(chrono::Utc::now());
+            json!({
+                "observed_at": estimate.observed_at,
+                "windows": estimate.windows,
+                "tightest_window": tightest.map(|(window, _)| window),
+                "remaining": tightest.map(|(_, remaining)| remaining),
+                "throttle": state.admission.throttle(),
+            })
+        });
+    Json(json!({
+        "key_id": key_id,
+        "usage": totals,
+        "cache_hit_rate": totals.cache_hit_rate(),
+        "quota": quota,
+        "tenant": tenant,
+        "rate_limits": rate_limits,
+    }))
+}
+
+/// Forget a server-side session
+pub async fn delete_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+) -> Result<impl IntoResponse, ProxyError> {
+    let Some(store) = &state.sessions else {
+        return Err(ProxyError::InvalidRequest("Sessions are not enabled on this proxy".to_string()));
+    };
+
+    let key_id = extract_client_key(&headers).map(key_fingerprint);
+    let key = sessions::session_key(key_id.as_deref(), &session_id);
+    let deleted = store
+        .delete(&key)
+        .map_err(|e| ProxyError::Internal(format!("Failed to delete session: {}", e)))?;
+
+    Ok(Json(json!({"id": session_id, "deleted": deleted})))
+}
+
+/// Approximate input token count of a Messages API body, computed locally
+pub async fn tokenize(Json(body): Json<Value>) -> impl IntoResponse {
+    let messages = body
+        .get("messages")
+        .and_then(|m| m.as_array())
+        .map(|m| m.as_slice())
+        .unwrap_or_default();
+    let tools = body.get("tools").and_then(|t| t.as_array()).map(|t| t.as_slice());
+    let input_tokens = tokenizer::count_prompt(messages, body.get("system"), tools);
+
+    Json(json!({
+        "input_tokens": input_tokens,
+        "approximate": true
+    }))
+}
+
+/// API key supplied by the client via `Authorization`, `x-api-key` or Azure-style `api-key`
+fn extract_client_key(headers: &HeaderMap) -> Option<&str> {
+    let auth_header = headers
+        .get("authorization")
+        .or_else(|| headers.get("x-api-key"))
+        .or_else(|| headers.get("api-key"))
+        .and_then(|v| v.to_str().ok())?;
+
+    // Support both "Bearer <key>" and direct key formats
+    if auth_header.starts_with("Bearer ") {
+        Some(auth_header.trim_start_matches("Bearer "))
+    } else {
+        Some(auth_header)
     }
 }
 
@@ -577,67 +3354,78 @@ async fn api_key_auth(
     headers: HeaderMap,
     request: Request,
     next: Next,
-) -> Result<Response, (StatusCode, Json<Value>)> {
+) -> Result<Response, ProxyError> {
     // Skip auth check if no API key is configured
-    let Some(required_key) = &state.api_key else {
+    if state.api_keys.is_empty() {
         return Ok(next.run(request).await);
-    };
+    }
+    let auth_start = Instant::now();
 
-    // Check Authorization header
-    let auth_header = headers
-        .get("authorization")
-        .or_else(|| headers.get("x-api-key"))
-        .and_then(|v| v.to_str().ok());
-
-    let provided_key = match auth_header {
-        Some(header) => {
-            // Support both "Bearer <key>" and direct key formats
-            if header.starts_with("Bearer ") {
-                header.trim_start_matches("Bearer ")
-            } else {
-                header
-            }
-        }
+    let provided_key = match extract_client_key(&headers) {
+        Some(key) => key,
         None => {
             warn!("API request missing authorization header");
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                Json(json!({
-                    "error": {
-                        "type": "authentication_error",
-                        "message": "Missing API key. Provide via Authorization header."
-                    }
-                })),
+            return Err(ProxyError::Authentication(
+                "Missing API key. Provide via Authorization header.".to_string(),
             ));
         }
     };
 
-    if provided_key != required_key {
+    // Several keys may be valid at once so clients can move to a new key before the old one is removed
+    if !state.api_keys.iter().any(|key| key == provided_key) {
         warn!("API request with invalid API key");
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            Json(json!({
-                "error": {
-                    "type": "authentication_error",
-                    "message": "Invalid API key"
-                }
-            })),
-        ));
+        return Err(ProxyError::Authentication("Invalid API key".to_string()));
     }
+    state.metrics.observe_phase(Phase::Auth, auth_start.elapsed());
 
     Ok(next.run(request).await)
 }
 
+/// Count error responses by category in `maximize_errors_total`
+async fn error_metrics_layer(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    if let Some(ErrorCategory(category)) = response.extensions().get::<ErrorCategory>() {
+        state.metrics.record_error(category);
+    }
+    response
+}
+
 pub fn create_router(state: AppState) -> Router {
+    // Virtual routes need the pipeline for their presets, so they're never passed through
+    let (messages, gateway) = if state.settings.passthrough {
+        (post(passthrough_messages), post(passthrough_gateway_messages))
+    } else {
+        (post(anthropic_messages), post(gateway_messages))
+    };
     let protected_routes = Router::new()
-        .route("/v1/messages", post(anthropic_messages))
+        .route("/v1/messages", messages)
+        .route("/v1/messages/:route", post(route_messages))
+        // OpenRouter-style base path used by gateway clients
+        .route("/api/v1/messages", gateway)
+        .route("/api/v1/chat/completions", post(gateway_chat_completions))
+        .route("/openai/deployments/:deployment/chat/completions", post(azure_chat_completions))
+        .route("/v1/tokenize", post(tokenize))
+        .route("/usage", get(usage))
+        .route("/auth/whoami", get(auth_whoami))
+        .route("/auth/exchange", post(auth_exchange))
+        .route("/auth/introspect", get(auth_introspect))
+        .route("/debug/preview", post(preview_request))
+        .route("/v1/sessions/:id", delete(delete_session))
+        .route("/admin/events", get(admin::admin_events))
+        .route("/admin/requests", get(admin::admin_requests))
+        .route("/admin/refreshes", get(admin::admin_refreshes))
+        .route("/admin/usage/export", get(admin::usage_export))
+        .route("/stats", get(admin::stats))
         .layer(middleware::from_fn_with_state(state.clone(), api_key_auth));
 
     Router::new()
         .route("/healthz", get(health_check))
         .route("/auth/status", get(auth_status))
-        .route("/debug/token", get(token_debug))  // Debug endpoint
+        .route("/metrics", get(admin::metrics))
+        .route("/openapi.json", get(openapi_document))
         .merge(protected_routes)
+        .layer(middleware::from_fn_with_state(state.clone(), error_metrics_layer))
         .layer(TraceLayer::new_for_http())
+        .layer(middleware::from_fn(request_id_layer))
         .with_state(state)
 }
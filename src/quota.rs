@@ -0,0 +1,303 @@
+use chrono::{DateTime, Datelike, Duration, NaiveTime, SecondsFormat, TimeZone, Utc, Weekday};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::settings::{KeyQuota, QuotaWindow};
+
+/// Length of a quota window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPeriod {
+    Daily,
+    Weekly,
+}
+
+impl QuotaPeriod {
+    fn length(&self) -> Duration {
+        match self {
+            QuotaPeriod::Daily => Duration::days(1),
+            QuotaPeriod::Weekly => Duration::weeks(1),
+        }
+    }
+
+    /// Reset schedule used when the window doesn't configure one: midnight UTC, Mondays for weekly
+    fn default_reset(&self) -> &'static str {
+        match self {
+            QuotaPeriod::Daily => "0 0 * * *",
+            QuotaPeriod::Weekly => "0 0 * * 1",
+        }
+    }
+}
+
+/// When a quota window resets, parsed from a cron-like `minute hour * * weekday` expression (UTC).
+/// Daily windows take `*` as weekday; weekly windows need one (0-7 or sun..sat).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResetSchedule {
+    time: NaiveTime,
+    weekday: Option<Weekday>,
+}
+
+impl ResetSchedule {
+    pub fn parse(expr: &str, period: QuotaPeriod) -> Result<Self, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("'{}' must have 5 fields: minute hour * * weekday", expr));
+        };
+        if day != "*" || month != "*" {
+            return Err(format!("'{}': day of month and month must be '*'", expr));
+        }
+
+        let minute: u32 = minute.parse().map_err(|_| format!("'{}': invalid minute", expr))?;
+        let hour: u32 = hour.parse().map_err(|_| format!("'{}': invalid hour", expr))?;
+        let time = NaiveTime::from_hms_opt(hour, minute, 0).ok_or_else(|| format!("'{}': time out of range", expr))?;
+
+        let weekday = match (period, weekday) {
+            (QuotaPeriod::Daily, "*") => None,
+            (QuotaPeriod::Daily, _) => return Err(format!("'{}': daily resets need '*' as weekday", expr)),
+            (QuotaPeriod::Weekly, "*") => return Err(format!("'{}': weekly resets need a weekday", expr)),
+            (QuotaPeriod::Weekly, day) => Some(parse_weekday(day).ok_or_else(|| format!("'{}': invalid weekday", expr))?),
+        };
+
+        Ok(Self { time, weekday })
+    }
+
+    /// Start of the window containing `now`: the latest reset at or before it
+    fn window_start(&self, now: DateTime<Utc>, period: QuotaPeriod) -> DateTime<Utc> {
+        let mut date = now.date_naive();
+        if let Some(weekday) = self.weekday {
+            let days_back = (7 + now.weekday().num_days_from_monday() - weekday.num_days_from_monday()) % 7;
+            date -= Duration::days(days_back as i64);
+        }
+        let start = Utc.from_utc_datetime(&date.and_time(self.time));
+        if start > now {
+            start - period.length()
+        } else {
+            start
+        }
+    }
+}
+
+fn parse_weekday(field: &str) -> Option<Weekday> {
+    Some(match field.to_ascii_lowercase().as_str() {
+        "0" | "7" | "sun" => Weekday::Sun,
+        "1" | "mon" => Weekday::Mon,
+        "2" | "tue" => Weekday::Tue,
+        "3" | "wed" => Weekday::Wed,
+        "4" | "thu" => Weekday::Thu,
+        "5" | "fri" => Weekday::Fri,
+        "6" | "sat" => Weekday::Sat,
+        _ => return None,
+    })
+}
+
+struct WindowLimit {
+    period: QuotaPeriod,
+    tokens: u64,
+    schedule: ResetSchedule,
+}
+
+struct KeyLimits {
+    name: Option<String>,
+    windows: Vec<WindowLimit>,
+}
+
+/// Tokens counted in the current window
+#[derive(Debug, Clone, Copy)]
+struct WindowUsage {
+    start: DateTime<Utc>,
+    used: u64,
+}
+
+/// Current state of one quota window, as reported on /usage
+#[derive(Debug, Clone, Serialize)]
+pub struct WindowStatus {
+    pub period: QuotaPeriod,
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub resets_at: DateTime<Utc>,
+}
+
+/// Header convention for advertising a quota window as a rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitStyle {
+    /// `anthropic-ratelimit-tokens-*`, as the Anthropic API sends
+    Anthropic,
+    /// `x-ratelimit-*-tokens`, as OpenAI-compatible APIs send
+    OpenAi,
+}
+
+impl WindowStatus {
+    /// The window as rate limit response headers, so client SDKs pace themselves against
+    /// the proxy's quota
+    pub fn rate_limit_headers(&self, style: RateLimitStyle) -> [(&'static str, String); 3] {
+        match style {
+            RateLimitStyle::Anthropic => [
+                ("anthropic-ratelimit-tokens-limit", self.limit.to_string()),
+                ("anthropic-ratelimit-tokens-remaining", self.remaining.to_string()),
+                (
+                    "anthropic-ratelimit-tokens-reset",
+                    self.resets_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                ),
+            ],
+            RateLimitStyle::OpenAi => [
+                ("x-ratelimit-limit-tokens", self.limit.to_string()),
+                ("x-ratelimit-remaining-tokens", self.remaining.to_string()),
+                ("x-ratelimit-reset-tokens", openai_duration(self.resets_at - Utc::now())),
+            ],
+        }
+    }
+}
+
+/// Time until a reset as OpenAI writes it, such as "45s", "6m0s" or "20h5m3s"
+fn openai_duration(duration: Duration) -> String {
+    let secs = duration.num_seconds().max(0);
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h{}m{}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m{}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Quota state of one client key
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyQuotaStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub windows: Vec<WindowStatus>,
+}
+
+/// A request refused because a quota window is used up
+#[derive(Debug, Clone)]
+pub struct QuotaExceeded {
+    pub name: Option<String>,
+    pub window: WindowStatus,
+}
+
+/// Daily and weekly token quotas per client key fingerprint. Usage is kept in memory
+/// and starts from zero when the proxy restarts.
+pub struct QuotaTracker {
+    limits: HashMap<String, KeyLimits>,
+    usage: Mutex<HashMap<(String, QuotaPeriod), WindowUsage>>,
+}
+
+impl QuotaTracker {
+    /// Build from config; windows with an invalid reset schedule are skipped with a warning
+    pub fn new(quotas: &HashMap<String, KeyQuota>) -> Self {
+        let limits = quotas
+            .iter()
+            .map(|(key_id, quota)| {
+                let windows = [(QuotaPeriod::Daily, &quota.daily), (QuotaPeriod::Weekly, &quota.weekly)]
+                    .into_iter()
+                    .filter_map(|(period, window)| Some((period, window.as_ref()?)))
+                    .filter_map(|(period, window)| match window_limit(period, window) {
+                        Ok(limit) => Some(limit),
+                        Err(e) => {
+                            warn!("Ignoring {:?} quota for key {}: {}", period, key_id, e);
+                            None
+                        }
+                    })
+                    .collect();
+                (
+                    key_id.clone(),
+                    KeyLimits {
+                        name: quota.name.clone(),
+                        windows,
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            limits,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Refuse the request if any of the key's windows is used up
+    pub fn check(&self, key_id: &str) -> Result<(), QuotaExceeded> {
+        let Some(status) = self.status(key_id) else {
+            return Ok(());
+        };
+        match status.windows.into_iter().find(|w| w.remaining == 0) {
+            Some(window) => Err(QuotaExceeded {
+                name: status.name,
+                window,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Count tokens used by a completed request against the key's windows
+    pub fn record(&self, key_id: &str, tokens: u64) {
+        let Some(limits) = self.limits.get(key_id) else {
+            return;
+        };
+        let now = Utc::now();
+        let mut usage = self.usage.lock().unwrap();
+        for window in &limits.windows {
+            let start = window.schedule.window_start(now, window.period);
+            let entry = usage
+                .entry((key_id.to_string(), window.period))
+                .or_insert(WindowUsage { start, used: 0 });
+            if entry.start != start {
+                *entry = WindowUsage { start, used: 0 };
+            }
+            entry.used += tokens;
+        }
+    }
+
+    /// Fraction of tokens left in the key's fullest window, if it has a quota
+    pub fn remaining_fraction(&self, key_id: &str) -> Option<f64> {
+        self.status(key_id)?
+            .windows
+            .iter()
+            .map(|w| if w.limit == 0 { 0.0 } else { w.remaining as f64 / w.limit as f64 })
+            .min_by(f64::total_cmp)
+    }
+
+    /// Usage and remaining tokens for a key, if it has a quota
+    pub fn status(&self, key_id: &str) -> Option<KeyQuotaStatus> {
+        let limits = self.limits.get(key_id)?;
+        let now = Utc::now();
+        let usage = self.usage.lock().unwrap();
+        let windows = limits
+            .windows
+            .iter()
+            .map(|window| {
+                let start = window.schedule.window_start(now, window.period);
+                let used = usage
+                    .get(&(key_id.to_string(), window.period))
+                    .filter(|u| u.start == start)
+                    .map(|u| u.used)
+                    .unwrap_or(0);
+                WindowStatus {
+                    period: window.period,
+                    limit: window.tokens,
+                    used,
+                    remaining: window.tokens.saturating_sub(used),
+                    resets_at: start + window.period.length(),
+                }
+            })
+            .collect();
+
+        Some(KeyQuotaStatus {
+            name: limits.name.clone(),
+            windows,
+        })
+    }
+}
+
+fn window_limit(period: QuotaPeriod, window: &QuotaWindow) -> Result<WindowLimit, String> {
+    let reset = window.reset.as_deref().unwrap_or(period.default_reset());
+    Ok(WindowLimit {
+        period,
+        tokens: window.tokens,
+        schedule: ResetSchedule::parse(reset, period)?,
+    })
+}
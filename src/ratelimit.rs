@@ -0,0 +1,144 @@
+use chrono::{DateTime, Utc};
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::settings::RateLimitConfig;
+
+const HEADER_PREFIX: &str = "anthropic-ratelimit-";
+
+/// One rate limit window as last reported by Anthropic, such as "requests", "input-tokens"
+/// or the subscription's "unified-5h"
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LimitWindow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u64>,
+    /// Share of the window already used, from 0 to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utilization: Option<f64>,
+    /// "allowed", "allowed_warning" or "rejected"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resets_at: Option<DateTime<Utc>>,
+}
+
+impl LimitWindow {
+    /// Share of the window still available, if the headers tell
+    pub fn remaining_share(&self) -> Option<f64> {
+        if self.status.as_deref() == Some("rejected") {
+            return Some(0.0);
+        }
+        if let Some(utilization) = self.utilization {
+            return Some((1.0 - utilization).clamp(0.0, 1.0));
+        }
+        match (self.limit, self.remaining) {
+            (Some(limit), Some(remaining)) if limit > 0 => Some((remaining as f64 / limit as f64).min(1.0)),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, field: &str, value: &str) -> bool {
+        match field {
+            "limit" => self.limit = value.parse().ok(),
+            "remaining" => self.remaining = value.parse().ok(),
+            "utilization" => self.utilization = value.parse().ok(),
+            "status" => self.status = Some(value.to_string()),
+            "reset" => self.resets_at = parse_reset(value),
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Resets are sent as RFC 3339 timestamps, or as Unix seconds by the unified limits
+fn parse_reset(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.with_timezone(&Utc))
+        .ok()
+        .or_else(|| value.parse().ok().and_then(|secs| DateTime::from_timestamp(secs, 0)))
+}
+
+/// An account's limits and remaining capacity, from the headers of its latest response
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitEstimate {
+    pub observed_at: DateTime<Utc>,
+    pub windows: BTreeMap<String, LimitWindow>,
+}
+
+impl RateLimitEstimate {
+    /// None if the response carried no rate limit headers
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let mut windows: BTreeMap<String, LimitWindow> = BTreeMap::new();
+        for (name, value) in headers {
+            let (Some(rest), Ok(value)) = (name.as_str().strip_prefix(HEADER_PREFIX), value.to_str()) else {
+                continue;
+            };
+            let Some((window, field)) = rest.rsplit_once('-') else {
+                continue;
+            };
+            let mut parsed = windows.get(window).cloned().unwrap_or_default();
+            if parsed.set(field, value.trim()) {
+                windows.insert(window.to_string(), parsed);
+            }
+        }
+        (!windows.is_empty()).then(|| Self {
+            observed_at: Utc::now(),
+            windows,
+        })
+    }
+
+    /// The window with the least capacity left and its remaining share, skipping windows
+    /// that have reset since they were reported
+    pub fn tightest(&self, now: DateTime<Utc>) -> Option<(&str, f64)> {
+        self.windows
+            .iter()
+            .filter(|(_, window)| window.resets_at.is_none_or(|reset| reset > now))
+            .filter_map(|(name, window)| Some((name.as_str(), window.remaining_share()?)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+/// Concurrency limit for an account with `remaining` of its tightest window left; None
+/// lifts the throttle
+pub fn throttle_limit(config: &RateLimitConfig, max_concurrent: usize, remaining: f64) -> Option<usize> {
+    if !config.auto_throttle || remaining >= config.throttle_below {
+        return None;
+    }
+    let min = config.min_concurrent as usize;
+    if max_concurrent == 0 {
+        return Some(min);
+    }
+    let scaled = (max_concurrent as f64 * remaining / config.throttle_below).floor() as usize;
+    Some(scaled.max(min).min(max_concurrent.max(min)))
+}
+
+/// Latest rate limit estimate of each OAuth account, by token file
+#[derive(Default)]
+pub struct RateLimitTracker {
+    estimates: Mutex<HashMap<PathBuf, RateLimitEstimate>>,
+}
+
+impl RateLimitTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the account's estimate with the one the response headers give, if any
+    pub fn observe(&self, account: &Path, headers: &HeaderMap) -> Option<RateLimitEstimate> {
+        let estimate = RateLimitEstimate::from_headers(headers)?;
+        self.estimates
+            .lock()
+            .unwrap()
+            .insert(account.to_path_buf(), estimate.clone());
+        Some(estimate)
+    }
+
+    pub fn estimate(&self, account: &Path) -> Option<RateLimitEstimate> {
+        self.estimates.lock().unwrap().get(account).cloned()
+    }
+}
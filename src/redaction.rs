@@ -0,0 +1,48 @@
+use regex::Regex;
+use std::borrow::Cow;
+
+/// Patterns redacted by default: bearer tokens, Anthropic/OpenAI-style keys and emails
+const DEFAULT_PATTERNS: &[&str] = &[
+    r"(?i)bearer\s+[A-Za-z0-9._~+/=-]+",
+    r"sk-ant-[A-Za-z0-9_-]+",
+    r"\bsk-[A-Za-z0-9_-]{16,}",
+    r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+];
+
+const REPLACEMENT: &str = "[REDACTED]";
+
+/// Masks sensitive substrings in text before it is logged or captured
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    rules: Vec<Regex>,
+}
+
+impl Redactor {
+    /// Build from user patterns, optionally preceded by the built-in defaults.
+    /// Invalid patterns are skipped with a warning.
+    pub fn new(patterns: &[String], include_defaults: bool) -> Self {
+        let defaults = DEFAULT_PATTERNS.iter().filter(|_| include_defaults).map(|p| p.to_string());
+        let rules = defaults
+            .chain(patterns.iter().cloned())
+            .filter_map(|pattern| match Regex::new(&pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    tracing::warn!("Ignoring invalid redaction pattern '{}': {}", pattern, e);
+                    None
+                }
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut result = Cow::Borrowed(text);
+        for rule in &self.rules {
+            if let Cow::Owned(replaced) = rule.replace_all(&result, REPLACEMENT) {
+                result = Cow::Owned(replaced);
+            }
+        }
+        result
+    }
+}
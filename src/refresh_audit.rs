@@ -0,0 +1,93 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Why a token refresh was attempted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshReason {
+    /// A request found the access token expired
+    Expired,
+    /// Anthropic rejected the access token with 401
+    UpstreamUnauthorized,
+    /// The background refresher renewed a token close to expiry
+    Background,
+    /// Requested from the CLI
+    Manual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshOutcome {
+    Success,
+    /// Network error, rate limit or server error; may be retried
+    TransientFailure,
+    /// The token endpoint refused the refresh token
+    Rejected,
+    /// Refused as invalid_grant; tokens were quarantined
+    Revoked,
+}
+
+/// One refresh attempt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshAuditEntry {
+    pub timestamp: i64,
+    pub reason: RefreshReason,
+    pub outcome: RefreshOutcome,
+    /// Expiry of the new access token, on success
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Append-only JSON-lines log of refresh attempts, kept next to the token file
+pub struct RefreshAuditLog {
+    path: PathBuf,
+    write_lock: Mutex<()>,
+}
+
+impl RefreshAuditLog {
+    pub fn for_token_file(token_path: &Path) -> Self {
+        let dir = token_path.parent().unwrap_or_else(|| Path::new("."));
+        Self {
+            path: dir.join("refresh_audit.jsonl"),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record an attempt; failures to write are logged, never fatal
+    pub fn record(&self, entry: RefreshAuditEntry) {
+        if let Err(e) = self.append(&entry) {
+            tracing::warn!("Failed to write refresh audit log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn append(&self, entry: &RefreshAuditEntry) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        Ok(())
+    }
+
+    /// Most recent attempts, newest first
+    pub fn recent(&self, limit: usize) -> Result<Vec<RefreshAuditEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let contents = fs::read_to_string(&self.path)?;
+        Ok(contents
+            .lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .take(limit)
+            .collect())
+    }
+}
@@ -0,0 +1,153 @@
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::info;
+
+use crate::proxy::AnthropicMessageRequest;
+use crate::redaction::Redactor;
+use crate::tokenizer;
+
+/// How much of a request/response part to log, independent of the global log level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogDetail {
+    #[default]
+    Off,
+    Summary,
+    Full,
+}
+
+impl LogDetail {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "off" | "none" => Some(LogDetail::Off),
+            "summary" => Some(LogDetail::Summary),
+            "full" => Some(LogDetail::Full),
+            _ => None,
+        }
+    }
+}
+
+/// Per-part body logging configuration
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct BodyLogging {
+    pub request_bodies: LogDetail,
+    pub headers: LogDetail,
+    pub response_bodies: LogDetail,
+}
+
+impl BodyLogging {
+    /// Everything logged in full, used for per-request debugging
+    pub fn full() -> Self {
+        Self {
+            request_bodies: LogDetail::Full,
+            headers: LogDetail::Full,
+            response_bodies: LogDetail::Full,
+        }
+    }
+}
+
+/// Picks which successful requests get their progress logged at info: every Nth one,
+/// none with 0. The others log it at debug.
+pub struct LogSampler {
+    every: u64,
+    seen: AtomicU64,
+}
+
+impl LogSampler {
+    pub fn new(every: u64) -> Self {
+        Self {
+            every,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    pub fn sample(&self) -> bool {
+        self.every > 0 && self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.every)
+    }
+}
+
+fn is_secret_header(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.contains("authorization") || name.contains("api-key")
+}
+
+pub fn log_headers(detail: LogDetail, request_id: &str, headers: &HeaderMap, redactor: &Redactor) {
+    match detail {
+        LogDetail::Off => {}
+        LogDetail::Summary => {
+            let names: Vec<&str> = headers.keys().map(|n| n.as_str()).collect();
+            info!("[{}] Request headers: {}", request_id, names.join(", "));
+        }
+        LogDetail::Full => {
+            for (name, value) in headers.iter() {
+                if is_secret_header(name.as_str()) {
+                    info!("[{}] Header {}: [REDACTED]", request_id, name);
+                } else if let Ok(v) = value.to_str() {
+                    info!("[{}] Header {}: {}", request_id, name, redactor.redact(v));
+                }
+            }
+        }
+    }
+}
+
+pub fn log_request_body(detail: LogDetail, request_id: &str, request: &AnthropicMessageRequest, redactor: &Redactor) {
+    match detail {
+        LogDetail::Off => {}
+        LogDetail::Summary => info!(
+            "[{}] Request body: model={} messages={} tools={} system={} stream={} max_tokens={} ~{} input tokens",
+            request_id,
+            request.model,
+            request.messages.len(),
+            request.tools.as_ref().map(|t| t.len()).unwrap_or(0),
+            request.system.is_some(),
+            request.stream,
+            request.max_tokens,
+            tokenizer::count_request(request)
+        ),
+        LogDetail::Full => info!(
+            "[{}] Request body: {}",
+            request_id,
+            redactor.redact(&serde_json::to_string_pretty(request).unwrap_or_default())
+        ),
+    }
+}
+
+/// `log_request_body` for a passthrough request, which is logged as sent upstream
+pub fn log_raw_request_body(detail: LogDetail, request_id: &str, model: &str, body: &[u8], redactor: &Redactor) {
+    match detail {
+        LogDetail::Off => {}
+        LogDetail::Summary => info!("[{}] Request body: model={} {} bytes (passthrough)", request_id, model, body.len()),
+        LogDetail::Full => info!(
+            "[{}] Request body: {}",
+            request_id,
+            redactor.redact(&String::from_utf8_lossy(body))
+        ),
+    }
+}
+
+pub fn log_response_body(detail: LogDetail, request_id: &str, response: &Value, redactor: &Redactor) {
+    match detail {
+        LogDetail::Off => {}
+        LogDetail::Summary => {
+            let block_types: Vec<&str> = response
+                .get("content")
+                .and_then(|c| c.as_array())
+                .map(|blocks| blocks.iter().filter_map(|b| b.get("type").and_then(|t| t.as_str())).collect())
+                .unwrap_or_default();
+            info!(
+                "[{}] Response body: stop_reason={} content=[{}] usage={}",
+                request_id,
+                response.get("stop_reason").and_then(|s| s.as_str()).unwrap_or("-"),
+                block_types.join(", "),
+                response.get("usage").map(|u| u.to_string()).unwrap_or_else(|| "-".to_string())
+            );
+        }
+        LogDetail::Full => info!(
+            "[{}] Response body: {}",
+            request_id,
+            redactor.redact(&serde_json::to_string_pretty(response).unwrap_or_default())
+        ),
+    }
+}
@@ -0,0 +1,92 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::settings::{RoutingHealthConfig, RoutingTarget};
+use crate::upstream::UpstreamKind;
+
+#[derive(Default)]
+struct Health {
+    consecutive_failures: u32,
+    down_until: Option<Instant>,
+}
+
+/// Tracks upstream health from request outcomes and picks among routing targets
+pub struct UpstreamHealth {
+    unhealthy_after: u32,
+    cooldown: Duration,
+    state: Mutex<HashMap<UpstreamKind, Health>>,
+}
+
+impl UpstreamHealth {
+    pub fn new(config: &RoutingHealthConfig) -> Self {
+        Self {
+            unhealthy_after: config.unhealthy_after.max(1),
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// False while the upstream is cooling down after repeated failures
+    pub fn is_healthy(&self, upstream: UpstreamKind) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .get(&upstream)
+            .and_then(|h| h.down_until)
+            .is_none_or(|until| Instant::now() >= until)
+    }
+
+    /// Count a request outcome. After a cooldown one more failure is enough to take the
+    /// upstream out again.
+    pub fn record(&self, upstream: UpstreamKind, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        let health = state.entry(upstream).or_default();
+        if success {
+            if health.down_until.is_some() {
+                info!("Upstream {} is healthy again", upstream.name());
+            }
+            *health = Health::default();
+            return;
+        }
+
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.unhealthy_after {
+            if health.down_until.is_none_or(|until| Instant::now() >= until) {
+                warn!(
+                    "Upstream {} failed {} requests in a row, skipping it for {}s",
+                    upstream.name(),
+                    health.consecutive_failures,
+                    self.cooldown.as_secs()
+                );
+            }
+            health.down_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    /// Pick a target by weight among the healthy ones, or among all of them if none is
+    pub fn choose(&self, targets: &[RoutingTarget]) -> UpstreamKind {
+        let healthy: Vec<&RoutingTarget> = targets.iter().filter(|t| self.is_healthy(t.upstream)).collect();
+        let candidates = if healthy.is_empty() { targets.iter().collect() } else { healthy };
+
+        let total: u32 = candidates.iter().map(|t| t.weight).sum();
+        if total == 0 {
+            return candidates.first().map(|t| t.upstream).unwrap_or_default();
+        }
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        for target in &candidates {
+            if pick < target.weight {
+                return target.upstream;
+            }
+            pick -= target.weight;
+        }
+        unreachable!("pick is below the total weight")
+    }
+}
+
+/// Whether an upstream status counts against the upstream's health: server errors,
+/// overload, rate limiting and rejected credentials, but not the client's own mistakes
+pub fn is_failure(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS || status == reqwest::StatusCode::UNAUTHORIZED
+}
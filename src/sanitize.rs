@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tracing::debug;
+
+/// When a sanitization rule applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleCondition {
+    #[default]
+    Always,
+    /// Only while extended thinking is enabled
+    Thinking,
+}
+
+/// What a rule does to its field. Rules never add a field the client didn't send.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum SanitizeAction {
+    /// Remove the field
+    Drop,
+    /// Remove the field when it's an empty list, object or string
+    DropIfEmpty,
+    /// Remove the field when it isn't a number within the bounds
+    DropOutside {
+        #[serde(default)]
+        min: Option<f64>,
+        #[serde(default)]
+        max: Option<f64>,
+    },
+    /// Move a number into the bounds
+    Clamp {
+        #[serde(default)]
+        min: Option<f64>,
+        #[serde(default)]
+        max: Option<f64>,
+    },
+    /// Replace the value
+    Set { value: Value },
+}
+
+/// One adjustment of a top-level request field, such as `temperature` or `tools`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SanitizeRule {
+    pub field: String,
+    #[serde(default)]
+    pub when: RuleCondition,
+    #[serde(flatten)]
+    pub action: SanitizeAction,
+}
+
+impl SanitizeRule {
+    fn new(field: &str, when: RuleCondition, action: SanitizeAction) -> Self {
+        Self {
+            field: field.to_string(),
+            when,
+            action,
+        }
+    }
+}
+
+/// Rules used unless configured otherwise: drop invalid sampling parameters and empty
+/// tool lists, and apply the API's constraints on sampling with thinking
+pub fn default_rules() -> Vec<SanitizeRule> {
+    use RuleCondition::{Always, Thinking};
+    use SanitizeAction::*;
+
+    vec![
+        SanitizeRule::new("top_p", Always, DropOutside { min: Some(0.0), max: Some(1.0) }),
+        SanitizeRule::new("temperature", Always, DropOutside { min: None, max: None }),
+        SanitizeRule::new("top_k", Always, DropOutside { min: Some(1.0), max: None }),
+        SanitizeRule::new("tools", Always, DropIfEmpty),
+        SanitizeRule::new("temperature", Thinking, Set { value: json!(1.0) }),
+        SanitizeRule::new("top_p", Thinking, Clamp { min: Some(0.95), max: Some(1.0) }),
+        SanitizeRule::new("top_k", Thinking, Drop),
+    ]
+}
+
+/// Apply the rules, in order, to a serialized request
+pub fn apply(request: &mut Map<String, Value>, rules: &[SanitizeRule]) {
+    let thinking = request
+        .get("thinking")
+        .and_then(|t| t.get("type"))
+        .and_then(|t| t.as_str())
+        == Some("enabled");
+
+    for rule in rules {
+        if rule.when == RuleCondition::Thinking && !thinking {
+            continue;
+        }
+        let Some(value) = request.get(&rule.field) else {
+            continue;
+        };
+        if value.is_null() {
+            request.remove(&rule.field);
+            continue;
+        }
+
+        match &rule.action {
+            SanitizeAction::Drop => {
+                debug!("Removing {} ({:?} rule)", rule.field, rule.when);
+                request.remove(&rule.field);
+            }
+            SanitizeAction::DropIfEmpty => {
+                let empty = match value {
+                    Value::Array(a) => a.is_empty(),
+                    Value::Object(o) => o.is_empty(),
+                    Value::String(s) => s.is_empty(),
+                    _ => false,
+                };
+                if empty {
+                    debug!("Removing empty {}", rule.field);
+                    request.remove(&rule.field);
+                }
+            }
+            SanitizeAction::DropOutside { min, max } => {
+                let valid = value
+                    .as_f64()
+                    .is_some_and(|n| min.is_none_or(|min| n >= min) && max.is_none_or(|max| n <= max));
+                if !valid {
+                    debug!("Removing invalid {} value: {}", rule.field, value);
+                    request.remove(&rule.field);
+                }
+            }
+            SanitizeAction::Clamp { min, max } => {
+                if let Some(n) = value.as_f64() {
+                    let clamped = max.map_or(n, |max| n.min(max));
+                    let clamped = min.map_or(clamped, |min| clamped.max(min));
+                    if clamped != n {
+                        debug!("Adjusting {} from {} to {} ({:?} rule)", rule.field, n, clamped, rule.when);
+                        // Integer fields such as top_k stay integers
+                        let clamped = if value.is_f64() { json!(clamped) } else { json!(clamped.round() as i64) };
+                        request.insert(rule.field.clone(), clamped);
+                    }
+                }
+            }
+            SanitizeAction::Set { value: new } => {
+                if value != new {
+                    debug!("Adjusting {} from {} to {} ({:?} rule)", rule.field, value, new, rule.when);
+                    request.insert(rule.field.clone(), new.clone());
+                }
+            }
+        }
+    }
+}
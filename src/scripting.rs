@@ -0,0 +1,109 @@
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::error::ProxyError;
+use crate::proxy::{AnthropicMessageRequest, HookContext, RequestHook, ResponseHook};
+
+/// Rhai script that can rewrite request and response JSON.
+///
+/// The script may define either or both of:
+///
+/// ```rhai
+/// fn on_request(request) { request.system += "..."; request }
+/// fn on_response(response) { response }
+/// ```
+///
+/// Each function receives the JSON as a map and must return the (modified) map.
+/// The file is recompiled whenever its modification time changes.
+pub struct ScriptTransform {
+    path: PathBuf,
+    engine: Engine,
+    compiled: Mutex<Option<(SystemTime, AST)>>,
+}
+
+impl ScriptTransform {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            engine: Engine::new(),
+            compiled: Mutex::new(None),
+        }
+    }
+
+    /// Return the current AST, recompiling if the script changed on disk
+    fn current_ast(&self) -> anyhow::Result<AST> {
+        let modified = fs::metadata(&self.path)?.modified()?;
+        let mut compiled = self.compiled.lock().unwrap();
+
+        if let Some((loaded_at, ast)) = compiled.as_ref() {
+            if *loaded_at == modified {
+                return Ok(ast.clone());
+            }
+        }
+
+        let source = fs::read_to_string(&self.path)?;
+        let ast = self
+            .engine
+            .compile(&source)
+            .map_err(|e| anyhow::anyhow!("Failed to compile {}: {}", self.path.display(), e))?;
+        tracing::info!("Loaded transform script: {}", self.path.display());
+        *compiled = Some((modified, ast.clone()));
+        Ok(ast)
+    }
+
+    /// Call `function` with `input` if the script defines it; `Ok(None)` when it doesn't
+    fn call(&self, function: &str, input: &Value) -> anyhow::Result<Option<Value>> {
+        let ast = self.current_ast()?;
+        if !ast.iter_functions().any(|f| f.name == function && f.params.len() == 1) {
+            return Ok(None);
+        }
+
+        let arg: Dynamic = rhai::serde::to_dynamic(input)
+            .map_err(|e| anyhow::anyhow!("Failed to convert JSON for script: {}", e))?;
+        let result: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), &ast, function, (arg,))
+            .map_err(|e| anyhow::anyhow!("Script function '{}' failed: {}", function, e))?;
+        let output: Value = rhai::serde::from_dynamic(&result)
+            .map_err(|e| anyhow::anyhow!("Script function '{}' returned invalid JSON: {}", function, e))?;
+
+        Ok(Some(output))
+    }
+}
+
+impl RequestHook for ScriptTransform {
+    fn on_request(&self, ctx: &HookContext, request: &mut AnthropicMessageRequest) -> Result<(), ProxyError> {
+        let script_error = |e: anyhow::Error| {
+            tracing::error!("[{}] Transform script error: {}", ctx.request_id, e);
+            ProxyError::Internal(format!("Transform script error: {}", e))
+        };
+
+        let input = serde_json::to_value(&*request).map_err(|e| script_error(e.into()))?;
+        if let Some(output) = self.call("on_request", &input).map_err(script_error)? {
+            *request = serde_json::from_value(output).map_err(|e| script_error(e.into()))?;
+            tracing::debug!("[{}] Request rewritten by transform script", ctx.request_id);
+        }
+
+        Ok(())
+    }
+}
+
+impl ResponseHook for ScriptTransform {
+    fn on_response(&self, ctx: &HookContext, response: &mut Value) {
+        match self.call("on_response", response) {
+            Ok(Some(output)) => {
+                *response = output;
+                tracing::debug!("[{}] Response rewritten by transform script", ctx.request_id);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                // Leave the upstream response untouched rather than failing a completed call
+                tracing::error!("[{}] Transform script error: {}", ctx.request_id, e);
+            }
+        }
+    }
+}
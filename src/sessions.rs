@@ -0,0 +1,190 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Persistent conversation history for stateful clients, keyed by session
+pub trait SessionStore: Send + Sync {
+    /// Messages stored for the session, oldest first (empty for unknown sessions)
+    fn load(&self, key: &str) -> Result<Vec<Value>>;
+
+    /// Append messages to the session, creating it if needed
+    fn append(&self, key: &str, messages: &[Value]) -> Result<()>;
+
+    /// Forget a session; returns whether it existed
+    fn delete(&self, key: &str) -> Result<bool>;
+}
+
+/// Sessions held in process memory, expiring after a period of inactivity
+pub struct MemorySessionStore {
+    ttl: Duration,
+    sessions: Mutex<HashMap<String, (Instant, Vec<Value>)>>,
+}
+
+impl MemorySessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn evict_expired(&self, sessions: &mut HashMap<String, (Instant, Vec<Value>)>) {
+        let ttl = self.ttl;
+        sessions.retain(|_, (last_used, _)| last_used.elapsed() < ttl);
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, key: &str) -> Result<Vec<Value>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.evict_expired(&mut sessions);
+        Ok(sessions.get(key).map(|(_, messages)| messages.clone()).unwrap_or_default())
+    }
+
+    fn append(&self, key: &str, messages: &[Value]) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions
+            .entry(key.to_string())
+            .or_insert_with(|| (Instant::now(), Vec::new()));
+        entry.0 = Instant::now();
+        entry.1.extend_from_slice(messages);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        Ok(self.sessions.lock().unwrap().remove(key).is_some())
+    }
+}
+
+/// Sessions persisted in a SQLite database, surviving restarts
+#[cfg(feature = "sqlite")]
+pub struct SqliteSessionStore {
+    ttl_seconds: i64,
+    conn: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteSessionStore {
+    pub fn open(path: &str, ttl: Duration) -> Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS session_messages (
+                session_key TEXT NOT NULL,
+                seq INTEGER NOT NULL,
+                message TEXT NOT NULL,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (session_key, seq)
+            );",
+        )?;
+
+        Ok(Self {
+            ttl_seconds: ttl.as_secs() as i64,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn evict_expired(&self, conn: &rusqlite::Connection) -> Result<()> {
+        let cutoff = chrono::Utc::now().timestamp() - self.ttl_seconds;
+        conn.execute(
+            "DELETE FROM session_messages WHERE session_key IN
+                (SELECT session_key FROM session_messages GROUP BY session_key HAVING MAX(updated_at) < ?1)",
+            [cutoff],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl SessionStore for SqliteSessionStore {
+    fn load(&self, key: &str) -> Result<Vec<Value>> {
+        let conn = self.conn.lock().unwrap();
+        self.evict_expired(&conn)?;
+
+        let mut statement =
+            conn.prepare("SELECT message FROM session_messages WHERE session_key = ?1 ORDER BY seq")?;
+        let rows = statement.query_map([key], |row| row.get::<_, String>(0))?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(serde_json::from_str(&row?)?);
+        }
+        Ok(messages)
+    }
+
+    fn append(&self, key: &str, messages: &[Value]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let tx = conn.transaction()?;
+
+        let next_seq: i64 = tx.query_row(
+            "SELECT COALESCE(MAX(seq) + 1, 0) FROM session_messages WHERE session_key = ?1",
+            [key],
+            |row| row.get(0),
+        )?;
+        for (offset, message) in messages.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO session_messages (session_key, seq, message, updated_at) VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![key, next_seq + offset as i64, message.to_string(), now],
+            )?;
+        }
+        tx.execute(
+            "UPDATE session_messages SET updated_at = ?2 WHERE session_key = ?1",
+            rusqlite::params![key, now],
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM session_messages WHERE session_key = ?1", [key])?;
+        Ok(removed > 0)
+    }
+}
+
+/// Session IDs are client-chosen; keep them short and printable
+pub fn is_valid_session_id(session_id: &str) -> bool {
+    !session_id.is_empty()
+        && session_id.len() <= 128
+        && session_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Storage key for a session, scoped to the calling client key so clients can't read each other's sessions
+pub fn session_key(key_id: Option<&str>, session_id: &str) -> String {
+    format!("{}:{}", key_id.unwrap_or("anonymous"), session_id)
+}
+
+/// The new turn of a session request, saved once the assistant reply is known
+pub struct SessionTurn {
+    store: Arc<dyn SessionStore>,
+    key: String,
+    new_messages: Vec<Value>,
+}
+
+impl SessionTurn {
+    pub fn new(store: Arc<dyn SessionStore>, key: String, new_messages: Vec<Value>) -> Self {
+        Self {
+            store,
+            key,
+            new_messages,
+        }
+    }
+
+    /// Persist the client's new messages followed by the assistant's reply
+    pub fn commit(self, response: &Value) {
+        let mut messages = self.new_messages;
+        messages.push(json!({
+            "role": "assistant",
+            "content": response.get("content").cloned().unwrap_or_else(|| json!([]))
+        }));
+
+        if let Err(e) = self.store.append(&self.key, &messages) {
+            tracing::error!("Failed to save session '{}': {}", self.key, e);
+        }
+    }
+}
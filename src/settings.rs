@@ -1,11 +1,34 @@
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::admission::{OverloadPolicy, Priority};
+use crate::compaction::CompactionSettings;
+use crate::connection::DnsStrategy;
+use crate::guardrails::GuardrailAction;
+use crate::pricing::{self, ModelPrice};
+use crate::images::ImageLimits;
+use crate::moderation::ModerationRule;
+use crate::request_log::{BodyLogging, LogDetail};
+use crate::sanitize::{self, SanitizeRule};
+use crate::tools::ToolLimits;
+use crate::upstream::UpstreamKind;
+use crate::validation::ValidationMode;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
+    /// 0 lets the OS pick a free port, reported in the log, /healthz and `discovery_file`
     pub port: u16,
     pub log_level: String,
     pub bind_address: String,
+    /// Run the `doctor` checks before accepting traffic in server-only mode
+    pub startup_self_test: bool,
+    /// File the listening address is written to once the server is up
+    pub discovery_file: Option<String>,
+    /// How many of the following ports to try when `port` is already in use
+    pub port_fallback: u16,
 }
 
 impl Default for ServerConfig {
@@ -14,36 +37,162 @@ impl Default for ServerConfig {
             port: 8081,
             log_level: "info".to_string(),
             bind_address: "0.0.0.0".to_string(),
+            startup_self_test: false,
+            discovery_file: None,
+            port_fallback: 0,
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelConfig {
+    /// Model used when the client sends none, or one not in `allowed`
     pub default: String,
+    /// Context window overrides in tokens, keyed by full model name
+    #[serde(default)]
+    pub context_windows: HashMap<String, u64>,
+    /// Models (nicknames or names) clients may ask for; empty allows any
+    #[serde(default)]
+    pub allowed: Vec<String>,
+    /// Report the model as the client named it in responses, instead of the model it
+    /// was mapped to
+    #[serde(default)]
+    pub echo_requested: bool,
+    /// Standard output token limit overrides, keyed by full model name
+    #[serde(default)]
+    pub output_limits: HashMap<String, u64>,
+    /// Beta flag raising the output limit, keyed by full model name; added when
+    /// max_tokens exceeds the standard limit. An empty flag turns it off.
+    #[serde(default)]
+    pub extended_output: HashMap<String, String>,
 }
 
 impl Default for ModelConfig {
     fn default() -> Self {
         Self {
             default: "l".to_string(), // Default to claude-sonnet-4
+            context_windows: HashMap::new(),
+            allowed: Vec::new(),
+            echo_requested: false,
+            output_limits: HashMap::new(),
+            extended_output: HashMap::new(),
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiConfig {
+    /// Seconds an upstream call may take unless the client asks otherwise; 0 means no limit
     pub request_timeout: u64,
+    /// Longest timeout a client may ask for per request; 0 means no cap
+    pub max_request_timeout: u64,
+    /// Milliseconds to wait for a connection to be established; 0 means no limit
+    pub connect_timeout_ms: u64,
+    /// Seconds between TCP keepalive probes on upstream connections; 0 disables them
+    pub tcp_keepalive_secs: u64,
+    pub dns_strategy: DnsStrategy,
+    /// Static addresses for hosts, such as api.anthropic.com, bypassing DNS
+    pub dns_overrides: HashMap<String, Vec<IpAddr>>,
+    /// Accepted client API keys. List several to rotate keys without breaking clients.
+    pub keys: Vec<String>,
+    /// Forward Messages request bodies as sent instead of through the transformation pipeline
+    pub passthrough: bool,
+    /// In passthrough mode, stream bodies larger than this many KiB, or of unknown size,
+    /// upstream as they arrive instead of buffering them; 0 buffers every body
+    pub stream_body_min_kb: u64,
+    /// Whether Messages requests are checked locally (strict) or forwarded for Anthropic
+    /// to judge (permissive)
+    pub validation: ValidationMode,
+    /// Service tier sent for client keys (by fingerprint, or "*") whose requests don't set one
+    pub service_tiers: HashMap<String, String>,
 }
 
 impl Default for ApiConfig {
     fn default() -> Self {
         Self {
             request_timeout: 120,
+            max_request_timeout: 3600,
+            connect_timeout_ms: 10_000,
+            tcp_keepalive_secs: 60,
+            dns_strategy: DnsStrategy::System,
+            dns_overrides: HashMap::new(),
+            keys: Vec::new(),
+            passthrough: false,
+            stream_body_min_kb: 1024,
+            validation: ValidationMode::Permissive,
+            service_tiers: HashMap::new(),
+        }
+    }
+}
+
+/// Public OAuth client parameters of the Claude Code login flow. Configurable so a rotated
+/// client can be followed without a new release.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthConfig {
+    pub client_id: String,
+    pub redirect_uri: String,
+    /// Space-separated scopes requested at login
+    pub scopes: String,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        Self {
+            client_id: "9d1c250a-e61b-44d9-88ed-5944d1962f5e".to_string(),
+            redirect_uri: "https://console.anthropic.com/oauth/code/callback".to_string(),
+            scopes: "org:create_api_key user:profile user:inference".to_string(),
         }
     }
 }
 
+/// Extra OAuth identities clients may pick per request with X-Maximize-Account. Requests
+/// without the header use the main token file, or the `pool` when one is configured.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct AccountsConfig {
+    /// Token file per account name; log in with TOKEN_FILE pointing at it to create one
+    #[serde(default)]
+    pub profiles: HashMap<String, String>,
+    /// Accounts each client key fingerprint may select, or "*" for any other key. A "*"
+    /// entry in a list allows every account. Keys not covered may not select accounts.
+    #[serde(default)]
+    pub keys: HashMap<String, Vec<String>>,
+    /// Accounts requests without the header are spread over, each conversation staying on
+    /// one account so its prompt cache keeps hitting; empty serves them all from the main
+    /// token file
+    #[serde(default)]
+    pub pool: Vec<String>,
+}
+
+/// A user of a shared deployment: the client keys they authenticate with, the token file
+/// of their own Claude account, their model policy and usage bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TenantConfig {
+    /// Client API keys of the tenant; they are accepted in addition to `api.keys`
+    pub keys: Vec<String>,
+    pub token_file: String,
+    /// Models the tenant may use; empty allows whatever the global policy allows
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Model used when the tenant asks for none or one it may not use
+    #[serde(default)]
+    pub default_model: Option<String>,
+    /// Name usage is recorded under; defaults to the tenant's name
+    #[serde(default)]
+    pub usage_bucket: Option<String>,
+    /// Model nicknames of the tenant, taking precedence over the global ones
+    #[serde(default)]
+    pub model_aliases: HashMap<String, String>,
+    /// System prompt prepended to every request of the tenant
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Token quota shared by all of the tenant's keys, on top of any per-key quota
+    #[serde(default)]
+    pub quota: Option<KeyQuota>,
+    /// anthropic-beta flags the tenant may send; others are dropped. None allows any.
+    #[serde(default)]
+    pub betas: Option<Vec<String>>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageConfig {
     pub token_file: String,
@@ -62,12 +211,642 @@ impl Default for StorageConfig {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ScriptingConfig {
+    pub transform_script: Option<String>,
+    pub wasm_filters: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminConfig {
+    pub history_size: u64,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self { history_size: 100 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheConfig {
+    pub auto_inject: bool,
+    pub min_prefix_chars: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            auto_inject: false,
+            min_prefix_chars: 4096, // ~1024 tokens, Anthropic's minimum cacheable prefix
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageConfig {
+    pub max_bytes: u64,
+    pub max_dimension: u64,
+    pub downscale: bool,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 5 * 1024 * 1024, // Anthropic's per-image limit
+            max_dimension: 8000,
+            downscale: false,
+        }
+    }
+}
+
+/// Limits on tool definitions and tool_result sizes; 0 disables a limit
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ToolConfig {
+    pub max_tools: u64,
+    pub max_schema_bytes: u64,
+    pub max_result_chars: u64,
+    pub truncate_results: bool,
+    /// Tool names each client key (by fingerprint, or "*" for any other key) may
+    /// declare or use through MCP servers; an empty list allows none
+    #[serde(default)]
+    pub allowed: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactionConfig {
+    pub enabled: bool,
+    pub max_tokens: u64,
+    pub keep_recent: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_tokens: 150_000,
+            keep_recent: 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionConfig {
+    pub enabled: bool,
+    /// "memory" or "sqlite" (requires the sqlite feature)
+    pub backend: String,
+    pub sqlite_path: String,
+    pub ttl_seconds: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        let home_dir = dirs::home_dir().unwrap_or_else(|| std::path::PathBuf::from("."));
+        let db_path = home_dir.join(".maximize").join("sessions.db");
+
+        Self {
+            enabled: false,
+            backend: "memory".to_string(),
+            sqlite_path: db_path.to_string_lossy().to_string(),
+            ttl_seconds: 86400,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataConfig {
+    /// Fill metadata.user_id with a hash of the client's API key
+    pub inject_user_id: bool,
+    /// Mixed into the hash so user IDs can't be linked across deployments
+    pub user_id_salt: String,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        Self {
+            inject_user_id: true,
+            user_id_salt: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggingConfig {
+    /// Apply the built-in redaction rules (emails, API keys, bearer tokens)
+    pub redact_defaults: bool,
+    /// Extra regular expressions whose matches are masked in logs
+    pub redaction_patterns: Vec<String>,
+    /// Request body logging: off, summary or full
+    pub request_bodies: LogDetail,
+    /// Client header logging: off, summary or full
+    pub headers: LogDetail,
+    /// Response body logging: off, summary or full
+    pub response_bodies: LogDetail,
+    /// Fingerprints of client keys allowed to use the X-Maximize-Debug header
+    pub debug_keys: Vec<String>,
+    /// Log the progress of every Nth successful request at info, the rest at debug; 0 logs
+    /// none at info. Warnings and errors are always logged.
+    pub sample_every: u64,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            redact_defaults: true,
+            redaction_patterns: Vec::new(),
+            request_bodies: LogDetail::Off,
+            headers: LogDetail::Off,
+            response_bodies: LogDetail::Off,
+            debug_keys: Vec::new(),
+            sample_every: 1,
+        }
+    }
+}
+
+/// Anthropic's API with a standard API key: a routing target of its own, and a fallback
+/// for requests the OAuth token can't serve
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct AnthropicApiConfig {
+    pub api_key: Option<String>,
+    /// Fall back when no valid OAuth token can be obtained or upstream keeps rejecting it
+    pub fallback_on_auth_failure: bool,
+    /// Fall back when the Max subscription's usage window is exhausted (429)
+    pub fallback_on_rate_limit: bool,
+}
+
+/// AWS Bedrock upstream; usable once credentials are set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BedrockConfig {
+    pub region: String,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// Needed with temporary credentials
+    pub session_token: Option<String>,
+    /// Bedrock model IDs (or inference profile IDs) keyed by Anthropic model name;
+    /// unlisted models map to `anthropic.<model>-v1:0`
+    pub model_ids: HashMap<String, String>,
+}
+
+impl Default for BedrockConfig {
+    fn default() -> Self {
+        Self {
+            region: "us-east-1".to_string(),
+            access_key_id: None,
+            secret_access_key: None,
+            session_token: None,
+            model_ids: HashMap::new(),
+        }
+    }
+}
+
+/// Google Vertex AI upstream; usable once a project is set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VertexConfig {
+    pub project_id: Option<String>,
+    /// Region such as "us-east5", or "global"
+    pub region: String,
+    /// Fixed access token; otherwise one is obtained from `credentials_file`, gcloud's
+    /// application default credentials or the metadata server
+    pub access_token: Option<String>,
+    /// Service account key or authorized_user credentials JSON
+    pub credentials_file: Option<String>,
+    /// Vertex model IDs keyed by Anthropic model name; unlisted models map to
+    /// `<name>@<date>`
+    pub model_ids: HashMap<String, String>,
+}
+
+impl Default for VertexConfig {
+    fn default() -> Self {
+        Self {
+            project_id: None,
+            region: "us-east5".to_string(),
+            access_token: None,
+            credentials_file: None,
+            model_ids: HashMap::new(),
+        }
+    }
+}
+
+/// An upstream a routing rule may pick, with its share of the rule's traffic
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RoutingTarget {
+    pub upstream: UpstreamKind,
+    #[serde(default = "default_routing_weight")]
+    pub weight: u32,
+}
+
+fn default_routing_weight() -> u32 {
+    1
+}
+
+impl From<UpstreamKind> for RoutingTarget {
+    fn from(upstream: UpstreamKind) -> Self {
+        Self {
+            upstream,
+            weight: default_routing_weight(),
+        }
+    }
+}
+
+/// Routes requests matching every listed condition (empty lists match anything) to one of
+/// `targets`, chosen by weight among the healthy ones
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoutingRule {
+    /// Model nicknames or full names
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Client key fingerprints
+    #[serde(default)]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub priorities: Vec<Priority>,
+    /// Only match while the key's fullest quota window has less than this fraction
+    /// (0.0-1.0) of its tokens left; keys without a quota never match
+    #[serde(default)]
+    pub quota_remaining_below: Option<f64>,
+    pub targets: Vec<RoutingTarget>,
+}
+
+/// Passive health checking: an upstream failing `unhealthy_after` requests in a row is
+/// skipped for `cooldown_secs`, then tried again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoutingHealthConfig {
+    pub unhealthy_after: u32,
+    pub cooldown_secs: u64,
+}
+
+impl Default for RoutingHealthConfig {
+    fn default() -> Self {
+        Self {
+            unhealthy_after: 3,
+            cooldown_secs: 30,
+        }
+    }
+}
+
+/// Which upstream serves a request. The first matching rule wins; then a key entry,
+/// then a model entry (nickname, full name or "*"); unmatched requests go to Anthropic.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RoutingConfig {
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+    #[serde(default)]
+    pub models: HashMap<String, UpstreamKind>,
+    #[serde(default)]
+    pub keys: HashMap<String, UpstreamKind>,
+    #[serde(default)]
+    pub health: RoutingHealthConfig,
+}
+
+/// Named system prompts prepended server-side, attached to client keys and virtual routes
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct PresetConfig {
+    /// Preset texts by name
+    #[serde(default)]
+    pub prompts: HashMap<String, String>,
+    /// Preset name per client key fingerprint, or "*" for any other key
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+    /// Preset name per virtual route, served at /v1/messages/<route>
+    #[serde(default)]
+    pub routes: HashMap<String, String>,
+}
+
+/// Model prices and the spending ceiling they enforce
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct PricingConfig {
+    /// Price overrides and additions, keyed by full model name
+    #[serde(default)]
+    pub models: HashMap<String, ModelPrice>,
+    /// Highest worst-case cost in USD a single request may have; 0 disables the check
+    #[serde(default)]
+    pub max_request_cost: f64,
+}
+
+/// Deny-list filter for request content
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct GuardrailConfig {
+    /// Regular expressions that must not match
+    pub patterns: Vec<String>,
+    /// Words or phrases that must not appear, matched case-insensitively
+    pub keywords: Vec<String>,
+    /// Count likely credentials (cloud keys, private keys, tokens, passwords)
+    pub detect_secrets: bool,
+    /// Likely credentials tolerated before the action applies
+    pub max_secrets: u64,
+    pub action: GuardrailAction,
+    /// Regular expressions masked in generated text before it reaches clients
+    pub response_patterns: Vec<String>,
+}
+
+/// Moderators consulted before a request is forwarded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationConfig {
+    /// External classifier POSTed each request; none disables it
+    pub url: Option<String>,
+    pub timeout_ms: u64,
+    /// Allow requests when the classifier fails instead of blocking them
+    pub fail_open: bool,
+    /// Local rules checked against the request's text
+    pub rules: Vec<ModerationRule>,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            timeout_ms: 2000,
+            fail_open: true,
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// Mirrors a share of requests to a second upstream and/or model in the background;
+/// mirrored responses never reach the client
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ShadowConfig {
+    /// Share of requests mirrored, 0-100; 0 disables mirroring
+    pub percent: f64,
+    /// Upstream mirrored to; defaults to the one serving the request
+    pub upstream: Option<UpstreamKind>,
+    /// Model nickname or name mirrored requests ask for; defaults to the request's model
+    pub model: Option<String>,
+    /// Add mirrored requests to the request history instead of only logging them
+    pub record: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct StreamingConfig {
+    /// Re-emit upstream SSE as complete `event:`/`data:` frames, one per chunk, instead of
+    /// passing through chunks that may split events
+    pub normalize_events: bool,
+    /// Send each tool call's input as one delta of valid JSON when its block stops
+    pub repair_tool_input: bool,
+}
+
+/// Payload shape expected by an alert webhook
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// The alert as plain JSON
+    #[default]
+    Json,
+    Slack,
+    Discord,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertWebhook {
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Alert kinds delivered to this webhook (e.g. "token_refresh_failed"); empty means all
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertConfig {
+    /// URL receiving every alert as JSON
+    pub webhook_url: Option<String>,
+    /// Additional webhooks with their own format and event selection
+    pub webhooks: Vec<AlertWebhook>,
+    /// Alert when tokens expire within this many hours and can't be refreshed
+    pub expiry_warning_hours: u64,
+    /// Alert after this many consecutive 401 responses
+    pub unauthorized_threshold: u64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            webhook_url: None,
+            webhooks: Vec::new(),
+            expiry_warning_hours: 6,
+            unauthorized_threshold: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThinkingMode {
+    /// Enable thinking even if the client didn't ask for it
+    Force,
+    /// Strip the thinking parameter from every request
+    Deny,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThinkingPolicy {
+    pub mode: ThinkingMode,
+    /// Budget used when forcing thinking on (defaults to 16000)
+    #[serde(default)]
+    pub budget_tokens: Option<i32>,
+}
+
+/// Thinking policies keyed by model (nickname, full name or "*") and by client key fingerprint
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ThinkingConfig {
+    #[serde(default)]
+    pub models: HashMap<String, ThinkingPolicy>,
+    #[serde(default)]
+    pub keys: HashMap<String, ThinkingPolicy>,
+}
+
+/// Adjustments applied to every request's parameters before it is sent upstream
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct SanitizationConfig {
+    /// Replaces the built-in rules when set
+    #[serde(default)]
+    pub rules: Option<Vec<SanitizeRule>>,
+}
+
+/// Concurrency limit and scheduling of requests forwarded upstream
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct AdmissionConfig {
+    /// Requests forwarded upstream at once; 0 means unlimited
+    pub max_concurrent: u64,
+    /// Requests allowed to wait for a slot before new ones get 429; 0 means unbounded
+    pub max_queue_depth: u64,
+    /// Seconds a request may wait for a slot before it gets 429; 0 means no limit
+    pub max_wait_secs: u64,
+    /// Priority of keys without an entry in `priorities`
+    pub default_priority: Priority,
+    /// Priority classes keyed by client key fingerprint
+    pub priorities: HashMap<String, Priority>,
+    /// Fingerprints of client keys allowed to raise their priority with X-Maximize-Priority
+    pub override_keys: Vec<String>,
+}
+
+/// Load shedding: which requests to reject once the proxy is overloaded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OverloadConfig {
+    /// Requests in flight above which shedding starts; 0 disables the check
+    pub max_in_flight: u64,
+    /// Resident memory in MiB above which shedding starts; 0 disables the check
+    pub max_memory_mb: u64,
+    /// Priority classes rejected while overloaded
+    pub shed_priorities: Vec<Priority>,
+    /// Also reject non-streaming requests while overloaded
+    pub shed_non_streaming: bool,
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 0,
+            max_memory_mb: 0,
+            shed_priorities: vec![Priority::Batch],
+            shed_non_streaming: false,
+        }
+    }
+}
+
+/// How the anthropic-ratelimit headers of OAuth responses tune the admission queue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Lower the concurrency limit as the default account runs out of capacity
+    pub auto_throttle: bool,
+    /// Remaining share of the tightest rate limit window below which throttling starts
+    pub throttle_below: f64,
+    /// Concurrency limit once a window is exhausted
+    pub min_concurrent: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            auto_throttle: true,
+            throttle_below: 0.1,
+            min_concurrent: 1,
+        }
+    }
+}
+
+/// Token quota of one quota window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaWindow {
+    /// Input plus output tokens allowed per window
+    pub tokens: u64,
+    /// Cron-like `minute hour * * weekday` reset time in UTC; defaults to midnight (Mondays for weekly)
+    #[serde(default)]
+    pub reset: Option<String>,
+}
+
+/// Token quotas for one client key, keyed by key fingerprint in `Config::quotas`
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct KeyQuota {
+    /// Label shown in /usage and quota errors
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub daily: Option<QuotaWindow>,
+    #[serde(default)]
+    pub weekly: Option<QuotaWindow>,
+}
+
+/// Gradual rollout of a new model for requests to a nickname or model, keyed by that
+/// name in `Config::canaries`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanaryConfig {
+    /// Model (nickname or name) the canary share of requests goes to
+    pub model: String,
+    /// Share of requests sent to the canary model, 0-100
+    pub percent: f64,
+}
+
+/// The side of a canary rollout a request was assigned to
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryArm {
+    /// Nickname or model the rollout is configured for
+    pub rollout: String,
+    /// Whether the request got the canary model rather than the stable one
+    pub canary: bool,
+    /// Resolved model name the request is sent with
+    pub model: String,
+}
+
+impl CanaryArm {
+    pub fn label(&self) -> &'static str {
+        if self.canary {
+            "canary"
+        } else {
+            "stable"
+        }
+    }
+}
+
+/// One system prompt variant of an experiment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptVariant {
+    pub name: String,
+    /// Text prepended to the client's system prompt; none leaves it unchanged (a control)
+    #[serde(default)]
+    pub system: Option<String>,
+    /// Share of traffic relative to the other variants
+    #[serde(default = "default_variant_weight")]
+    pub weight: u32,
+}
+
+fn default_variant_weight() -> u32 {
+    1
+}
+
+/// A/B experiment splitting traffic between system prompt variants
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptExperiment {
+    pub name: String,
+    pub variants: Vec<PromptVariant>,
+}
+
+/// The prompt experiment variant a request was assigned to
+#[derive(Debug, Clone, Serialize)]
+pub struct ExperimentArm {
+    pub experiment: String,
+    pub variant: String,
+    #[serde(skip)]
+    pub system: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Config {
     pub server: ServerConfig,
     pub models: ModelConfig,
     pub api: ApiConfig,
     pub storage: StorageConfig,
+    pub oauth: OAuthConfig,
+    pub accounts: AccountsConfig,
+    pub tenants: HashMap<String, TenantConfig>,
+    pub scripting: ScriptingConfig,
+    pub admin: AdminConfig,
+    pub cache: CacheConfig,
+    pub thinking: ThinkingConfig,
+    pub sanitization: SanitizationConfig,
+    pub images: ImageConfig,
+    pub tools: ToolConfig,
+    pub compaction: CompactionConfig,
+    pub sessions: SessionConfig,
+    pub metadata: MetadataConfig,
+    pub logging: LoggingConfig,
+    pub alerts: AlertConfig,
+    pub quotas: HashMap<String, KeyQuota>,
+    pub admission: AdmissionConfig,
+    pub overload: OverloadConfig,
+    pub rate_limits: RateLimitConfig,
+    pub streaming: StreamingConfig,
+    pub anthropic_api: AnthropicApiConfig,
+    pub bedrock: BedrockConfig,
+    pub vertex: VertexConfig,
+    pub routing: RoutingConfig,
+    pub shadow: ShadowConfig,
+    pub canaries: HashMap<String, CanaryConfig>,
+    pub prompt_experiment: Option<PromptExperiment>,
+    pub presets: PresetConfig,
+    pub guardrails: GuardrailConfig,
+    pub moderation: ModerationConfig,
+    pub pricing: PricingConfig,
 }
 
 #[derive(Debug, Clone)]
@@ -75,16 +854,98 @@ pub struct Settings {
     pub port: u16,
     pub log_level: String,
     pub bind_address: String,
+    pub startup_self_test: bool,
+    pub discovery_file: Option<String>,
+    pub port_fallback: u16,
     pub default_model: String,
+    /// Models clients may ask for; empty allows any
+    pub allowed_models: Vec<String>,
+    pub echo_requested_model: bool,
     pub request_timeout: u64,
+    pub max_request_timeout: u64,
+    pub connect_timeout_ms: u64,
+    pub tcp_keepalive_secs: u64,
+    pub dns_strategy: DnsStrategy,
+    pub dns_overrides: HashMap<String, Vec<IpAddr>>,
+    /// Serve /v1/messages and /api/v1/messages in raw passthrough mode (see `passthrough`)
+    pub passthrough: bool,
+    pub stream_body_min_kb: u64,
+    pub validation: ValidationMode,
+    pub service_tiers: HashMap<String, String>,
     pub token_file: String,
+    pub oauth: OAuthConfig,
+    pub accounts: AccountsConfig,
+    /// Tenants keyed by name; their keys are included in `api_keys`
+    pub tenants: HashMap<String, TenantConfig>,
     pub model_map: HashMap<String, String>,
-    pub api_key: Option<String>,
+    pub api_keys: Vec<String>,
+    pub transform_script: Option<String>,
+    pub wasm_filters: Vec<String>,
+    pub history_size: usize,
+    pub auto_prompt_cache: bool,
+    pub auto_cache_min_chars: usize,
+    pub thinking: ThinkingConfig,
+    pub sanitize_rules: Vec<SanitizeRule>,
+    pub context_windows: HashMap<String, u64>,
+    pub output_limits: HashMap<String, u64>,
+    pub extended_output_betas: HashMap<String, String>,
+    pub image_limits: ImageLimits,
+    pub tool_limits: ToolLimits,
+    pub tool_allowlists: HashMap<String, Vec<String>>,
+    /// Conversation compaction, when enabled
+    pub compaction: Option<CompactionSettings>,
+    pub sessions_enabled: bool,
+    pub session_backend: String,
+    pub session_db: String,
+    pub session_ttl: u64,
+    pub inject_user_id: bool,
+    pub user_id_salt: String,
+    pub redact_defaults: bool,
+    pub redaction_patterns: Vec<String>,
+    pub body_logging: BodyLogging,
+    pub debug_keys: Vec<String>,
+    pub log_sample_every: u64,
+    /// Alert destinations; alerting is off when empty
+    pub alert_webhooks: Vec<AlertWebhook>,
+    pub alert_expiry_warning_hours: u64,
+    pub alert_unauthorized_threshold: u64,
+    /// Token quotas keyed by client key fingerprint
+    pub quotas: HashMap<String, KeyQuota>,
+    /// Requests forwarded upstream at once; 0 means unlimited
+    pub max_concurrent_requests: usize,
+    pub max_queue_depth: usize,
+    /// Longest a request may wait for a slot; None waits indefinitely
+    pub max_queue_wait: Option<Duration>,
+    pub default_priority: Priority,
+    pub key_priorities: HashMap<String, Priority>,
+    pub priority_override_keys: Vec<String>,
+    pub overload: OverloadPolicy,
+    pub rate_limits: RateLimitConfig,
+    pub normalize_sse: bool,
+    pub repair_tool_input: bool,
+    pub anthropic_api: AnthropicApiConfig,
+    pub bedrock: BedrockConfig,
+    pub vertex: VertexConfig,
+    pub routing: RoutingConfig,
+    pub shadow: ShadowConfig,
+    /// Canary rollouts keyed by the nickname or model they apply to
+    pub canaries: HashMap<String, CanaryConfig>,
+    pub prompt_experiment: Option<PromptExperiment>,
+    pub presets: PresetConfig,
+    pub guardrails: GuardrailConfig,
+    pub moderation: ModerationConfig,
+    /// Prices of the known models and configured additions, keyed by full model name
+    pub prices: HashMap<String, ModelPrice>,
+    /// Highest worst-case cost in USD per request; 0 disables the check
+    pub max_request_cost: f64,
 }
 
 impl Settings {
     pub fn load() -> anyhow::Result<Self> {
-        let config = crate::config_loader::ConfigLoader::load()?;
+        Ok(Self::from_config(&crate::config_loader::ConfigLoader::load()?))
+    }
+
+    pub fn from_config(config: &Config) -> Self {
 
         // Create model nickname mapping
         let mut model_map = HashMap::new();
@@ -95,28 +956,347 @@ impl Settings {
         model_map.insert("xl".to_string(), "claude-opus-4-20250514".to_string());
         model_map.insert("xxl".to_string(), "claude-opus-4-1-20250805".to_string());
 
-        // Load API key from environment
-        let api_key = std::env::var("MAXIMIZE_API_KEY").ok();
+        // Undated ids used by OpenRouter and LiteLLM, each pinned to one snapshot:
+        // claude-3.5-sonnet is the 20241022 upgrade, not the original 20240620 release
+        for (alias, model) in [
+            ("claude-3.5-haiku", "claude-3-5-haiku-20241022"),
+            ("claude-3.5-sonnet", "claude-3-5-sonnet-20241022"),
+            ("claude-3.7-sonnet", "claude-3-7-sonnet-20250219"),
+            ("claude-sonnet-4", "claude-sonnet-4-20250514"),
+            ("claude-opus-4", "claude-opus-4-20250514"),
+            ("claude-opus-4.1", "claude-opus-4-1-20250805"),
+        ] {
+            model_map.insert(alias.to_string(), model.to_string());
+        }
+
+        // Context windows of the known models, overridable from config
+        let mut context_windows: HashMap<String, u64> = model_map
+            .values()
+            .map(|model| (model.clone(), 200_000))
+            .collect();
+        context_windows.extend(config.models.context_windows.clone());
+
+        // Standard output limits of the known models and the betas raising them
+        let mut output_limits: HashMap<String, u64> = [
+            ("claude-3-5-haiku-20241022", 8_192),
+            ("claude-3-5-sonnet-20241022", 8_192),
+            ("claude-3-7-sonnet-20250219", 64_000),
+            ("claude-sonnet-4-20250514", 64_000),
+            ("claude-opus-4-20250514", 32_000),
+            ("claude-opus-4-1-20250805", 32_000),
+        ]
+        .into_iter()
+        .map(|(model, limit)| (model.to_string(), limit))
+        .collect();
+        output_limits.extend(config.models.output_limits.clone());
+        let mut extended_output_betas = HashMap::from([(
+            "claude-3-7-sonnet-20250219".to_string(),
+            "output-128k-2025-02-19".to_string(),
+        )]);
+        extended_output_betas.extend(config.models.extended_output.clone());
+
+        let mut prices = pricing::default_prices();
+        prices.extend(config.pricing.models.clone());
 
-        Ok(Self {
+        // The plain webhook URL is shorthand for a JSON webhook receiving every alert
+        let alert_webhooks: Vec<AlertWebhook> = config
+            .alerts
+            .webhook_url
+            .iter()
+            .map(|url| AlertWebhook {
+                url: url.clone(),
+                format: WebhookFormat::Json,
+                events: Vec::new(),
+            })
+            .chain(config.alerts.webhooks.iter().cloned())
+            .collect();
+
+        Self {
             port: config.server.port,
             log_level: config.server.log_level.clone(),
             bind_address: config.server.bind_address.clone(),
+            startup_self_test: config.server.startup_self_test,
+            discovery_file: config.server.discovery_file.clone(),
+            port_fallback: config.server.port_fallback,
             default_model: config.models.default.clone(),
+            allowed_models: config.models.allowed.clone(),
+            echo_requested_model: config.models.echo_requested,
             request_timeout: config.api.request_timeout,
+            max_request_timeout: config.api.max_request_timeout,
+            connect_timeout_ms: config.api.connect_timeout_ms,
+            tcp_keepalive_secs: config.api.tcp_keepalive_secs,
+            dns_strategy: config.api.dns_strategy,
+            dns_overrides: config.api.dns_overrides.clone(),
+            passthrough: config.api.passthrough,
+            stream_body_min_kb: config.api.stream_body_min_kb,
+            validation: config.api.validation,
+            service_tiers: config.api.service_tiers.clone(),
             token_file: config.storage.token_file.clone(),
+            oauth: config.oauth.clone(),
+            accounts: config.accounts.clone(),
+            tenants: config.tenants.clone(),
             model_map,
-            api_key,
-        })
+            api_keys: config
+                .api
+                .keys
+                .iter()
+                .chain(config.tenants.values().flat_map(|tenant| &tenant.keys))
+                .cloned()
+                .collect(),
+            transform_script: config.scripting.transform_script.clone(),
+            wasm_filters: config.scripting.wasm_filters.clone(),
+            history_size: config.admin.history_size as usize,
+            auto_prompt_cache: config.cache.auto_inject,
+            auto_cache_min_chars: config.cache.min_prefix_chars as usize,
+            thinking: config.thinking.clone(),
+            sanitize_rules: config.sanitization.rules.clone().unwrap_or_else(sanitize::default_rules),
+            context_windows,
+            output_limits,
+            extended_output_betas,
+            image_limits: ImageLimits {
+                max_bytes: config.images.max_bytes as usize,
+                max_dimension: config.images.max_dimension as u32,
+                downscale: config.images.downscale,
+            },
+            tool_limits: ToolLimits {
+                max_tools: config.tools.max_tools as usize,
+                max_schema_bytes: config.tools.max_schema_bytes as usize,
+                max_result_chars: config.tools.max_result_chars as usize,
+                truncate_results: config.tools.truncate_results,
+            },
+            tool_allowlists: config.tools.allowed.clone(),
+            compaction: config.compaction.enabled.then_some(CompactionSettings {
+                max_tokens: config.compaction.max_tokens,
+                keep_recent: config.compaction.keep_recent as usize,
+            }),
+            sessions_enabled: config.sessions.enabled,
+            session_backend: config.sessions.backend.clone(),
+            session_db: config.sessions.sqlite_path.clone(),
+            session_ttl: config.sessions.ttl_seconds,
+            inject_user_id: config.metadata.inject_user_id,
+            user_id_salt: config.metadata.user_id_salt.clone(),
+            redact_defaults: config.logging.redact_defaults,
+            redaction_patterns: config.logging.redaction_patterns.clone(),
+            body_logging: BodyLogging {
+                request_bodies: config.logging.request_bodies,
+                headers: config.logging.headers,
+                response_bodies: config.logging.response_bodies,
+            },
+            debug_keys: config.logging.debug_keys.clone(),
+            log_sample_every: config.logging.sample_every,
+            alert_webhooks,
+            alert_expiry_warning_hours: config.alerts.expiry_warning_hours,
+            alert_unauthorized_threshold: config.alerts.unauthorized_threshold,
+            quotas: config.quotas.clone(),
+            max_concurrent_requests: config.admission.max_concurrent as usize,
+            max_queue_depth: config.admission.max_queue_depth as usize,
+            max_queue_wait: (config.admission.max_wait_secs > 0)
+                .then(|| Duration::from_secs(config.admission.max_wait_secs)),
+            default_priority: config.admission.default_priority,
+            key_priorities: config.admission.priorities.clone(),
+            priority_override_keys: config.admission.override_keys.clone(),
+            normalize_sse: config.streaming.normalize_events,
+            repair_tool_input: config.streaming.repair_tool_input,
+            anthropic_api: config.anthropic_api.clone(),
+            bedrock: config.bedrock.clone(),
+            vertex: config.vertex.clone(),
+            routing: config.routing.clone(),
+            shadow: config.shadow.clone(),
+            canaries: config.canaries.clone(),
+            prompt_experiment: config.prompt_experiment.clone(),
+            presets: config.presets.clone(),
+            guardrails: config.guardrails.clone(),
+            moderation: config.moderation.clone(),
+            prices,
+            max_request_cost: config.pricing.max_request_cost,
+            overload: OverloadPolicy {
+                max_in_flight: config.overload.max_in_flight as usize,
+                max_memory_mb: config.overload.max_memory_mb,
+                shed_priorities: config.overload.shed_priorities.clone(),
+                shed_non_streaming: config.overload.shed_non_streaming,
+            },
+            rate_limits: config.rate_limits.clone(),
+        }
     }
 
+    /// Full model name for a nickname, alias or gateway-style id ("anthropic/claude-sonnet-4")
     pub fn resolve_model(&self, nickname: &str) -> String {
+        let nickname = nickname.strip_prefix("anthropic/").unwrap_or(nickname);
         self.model_map
             .get(nickname)
             .cloned()
             .unwrap_or_else(|| nickname.to_string())
     }
 
+    /// Context window size in tokens, if known for this model
+    pub fn context_window(&self, model: &str) -> Option<u64> {
+        self.context_windows.get(model).copied()
+    }
+
+    pub fn output_limit(&self, model: &str) -> Option<u64> {
+        self.output_limits.get(model).copied()
+    }
+
+    pub fn price(&self, model: &str) -> Option<&ModelPrice> {
+        self.prices.get(model)
+    }
+
+    pub fn extended_output_beta(&self, model: &str) -> Option<&str> {
+        self.extended_output_betas.get(model).map(String::as_str).filter(|b| !b.is_empty())
+    }
+
+    /// Thinking policy for a request: a key-specific policy wins over a model policy,
+    /// which wins over the "*" wildcard
+    pub fn thinking_policy(&self, model: &str, key_id: Option<&str>) -> Option<&ThinkingPolicy> {
+        if let Some(policy) = key_id.and_then(|k| self.thinking.keys.get(k)) {
+            return Some(policy);
+        }
+
+        let resolved = self.resolve_model(model);
+        self.thinking
+            .models
+            .get(model)
+            .or_else(|| {
+                self.thinking
+                    .models
+                    .iter()
+                    .find(|(name, _)| *name != "*" && self.resolve_model(name) == resolved)
+                    .map(|(_, policy)| policy)
+            })
+            .or_else(|| self.thinking.models.get("*"))
+    }
+
+    /// Name of the preset system prompt for a request: the route's wins over the key's
+    pub fn preset_name(&self, route: Option<&str>, key_id: Option<&str>) -> Option<&str> {
+        let name = route
+            .and_then(|r| self.presets.routes.get(r))
+            .or_else(|| key_id.and_then(|k| self.presets.keys.get(k)))
+            .or_else(|| self.presets.keys.get("*"))?;
+        self.presets.prompts.contains_key(name).then_some(name.as_str())
+    }
+
+    /// Tool names a client key may declare; None allows any
+    pub fn allowed_tools(&self, key_id: Option<&str>) -> Option<&[String]> {
+        key_id
+            .and_then(|k| self.tool_allowlists.get(k))
+            .or_else(|| self.tool_allowlists.get("*"))
+            .map(Vec::as_slice)
+    }
+
+    /// Service tier for requests of a client key that don't ask for one
+    pub fn service_tier(&self, key_id: Option<&str>) -> Option<&str> {
+        key_id
+            .and_then(|k| self.service_tiers.get(k))
+            .or_else(|| self.service_tiers.get("*"))
+            .map(String::as_str)
+            .filter(|tier| !tier.is_empty())
+    }
+
+    /// Whether a client key may have its requests served by the named account
+    pub fn account_allowed(&self, key_id: Option<&str>, account: &str) -> bool {
+        key_id
+            .and_then(|k| self.accounts.keys.get(k))
+            .or_else(|| self.accounts.keys.get("*"))
+            .is_some_and(|allowed| allowed.iter().any(|a| a == account || a == "*"))
+    }
+
+    /// Admission priority of a request. Any key may lower its priority for a call;
+    /// only keys in `priority_override_keys` may raise it.
+    pub fn priority(&self, key_id: Option<&str>, requested: Option<Priority>) -> Priority {
+        let assigned = key_id
+            .and_then(|k| self.key_priorities.get(k))
+            .copied()
+            .unwrap_or(self.default_priority);
+
+        match requested {
+            Some(requested) if requested >= assigned => requested,
+            Some(requested) if key_id.is_some_and(|k| self.priority_override_keys.iter().any(|o| o == k)) => requested,
+            _ => assigned,
+        }
+    }
+
+    /// Whether clients may ask for this model (nickname or name)
+    pub fn model_allowed(&self, model: &str) -> bool {
+        let resolved = self.resolve_model(model);
+        self.allowed_models.is_empty() || self.allowed_models.iter().any(|m| self.resolve_model(m) == resolved)
+    }
+
+    /// Assign a request for `model` (as the client named it) to an arm of the canary rollout
+    /// configured for it, if any
+    pub fn canary_arm(&self, model: &str) -> Option<CanaryArm> {
+        let resolved = self.resolve_model(model);
+        let (rollout, canary) = self
+            .canaries
+            .get_key_value(model)
+            .or_else(|| self.canaries.iter().find(|(name, _)| self.resolve_model(name) == resolved))?;
+
+        let on_canary = rand::thread_rng().gen_range(0.0..100.0) < canary.percent;
+        Some(CanaryArm {
+            rollout: rollout.clone(),
+            canary: on_canary,
+            model: if on_canary { self.resolve_model(&canary.model) } else { resolved },
+        })
+    }
+
+    /// Assign a request to a variant of the prompt experiment, by weight
+    pub fn experiment_arm(&self) -> Option<ExperimentArm> {
+        let experiment = self.prompt_experiment.as_ref()?;
+        let total: u32 = experiment.variants.iter().map(|v| v.weight).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        let variant = experiment.variants.iter().find(|v| {
+            if pick < v.weight {
+                return true;
+            }
+            pick -= v.weight;
+            false
+        })?;
+        Some(ExperimentArm {
+            experiment: experiment.name.clone(),
+            variant: variant.name.clone(),
+            system: variant.system.clone(),
+        })
+    }
+
+    /// Upstreams a request to an already resolved model may go to. `quota_remaining` is
+    /// the fraction left in the key's fullest quota window, if it has a quota.
+    pub fn routing_targets(
+        &self,
+        model: &str,
+        key_id: Option<&str>,
+        priority: Priority,
+        quota_remaining: Option<f64>,
+    ) -> Vec<RoutingTarget> {
+        let matches_model = |name: &String| self.resolve_model(name) == model;
+        let rule = self.routing.rules.iter().find(|rule| {
+            (rule.models.is_empty() || rule.models.iter().any(matches_model))
+                && (rule.keys.is_empty() || key_id.is_some_and(|k| rule.keys.iter().any(|r| r == k)))
+                && (rule.priorities.is_empty() || rule.priorities.contains(&priority))
+                && rule
+                    .quota_remaining_below
+                    .is_none_or(|below| quota_remaining.is_some_and(|left| left < below))
+        });
+        if let Some(rule) = rule.filter(|rule| !rule.targets.is_empty()) {
+            return rule.targets.clone();
+        }
+
+        if let Some(upstream) = key_id.and_then(|k| self.routing.keys.get(k)) {
+            return vec![(*upstream).into()];
+        }
+        let upstream = self
+            .routing
+            .models
+            .iter()
+            .find(|(name, _)| matches_model(name))
+            .or_else(|| self.routing.models.get_key_value("*"))
+            .map(|(_, upstream)| *upstream)
+            .unwrap_or_default();
+        vec![upstream.into()]
+    }
+
     // Constants (not user configurable)
     pub fn anthropic_version() -> &'static str {
         "2023-06-01"
@@ -137,16 +1317,27 @@ impl Settings {
     pub fn auth_base_token() -> &'static str {
         "https://console.anthropic.com"
     }
+}
 
-    pub fn client_id() -> &'static str {
-        "9d1c250a-e61b-44d9-88ed-5944d1962f5e"
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn redirect_uri() -> &'static str {
-        "https://console.anthropic.com/oauth/code/callback"
+    fn policy(mode: ThinkingMode) -> ThinkingPolicy {
+        ThinkingPolicy { mode, budget_tokens: None }
     }
 
-    pub fn scopes() -> &'static str {
-        "org:create_api_key user:profile user:inference"
+    #[test]
+    fn thinking_policy_keyed_by_nickname_matches_resolved_model() {
+        let mut config = Config::default();
+        config.thinking.models.insert("xl".to_string(), policy(ThinkingMode::Force));
+        config.thinking.models.insert("*".to_string(), policy(ThinkingMode::Deny));
+        let settings = Settings::from_config(&config);
+
+        // The pipeline looks policies up with the model already resolved
+        let resolved = settings.resolve_model("xl");
+        assert_eq!(settings.thinking_policy(&resolved, None).map(|p| p.mode), Some(ThinkingMode::Force));
+        assert_eq!(settings.thinking_policy("xl", None).map(|p| p.mode), Some(ThinkingMode::Force));
+        assert_eq!(settings.thinking_policy("l", None).map(|p| p.mode), Some(ThinkingMode::Deny));
     }
 }
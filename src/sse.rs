@@ -0,0 +1,397 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tracing::warn;
+
+/// One server-sent event
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+impl SseEvent {
+    /// Parse the data payload as JSON
+    pub fn json(&self) -> Option<Value> {
+        serde_json::from_str(&self.data).ok()
+    }
+
+    /// The event as one complete frame: `event:` and `data:` lines ending in a blank line
+    pub fn to_frame(&self) -> String {
+        let mut frame = String::new();
+        if let Some(event) = &self.event {
+            frame.push_str(&format!("event: {}\n", event));
+        }
+        for line in self.data.split('\n') {
+            frame.push_str(&format!("data: {}\n", line));
+        }
+        frame.push('\n');
+        frame
+    }
+}
+
+/// Wire format of a streamed response sent to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamFormat {
+    /// Server-sent events, as sent by Anthropic
+    #[default]
+    Sse,
+    /// One JSON object per line: each event's data payload
+    Ndjson,
+}
+
+impl StreamFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            StreamFormat::Sse => "text/event-stream",
+            StreamFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// One complete event in this format
+    pub fn encode(&self, event: &SseEvent) -> String {
+        match self {
+            StreamFormat::Sse => event.to_frame(),
+            StreamFormat::Ndjson => {
+                // Anthropic's event data is JSON carrying its own "type"; wrap anything else
+                let line = event
+                    .json()
+                    .unwrap_or_else(|| json!({"type": event.event, "data": event.data}));
+                format!("{}\n", line)
+            }
+        }
+    }
+}
+
+/// Incremental SSE parser: feed raw chunks, get back complete events.
+/// Chunks may split events (or lines) at arbitrary byte positions.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+
+        while let Some((end, separator_len)) = find_event_end(&self.buffer) {
+            let raw: Vec<u8> = self.buffer.drain(..end + separator_len).collect();
+            if let Some(event) = parse_event(&String::from_utf8_lossy(&raw[..end])) {
+                events.push(event);
+            }
+        }
+
+        events
+    }
+}
+
+/// Position of the blank line terminating the first event, and the separator length
+fn find_event_end(buffer: &[u8]) -> Option<(usize, usize)> {
+    (0..buffer.len()).find_map(|i| {
+        if buffer[i..].starts_with(b"\r\n\r\n") {
+            Some((i, 4))
+        } else if buffer[i..].starts_with(b"\n\n") {
+            Some((i, 2))
+        } else {
+            None
+        }
+    })
+}
+
+fn parse_event(raw: &str) -> Option<SseEvent> {
+    let mut event = SseEvent::default();
+    let mut data_lines = Vec::new();
+
+    for line in raw.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            event.event = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data_lines.push(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+
+    if event.event.is_none() && data_lines.is_empty() {
+        return None;
+    }
+
+    event.data = data_lines.join("\n");
+    Some(event)
+}
+
+/// Rebuilds the final assistant message from a Messages API event stream
+#[derive(Debug, Default)]
+pub struct MessageAssembler {
+    message: Option<Value>,
+    blocks: Vec<Value>,
+    /// Raw partial JSON of tool_use inputs, per block index
+    partial_inputs: Vec<String>,
+    complete: bool,
+}
+
+impl MessageAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, event: &SseEvent) {
+        let Some(data) = event.json() else {
+            return;
+        };
+
+        match data.get("type").and_then(|t| t.as_str()) {
+            Some("message_start") => {
+                self.message = data.get("message").cloned();
+            }
+            Some("content_block_start") => {
+                let index = data.get("index").and_then(|i| i.as_u64()).unwrap_or(0) as usize;
+                if self.blocks.len() <= index {
+                    self.blocks.resize(index + 1, Value::Null);
+                    self.partial_inputs.resize(index + 1, String::new());
+                }
+                self.blocks[index] = data.get("content_block").cloned().unwrap_or(Value::Null);
+            }
+            Some("content_block_delta") => {
+                let index = data.get("index").and_then(|i| i.as_u64()).unwrap_or(0) as usize;
+                let (Some(block), Some(delta)) = (self.blocks.get_mut(index), data.get("delta")) else {
+                    return;
+                };
+                match delta.get("type").and_then(|t| t.as_str()) {
+                    Some("text_delta") => append_str(block, "text", delta.get("text")),
+                    Some("thinking_delta") => append_str(block, "thinking", delta.get("thinking")),
+                    Some("signature_delta") => append_str(block, "signature", delta.get("signature")),
+                    Some("input_json_delta") => {
+                        if let Some(partial) = delta.get("partial_json").and_then(|p| p.as_str()) {
+                            self.partial_inputs[index].push_str(partial);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Some("content_block_stop") => {
+                let index = data.get("index").and_then(|i| i.as_u64()).unwrap_or(0) as usize;
+                if let (Some(block), Some(partial)) = (self.blocks.get_mut(index), self.partial_inputs.get(index)) {
+                    if !partial.is_empty() {
+                        block["input"] = serde_json::from_str(partial).unwrap_or_else(|_| json!({}));
+                    }
+                }
+            }
+            Some("message_delta") => {
+                if let (Some(message), Some(delta)) = (self.message.as_mut(), data.get("delta")) {
+                    if let Some(stop_reason) = delta.get("stop_reason") {
+                        message["stop_reason"] = stop_reason.clone();
+                    }
+                }
+            }
+            Some("message_stop") => {
+                self.complete = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the stream reached message_stop
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// The assembled message in non-streaming response shape
+    pub fn message(&self) -> Value {
+        let mut message = self.message.clone().unwrap_or_else(|| json!({"role": "assistant"}));
+        message["content"] = Value::Array(self.blocks.iter().filter(|b| !b.is_null()).cloned().collect());
+        message
+    }
+}
+
+fn append_str(block: &mut Value, field: &str, value: Option<&Value>) {
+    let Some(addition) = value.and_then(|v| v.as_str()) else {
+        return;
+    };
+    let current = block.get(field).and_then(|v| v.as_str()).unwrap_or_default();
+    block[field] = Value::String(format!("{}{}", current, addition));
+}
+
+/// Holds back a tool call's `input_json_delta` fragments and emits its input as one
+/// delta of valid JSON right before the block stops, repairing truncated JSON
+#[derive(Debug, Default)]
+pub struct ToolInputRepair {
+    /// Buffered partial JSON of tool_use blocks, per block index
+    pending: HashMap<u64, String>,
+}
+
+impl ToolInputRepair {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The events to send in place of this one
+    pub fn process(&mut self, event: SseEvent) -> Vec<SseEvent> {
+        let Some(data) = event.json() else {
+            return vec![event];
+        };
+        let index = data.get("index").and_then(|i| i.as_u64()).unwrap_or(0);
+
+        match data.get("type").and_then(|t| t.as_str()) {
+            Some("content_block_start") => {
+                let block_type = data.pointer("/content_block/type").and_then(|t| t.as_str());
+                if matches!(block_type, Some("tool_use") | Some("server_tool_use")) {
+                    self.pending.insert(index, String::new());
+                }
+                vec![event]
+            }
+            Some("content_block_delta") if self.pending.contains_key(&index) => {
+                if data.pointer("/delta/type").and_then(|t| t.as_str()) != Some("input_json_delta") {
+                    return vec![event];
+                }
+                if let (Some(partial), Some(buffer)) = (
+                    data.pointer("/delta/partial_json").and_then(|p| p.as_str()),
+                    self.pending.get_mut(&index),
+                ) {
+                    buffer.push_str(partial);
+                }
+                Vec::new()
+            }
+            Some("content_block_stop") => {
+                let Some(partial) = self.pending.remove(&index) else {
+                    return vec![event];
+                };
+                if partial.is_empty() {
+                    return vec![event];
+                }
+                let input = repair_json(&partial).unwrap_or_else(|| {
+                    warn!("Replacing unrepairable tool input with {{}}: {}", partial);
+                    "{}".to_string()
+                });
+                let delta = SseEvent {
+                    event: Some("content_block_delta".to_string()),
+                    data: json!({
+                        "type": "content_block_delta",
+                        "index": index,
+                        "delta": {"type": "input_json_delta", "partial_json": input}
+                    })
+                    .to_string(),
+                };
+                vec![delta, event]
+            }
+            _ => vec![event],
+        }
+    }
+}
+
+/// Valid JSON from possibly truncated JSON: drops a dangling escape, key or separator
+/// and closes open strings, objects and lists. None if that isn't enough.
+pub fn repair_json(raw: &str) -> Option<String> {
+    if serde_json::from_str::<Value>(raw).is_ok() {
+        return Some(raw.to_string());
+    }
+
+    let mut repaired = String::with_capacity(raw.len() + 8);
+    let mut in_string = false;
+    let mut chars = raw.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_string {
+            match c {
+                '"' => in_string = false,
+                '\\' => {
+                    // A complete escape or nothing: \uXXXX needs its four hex digits
+                    let Some(&next) = chars.peek() else {
+                        break;
+                    };
+                    if next == 'u' {
+                        let escape: String = chars.clone().skip(1).take(4).collect();
+                        if escape.len() < 4 || !escape.chars().all(|h| h.is_ascii_hexdigit()) {
+                            break;
+                        }
+                    }
+                    repaired.push(c);
+                    repaired.push(next);
+                    chars.next();
+                    continue;
+                }
+                // Raw control characters aren't allowed in JSON strings
+                c if c.is_control() => {
+                    repaired.push_str(&format!("\\u{:04x}", c as u32));
+                    continue;
+                }
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+        }
+        repaired.push(c);
+    }
+    if in_string {
+        repaired.push('"');
+    }
+
+    // Drop what can't be completed: a trailing separator, a key without a value or a
+    // truncated literal, then close what's still open
+    loop {
+        let candidate = format!("{}{}", repaired, closing_brackets(&repaired)?);
+        if serde_json::from_str::<Value>(&candidate).is_ok() {
+            return Some(candidate);
+        }
+        let trimmed = repaired.trim_end();
+        let cut = match trimmed.chars().last()? {
+            // A separator, or a number cut off in its fraction or exponent
+            ',' | ':' | '.' | 'e' | 'E' | '+' | '-' => trimmed.len() - 1,
+            // A key without a value
+            '"' => string_start(trimmed)?,
+            _ => trimmed.rfind([',', ':', '{', '[']).map(|i| i + 1)?,
+        };
+        if cut >= repaired.len() {
+            return None;
+        }
+        repaired.truncate(cut);
+    }
+}
+
+/// The brackets closing everything left open, or None if they don't match
+fn closing_brackets(json: &str) -> Option<String> {
+    let mut closers = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in json.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => closers.push('}'),
+            '[' => closers.push(']'),
+            '}' | ']' if closers.pop() != Some(c) => return None,
+            _ => {}
+        }
+    }
+    Some(closers.iter().rev().collect())
+}
+
+/// Byte offset of the opening quote of the string that ends `json`
+fn string_start(json: &str) -> Option<usize> {
+    let mut start = None;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in json.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+            start = Some(i);
+        }
+    }
+    start
+}
@@ -0,0 +1,109 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Windows reported by /stats, with their labels
+const WINDOWS: &[(&str, Duration)] = &[
+    ("1m", Duration::from_secs(60)),
+    ("5m", Duration::from_secs(5 * 60)),
+    ("1h", Duration::from_secs(60 * 60)),
+];
+
+/// Upper bound on retained samples, so a traffic burst can't grow memory without limit
+const MAX_SAMPLES: usize = 100_000;
+
+struct Sample {
+    at: Instant,
+    latency_ms: u64,
+    error: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WindowStats {
+    pub requests: usize,
+    pub errors: usize,
+    pub error_rate: f64,
+    pub requests_per_second: f64,
+    pub p50_ms: Option<u64>,
+    pub p90_ms: Option<u64>,
+    pub p99_ms: Option<u64>,
+}
+
+/// Latency and error samples from the last hour, summarized over rolling windows
+pub struct RollingStats {
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+impl RollingStats {
+    pub fn new() -> Self {
+        Self {
+            samples: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, latency_ms: u64, error: bool) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back(Sample {
+            at: Instant::now(),
+            latency_ms,
+            error,
+        });
+        if samples.len() > MAX_SAMPLES {
+            samples.pop_front();
+        }
+        Self::evict_expired(&mut samples);
+    }
+
+    fn evict_expired(samples: &mut VecDeque<Sample>) {
+        let longest = WINDOWS.iter().map(|(_, d)| *d).max().unwrap_or_default();
+        while samples.front().is_some_and(|s| s.at.elapsed() > longest) {
+            samples.pop_front();
+        }
+    }
+
+    /// Stats for every window, keyed by window label
+    pub fn snapshot(&self) -> Vec<(&'static str, WindowStats)> {
+        let mut samples = self.samples.lock().unwrap();
+        Self::evict_expired(&mut samples);
+
+        WINDOWS
+            .iter()
+            .map(|(label, window)| {
+                let in_window: Vec<&Sample> = samples.iter().filter(|s| s.at.elapsed() <= *window).collect();
+                (*label, summarize(&in_window, *window))
+            })
+            .collect()
+    }
+}
+
+impl Default for RollingStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn summarize(samples: &[&Sample], window: Duration) -> WindowStats {
+    let mut latencies: Vec<u64> = samples.iter().map(|s| s.latency_ms).collect();
+    latencies.sort_unstable();
+    let errors = samples.iter().filter(|s| s.error).count();
+
+    WindowStats {
+        requests: samples.len(),
+        errors,
+        error_rate: if samples.is_empty() { 0.0 } else { errors as f64 / samples.len() as f64 },
+        requests_per_second: samples.len() as f64 / window.as_secs_f64(),
+        p50_ms: percentile(&latencies, 0.50),
+        p90_ms: percentile(&latencies, 0.90),
+        p99_ms: percentile(&latencies, 0.99),
+    }
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[u64], quantile: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
@@ -2,22 +2,43 @@ use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 #[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TokenData {
     pub access_token: String,
     pub refresh_token: String,
     pub expires_at: i64,
+    /// When the refresh token was rejected as revoked; a re-login is required
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<i64>,
+    /// Space-separated scopes granted with the tokens, when the token endpoint reported them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// When the access token was issued or first loaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub obtained_at: Option<i64>,
+}
+
+/// Where the tokens in use were loaded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenSource {
+    /// MAXIMIZE_ACCESS_TOKEN and MAXIMIZE_REFRESH_TOKEN
+    Env,
+    File,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TokenStatus {
     pub has_tokens: bool,
     pub is_expired: bool,
+    /// The refresh token was revoked; tokens can't be renewed until the next login
+    pub is_revoked: bool,
     pub expires_at: Option<String>,
     pub time_until_expiry: String,
     pub expires_in_seconds: Option<i64>,
@@ -63,26 +84,22 @@ impl TokenStorage {
         Ok(())
     }
 
-    pub fn save_tokens(&self, access_token: &str, refresh_token: &str, expires_in: i64) -> Result<()> {
-        let expires_at = Utc::now().timestamp() + expires_in;
+    /// Store new tokens; without a granted scope, the one stored with the previous tokens is kept
+    pub fn save_tokens(&self, access_token: &str, refresh_token: &str, expires_in: i64, scope: Option<&str>) -> Result<()> {
+        let now = Utc::now().timestamp();
+        let scope = match scope {
+            Some(scope) => Some(scope.to_string()),
+            None => self.try_load_from_file().ok().flatten().and_then(|t| t.scope),
+        };
         let data = TokenData {
             access_token: access_token.to_string(),
             refresh_token: refresh_token.to_string(),
-            expires_at,
+            expires_at: now + expires_in,
+            revoked_at: None,
+            scope,
+            obtained_at: Some(now),
         };
-
-        let json = serde_json::to_string_pretty(&data)?;
-        fs::write(&self.token_path, json)?;
-
-        #[cfg(unix)]
-        {
-            let metadata = fs::metadata(&self.token_path)?;
-            let mut permissions = metadata.permissions();
-            permissions.set_mode(0o600);
-            fs::set_permissions(&self.token_path, permissions)?;
-        }
-
-        Ok(())
+        self.save_token_data(&data)
     }
 
     fn try_load_from_file(&self) -> Result<Option<TokenData>> {
@@ -111,18 +128,31 @@ impl TokenStorage {
         Ok(Some(data))
     }
 
+    /// Write the tokens to a private temporary file and rename it over the token file, so
+    /// readers see either the old tokens or the new ones, never a partial write
     fn save_token_data(&self, data: &TokenData) -> Result<()> {
         let json = serde_json::to_string_pretty(data)?;
-        fs::write(&self.token_path, json)?;
-
+        let file_name = self
+            .token_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "tokens.json".to_string());
+        let temp_path = self
+            .token_path
+            .with_file_name(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4().simple()));
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
         #[cfg(unix)]
-        {
-            let metadata = fs::metadata(&self.token_path)?;
-            let mut permissions = metadata.permissions();
-            permissions.set_mode(0o600);
-            fs::set_permissions(&self.token_path, permissions)?;
+        options.mode(0o600);
+        let written = options.open(&temp_path).and_then(|mut file| {
+            file.write_all(json.as_bytes())?;
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&temp_path, &self.token_path)) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e).context(format!("Failed to save tokens to {}", self.token_path.display()));
         }
-
         Ok(())
     }
 
@@ -164,6 +194,9 @@ impl TokenStorage {
                     access_token,
                     refresh_token,
                     expires_at,
+                    revoked_at: None,
+                    scope: None,
+                    obtained_at: Some(now),
                 };
                 
                 // Save to file to persist expiry time
@@ -183,6 +216,34 @@ impl TokenStorage {
         self.try_load_from_file()
     }
 
+    /// Whether load_tokens takes the tokens from the environment or the token file
+    pub fn source(&self) -> TokenSource {
+        let populated = |name: &str| std::env::var(name).is_ok_and(|v| !v.trim().is_empty());
+        if populated("MAXIMIZE_ACCESS_TOKEN") && populated("MAXIMIZE_REFRESH_TOKEN") {
+            TokenSource::Env
+        } else {
+            TokenSource::File
+        }
+    }
+
+    /// Quarantine the stored tokens after their refresh token was revoked. Tokens saved
+    /// since, by a refresh or login that rotated it, are left alone; returns whether the
+    /// rejected token was still the stored one.
+    pub fn mark_revoked(&self, rejected_refresh_token: &str) -> Result<bool> {
+        match self.load_tokens()? {
+            Some(mut data) if data.refresh_token == rejected_refresh_token => {
+                data.revoked_at = Some(Utc::now().timestamp());
+                self.save_token_data(&data)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        matches!(self.load_tokens(), Ok(Some(tokens)) if tokens.revoked_at.is_some())
+    }
+
     pub fn clear_tokens(&self) -> Result<()> {
         if self.token_path.exists() {
             fs::remove_file(&self.token_path)?;
@@ -240,6 +301,7 @@ impl TokenStorage {
                     TokenStatus {
                         has_tokens: true,
                         is_expired: true,
+                        is_revoked: tokens.revoked_at.is_some(),
                         expires_at,
                         time_until_expiry: time_str,
                         expires_in_seconds: None,
@@ -258,6 +320,7 @@ impl TokenStorage {
                     TokenStatus {
                         has_tokens: true,
                         is_expired: false,
+                        is_revoked: tokens.revoked_at.is_some(),
                         expires_at,
                         time_until_expiry: time_str,
                         expires_in_seconds: Some(time_remaining),
@@ -267,6 +330,7 @@ impl TokenStorage {
             _ => TokenStatus {
                 has_tokens: false,
                 is_expired: true,
+                is_revoked: false,
                 expires_at: None,
                 time_until_expiry: "No tokens".to_string(),
                 expires_in_seconds: None,
@@ -278,3 +342,23 @@ impl TokenStorage {
         &self.token_path
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mark_revoked_spares_tokens_saved_since() {
+        let path = std::env::temp_dir().join(format!("maximize-revoked-{}.json", std::process::id()));
+        let storage = TokenStorage::new(path.to_str().unwrap()).unwrap();
+        storage.save_tokens("access-2", "refresh-2", 3600, None).unwrap();
+
+        // A concurrent refresh already rotated refresh-1 into refresh-2
+        assert!(!storage.mark_revoked("refresh-1").unwrap());
+        assert!(!storage.is_revoked());
+
+        assert!(storage.mark_revoked("refresh-2").unwrap());
+        assert!(storage.is_revoked());
+        let _ = fs::remove_file(&path);
+    }
+}
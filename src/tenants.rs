@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::connection;
+use crate::oauth::OAuthManager;
+use crate::proxy::key_fingerprint;
+use crate::settings::{KeyQuota, Settings};
+
+/// An independent user of a shared deployment, with their own Claude account
+pub struct Tenant {
+    pub name: String,
+    /// Token store of the tenant's account; None if it could not be opened
+    pub oauth: Option<Arc<OAuthManager>>,
+    /// Models the tenant may use, by nickname or name; empty defers to the global list
+    pub models: Vec<String>,
+    pub default_model: Option<String>,
+    /// Name the tenant's usage is recorded under
+    pub usage_bucket: String,
+    pub model_aliases: HashMap<String, String>,
+    pub system_prompt: Option<String>,
+    /// anthropic-beta flags the tenant may send; None allows any
+    pub betas: Option<Vec<String>>,
+}
+
+impl Tenant {
+    /// Full model name for a model or nickname, trying the tenant's aliases first
+    pub fn resolve_model(&self, settings: &Settings, model: &str) -> String {
+        let aliased = self.model_aliases.get(model).map(String::as_str).unwrap_or(model);
+        settings.resolve_model(aliased)
+    }
+
+    /// Whether the tenant may use a model, on top of the global allowed list
+    pub fn model_allowed(&self, settings: &Settings, model: &str) -> bool {
+        let resolved = self.resolve_model(settings, model);
+        settings.model_allowed(&resolved)
+            && (self.models.is_empty() || self.models.iter().any(|m| self.resolve_model(settings, m) == resolved))
+    }
+
+    /// Whether the tenant may send an anthropic-beta flag
+    pub fn beta_allowed(&self, beta: &str) -> bool {
+        self.betas.as_ref().is_none_or(|betas| betas.iter().any(|b| b == beta))
+    }
+
+    /// Key under which the tenant's shared quota is tracked
+    pub fn quota_id(name: &str) -> String {
+        format!("tenant:{}", name)
+    }
+}
+
+/// Per-key quotas plus each tenant's shared quota, for the quota tracker
+pub fn quotas_with_tenants(settings: &Settings) -> HashMap<String, KeyQuota> {
+    let mut quotas = settings.quotas.clone();
+    for (name, tenant) in &settings.tenants {
+        if let Some(quota) = &tenant.quota {
+            let mut quota = quota.clone();
+            quota.name.get_or_insert_with(|| name.clone());
+            quotas.insert(Tenant::quota_id(name), quota);
+        }
+    }
+    quotas
+}
+
+/// Tenants by the fingerprints of their client keys
+#[derive(Default)]
+pub struct Tenants {
+    by_key: HashMap<String, Arc<Tenant>>,
+}
+
+impl Tenants {
+    /// Open each tenant's token store and refresh it in the background
+    pub fn open(settings: &Settings) -> Self {
+        let mut by_key = HashMap::new();
+        for (name, config) in &settings.tenants {
+            let oauth = match OAuthManager::new(&config.token_file, settings.oauth.clone(), connection::http_client(settings)) {
+                Ok(manager) => {
+                    let manager = Arc::new(manager);
+                    manager.spawn_background_refresh();
+                    tracing::info!("Tenant '{}': tokens in {}", name, config.token_file);
+                    Some(manager)
+                }
+                Err(e) => {
+                    tracing::error!("Tenant '{}' can't be served, its token store is unavailable: {}", name, e);
+                    None
+                }
+            };
+            let tenant = Arc::new(Tenant {
+                name: name.clone(),
+                oauth,
+                models: config.models.clone(),
+                default_model: config.default_model.clone(),
+                usage_bucket: config.usage_bucket.clone().unwrap_or_else(|| name.clone()),
+                model_aliases: config.model_aliases.clone(),
+                system_prompt: config.system_prompt.clone(),
+                betas: config.betas.clone(),
+            });
+            for key in &config.keys {
+                by_key.insert(key_fingerprint(key), tenant.clone());
+            }
+        }
+        Self { by_key }
+    }
+
+    /// Tenant a client key belongs to, if any
+    pub fn for_key(&self, key_id: Option<&str>) -> Option<&Arc<Tenant>> {
+        self.by_key.get(key_id?)
+    }
+}
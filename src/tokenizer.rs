@@ -0,0 +1,91 @@
+use serde_json::Value;
+
+use crate::proxy::AnthropicMessageRequest;
+
+/// Approximate cost of an image block whose dimensions are unknown
+const IMAGE_TOKENS: u64 = 1600;
+
+/// Fixed framing overhead per message (role markers, separators)
+const MESSAGE_OVERHEAD: u64 = 4;
+
+/// Approximate token count of a piece of text.
+///
+/// This is not Claude's tokenizer; it mimics BPE behaviour closely enough for
+/// pre-flight budget checks: words cost roughly one token per four letters,
+/// digits group in threes, punctuation and non-Latin characters cost one each,
+/// and whitespace folds into the following token.
+pub fn count_text(text: &str) -> u64 {
+    let mut tokens = 0u64;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_ascii_alphabetic() {
+            let mut len = 1u64;
+            while chars.peek().is_some_and(|n| n.is_ascii_alphabetic()) {
+                chars.next();
+                len += 1;
+            }
+            tokens += 1 + (len - 1) / 4;
+        } else if c.is_ascii_digit() {
+            let mut len = 1u64;
+            while chars.peek().is_some_and(|n| n.is_ascii_digit()) {
+                chars.next();
+                len += 1;
+            }
+            tokens += len.div_ceil(3);
+        } else if c.is_whitespace() {
+            // Merged into the next word
+        } else {
+            tokens += 1;
+        }
+    }
+
+    tokens
+}
+
+/// Approximate token count of a single content block
+fn count_block(block: &Value) -> u64 {
+    match block.get("type").and_then(|t| t.as_str()) {
+        Some("text") => block.get("text").and_then(|t| t.as_str()).map(count_text).unwrap_or(0),
+        Some("image") => IMAGE_TOKENS,
+        Some("tool_use") => {
+            let name = block.get("name").and_then(|n| n.as_str()).map(count_text).unwrap_or(0);
+            let input = block.get("input").map(|i| count_text(&i.to_string())).unwrap_or(0);
+            name + input
+        }
+        Some("tool_result") => count_content(block.get("content")),
+        Some("thinking") => block.get("thinking").and_then(|t| t.as_str()).map(count_text).unwrap_or(0),
+        _ => count_text(&block.to_string()),
+    }
+}
+
+/// Approximate token count of a `content` or `system` field (string or block list)
+pub fn count_content(content: Option<&Value>) -> u64 {
+    match content {
+        Some(Value::String(text)) => count_text(text),
+        Some(Value::Array(blocks)) => blocks.iter().map(count_block).sum(),
+        Some(other) => count_text(&other.to_string()),
+        None => 0,
+    }
+}
+
+/// Approximate input tokens of a prompt made of messages, an optional system prompt and tools
+pub fn count_prompt(messages: &[Value], system: Option<&Value>, tools: Option<&[Value]>) -> u64 {
+    let messages_tokens: u64 = messages
+        .iter()
+        .map(|m| MESSAGE_OVERHEAD + count_content(m.get("content")))
+        .sum();
+    let system_tokens = count_content(system);
+    let tools_tokens: u64 = tools
+        .unwrap_or_default()
+        .iter()
+        .map(|t| count_text(&t.to_string()))
+        .sum();
+
+    messages_tokens + system_tokens + tools_tokens
+}
+
+/// Approximate input tokens of a Messages API request
+pub fn count_request(request: &AnthropicMessageRequest) -> u64 {
+    count_prompt(&request.messages, request.system.as_ref(), request.tools.as_deref())
+}
@@ -0,0 +1,315 @@
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::proxy::AnthropicMessageRequest;
+
+/// Beta flags Anthropic requires for server-side tool types. Tools missing here, such
+/// as web_search, are generally available.
+const TOOL_TYPE_BETAS: &[(&str, &str)] = &[
+    ("code_execution_20250522", "code-execution-2025-05-22"),
+    ("code_execution_20250825", "code-execution-2025-08-25"),
+    ("web_fetch_20250910", "web-fetch-2025-09-10"),
+    ("memory_20250818", "context-management-2025-06-27"),
+    // Computer use: the screen tool and the bash and editor tools released with it
+    ("computer_20241022", "computer-use-2024-10-22"),
+    ("bash_20241022", "computer-use-2024-10-22"),
+    ("text_editor_20241022", "computer-use-2024-10-22"),
+    ("computer_20250124", "computer-use-2025-01-24"),
+    ("bash_20250124", "computer-use-2025-01-24"),
+    ("text_editor_20250124", "computer-use-2025-01-24"),
+    ("computer_20251124", "computer-use-2025-11-24"),
+];
+
+/// Limits on tool definitions and tool results; 0 disables a limit
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ToolLimits {
+    pub max_tools: usize,
+    /// Largest serialized tool definition (name, description and input schema)
+    pub max_schema_bytes: usize,
+    /// Most text characters in a single tool_result
+    pub max_result_chars: usize,
+    /// Truncate oversized tool results instead of rejecting the request
+    pub truncate_results: bool,
+}
+
+fn truncation_note(removed: usize) -> String {
+    format!("\n[... {} characters truncated by proxy]", removed)
+}
+
+/// Cut a string to `max` characters, returning how many were removed
+fn truncate_text(text: &mut String, max: usize) -> usize {
+    let Some((cut, _)) = text.char_indices().nth(max) else {
+        return 0;
+    };
+    let removed = text[cut..].chars().count();
+    text.truncate(cut);
+    text.push_str(&truncation_note(removed));
+    removed
+}
+
+/// Characters of text in a tool_result's content, which is a string or a list of blocks
+fn result_chars(content: &Value) -> usize {
+    match content {
+        Value::String(s) => s.chars().count(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|b| b.get("text").and_then(|t| t.as_str()))
+            .map(|t| t.chars().count())
+            .sum(),
+        _ => 0,
+    }
+}
+
+/// Keep the first `max` characters of a tool_result's text, dropping text blocks past
+/// the limit. Other blocks, such as images, are left alone.
+fn truncate_result(content: &mut Value, max: usize) {
+    match content {
+        Value::String(s) => {
+            truncate_text(s, max);
+        }
+        Value::Array(blocks) => {
+            let mut remaining = max;
+            let mut removed = 0;
+            blocks.retain_mut(|block| {
+                let Some(Value::String(text)) = block.get_mut("text") else {
+                    return true;
+                };
+                if remaining == 0 {
+                    removed += text.chars().count();
+                    return false;
+                }
+                let len = text.chars().count();
+                if len <= remaining {
+                    remaining -= len;
+                } else {
+                    removed += truncate_text(text, remaining);
+                    remaining = 0;
+                }
+                true
+            });
+            if removed > 0 && !blocks.iter().any(|b| b.get("text").is_some()) {
+                blocks.push(json!({"type": "text", "text": truncation_note(removed)}));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Check tool definitions and tool results against the configured limits, truncating
+/// results if configured. Returns a client-facing message describing the first violation.
+pub fn enforce_limits(request: &mut AnthropicMessageRequest, limits: &ToolLimits) -> Result<(), String> {
+    if let Some(tools) = &request.tools {
+        if limits.max_tools > 0 && tools.len() > limits.max_tools {
+            return Err(format!("{} tools exceed the limit of {} per request", tools.len(), limits.max_tools));
+        }
+        if limits.max_schema_bytes > 0 {
+            for (index, tool) in tools.iter().enumerate() {
+                let size = serde_json::to_vec(tool).map(|v| v.len()).unwrap_or_default();
+                if size > limits.max_schema_bytes {
+                    let name = tool.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+                    return Err(format!(
+                        "tools.{} ({}): definition of {} bytes exceeds the limit of {} bytes",
+                        index, name, size, limits.max_schema_bytes
+                    ));
+                }
+            }
+        }
+    }
+
+    if limits.max_result_chars == 0 {
+        return Ok(());
+    }
+    for (index, message) in request.messages.iter_mut().enumerate() {
+        let Some(Value::Array(blocks)) = message.get_mut("content") else {
+            continue;
+        };
+        for block in blocks.iter_mut() {
+            if block.get("type").and_then(|t| t.as_str()) != Some("tool_result") {
+                continue;
+            }
+            let Some(content) = block.get_mut("content") else {
+                continue;
+            };
+            let chars = result_chars(content);
+            if chars <= limits.max_result_chars {
+                continue;
+            }
+            if !limits.truncate_results {
+                return Err(format!(
+                    "messages.{}: tool_result of {} characters exceeds the limit of {} characters",
+                    index, chars, limits.max_result_chars
+                ));
+            }
+            debug!("Truncating tool_result of {} characters in messages.{}", chars, index);
+            truncate_result(content, limits.max_result_chars);
+        }
+    }
+    Ok(())
+}
+
+/// Check that computer use tools carry the display size the model needs to act on
+/// screenshots; they and their other parameters are forwarded unchanged
+pub fn validate_computer_tools(request: &AnthropicMessageRequest) -> Result<(), String> {
+    for (index, tool) in request.tools.iter().flatten().enumerate() {
+        let is_computer = tool
+            .get("type")
+            .and_then(|t| t.as_str())
+            .is_some_and(|t| t.starts_with("computer_"));
+        if !is_computer {
+            continue;
+        }
+        for param in ["display_width_px", "display_height_px"] {
+            if tool.get(param).and_then(|v| v.as_u64()).is_none_or(|v| v == 0) {
+                return Err(format!("tools.{}: computer use tool needs a positive integer {}", index, param));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove tools whose names aren't in `allowed` (every tool if it's empty) and point
+/// a tool_choice naming a removed tool back to "auto". Without tools left, only a
+/// tool_choice of "none" is kept. MCP servers are limited to the allowed tools too, and
+/// dropped when that leaves them none. Returns the removed tools' and servers' names.
+pub fn filter_tools(request: &mut AnthropicMessageRequest, allowed: Option<&[String]>) -> Vec<String> {
+    let mut removed = Vec::new();
+    if let (Some(allowed), Some(tools)) = (allowed, request.tools.as_mut()) {
+        tools.retain(|tool| {
+            let name = tool.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+            let keep = allowed.iter().any(|a| a == name);
+            if !keep {
+                removed.push(name.to_string());
+            }
+            keep
+        });
+        if tools.is_empty() {
+            request.tools = None;
+        }
+    }
+    if let (Some(allowed), Some(servers)) = (allowed, request.mcp_servers.as_mut()) {
+        servers.retain_mut(|server| {
+            let keep = limit_mcp_server(server, allowed);
+            if !keep {
+                let name = server.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+                removed.push(format!("{} (MCP server)", name));
+            }
+            keep
+        });
+        if servers.is_empty() {
+            request.mcp_servers = None;
+        }
+    }
+
+    if let Some(choice) = request.tool_choice.as_mut() {
+        let choice_type = choice.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+        if request.tools.is_none() && choice_type != "none" {
+            request.tool_choice = None;
+        } else if choice_type == "tool" {
+            let name = choice.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+            if removed.iter().any(|r| r == name) {
+                choice["type"] = json!("auto");
+                if let Value::Object(map) = choice {
+                    map.remove("name");
+                }
+            }
+        }
+    }
+    removed
+}
+
+/// Narrow an MCP server's `tool_configuration.allowed_tools` to the allowed names, which
+/// all of its tools are when it lists none. Returns whether any tool is left.
+fn limit_mcp_server(server: &mut Value, allowed: &[String]) -> bool {
+    let requested: Option<Vec<String>> = server
+        .pointer("/tool_configuration/allowed_tools")
+        .and_then(|tools| serde_json::from_value(tools.clone()).ok());
+    let tools: Vec<String> = match requested {
+        Some(requested) => requested.into_iter().filter(|name| allowed.contains(name)).collect(),
+        None => allowed.to_vec(),
+    };
+    if tools.is_empty() {
+        return false;
+    }
+
+    let Value::Object(server) = server else {
+        return false;
+    };
+    let configuration = server.entry("tool_configuration").or_insert_with(|| json!({}));
+    if !configuration.is_object() {
+        *configuration = json!({});
+    }
+    configuration["allowed_tools"] = json!(tools);
+    true
+}
+
+/// Beta flags needed by the server-side tools the request declares
+pub fn tool_betas(request: &AnthropicMessageRequest) -> Vec<&'static str> {
+    let mut betas: Vec<&'static str> = request
+        .tools
+        .iter()
+        .flatten()
+        .filter_map(|tool| tool.get("type").and_then(|t| t.as_str()))
+        .filter_map(|tool_type| TOOL_TYPE_BETAS.iter().find(|(t, _)| *t == tool_type).map(|(_, beta)| *beta))
+        .collect();
+    if request.mcp_servers.as_ref().is_some_and(|servers| !servers.is_empty()) {
+        betas.push("mcp-client-2025-04-04");
+    }
+    betas.sort();
+    betas.dedup();
+    betas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(body: Value) -> AnthropicMessageRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn allowlist_limits_mcp_servers() {
+        let mut request = request(json!({
+            "model": "l",
+            "max_tokens": 100,
+            "messages": [{"role": "user", "content": "hi"}],
+            "tools": [{"name": "search", "input_schema": {"type": "object"}}],
+            "mcp_servers": [
+                {"type": "url", "url": "https://a.example/mcp", "name": "open"},
+                {
+                    "type": "url",
+                    "url": "https://b.example/mcp",
+                    "name": "narrowed",
+                    "tool_configuration": {"enabled": true, "allowed_tools": ["search", "delete_repo"]}
+                },
+                {
+                    "type": "url",
+                    "url": "https://c.example/mcp",
+                    "name": "denied",
+                    "tool_configuration": {"allowed_tools": ["delete_repo"]}
+                }
+            ]
+        }));
+        let allowed = vec!["search".to_string()];
+
+        let removed = filter_tools(&mut request, Some(&allowed));
+        assert_eq!(removed, vec!["denied (MCP server)"]);
+        let servers = request.mcp_servers.unwrap();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0]["tool_configuration"], json!({"allowed_tools": ["search"]}));
+        assert_eq!(servers[1]["tool_configuration"], json!({"enabled": true, "allowed_tools": ["search"]}));
+    }
+
+    #[test]
+    fn empty_allowlist_drops_mcp_servers() {
+        let mut request = request(json!({
+            "model": "l",
+            "max_tokens": 100,
+            "messages": [{"role": "user", "content": "hi"}],
+            "mcp_servers": [{"type": "url", "url": "https://a.example/mcp", "name": "open"}]
+        }));
+
+        assert_eq!(filter_tools(&mut request, Some(&[])), vec!["open (MCP server)"]);
+        assert!(request.mcp_servers.is_none());
+    }
+}
@@ -0,0 +1,136 @@
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io;
+
+use crate::bedrock;
+use crate::error;
+
+/// Where a request is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamKind {
+    /// Anthropic's API with the Claude Max OAuth token
+    #[default]
+    Anthropic,
+    /// Anthropic's API with a standard API key
+    ApiKey,
+    /// Anthropic models on AWS Bedrock
+    Bedrock,
+    /// Anthropic models on Google Vertex AI
+    Vertex,
+}
+
+impl UpstreamKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "anthropic" => Some(UpstreamKind::Anthropic),
+            "api_key" => Some(UpstreamKind::ApiKey),
+            "bedrock" => Some(UpstreamKind::Bedrock),
+            "vertex" => Some(UpstreamKind::Vertex),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            UpstreamKind::Anthropic => "anthropic",
+            UpstreamKind::ApiKey => "api_key",
+            UpstreamKind::Bedrock => "bedrock",
+            UpstreamKind::Vertex => "vertex",
+        }
+    }
+}
+
+/// Wire format of an upstream response body
+enum BodyFormat {
+    /// Messages API JSON or server-sent events, as Anthropic sends them
+    Anthropic,
+    /// Bedrock: JSON errors with only a message, binary event stream when streaming
+    Bedrock { streaming: bool },
+    /// Vertex AI: Anthropic's format, except for errors raised by Google's frontend
+    Vertex,
+}
+
+/// Response from any upstream, presented the way Anthropic's API would have sent it
+pub struct UpstreamResponse {
+    response: reqwest::Response,
+    format: BodyFormat,
+}
+
+impl UpstreamResponse {
+    pub fn anthropic(response: reqwest::Response) -> Self {
+        Self {
+            response,
+            format: BodyFormat::Anthropic,
+        }
+    }
+
+    pub fn bedrock(response: reqwest::Response, streaming: bool) -> Self {
+        Self {
+            response,
+            format: BodyFormat::Bedrock { streaming },
+        }
+    }
+
+    pub fn vertex(response: reqwest::Response) -> Self {
+        Self {
+            response,
+            format: BodyFormat::Vertex,
+        }
+    }
+
+    pub fn status(&self) -> reqwest::StatusCode {
+        self.response.status()
+    }
+
+    pub fn headers(&self) -> &reqwest::header::HeaderMap {
+        self.response.headers()
+    }
+
+    /// Whole body; error bodies are converted to Anthropic's error shape
+    pub async fn text(self) -> Result<String, reqwest::Error> {
+        let status = self.response.status();
+        let text = self.response.text().await?;
+        match self.format {
+            BodyFormat::Bedrock { .. } if !status.is_success() => Ok(anthropic_error(status, &text).to_string()),
+            BodyFormat::Vertex if !status.is_success() && !is_anthropic_error(&text) => {
+                Ok(anthropic_error(status, &text).to_string())
+            }
+            _ => Ok(text),
+        }
+    }
+
+    /// Body as a stream of server-sent event bytes
+    pub fn bytes_stream(self) -> BoxStream<'static, io::Result<Bytes>> {
+        let upstream = self.response.bytes_stream().map(|chunk| chunk.map_err(io::Error::other));
+        match self.format {
+            BodyFormat::Bedrock { streaming: true } => bedrock::event_stream_to_sse(upstream).boxed(),
+            _ => upstream.boxed(),
+        }
+    }
+}
+
+fn is_anthropic_error(body: &str) -> bool {
+    serde_json::from_str::<Value>(body).is_ok_and(|v| v.get("type").and_then(|t| t.as_str()) == Some("error"))
+}
+
+/// Wrap a non-Anthropic error body in Anthropic's error shape. Understands AWS
+/// (`{"message": ...}`) and Google (`{"error": {"message": ...}}`, possibly in a list)
+/// errors and plain text.
+fn anthropic_error(status: reqwest::StatusCode, body: &str) -> Value {
+    let message = serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|v| {
+            ["/message", "/error/message", "/0/error/message"]
+                .into_iter()
+                .find_map(|pointer| v.pointer(pointer).and_then(|m| m.as_str()).map(str::to_string))
+        })
+        .unwrap_or_else(|| body.to_string());
+    json!({
+        "type": "error",
+        "error": {"type": error::error_type_for(status.as_u16()), "message": message}
+    })
+}
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::sse::SseEvent;
+
+/// Token counts reported by Anthropic in a response's `usage` block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    #[serde(default)]
+    pub cache_creation_input_tokens: u64,
+    #[serde(default)]
+    pub cache_read_input_tokens: u64,
+}
+
+impl TokenUsage {
+    /// Extract usage from a non-streaming Messages API response body
+    pub fn from_response(response: &Value) -> Option<Self> {
+        let mut usage = Self::default();
+        usage.merge(response.get("usage")?);
+        Some(usage)
+    }
+
+    /// Overwrite the counts present in a `usage` block, keeping the others
+    fn merge(&mut self, usage: &Value) {
+        let count = |field: &str| usage.get(field).and_then(|v| v.as_u64());
+        if let Some(n) = count("input_tokens") {
+            self.input_tokens = n;
+        }
+        if let Some(n) = count("output_tokens") {
+            self.output_tokens = n;
+        }
+        if let Some(n) = count("cache_creation_input_tokens") {
+            self.cache_creation_input_tokens = n;
+        }
+        if let Some(n) = count("cache_read_input_tokens") {
+            self.cache_read_input_tokens = n;
+        }
+    }
+}
+
+/// Share of input tokens read from the prompt cache; None without input tokens.
+/// Anthropic counts cached tokens apart from `input_tokens`, so all three make up the prompt.
+pub fn cache_hit_rate(input_tokens: u64, cache_creation_input_tokens: u64, cache_read_input_tokens: u64) -> Option<f64> {
+    let prompt = input_tokens + cache_creation_input_tokens + cache_read_input_tokens;
+    (prompt > 0).then(|| cache_read_input_tokens as f64 / prompt as f64)
+}
+
+/// Follows a Messages API event stream to find its usage: message_start carries the input
+/// counts, message_delta the cumulative output count (and, on newer API versions, final input counts)
+#[derive(Debug, Default)]
+pub struct StreamUsage {
+    usage: Option<TokenUsage>,
+}
+
+impl StreamUsage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, event: &SseEvent) {
+        // Cheap pre-check so content deltas, the bulk of the stream, aren't parsed as JSON
+        if !event.data.contains("\"usage\"") {
+            return;
+        }
+        let Some(data) = event.json() else {
+            return;
+        };
+
+        let usage = match data.get("type").and_then(|t| t.as_str()) {
+            Some("message_start") => data.get("message").and_then(|m| m.get("usage")),
+            Some("message_delta") => data.get("usage"),
+            _ => None,
+        };
+        if let Some(usage) = usage {
+            self.usage.get_or_insert_with(TokenUsage::default).merge(usage);
+        }
+    }
+
+    /// Usage seen so far, if the stream reported any
+    pub fn usage(&self) -> Option<TokenUsage> {
+        self.usage
+    }
+}
+
+/// Tokens used by one client key since the proxy started
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, usage: &TokenUsage) {
+        self.requests += 1;
+        self.input_tokens += usage.input_tokens;
+        self.output_tokens += usage.output_tokens;
+        self.cache_creation_input_tokens += usage.cache_creation_input_tokens;
+        self.cache_read_input_tokens += usage.cache_read_input_tokens;
+    }
+
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        cache_hit_rate(self.input_tokens, self.cache_creation_input_tokens, self.cache_read_input_tokens)
+    }
+}
+
+/// In-memory usage totals per client key fingerprint (None for unauthenticated requests)
+/// and per tenant usage bucket
+#[derive(Default)]
+pub struct UsageTracker {
+    totals: Mutex<HashMap<Option<String>, UsageTotals>>,
+    buckets: Mutex<HashMap<String, UsageTotals>>,
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, key_id: Option<&str>, usage: &TokenUsage) {
+        let mut totals = self.totals.lock().unwrap();
+        totals.entry(key_id.map(str::to_string)).or_default().add(usage);
+    }
+
+    pub fn record_bucket(&self, bucket: &str, usage: &TokenUsage) {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.entry(bucket.to_string()).or_default().add(usage);
+    }
+
+    pub fn totals(&self, key_id: Option<&str>) -> UsageTotals {
+        let totals = self.totals.lock().unwrap();
+        totals.get(&key_id.map(str::to_string)).copied().unwrap_or_default()
+    }
+
+    pub fn bucket_totals(&self, bucket: &str) -> UsageTotals {
+        let buckets = self.buckets.lock().unwrap();
+        buckets.get(bucket).copied().unwrap_or_default()
+    }
+}
+
+/// One accounted request, as persisted in the usage log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub timestamp: i64,
+    pub request_id: String,
+    /// Fingerprint of the client key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    /// Usage bucket of the key's tenant, in multi-tenant mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Model the request was sent to
+    pub model: String,
+    #[serde(flatten)]
+    pub usage: TokenUsage,
+    /// Cost in USD at list prices, when the model's price is known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+/// Append-only JSON-lines log of accounted requests, kept next to the token file
+pub struct UsageLog {
+    path: PathBuf,
+    write_lock: Mutex<()>,
+}
+
+impl UsageLog {
+    pub fn for_token_file(token_path: &Path) -> Self {
+        let dir = token_path.parent().unwrap_or_else(|| Path::new("."));
+        Self {
+            path: dir.join("usage.jsonl"),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Persist a record; failures to write are logged, never fatal
+    pub fn record(&self, record: &UsageRecord) {
+        if let Err(e) = self.append(record) {
+            tracing::warn!("Failed to write usage log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn append(&self, record: &UsageRecord) -> anyhow::Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    }
+
+    /// Records with `from <= timestamp < to`, oldest first
+    pub fn read(&self, from: Option<i64>, to: Option<i64>) -> anyhow::Result<Vec<UsageRecord>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let contents = fs::read_to_string(&self.path)?;
+        Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str::<UsageRecord>(line).ok())
+            .filter(|r| from.is_none_or(|from| r.timestamp >= from) && to.is_none_or(|to| r.timestamp < to))
+            .collect())
+    }
+}
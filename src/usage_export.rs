@@ -0,0 +1,186 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::usage::UsageRecord;
+
+/// Columns of an export, in order
+const COLUMNS: &[&str] = &[
+    "timestamp",
+    "request_id",
+    "key_id",
+    "tenant",
+    "model",
+    "input_tokens",
+    "output_tokens",
+    "cache_creation_input_tokens",
+    "cache_read_input_tokens",
+    "cost",
+];
+
+#[cfg(feature = "parquet")]
+const PARQUET_SCHEMA: &str = "
+message usage {
+    REQUIRED INT64 timestamp (TIMESTAMP(MILLIS,true));
+    REQUIRED BYTE_ARRAY request_id (UTF8);
+    OPTIONAL BYTE_ARRAY key_id (UTF8);
+    OPTIONAL BYTE_ARRAY tenant (UTF8);
+    REQUIRED BYTE_ARRAY model (UTF8);
+    REQUIRED INT64 input_tokens;
+    REQUIRED INT64 output_tokens;
+    REQUIRED INT64 cache_creation_input_tokens;
+    REQUIRED INT64 cache_read_input_tokens;
+    OPTIONAL DOUBLE cost;
+}
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    /// Needs the `parquet` feature
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "csv" => Some(ExportFormat::Csv),
+            "parquet" => Some(ExportFormat::Parquet),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// Unix timestamp of an RFC 3339 time or a date (midnight UTC)
+pub fn parse_time(value: &str) -> Option<i64> {
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.timestamp());
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp())
+}
+
+/// Parse optional `from` and `to` bounds, explaining the first invalid one
+pub fn parse_range(from: Option<&str>, to: Option<&str>) -> Result<(Option<i64>, Option<i64>), String> {
+    let parse = |value: Option<&str>| match value {
+        Some(value) => parse_time(value)
+            .map(Some)
+            .ok_or_else(|| format!("invalid time '{}', expected RFC 3339 or YYYY-MM-DD", value)),
+        None => Ok(None),
+    };
+    Ok((parse(from)?, parse(to)?))
+}
+
+/// Encode usage records in the given format
+pub fn export(records: &[UsageRecord], format: ExportFormat) -> Result<Vec<u8>> {
+    match format {
+        ExportFormat::Csv => Ok(to_csv(records).into_bytes()),
+        ExportFormat::Parquet => to_parquet(records),
+    }
+}
+
+/// Quote a CSV field when it holds a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn to_csv(records: &[UsageRecord]) -> String {
+    let mut out = COLUMNS.join(",");
+    out.push('\n');
+    for record in records {
+        let timestamp = DateTime::<Utc>::from_timestamp(record.timestamp, 0)
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default();
+        let fields = [
+            timestamp,
+            csv_field(&record.request_id),
+            csv_field(record.key_id.as_deref().unwrap_or_default()),
+            csv_field(record.tenant.as_deref().unwrap_or_default()),
+            csv_field(&record.model),
+            record.usage.input_tokens.to_string(),
+            record.usage.output_tokens.to_string(),
+            record.usage.cache_creation_input_tokens.to_string(),
+            record.usage.cache_read_input_tokens.to_string(),
+            record.cost.map(|c| format!("{:.6}", c)).unwrap_or_default(),
+        ];
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(not(feature = "parquet"))]
+fn to_parquet(_records: &[UsageRecord]) -> Result<Vec<u8>> {
+    bail!("Parquet export is not available: rebuild with --features parquet")
+}
+
+#[cfg(feature = "parquet")]
+fn to_parquet(records: &[UsageRecord]) -> Result<Vec<u8>> {
+    use parquet::data_type::{ByteArray, ByteArrayType, DataType, DoubleType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+    use parquet::schema::parser::parse_message_type;
+    use std::sync::Arc;
+
+    /// Write the next column; optional columns take one definition level per row
+    fn write_column<T: DataType>(
+        row_group: &mut SerializedRowGroupWriter<'_, &mut Vec<u8>>,
+        values: &[T::T],
+        def_levels: Option<&[i16]>,
+    ) -> Result<()> {
+        let Some(mut column) = row_group.next_column()? else {
+            bail!("Parquet schema has fewer columns than the export");
+        };
+        column.typed::<T>().write_batch(values, def_levels, None)?;
+        column.close()?;
+        Ok(())
+    }
+
+    let tokens = |count: fn(&UsageRecord) -> u64| -> Vec<i64> { records.iter().map(|r| count(r) as i64).collect() };
+    let strings = |value: fn(&UsageRecord) -> &str| -> Vec<ByteArray> {
+        records.iter().map(|r| ByteArray::from(value(r))).collect()
+    };
+    let key_levels: Vec<i16> = records.iter().map(|r| r.key_id.is_some() as i16).collect();
+    let key_ids: Vec<ByteArray> = records.iter().filter_map(|r| r.key_id.as_deref()).map(ByteArray::from).collect();
+    let tenant_levels: Vec<i16> = records.iter().map(|r| r.tenant.is_some() as i16).collect();
+    let tenants: Vec<ByteArray> = records.iter().filter_map(|r| r.tenant.as_deref()).map(ByteArray::from).collect();
+    let timestamps: Vec<i64> = records.iter().map(|r| r.timestamp * 1000).collect();
+    let cost_levels: Vec<i16> = records.iter().map(|r| r.cost.is_some() as i16).collect();
+    let costs: Vec<f64> = records.iter().filter_map(|r| r.cost).collect();
+
+    let mut buffer = Vec::new();
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+    let mut writer = SerializedFileWriter::new(&mut buffer, schema, Arc::new(WriterProperties::builder().build()))?;
+    let mut row_group = writer.next_row_group()?;
+    write_column::<Int64Type>(&mut row_group, &timestamps, None)?;
+    write_column::<ByteArrayType>(&mut row_group, &strings(|r| &r.request_id), None)?;
+    write_column::<ByteArrayType>(&mut row_group, &key_ids, Some(&key_levels))?;
+    write_column::<ByteArrayType>(&mut row_group, &tenants, Some(&tenant_levels))?;
+    write_column::<ByteArrayType>(&mut row_group, &strings(|r| &r.model), None)?;
+    write_column::<Int64Type>(&mut row_group, &tokens(|r| r.usage.input_tokens), None)?;
+    write_column::<Int64Type>(&mut row_group, &tokens(|r| r.usage.output_tokens), None)?;
+    write_column::<Int64Type>(&mut row_group, &tokens(|r| r.usage.cache_creation_input_tokens), None)?;
+    write_column::<Int64Type>(&mut row_group, &tokens(|r| r.usage.cache_read_input_tokens), None)?;
+    write_column::<DoubleType>(&mut row_group, &costs, Some(&cost_levels))?;
+    row_group.close()?;
+    writer.close()?;
+    Ok(buffer)
+}
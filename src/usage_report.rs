@@ -0,0 +1,160 @@
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use console::style;
+use std::collections::BTreeMap;
+
+use crate::usage::{self, UsageRecord};
+
+/// Length of a Claude Max usage window, which opens with the first request after the
+/// previous one has ended
+const MAX_WINDOW_HOURS: i64 = 5;
+
+/// Usage summed over a group of records
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReportTotals {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
+    /// Cost of the records whose model has a price
+    pub cost: f64,
+    /// Records left out of the cost for lack of a price
+    pub unpriced: u64,
+}
+
+impl ReportTotals {
+    fn add(&mut self, record: &UsageRecord) {
+        self.requests += 1;
+        self.input_tokens += record.usage.input_tokens;
+        self.output_tokens += record.usage.output_tokens;
+        self.cache_creation_input_tokens += record.usage.cache_creation_input_tokens;
+        self.cache_read_input_tokens += record.usage.cache_read_input_tokens;
+        match record.cost {
+            Some(cost) => self.cost += cost,
+            None => self.unpriced += 1,
+        }
+    }
+
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        usage::cache_hit_rate(self.input_tokens, self.cache_creation_input_tokens, self.cache_read_input_tokens)
+    }
+}
+
+/// The Max window the proxy's traffic is estimated to be in
+#[derive(Debug, Clone, Copy)]
+pub struct MaxWindow {
+    pub start: DateTime<Utc>,
+    pub resets_at: DateTime<Utc>,
+    pub totals: ReportTotals,
+}
+
+/// Totals per key and per model of the records at or after `since`
+pub fn summarize(
+    records: &[UsageRecord],
+    since: DateTime<Utc>,
+) -> (BTreeMap<String, ReportTotals>, BTreeMap<String, ReportTotals>) {
+    let mut by_key: BTreeMap<String, ReportTotals> = BTreeMap::new();
+    let mut by_model: BTreeMap<String, ReportTotals> = BTreeMap::new();
+    for record in records.iter().filter(|r| r.timestamp >= since.timestamp()) {
+        let key = record.key_id.clone().unwrap_or_else(|| "(no key)".to_string());
+        by_key.entry(key).or_default().add(record);
+        by_model.entry(record.model.clone()).or_default().add(record);
+    }
+    (by_key, by_model)
+}
+
+/// Replay the records' timestamps to find the window open at `now`, if any. Only this
+/// proxy's traffic is seen, so the estimate misses other clients of the same account.
+pub fn max_window(records: &[UsageRecord], now: DateTime<Utc>) -> Option<MaxWindow> {
+    let length = Duration::hours(MAX_WINDOW_HOURS);
+    let mut timestamps: Vec<i64> = records.iter().map(|r| r.timestamp).collect();
+    timestamps.sort_unstable();
+
+    let mut start: Option<i64> = None;
+    for timestamp in timestamps {
+        if start.is_none_or(|start| timestamp >= start + length.num_seconds()) {
+            start = Some(timestamp);
+        }
+    }
+    let start = Utc.timestamp_opt(start?, 0).single()?;
+    let resets_at = start + length;
+    if resets_at <= now {
+        return None;
+    }
+
+    let mut totals = ReportTotals::default();
+    for record in records.iter().filter(|r| r.timestamp >= start.timestamp()) {
+        totals.add(record);
+    }
+    Some(MaxWindow { start, resets_at, totals })
+}
+
+fn print_table(label: &str, rows: &BTreeMap<String, ReportTotals>) {
+    println!(
+        "  {:<28} {:>8} {:>12} {:>12} {:>12} {:>12} {:>9} {:>10}",
+        label,
+        "Requests",
+        "Input",
+        "Output",
+        "Cache write",
+        "Cache read",
+        "Cache hit",
+        "Cost"
+    );
+    for (name, totals) in rows {
+        let marker = if totals.unpriced > 0 { "*" } else { "" };
+        println!(
+            "  {:<28} {:>8} {:>12} {:>12} {:>12} {:>12} {:>9} {:>10}",
+            name,
+            totals.requests,
+            totals.input_tokens,
+            totals.output_tokens,
+            totals.cache_creation_input_tokens,
+            totals.cache_read_input_tokens,
+            totals.cache_hit_rate().map(|rate| format!("{:.1}%", rate * 100.0)).unwrap_or_default(),
+            format!("${:.4}{}", totals.cost, marker)
+        );
+    }
+}
+
+/// Print usage for today and this week (UTC) and the estimated Max window
+pub fn print(records: &[UsageRecord], now: DateTime<Utc>) {
+    let today = now.date_naive().and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+    let week = today - Duration::days(now.weekday().num_days_from_monday() as i64);
+
+    for (title, since) in [("Today", today), ("This week", week)] {
+        println!("\n{} (since {} UTC)", style(title).cyan().bold(), since.format("%Y-%m-%d %H:%M"));
+        println!("{}", "-".repeat(110));
+        let (by_key, by_model) = summarize(records, since);
+        if by_key.is_empty() {
+            println!("  No requests");
+            continue;
+        }
+        print_table("Key", &by_key);
+        println!();
+        print_table("Model", &by_model);
+    }
+    if records.iter().any(|r| r.timestamp >= week.timestamp() && r.cost.is_none()) {
+        println!("\n  * cost leaves out requests to models without a known price");
+    }
+
+    println!("\n{}", style("Max window (estimated)").cyan().bold());
+    println!("{}", "-".repeat(110));
+    match max_window(records, now) {
+        Some(window) => {
+            let left = window.resets_at - now;
+            println!(
+                "  Started {} UTC, resets {} UTC ({}h {:02}m left)",
+                window.start.format("%H:%M"),
+                window.resets_at.format("%H:%M"),
+                left.num_hours(),
+                left.num_minutes() % 60
+            );
+            println!(
+                "  {} requests, {} input and {} output tokens so far",
+                window.totals.requests, window.totals.input_tokens, window.totals.output_tokens
+            );
+        }
+        None => println!("  No open window; the next request starts a new {}-hour window", MAX_WINDOW_HOURS),
+    }
+}
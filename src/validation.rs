@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+
+use crate::proxy::AnthropicMessageRequest;
+use crate::tools;
+
+/// How the proxy treats Messages requests Anthropic may not accept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationMode {
+    /// Forward anything plausible, unknown fields included, and let Anthropic decide
+    #[default]
+    Permissive,
+    /// Reject unknown fields and invalid parameter combinations locally, listing every problem
+    Strict,
+}
+
+impl ValidationMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "permissive" => Some(ValidationMode::Permissive),
+            "strict" => Some(ValidationMode::Strict),
+            _ => None,
+        }
+    }
+}
+
+/// Smallest thinking budget Anthropic accepts
+const MIN_THINKING_BUDGET: i32 = 1024;
+
+/// Every reason Anthropic would reject the request as the client sent it, checked before
+/// the proxy's own fix-ups. Empty when it is valid.
+pub fn check(request: &AnthropicMessageRequest) -> Vec<String> {
+    let mut problems: Vec<String> = request.extra.keys().map(|field| format!("{}: unknown field", field)).collect();
+
+    if request.messages.is_empty() {
+        problems.push("messages: at least one message is required".to_string());
+    }
+    for (index, message) in request.messages.iter().enumerate() {
+        match message.get("role").and_then(Value::as_str) {
+            Some("user" | "assistant") => {}
+            Some(role) => problems.push(format!("messages.{}.role: '{}' is not 'user' or 'assistant'", index, role)),
+            None => problems.push(format!("messages.{}.role: missing", index)),
+        }
+        if !message.get("content").is_some_and(|c| c.is_string() || c.is_array()) {
+            problems.push(format!("messages.{}.content: must be a string or a list of content blocks", index));
+        }
+    }
+
+    if request.max_tokens < 1 {
+        problems.push(format!("max_tokens: must be at least 1, got {}", request.max_tokens));
+    }
+    if let Some(temperature) = request.temperature.filter(|t| !(0.0..=1.0).contains(t)) {
+        problems.push(format!("temperature: must be between 0 and 1, got {}", temperature));
+    }
+    if let Some(top_p) = request.top_p.filter(|p| !(0.0..=1.0).contains(p)) {
+        problems.push(format!("top_p: must be between 0 and 1, got {}", top_p));
+    }
+    if let Some(top_k) = request.top_k.filter(|&k| k < 1) {
+        problems.push(format!("top_k: must be at least 1, got {}", top_k));
+    }
+
+    check_tools(request, &mut problems);
+    check_thinking(request, &mut problems);
+
+    if request.stream_options.is_some() && !request.stream {
+        problems.push("stream_options: only allowed with stream: true".to_string());
+    }
+    problems
+}
+
+fn check_tools(request: &AnthropicMessageRequest, problems: &mut Vec<String>) {
+    let mut names = HashSet::new();
+    for (index, tool) in request.tools.iter().flatten().enumerate() {
+        let Some(name) = tool.get("name").and_then(Value::as_str) else {
+            problems.push(format!("tools.{}.name: missing", index));
+            continue;
+        };
+        if !names.insert(name) {
+            problems.push(format!("tools.{}.name: '{}' is declared more than once", index, name));
+        }
+        // Server tools carry a versioned type instead of a schema
+        let custom = tool.get("type").and_then(Value::as_str).is_none_or(|t| t == "custom");
+        if custom && !tool.get("input_schema").is_some_and(Value::is_object) {
+            problems.push(format!("tools.{}.input_schema: required for custom tool '{}'", index, name));
+        }
+    }
+    if let Err(message) = tools::validate_computer_tools(request) {
+        problems.push(message);
+    }
+
+    let Some(choice) = &request.tool_choice else {
+        return;
+    };
+    match choice.get("type").and_then(Value::as_str) {
+        Some("auto" | "none") => {}
+        Some("any") if names.is_empty() => problems.push("tool_choice: 'any' needs tools".to_string()),
+        Some("any") => {}
+        Some("tool") => match choice.get("name").and_then(Value::as_str) {
+            Some(name) if !names.contains(name) => {
+                problems.push(format!("tool_choice.name: no tool named '{}' is declared", name))
+            }
+            Some(_) => {}
+            None => problems.push("tool_choice.name: required when type is 'tool'".to_string()),
+        },
+        Some(other) => problems.push(format!(
+            "tool_choice.type: '{}' is not one of auto, any, tool or none",
+            other
+        )),
+        None => problems.push("tool_choice.type: missing".to_string()),
+    }
+}
+
+fn check_thinking(request: &AnthropicMessageRequest, problems: &mut Vec<String>) {
+    let Some(thinking) = &request.thinking else {
+        return;
+    };
+    match thinking.type_.as_str() {
+        "enabled" => {}
+        "disabled" => return,
+        other => {
+            problems.push(format!("thinking.type: '{}' is not 'enabled' or 'disabled'", other));
+            return;
+        }
+    }
+
+    if thinking.budget_tokens < MIN_THINKING_BUDGET {
+        problems.push(format!(
+            "thinking.budget_tokens: must be at least {}, got {}",
+            MIN_THINKING_BUDGET, thinking.budget_tokens
+        ));
+    }
+    if thinking.budget_tokens >= request.max_tokens {
+        problems.push(format!(
+            "thinking.budget_tokens: must be less than max_tokens ({}), got {}",
+            request.max_tokens, thinking.budget_tokens
+        ));
+    }
+    if let Some(temperature) = request.temperature.filter(|&t| t != 1.0) {
+        problems.push(format!("temperature: must be 1 or unset with thinking, got {}", temperature));
+    }
+    if let Some(top_p) = request.top_p.filter(|&p| p < 0.95) {
+        problems.push(format!("top_p: must be between 0.95 and 1 with thinking, got {}", top_p));
+    }
+    if request.top_k.is_some() {
+        problems.push("top_k: not allowed with thinking".to_string());
+    }
+    if let Some(kind @ ("any" | "tool")) = request.tool_choice.as_ref().and_then(|c| c.get("type")).and_then(Value::as_str) {
+        problems.push(format!("tool_choice: '{}' forces tool use, which thinking doesn't allow", kind));
+    }
+}
@@ -0,0 +1,289 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::proxy::AnthropicMessageRequest;
+use crate::settings::VertexConfig;
+use crate::upstream::UpstreamResponse;
+
+/// `anthropic_version` Vertex AI expects in the request body
+const VERTEX_ANTHROPIC_VERSION: &str = "vertex-2023-10-16";
+
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// Access tokens are refreshed this long before they expire
+const EXPIRY_MARGIN_SECS: i64 = 60;
+
+/// Where Google access tokens come from
+enum Credentials {
+    /// Configured access token, used as is
+    Static(String),
+    /// `gcloud auth application-default login` credentials
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        token_uri: String,
+    },
+    /// The GCE/GKE/Cloud Run metadata server
+    Metadata,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CredentialsFile {
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        #[serde(default)]
+        token_uri: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct JwtClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+struct CachedToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Sends Messages API requests to Anthropic models on Google Vertex AI
+pub struct VertexClient {
+    config: VertexConfig,
+    project_id: String,
+    credentials: Credentials,
+    token: Mutex<Option<CachedToken>>,
+    client: reqwest::Client,
+}
+
+impl VertexClient {
+    /// None unless a project is configured and credentials can be loaded. Credentials are,
+    /// in order: the configured access token, the configured credentials file, gcloud's
+    /// application default credentials, the metadata server.
+    pub fn new(config: &VertexConfig, client: reqwest::Client) -> Option<Self> {
+        let project_id = config.project_id.clone()?;
+        let credentials = match load_credentials(config) {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                warn!("Vertex AI upstream disabled: {:#}", e);
+                return None;
+            }
+        };
+
+        Some(Self {
+            config: config.clone(),
+            project_id,
+            credentials,
+            token: Mutex::new(None),
+            client,
+        })
+    }
+
+    /// Vertex model ID for an Anthropic model name: configured, or the model name with
+    /// its date joined by '@' the way Vertex names Anthropic's models
+    pub fn model_id(&self, model: &str) -> String {
+        if let Some(id) = self.config.model_ids.get(model) {
+            return id.clone();
+        }
+        match model.rsplit_once('-') {
+            Some((name, date)) if date.len() == 8 && date.chars().all(|c| c.is_ascii_digit()) => {
+                format!("{}@{}", name, date)
+            }
+            _ => model.to_string(),
+        }
+    }
+
+    pub async fn send(&self, request: &AnthropicMessageRequest, betas: &[&str]) -> Result<UpstreamResponse> {
+        // reqwest errors already include their cause, so the chain is not formatted with {:#}
+        let access_token = self
+            .access_token()
+            .await
+            .map_err(|e| anyhow!("Failed to get a Google access token: {}", e))?;
+
+        let region = &self.config.region;
+        let host = if region == "global" {
+            "aiplatform.googleapis.com".to_string()
+        } else {
+            format!("{}-aiplatform.googleapis.com", region)
+        };
+        let method = if request.stream { "streamRawPredict" } else { "rawPredict" };
+        let url = format!(
+            "https://{}/v1/projects/{}/locations/{}/publishers/anthropic/models/{}:{}",
+            host,
+            self.project_id,
+            region,
+            self.model_id(&request.model),
+            method
+        );
+
+        // Vertex takes the model from the URL and the version in the body, and has no
+        // service tiers
+        let mut body = serde_json::to_value(request).unwrap_or_else(|_| json!({}));
+        if let Value::Object(map) = &mut body {
+            map.remove("model");
+            map.remove("service_tier");
+            map.insert("anthropic_version".to_string(), json!(VERTEX_ANTHROPIC_VERSION));
+        }
+
+        let mut builder = self.client.post(url).bearer_auth(access_token).json(&body);
+        if !betas.is_empty() {
+            builder = builder.header("anthropic-beta", betas.join(","));
+        }
+        Ok(UpstreamResponse::vertex(builder.send().await?))
+    }
+
+    /// Current access token, fetching a new one shortly before the cached one expires
+    async fn access_token(&self) -> Result<String> {
+        if let Credentials::Static(token) = &self.credentials {
+            return Ok(token.clone());
+        }
+
+        let mut cached = self.token.lock().await;
+        if let Some(token) = cached.as_ref() {
+            if token.expires_at - Duration::seconds(EXPIRY_MARGIN_SECS) > Utc::now() {
+                return Ok(token.token.clone());
+            }
+        }
+
+        let response = self.fetch_token().await?;
+        info!("Obtained Google access token for Vertex AI (expires in {}s)", response.expires_in);
+        let token = response.access_token.clone();
+        *cached = Some(CachedToken {
+            token: response.access_token,
+            expires_at: Utc::now() + Duration::seconds(response.expires_in),
+        });
+        Ok(token)
+    }
+
+    async fn fetch_token(&self) -> Result<TokenResponse> {
+        let response = match &self.credentials {
+            Credentials::Static(_) => unreachable!("static tokens are never fetched"),
+            Credentials::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+            } => {
+                self.client
+                    .post(GOOGLE_TOKEN_URL)
+                    .form(&[
+                        ("grant_type", "refresh_token"),
+                        ("client_id", client_id),
+                        ("client_secret", client_secret),
+                        ("refresh_token", refresh_token),
+                    ])
+                    .send()
+                    .await?
+            }
+            Credentials::ServiceAccount {
+                client_email,
+                private_key,
+                token_uri,
+            } => {
+                let now = Utc::now().timestamp();
+                let claims = JwtClaims {
+                    iss: client_email,
+                    scope: CLOUD_PLATFORM_SCOPE,
+                    aud: token_uri,
+                    iat: now,
+                    exp: now + 3600,
+                };
+                let key = jsonwebtoken::EncodingKey::from_rsa_pem(private_key.as_bytes())
+                    .map_err(|e| anyhow!("Invalid service account private key: {}", e))?;
+                let assertion = jsonwebtoken::encode(&jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256), &claims, &key)?;
+                self.client
+                    .post(token_uri)
+                    .form(&[
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                        ("assertion", &assertion),
+                    ])
+                    .send()
+                    .await?
+            }
+            Credentials::Metadata => {
+                self.client
+                    .get(METADATA_TOKEN_URL)
+                    .header("Metadata-Flavor", "Google")
+                    .send()
+                    .await?
+            }
+        };
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("token endpoint returned {}: {}", status, body);
+        }
+        Ok(response.json().await?)
+    }
+}
+
+fn load_credentials(config: &VertexConfig) -> Result<Credentials> {
+    if let Some(token) = &config.access_token {
+        return Ok(Credentials::Static(token.clone()));
+    }
+
+    let path = match &config.credentials_file {
+        Some(path) => Some(PathBuf::from(path)),
+        None => dirs::config_dir()
+            .map(|dir| dir.join("gcloud").join("application_default_credentials.json"))
+            .filter(|path| path.exists()),
+    };
+    let Some(path) = path else {
+        return Ok(Credentials::Metadata);
+    };
+
+    let contents =
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let file: CredentialsFile = serde_json::from_str(&contents)
+        .map_err(|e| anyhow!("{}: not authorized_user or service_account credentials: {}", path.display(), e))?;
+
+    Ok(match file {
+        CredentialsFile::AuthorizedUser {
+            client_id,
+            client_secret,
+            refresh_token,
+        } => Credentials::AuthorizedUser {
+            client_id,
+            client_secret,
+            refresh_token,
+        },
+        CredentialsFile::ServiceAccount {
+            client_email,
+            private_key,
+            token_uri,
+        } => Credentials::ServiceAccount {
+            client_email,
+            private_key,
+            token_uri: token_uri.unwrap_or_else(|| GOOGLE_TOKEN_URL.to_string()),
+        },
+    })
+}
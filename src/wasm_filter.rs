@@ -0,0 +1,124 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::path::Path;
+use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::error::ProxyError;
+use crate::proxy::{AnthropicMessageRequest, HookContext, RequestHook, ResponseHook};
+
+/// Instructions a filter may execute per call before it is aborted
+const FUEL_PER_CALL: u64 = 50_000_000;
+
+/// Maximum linear memory a filter instance may grow to
+const MAX_MEMORY_BYTES: usize = 64 * 1024 * 1024;
+
+/// Filter implemented as a WebAssembly module.
+///
+/// Modules get no imports (no WASI, no host functions), so they can only transform the
+/// bytes they are handed. The expected exports are:
+///
+/// - `memory`: the module's linear memory
+/// - `alloc(len: i32) -> i32`: reserve `len` bytes and return a pointer to them
+/// - `filter_request(ptr: i32, len: i32) -> i64` (optional)
+/// - `filter_response(ptr: i32, len: i32) -> i64` (optional)
+///
+/// Filters receive UTF-8 JSON and return `(out_ptr << 32) | out_len` pointing at the
+/// replacement JSON, or `0` to leave the input unchanged. Every call runs in a fresh
+/// instance with bounded fuel and memory.
+pub struct WasmFilter {
+    name: String,
+    engine: Engine,
+    module: Module,
+}
+
+impl WasmFilter {
+    pub fn load(path: &str) -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::from_file(&engine, path)
+            .with_context(|| format!("Failed to load WASM filter: {}", path))?;
+
+        let name = Path::new(path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string());
+
+        Ok(Self { name, engine, module })
+    }
+
+    /// Run `export` over `input`; `Ok(None)` when the export is missing or returns 0
+    fn run(&self, export: &str, input: &[u8]) -> Result<Option<Vec<u8>>> {
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY_BYTES).build();
+        let mut store: Store<StoreLimits> = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FUEL_PER_CALL)?;
+
+        let instance = Instance::new(&mut store, &self.module, &[])?;
+        let Ok(filter) = instance.get_typed_func::<(i32, i32), i64>(&mut store, export) else {
+            return Ok(None);
+        };
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("WASM filter does not export 'memory'")?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .context("WASM filter does not export 'alloc'")?;
+
+        let input_len = i32::try_from(input.len()).context("Input too large for WASM filter")?;
+        let input_ptr = alloc.call(&mut store, input_len)?;
+        memory.write(&mut store, input_ptr as u32 as usize, input)?;
+
+        let packed = filter.call(&mut store, (input_ptr, input_len))?;
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let output_ptr = (packed as u64 >> 32) as usize;
+        let output_len = (packed as u64 & 0xffff_ffff) as usize;
+        let mut output = vec![0u8; output_len];
+        memory.read(&store, output_ptr, &mut output)?;
+
+        Ok(Some(output))
+    }
+}
+
+impl RequestHook for WasmFilter {
+    fn on_request(&self, ctx: &HookContext, request: &mut AnthropicMessageRequest) -> Result<(), ProxyError> {
+        let filter_error = |e: anyhow::Error| {
+            tracing::error!("[{}] WASM filter '{}' failed: {}", ctx.request_id, self.name, e);
+            ProxyError::Internal(format!("WASM filter '{}' failed", self.name))
+        };
+
+        let input = serde_json::to_vec(&*request).map_err(|e| filter_error(e.into()))?;
+        if let Some(output) = self.run("filter_request", &input).map_err(filter_error)? {
+            *request = serde_json::from_slice(&output).map_err(|e| filter_error(e.into()))?;
+            tracing::debug!("[{}] Request rewritten by WASM filter '{}'", ctx.request_id, self.name);
+        }
+
+        Ok(())
+    }
+}
+
+impl ResponseHook for WasmFilter {
+    fn on_response(&self, ctx: &HookContext, response: &mut Value) {
+        let result = serde_json::to_vec(response)
+            .map_err(anyhow::Error::from)
+            .and_then(|input| self.run("filter_response", &input))
+            .and_then(|output| match output {
+                Some(bytes) => Ok(Some(serde_json::from_slice::<Value>(&bytes)?)),
+                None => Ok(None),
+            });
+
+        match result {
+            Ok(Some(output)) => {
+                *response = output;
+                tracing::debug!("[{}] Response rewritten by WASM filter '{}'", ctx.request_id, self.name);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::error!("[{}] WASM filter '{}' failed: {}", ctx.request_id, self.name, e);
+            }
+        }
+    }
+}
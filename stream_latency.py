@@ -0,0 +1,127 @@
+"""
+Streaming latency harness for Maximize proxy
+Streams a few responses through the proxy, measures time to first byte and the gaps
+between chunks as the client sees them, and compares them with the proxy's own metrics
+to show how much latency the proxy adds.
+
+Usage: python stream_latency.py [runs]
+"""
+import os
+import re
+import sys
+import time
+
+import requests
+
+# Configuration
+BASE_URL = os.getenv("MAXIMIZE_BASE_URL", "http://localhost:8081")
+API_KEY = os.getenv("MAXIMIZE_API_KEY", "dummy")
+MODEL = os.getenv("MAXIMIZE_MODEL", "xs")
+PROMPT = "Count from 1 to 60, one number per line, with no other text."
+
+
+def percentile(values, fraction):
+    """Nearest-rank percentile of a non-empty list"""
+    ordered = sorted(values)
+    return ordered[min(len(ordered) - 1, int(fraction * len(ordered)))]
+
+
+def stream_once():
+    """Stream one response; returns (ttfb, gaps between reads, SSE events per read)"""
+    start = time.perf_counter()
+    response = requests.post(
+        f"{BASE_URL}/v1/messages",
+        headers={
+            "x-api-key": API_KEY,
+            "anthropic-version": "2023-06-01",
+            "content-type": "application/json",
+        },
+        json={
+            "model": MODEL,
+            "max_tokens": 512,
+            "stream": True,
+            "messages": [{"role": "user", "content": PROMPT}],
+        },
+        stream=True,
+        timeout=120,
+    )
+    if response.status_code != 200:
+        raise RuntimeError(f"status {response.status_code}: {response.text[:200]}")
+
+    arrivals = []
+    events_per_read = []
+    # chunk_size=None hands over data as soon as it arrives, without waiting to fill a buffer
+    for data in response.iter_content(chunk_size=None):
+        arrivals.append(time.perf_counter())
+        events_per_read.append(data.count(b"\n\n"))
+
+    if not arrivals:
+        raise RuntimeError("empty response")
+    gaps = [b - a for a, b in zip(arrivals, arrivals[1:])]
+    return arrivals[0] - start, gaps, events_per_read
+
+
+def scrape_metrics():
+    """Every maximize_ series on /metrics, keyed by name and labels"""
+    text = requests.get(f"{BASE_URL}/metrics", timeout=5).text
+    series = {}
+    for name, labels, value in re.findall(r'^(maximize_[a-z_]+?)(\{[^}]*\})? ([0-9.e+-]+)$', text, re.M):
+        series[name + labels] = float(value)
+    return series
+
+
+def average(series, name, labels=""):
+    count = series.get(f"{name}_count{labels}", 0)
+    return series.get(f"{name}_sum{labels}", 0) / count if count else None
+
+
+def main():
+    runs = int(sys.argv[1]) if len(sys.argv) > 1 else 3
+    print(f"Streaming {runs} responses from {MODEL} through {BASE_URL}\n")
+
+    before = scrape_metrics()
+    ttfbs, gaps, events = [], [], []
+    for run in range(1, runs + 1):
+        try:
+            ttfb, run_gaps, run_events = stream_once()
+        except Exception as e:
+            print(f"❌ Run {run} failed: {e}")
+            sys.exit(1)
+        ttfbs.append(ttfb)
+        gaps.extend(run_gaps)
+        events.extend(run_events)
+        print(f"  run {run}: first byte after {ttfb * 1000:.0f}ms, {len(run_events)} reads")
+    after = scrape_metrics()
+
+    print("\nAs seen by the client:")
+    print(f"  time to first byte   p50 {percentile(ttfbs, 0.5) * 1000:.0f}ms, max {max(ttfbs) * 1000:.0f}ms")
+    if gaps:
+        print(
+            f"  gap between chunks   p50 {percentile(gaps, 0.5) * 1000:.1f}ms, "
+            f"p95 {percentile(gaps, 0.95) * 1000:.1f}ms, max {max(gaps) * 1000:.1f}ms"
+        )
+    print(f"  SSE events per read  avg {sum(events) / len(events):.2f}, max {max(events)}")
+
+    # Only this harness's requests, assuming no other traffic while it ran
+    delta = {key: after.get(key, 0) - before.get(key, 0) for key in after}
+    first_byte = average(delta, "maximize_stream_first_byte_seconds")
+    upstream = average(delta, "maximize_phase_duration_seconds", '{phase="upstream_ttfb"}')
+    chunk_delay = average(delta, "maximize_stream_chunk_delay_seconds")
+
+    print("\nAs measured by the proxy (/metrics):")
+    if first_byte is not None and upstream is not None:
+        print(f"  first byte to client avg {first_byte * 1000:.1f}ms")
+        print(f"  upstream TTFB        avg {upstream * 1000:.1f}ms")
+        print(f"  added before first byte (queue, token, transform) avg {(first_byte - upstream) * 1000:.1f}ms")
+    if chunk_delay is not None:
+        print(f"  per-chunk delay in the proxy avg {chunk_delay * 1000:.3f}ms")
+
+    # Anthropic sends roughly one event per write; many per read means something buffered them
+    if events and sum(events) / len(events) > 3:
+        print("\n⚠️  Events arrive in batches: check for a buffering reverse proxy in front of Maximize")
+    else:
+        print("\n✅ Events arrive as they are produced")
+
+
+if __name__ == "__main__":
+    main()